@@ -1,4 +1,6 @@
 use std::fmt;
+use std::path::Path;
+use std::sync::MutexGuard;
 
 use cdimage::{Image, CdError};
 use cdimage::msf::Msf;
@@ -7,24 +9,43 @@ use cdimage::sector::Sector;
 
 use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
 
+use parallel_io::exe_loader::{self, ExeLoader};
+
 use super::iso9660;
+use super::subchannel;
+use super::prefetch::Prefetcher;
+use super::system_cnf::SystemCnf;
 
 /// PlayStation disc.
 ///
 /// XXX: add support for CD-DA? Not really useful but shouldn't
 /// be very hard either. We need to support audio tracks anyway...
 pub struct Disc {
-    /// Image file
-    image: Box<Image>,
+    /// Image file, accessed through the read-ahead cache
+    reader: Prefetcher,
     /// Disc serial number
     serial: SerialNumber,
+    /// Contents of the SYSTEM.CNF file. Not stored in savestates.
+    system_cnf: Option<SystemCnf>,
+    /// Subchannel Q patches loaded from a side file (used to emulate
+    /// LibCrypt-protected discs)
+    subchannel_patches: subchannel::Patches,
 }
 
 impl Disc {
-    /// Reify a disc using `image` as a backend.
-    pub fn new(mut image: Box<Image>) -> Result<Disc, String> {
+    /// Reify a disc using `image` as a backend. The image must be
+    /// `Send` since it's accessed from the read-ahead thread.
+    pub fn new(mut image: Box<Image + Send>) -> Result<Disc, String> {
+        let system_cnf =
+            match read_system_cnf(&mut *image) {
+                Ok(c) => c,
+                Err(e) => {
+                    return Err(format!("Couldn't read SYSTEM.CNF: {:?}", e));
+                }
+            };
+
         let serial =
-            match extract_serial_number(&mut *image) {
+            match extract_serial_number(&system_cnf) {
                 Some(s) => s,
                 None => {
                     return Err("Couldn't find disc serial number".into());
@@ -32,8 +53,10 @@ impl Disc {
             };
 
         let disc = Disc {
-            image: image,
+            reader: Prefetcher::new(image),
             serial: serial,
+            system_cnf: Some(system_cnf),
+            subchannel_patches: subchannel::Patches::new(),
         };
 
         Ok(disc)
@@ -53,8 +76,76 @@ impl Disc {
         self.serial
     }
 
-    pub fn image(&mut self) -> &mut Image {
-        &mut*self.image
+    /// Return the parsed SYSTEM.CNF. Returns `None` if the disc was
+    /// restored from a savestate.
+    pub fn system_cnf(&self) -> Option<&SystemCnf> {
+        self.system_cnf.as_ref()
+    }
+
+    /// Load the executable the BIOS would boot: the BOOT entry of
+    /// SYSTEM.CNF. Combined with `ExeLoader::patch_bios` this can be
+    /// used to boot the game without going through the BIOS shell.
+    ///
+    /// Fails if the disc was restored from a savestate, the frontend
+    /// has to reload the image first.
+    pub fn boot_executable(&mut self) -> Result<ExeLoader, exe_loader::Error> {
+        let (boot, stack) =
+            match self.system_cnf {
+                Some(ref c) => (c.boot.clone(), c.stack),
+                None => return Err(exe_loader::Error::MissingSystemCnf),
+            };
+
+        let mut image = self.image();
+
+        let root = try!(iso9660::open_image(&mut **image));
+
+        let entry = try!(root.entry_by_path(&mut **image, &boot));
+
+        let mut reader = try!(entry.reader(&mut **image));
+
+        let mut exe = try!(ExeLoader::load(&mut reader));
+
+        // If the executable doesn't specify a stack pointer the BIOS
+        // uses the one from SYSTEM.CNF
+        exe.set_default_sp(stack);
+
+        Ok(exe)
+    }
+
+    /// Lock and return the underlying disc image. Read-ahead is
+    /// suspended while the returned guard is alive.
+    pub fn image(&mut self) -> MutexGuard<Box<Image + Send>> {
+        self.reader.image()
+    }
+
+    /// Read the sector at `msf`, using the read-ahead cache if
+    /// possible.
+    pub fn read_sector(&mut self, msf: Msf) -> Result<Sector, CdError> {
+        self.reader.read_sector(msf)
+    }
+
+    /// Load a .SBI or .LSD file containing the subchannel Q data of
+    /// the sectors modified by the LibCrypt protection. Replaces any
+    /// previously loaded patches.
+    pub fn load_subchannel_patches(&mut self,
+                                   path: &Path) -> Result<(), subchannel::Error> {
+        let patches = try!(subchannel::Patches::load_file(path));
+
+        info!("Loaded {} subchannel Q patches", patches.len());
+
+        self.subchannel_patches = patches;
+
+        Ok(())
+    }
+
+    /// Return the Q subchannel data for `sector` which must have been
+    /// read from this disc, with the side file patches applied.
+    pub fn subchannel_q(&self, sector: &Sector) -> subchannel::Q {
+        let mut q = subchannel::Q::from_sector(sector);
+
+        self.subchannel_patches.apply(sector.metadata().msf, &mut q);
+
+        q
     }
 }
 
@@ -69,10 +160,12 @@ impl Decodable for Disc {
     fn decode<D: Decoder>(d: &mut D) -> Result<Disc, D::Error> {
         let serial = try!(SerialNumber::decode(d));
 
-        // Placeholder disc image
+        // Placeholder disc image, there's nothing to read ahead
         Ok(Disc {
-            image: Box::new(MissingImage),
+            reader: Prefetcher::synchronous(Box::new(MissingImage)),
             serial: serial,
+            system_cnf: None,
+            subchannel_patches: subchannel::Patches::new(),
         })
     }
 }
@@ -109,7 +202,7 @@ pub enum Region {
 }
 
 /// Disc serial number
-#[derive(Copy, Clone, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, RustcDecodable, RustcEncodable)]
 pub struct SerialNumber([u8; 10]);
 
 impl SerialNumber {
@@ -225,59 +318,14 @@ pub fn extract_system_region(image: &mut Image) -> Result<Region, CdError> {
     Ok(region)
 }
 
-/// Attempt to extract the serial number of the disc. All officially
-/// licensed PlayStation game should have a serial number.
-fn extract_serial_number(image: &mut Image) -> Option<SerialNumber> {
-
-    let system_cnf =
-        match read_system_cnf(image) {
-            Ok(c) => c,
-            Err(e) => {
-                warn!("Couldn't read SYSTEM.CNF: {:?}", e);
-                return None;
-            }
-        };
-
-    // Now we need to parse the SYSTEM.CNF file to get the content of
-    // the "BOOT" line
-    let mut boot_path = None;
-
-    for line in system_cnf.split(|&b| b == b'\n') {
-        let words: Vec<_> = line
-            .split(|&b| b == b' ' || b == b'\t' || b == b'=')
-            .filter(|w| !w.is_empty())
-            .collect();
+/// Attempt to extract the serial number of the disc from the boot
+/// executable name. All officially licensed PlayStation game should
+/// have a serial number.
+fn extract_serial_number(system_cnf: &SystemCnf) -> Option<SerialNumber> {
+    // boot path should look like "cdrom:\FOO\BAR\...\aaaa_ddd.dd;1"
+    let bin_name = system_cnf.boot_file_name();
 
-        if words.len() == 2 {
-            if words[0] == b"BOOT" {
-                boot_path = Some(words[1]);
-                break;
-            }
-        }
-    }
-
-    let boot_path =
-        match boot_path {
-            Some(b) => b,
-            None => {
-                warn!("Couldn't find BOOT line in SYSTEM.CNF");
-                return None;
-            }
-        };
-
-    // boot_path should look like "cdrom:\FOO\BAR\...\aaaa_ddd.dd;1"
-    let path: Vec<_> = boot_path
-        .split(|&b| b == b':' || b == b';' || b == b'\\')
-        .collect();
-
-    if path.len() < 2 {
-        warn!("Unexpected boot path: {}", String::from_utf8_lossy(boot_path));
-        return None;
-    }
-
-    let bin_name = path[path.len() - 2];
-
-    let serial = SerialNumber::from_bin_name(&bin_name);
+    let serial = SerialNumber::from_bin_name(bin_name);
 
     if serial.is_none() {
         warn!("Unexpected bin name: {}", String::from_utf8_lossy(bin_name));
@@ -286,7 +334,7 @@ fn extract_serial_number(image: &mut Image) -> Option<SerialNumber> {
     serial
 }
 
-fn read_system_cnf(image: &mut Image) -> Result<Vec<u8>, iso9660::Error> {
+fn read_system_cnf(image: &mut Image) -> Result<SystemCnf, iso9660::Error> {
     let dir = try!(iso9660::open_image(image));
 
     let system_cnf = try!(dir.entry_by_name(b"SYSTEM.CNF;1"));
@@ -300,5 +348,11 @@ fn read_system_cnf(image: &mut Image) -> Result<Vec<u8>, iso9660::Error> {
         return Err(iso9660::Error::BadFormat(desc));
     }
 
-    system_cnf.read_file(image)
+    let raw = try!(system_cnf.read_file(image));
+
+    SystemCnf::parse(&raw).map_err(|e| {
+        let desc = format!("Invalid SYSTEM.CNF: {:?}", e);
+
+        iso9660::Error::BadFormat(desc)
+    })
 }
@@ -1,3 +1,6 @@
+use std::io;
+use std::vec;
+
 use cdimage::{Image, CdError};
 use cdimage::sector::Sector;
 use cdimage::msf::Msf;
@@ -24,15 +27,8 @@ impl Directory {
         // Directory entries cannot span multiple sectors so it's safe
         // to handle them one by one
         let mut extent_len = entry.extent_len() as usize;
-        let extent_location = entry.extent_location();
-
-        let track_msf =
-            match Msf::from_sector_index(extent_location) {
-                Some(m) => m,
-                None => return Err(Error::BadExtent(extent_location)),
-            };
 
-        let mut msf = try!(image.track_msf(Bcd::one(), track_msf));
+        let mut msf = try!(extent_msf(image, entry.extent_location()));
 
         let mut sector = Sector::empty();
 
@@ -48,7 +44,7 @@ impl Directory {
                     extent_len
                 };
 
-            try!(dir.parse_entries(&data[0..len]));
+            try!(dir.parse_entries(&data[0..len], entry.joliet));
 
             extent_len -= len;
             msf = msf.next().unwrap();
@@ -57,7 +53,7 @@ impl Directory {
         Ok(dir)
     }
 
-    fn parse_entries(&mut self, mut raw: &[u8]) -> Result<(), Error> {
+    fn parse_entries(&mut self, mut raw: &[u8], joliet: bool) -> Result<(), Error> {
 
         while raw.len() > 0 {
             let dir_len = raw[0] as usize;
@@ -77,6 +73,10 @@ impl Directory {
                 return Err(Error::BadFormat(desc));
             }
 
+            if dir_len > raw.len() {
+                return Err(Error::BadFormat("Truncated directory entry".into()));
+            }
+
             let name_len = raw[32] as usize;
 
             let name_end = 33 + name_len;
@@ -85,7 +85,26 @@ impl Directory {
                 return Err(Error::BadFormat("Entry name too long".into()));
             }
 
-            self.entries.push(Entry::new(&raw[0..dir_len]));
+            let entry = Entry::new(&raw[0..dir_len], joliet);
+
+            // Files bigger than 4GB (or whose extents are not
+            // contiguous) are split in several records with the same
+            // name. All records but the last one have the
+            // "multi-extent" flag set.
+            let continuation =
+                match self.entries.last() {
+                    Some(prev) => prev.is_multi_extent() &&
+                        prev.name() == entry.name(),
+                    None => false,
+                };
+
+            if continuation {
+                let prev = self.entries.last_mut().unwrap();
+
+                prev.add_extent(&entry);
+            } else {
+                self.entries.push(entry);
+            }
 
             raw = &raw[dir_len..];
         }
@@ -103,75 +122,374 @@ impl Directory {
         Directory::new(image, entry)
     }
 
+    /// Look for an entry in this directory. The comparison is case
+    /// insensitive and the version suffix (";1") can be omitted.
     pub fn entry_by_name(&self, name: &[u8]) -> Result<&Entry, Error> {
         match
-            self.entries.iter().find(|e| e.name() == name) {
+            self.entries.iter().find(|e| e.matches_name(name)) {
                 Some(e) => Ok(e),
                 None => Err(Error::EntryNotFound),
             }
     }
 
+    /// Look for an entry using a path relative to this directory,
+    /// for instance `\MOVIES\INTRO.STR;1`. A leading device name
+    /// (such as `cdrom:`) is ignored, both `\` and `/` are accepted
+    /// as separators.
+    pub fn entry_by_path(&self,
+                         image: &mut Image,
+                         path: &[u8]) -> Result<Entry, Error> {
+        // Strip the device name, if any
+        let path =
+            match path.iter().position(|&b| b == b':') {
+                Some(p) => &path[p + 1..],
+                None => path,
+            };
+
+        let mut components =
+            path.split(|&b| b == b'\\' || b == b'/')
+            .filter(|c| !c.is_empty())
+            .peekable();
+
+        let mut dir = None;
+        let mut entry = None;
+
+        while let Some(name) = components.next() {
+            let e = {
+                let cur = match dir {
+                    Some(ref d) => d,
+                    None => self,
+                };
+
+                try!(cur.entry_by_name(name)).clone()
+            };
+
+            if components.peek().is_some() {
+                dir = Some(try!(Directory::new(image, &e)));
+            }
+
+            entry = Some(e);
+        }
+
+        match entry {
+            Some(e) => Ok(e),
+            // Empty path
+            None => Err(Error::EntryNotFound),
+        }
+    }
+
     /// Retreive a list of all the entries in this directory
     pub fn ls(&self) -> &[Entry] {
         &self.entries
     }
+
+    /// Recursively iterate over all the entries in this directory and
+    /// its subdirectories. The `.` and `..` entries are skipped.
+    pub fn walk<'a>(&self, image: &'a mut Image) -> Walk<'a> {
+        Walk {
+            image: image,
+            stack: vec![(String::new(), self.entries.clone().into_iter())],
+        }
+    }
+}
+
+/// Recursive directory iterator, returned by `Directory::walk`. Yields
+/// the full path of each entry along with the entry itself.
+pub struct Walk<'a> {
+    image: &'a mut Image,
+    /// Stack of directories being explored along with their path
+    stack: Vec<(String, vec::IntoIter<Entry>)>,
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = Result<(String, Entry), Error>;
+
+    fn next(&mut self) -> Option<Result<(String, Entry), Error>> {
+        loop {
+            let next =
+                match self.stack.last_mut() {
+                    Some(&mut (ref path, ref mut entries)) =>
+                        entries.next().map(|e| (path.clone(), e)),
+                    None => return None,
+                };
+
+            let (parent, entry) =
+                match next {
+                    Some(n) => n,
+                    None => {
+                        // We're done with this directory
+                        self.stack.pop();
+                        continue;
+                    }
+                };
+
+            if entry.is_self_or_parent() {
+                continue;
+            }
+
+            let path = format!("{}\\{}", parent, entry.name_string());
+
+            if entry.is_dir() {
+                match Directory::new(self.image, &entry) {
+                    Ok(d) => self.stack.push((path.clone(),
+                                              d.entries.into_iter())),
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+
+            return Some(Ok((path, entry)));
+        }
+    }
 }
 
 /// A single directory entry
-pub struct Entry(Vec<u8>);
+#[derive(Clone)]
+pub struct Entry {
+    /// Raw directory record
+    record: Vec<u8>,
+    /// True if this entry comes from a Joliet directory hierarchy, in
+    /// which case the names are encoded in UCS-2
+    joliet: bool,
+    /// Additional `(location, length)` extents for multi-extent
+    /// files. The first extent is in `record`.
+    extra_extents: Vec<(u32, u32)>,
+}
 
 impl Entry {
 
-    fn new(entry: &[u8]) -> Entry {
-        Entry(entry.into())
+    fn new(entry: &[u8], joliet: bool) -> Entry {
+        Entry {
+            record: entry.into(),
+            joliet: joliet,
+            extra_extents: Vec::new(),
+        }
     }
 
+    /// Raw name of the entry, as stored in the directory record.
     pub fn name(&self) -> &[u8] {
-        let name_len = self.0[32] as usize;
+        let name_len = self.record[32] as usize;
 
         let name_end = 33 + name_len;
 
         // No need to validate the len, it should've been done on
         // entry creation
-        &self.0[33..name_end]
+        &self.record[33..name_end]
+    }
+
+    /// Name of the entry decoded as a string. Joliet names are
+    /// converted from UCS-2.
+    pub fn name_string(&self) -> String {
+        let name = self.name();
+
+        if self.joliet {
+            let ucs2: Vec<u16> = name.chunks(2)
+                .filter(|c| c.len() == 2)
+                .map(|c| ((c[0] as u16) << 8) | c[1] as u16)
+                .collect();
+
+            String::from_utf16_lossy(&ucs2)
+        } else {
+            String::from_utf8_lossy(name).into_owned()
+        }
+    }
+
+    /// Return true if `name` designates this entry. The comparison
+    /// is case insensitive and the version suffix is optional.
+    fn matches_name(&self, name: &[u8]) -> bool {
+        let own = self.name_string().to_uppercase();
+        let name = String::from_utf8_lossy(name).to_uppercase();
+
+        if own == name {
+            return true;
+        }
+
+        if name.contains(';') {
+            return false;
+        }
+
+        // Strip our version number and compare again
+        match own.rfind(';') {
+            Some(p) => own[..p] == name,
+            None => false,
+        }
+    }
+
+    /// Return true for the special `.` and `..` entries which are
+    /// respectively named `\0` and `\1`.
+    pub fn is_self_or_parent(&self) -> bool {
+        let name = self.name();
+
+        name == b"\0" || name == b"\x01"
+    }
+
+    /// Raw file flags
+    pub fn flags(&self) -> u8 {
+        self.record[25]
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        (self.flags() & 0x1) != 0
     }
 
     pub fn is_dir(&self) -> bool {
-        let flags = self.0[25];
+        (self.flags() & 0x2) != 0
+    }
+
+    pub fn is_associated_file(&self) -> bool {
+        (self.flags() & 0x4) != 0
+    }
 
-        (flags & 0x2) != 0
+    /// True if this record is not the last one for this file
+    fn is_multi_extent(&self) -> bool {
+        (self.flags() & 0x80) != 0
     }
 
+    /// Location of the first extent of this entry
     pub fn extent_location(&self) -> u32 {
-        read_u32(&self.0[2..10])
+        read_u32(&self.record[2..10])
     }
 
+    /// Length of the first extent of this entry
     pub fn extent_len(&self) -> u32 {
-        read_u32(&self.0[10..18])
+        read_u32(&self.record[10..18])
     }
 
-    pub fn read_file(&self, image: &mut Image) -> Result<Vec<u8>, Error> {
-        if self.is_dir() {
-            return Err(Error::NotAFile);
+    /// Return the list of `(location, length)` extents for this
+    /// entry. There's only one unless the file is multi-extent.
+    pub fn extents(&self) -> Vec<(u32, u32)> {
+        let mut extents = vec![(self.extent_location(), self.extent_len())];
+
+        extents.extend_from_slice(&self.extra_extents);
+
+        extents
+    }
+
+    /// Total length of the file in bytes, accross all extents
+    pub fn len(&self) -> u64 {
+        self.extents().iter().map(|&(_, len)| len as u64).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn add_extent(&mut self, continuation: &Entry) {
+        let extent = (continuation.extent_location(),
+                      continuation.extent_len());
+
+        self.extra_extents.push(extent);
+
+        // The flags of the last record are the ones that matter, it
+        // won't have the multi-extent bit set.
+        self.record[25] = continuation.flags();
+    }
+
+    /// Recording date and time of the entry
+    pub fn recording_date(&self) -> Timestamp {
+        let d = &self.record[18..25];
+
+        Timestamp {
+            year: 1900 + d[0] as u16,
+            month: d[1],
+            day: d[2],
+            hour: d[3],
+            minute: d[4],
+            second: d[5],
+            gmt_offset: d[6] as i8,
         }
+    }
 
-        let mut extent_len = self.extent_len() as usize;
-        let extent_location = self.extent_location();
+    /// Return the `(file unit size, interleave gap size)` pair if
+    /// the file is recorded in interleaved mode.
+    pub fn interleave(&self) -> Option<(u8, u8)> {
+        let unit_size = self.record[26];
+        let gap_size = self.record[27];
 
-        let mut contents = Vec::with_capacity(extent_len);
+        if unit_size == 0 {
+            None
+        } else {
+            Some((unit_size, gap_size))
+        }
+    }
+
+    /// Volume sequence number
+    pub fn volume_sequence_number(&self) -> u16 {
+        read_u16(&self.record[28..32])
+    }
+
+    /// Retreive the CD-ROM XA extended attributes stored in the
+    /// system use area of the record, if any
+    pub fn xa_attributes(&self) -> Option<XaAttributes> {
+        let name_len = self.record[32] as usize;
+
+        // The name is padded to an even length
+        let start = 33 + name_len + (1 - (name_len & 1));
+
+        if self.record.len() < start + 14 {
+            return None;
+        }
+
+        let xa = &self.record[start..start + 14];
 
-        let track_msf =
-            match Msf::from_sector_index(extent_location) {
-                Some(m) => m,
-                None => return Err(Error::BadExtent(extent_location)),
+        if &xa[6..8] != b"XA" {
+            return None;
+        }
+
+        // XA attributes are big endian
+        let be16 = |b: &[u8]| ((b[0] as u16) << 8) | b[1] as u16;
+
+        Some(XaAttributes {
+            owner_group: be16(&xa[0..2]),
+            owner_user: be16(&xa[2..4]),
+            attributes: be16(&xa[4..6]),
+            file_number: xa[8],
+        })
+    }
+
+    /// Return an iterator over the sectors of the file. This is
+    /// useful for Form 2 files (XA audio, STR videos...) whose
+    /// contents can be retreived with `Sector::mode2_xa_payload`.
+    pub fn sectors<'a>(&self, image: &'a mut Image) -> Sectors<'a> {
+        let extents: Vec<(u32, u32)> =
+            if self.is_dir() {
+                Vec::new()
+            } else {
+                self.extents()
             };
 
-        let mut msf = try!(image.track_msf(Bcd::one(), track_msf));
+        Sectors {
+            image: image,
+            extents: extents.into_iter(),
+            current: None,
+        }
+    }
 
-        let mut sector = Sector::empty();
+    /// Return a streaming reader for the (Form 1) contents of the
+    /// file
+    pub fn reader<'a>(&self, image: &'a mut Image) -> Result<FileReader<'a>, Error> {
+        if self.is_dir() {
+            return Err(Error::NotAFile);
+        }
 
-        while extent_len > 0 {
-            try!(image.read_sector(&mut sector, msf));
+        Ok(FileReader {
+            sectors: self.sectors(image),
+            remaining: self.len(),
+            buffer: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    pub fn read_file(&self, image: &mut Image) -> Result<Vec<u8>, Error> {
+        if self.is_dir() {
+            return Err(Error::NotAFile);
+        }
+
+        let mut extent_len = self.len() as usize;
+
+        let mut contents = Vec::with_capacity(extent_len);
+
+        for sector in self.sectors(image) {
+            let sector = try!(sector);
 
             let data = try!(sector.mode2_xa_payload());
 
@@ -182,18 +500,183 @@ impl Entry {
                     extent_len
                 };
 
-            for &b in &data[0..len] {
-                contents.push(b);
-            }
+            contents.extend_from_slice(&data[0..len]);
 
             extent_len -= len;
-            msf = msf.next().unwrap();
         }
 
         Ok(contents)
     }
 }
 
+/// Date and time of an ISO9660 directory record
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Timestamp {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    /// Offset from GMT in 15 minute intervals
+    pub gmt_offset: i8,
+}
+
+/// CD-ROM XA extended attributes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct XaAttributes {
+    pub owner_group: u16,
+    pub owner_user: u16,
+    /// Raw attribute word. The low bits contain the permissions
+    pub attributes: u16,
+    /// File number used to identify interleaved streams in the
+    /// sector sub-headers
+    pub file_number: u8,
+}
+
+impl XaAttributes {
+    /// File contains Mode 2 Form 1 sectors
+    pub fn is_form1(&self) -> bool {
+        self.attributes & 0x0800 != 0
+    }
+
+    /// File contains Mode 2 Form 2 sectors
+    pub fn is_form2(&self) -> bool {
+        self.attributes & 0x1000 != 0
+    }
+
+    /// File contains interleaved sectors (several streams
+    /// multiplexed by file number)
+    pub fn is_interleaved(&self) -> bool {
+        self.attributes & 0x2000 != 0
+    }
+
+    /// File is a CD-DA track
+    pub fn is_cdda(&self) -> bool {
+        self.attributes & 0x4000 != 0
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.attributes & 0x8000 != 0
+    }
+}
+
+/// Iterator over the sectors of a file, returned by `Entry::sectors`
+pub struct Sectors<'a> {
+    image: &'a mut Image,
+    /// Extents left to read
+    extents: vec::IntoIter<(u32, u32)>,
+    /// Position of the next sector in the current extent and number
+    /// of bytes left in the extent
+    current: Option<(Msf, u32)>,
+}
+
+impl<'a> Iterator for Sectors<'a> {
+    type Item = Result<Sector, Error>;
+
+    fn next(&mut self) -> Option<Result<Sector, Error>> {
+        loop {
+            match self.current {
+                Some((msf, remaining)) if remaining > 0 => {
+                    let mut sector = Sector::empty();
+
+                    if let Err(e) = self.image.read_sector(&mut sector, msf) {
+                        self.current = None;
+                        return Some(Err(Error::CdError(e)));
+                    }
+
+                    let remaining = remaining.saturating_sub(2048);
+
+                    self.current =
+                        match msf.next() {
+                            Some(m) => Some((m, remaining)),
+                            None => None,
+                        };
+
+                    return Some(Ok(sector));
+                }
+                _ => {
+                    // Move on to the next extent
+                    let (location, len) =
+                        match self.extents.next() {
+                            Some(e) => e,
+                            None => return None,
+                        };
+
+                    match extent_msf(self.image, location) {
+                        Ok(msf) => self.current = Some((msf, len)),
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Streaming reader returning the Form 1 contents of a file,
+/// returned by `Entry::reader`
+pub struct FileReader<'a> {
+    sectors: Sectors<'a>,
+    /// Number of bytes of the file not yet loaded in `buffer`
+    remaining: u64,
+    /// Data of the current sector
+    buffer: Vec<u8>,
+    /// Read position in `buffer`
+    pos: usize,
+}
+
+impl<'a> io::Read for FileReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.buffer.len() {
+            if self.remaining == 0 {
+                // EOF
+                return Ok(0);
+            }
+
+            let sector =
+                match self.sectors.next() {
+                    Some(Ok(s)) => s,
+                    Some(Err(e)) =>
+                        return Err(io::Error::new(io::ErrorKind::Other,
+                                                  format!("{:?}", e))),
+                    None =>
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                  "ISO9660 file truncated")),
+                };
+
+            let data =
+                match sector.mode2_xa_payload() {
+                    Ok(d) => d,
+                    Err(e) =>
+                        return Err(io::Error::new(io::ErrorKind::Other,
+                                                  format!("{:?}", e))),
+                };
+
+            let len =
+                if self.remaining > 2048 {
+                    2048
+                } else {
+                    self.remaining as usize
+                };
+
+            self.buffer.clear();
+            self.buffer.extend_from_slice(&data[0..len]);
+            self.pos = 0;
+            self.remaining -= len as u64;
+        }
+
+        let available = &self.buffer[self.pos..];
+
+        let len = ::std::cmp::min(available.len(), buf.len());
+
+        buf[0..len].copy_from_slice(&available[0..len]);
+
+        self.pos += len;
+
+        Ok(len)
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     /// Cdimage access error
@@ -202,6 +685,8 @@ pub enum Error {
     BadMagic,
     /// Couldn't find the Primary Volume Descriptor
     MissingPrimaryVolumeDescriptor,
+    /// Couldn't find a Joliet Supplementary Volume Descriptor
+    MissingJolietVolumeDescriptor,
     /// Unexpected Volume Descriptor version
     BadVolumDescriptorVersion,
     /// Encountered an invalid extent location
@@ -223,7 +708,45 @@ impl From<CdError> for Error {
     }
 }
 
+/// Open the root directory of the primary ISO9660 hierarchy
 pub fn open_image(image: &mut Image) -> Result<Directory, Error> {
+    let root_dir =
+        try!(find_volume_descriptor(image, |vd| {
+            // Primary Volume Descriptor
+            vd[0] == 0x01
+        }));
+
+    match root_dir {
+        Some(root_dir) => Directory::new(image, &Entry::new(&root_dir, false)),
+        None => Err(Error::MissingPrimaryVolumeDescriptor),
+    }
+}
+
+/// Open the root directory of the Joliet hierarchy, if the disc has
+/// one. Joliet supports long file names in UCS-2.
+pub fn open_image_joliet(image: &mut Image) -> Result<Directory, Error> {
+    let root_dir =
+        try!(find_volume_descriptor(image, |vd| {
+            // Supplementary Volume Descriptor with one of the Joliet
+            // UCS-2 escape sequences
+            vd[0] == 0x02 &&
+                (&vd[88..91] == b"%/@" ||
+                 &vd[88..91] == b"%/C" ||
+                 &vd[88..91] == b"%/E")
+        }));
+
+    match root_dir {
+        Some(root_dir) => Directory::new(image, &Entry::new(&root_dir, true)),
+        None => Err(Error::MissingJolietVolumeDescriptor),
+    }
+}
+
+/// Look for a volume descriptor matching `matches` and return its
+/// root directory record. Returns `None` if we reach the end of the
+/// Volume Descriptor Set without a match.
+fn find_volume_descriptor<F>(image: &mut Image,
+                             matches: F) -> Result<Option<Vec<u8>>, Error>
+    where F: Fn(&[u8]) -> bool {
     // The first 16 sectors are the "system area" which is ignored by
     // the ISO filesystem. The Volume Descriptor Set should start at
     // 00:00:16 in track 01
@@ -232,7 +755,6 @@ pub fn open_image(image: &mut Image) -> Result<Directory, Error> {
 
     let mut sector = Sector::empty();
 
-    // Look for the primary volume descriptor
     loop {
         try!(image.read_sector(&mut sector, msf));
 
@@ -243,33 +765,38 @@ pub fn open_image(image: &mut Image) -> Result<Directory, Error> {
             return Err(Error::BadMagic);
         }
 
-        // Byte 0 contains the "volume descriptor type".
-        match volume_descriptor[0] {
-            // Primary Volume Descriptor
-            0x01 => break,
-            // Volume Descriptor Set Terminator
-            0xff => return Err(Error::MissingPrimaryVolumeDescriptor),
-            // Unhandled volume descriptor type, ignore
-            _ => (),
+        // Byte 0 contains the "volume descriptor type". 0xff is the
+        // Volume Descriptor Set Terminator
+        if volume_descriptor[0] == 0xff {
+            return Ok(None);
         }
 
-        // Not the primary volume descriptor, move on to the next
-        // sector
-        msf = msf.next().unwrap();
-    }
+        if matches(&volume_descriptor[..]) {
+            // Volume Descriptor Version
+            if volume_descriptor[6] != 0x01 {
+                return Err(Error::BadVolumDescriptorVersion);
+            }
 
-    let volume_descriptor = try!(sector.mode2_xa_payload());
+            // Root directory descriptor
+            return Ok(Some(volume_descriptor[156..190].into()));
+        }
 
-    // Volume Descriptor Version
-    if volume_descriptor[6] != 0x01 {
-        return Err(Error::BadVolumDescriptorVersion);
+        // Not the descriptor we want, move on to the next sector
+        msf = msf.next().unwrap();
     }
+}
+
+/// Return the position of the extent starting at sector `location`
+fn extent_msf(image: &mut Image, location: u32) -> Result<Msf, Error> {
+    let track_msf =
+        match Msf::from_sector_index(location) {
+            Some(m) => m,
+            None => return Err(Error::BadExtent(location)),
+        };
 
-    // We can now open the root directory descriptor
-    let root_dir_descriptor = &volume_descriptor[156..190];
+    let msf = try!(image.track_msf(Bcd::one(), track_msf));
 
-    let root_dir = Entry::new(root_dir_descriptor);
-    Directory::new(image, &root_dir)
+    Ok(msf)
 }
 
 /// Read a 32bit number stored in "both byte order" format
@@ -281,3 +808,104 @@ fn read_u32(v: &[u8]) -> u32 {
     ((v[2] as u32) << 16) |
     ((v[3] as u32) << 24)
 }
+
+/// Read a 16bit number stored in "both byte order" format
+fn read_u16(v: &[u8]) -> u16 {
+    v[0] as u16 | ((v[1] as u16) << 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Directory, Error};
+
+    /// Build a raw directory record
+    fn record(name: &[u8], flags: u8, location: u32, len: u32) -> Vec<u8> {
+        // The record length must be even
+        let rec_len = (33 + name.len() + 1) & !1;
+
+        let mut r = vec![0; rec_len];
+
+        r[0] = rec_len as u8;
+
+        for i in 0..4 {
+            let shift = i * 8;
+
+            r[2 + i] = (location >> shift) as u8;
+            r[9 - i] = (location >> shift) as u8;
+            r[10 + i] = (len >> shift) as u8;
+            r[17 - i] = (len >> shift) as u8;
+        }
+
+        r[25] = flags;
+        r[32] = name.len() as u8;
+        r[33..33 + name.len()].copy_from_slice(name);
+
+        r
+    }
+
+    /// Encode `name` in big endian UCS-2
+    fn ucs2(name: &str) -> Vec<u8> {
+        name.encode_utf16()
+            .flat_map(|c| vec![(c >> 8) as u8, c as u8])
+            .collect()
+    }
+
+    #[test]
+    fn joliet_multi_extent() {
+        let mut raw = Vec::new();
+
+        raw.extend(record(b"\0", 0x02, 20, 2048));
+        raw.extend(record(b"\x01", 0x02, 20, 2048));
+        raw.extend(record(&ucs2("Long movie name.str;1"), 0x80, 100, 4096));
+        raw.extend(record(&ucs2("Long movie name.str;1"), 0x80, 200, 2048));
+        raw.extend(record(&ucs2("Long movie name.str;1"), 0x00, 300, 1000));
+        raw.extend(record(&ucs2("Other.dat;1"), 0x00, 400, 10));
+        // Padding up to the end of the sector
+        raw.extend_from_slice(&[0; 16]);
+
+        let mut dir = Directory { entries: Vec::new() };
+
+        dir.parse_entries(&raw, true).unwrap();
+
+        // The continuation records must have been merged
+        assert_eq!(dir.ls().len(), 4);
+
+        let movie = dir.entry_by_name(b"LONG MOVIE NAME.STR").unwrap();
+
+        assert_eq!(movie.name_string(), "Long movie name.str;1");
+        assert_eq!(movie.extents(),
+                   vec![(100, 4096), (200, 2048), (300, 1000)]);
+        assert_eq!(movie.len(), 4096 + 2048 + 1000);
+        assert!(!movie.is_multi_extent());
+        assert!(!movie.is_dir());
+
+        let other = dir.entry_by_name(b"other.dat;1").unwrap();
+
+        assert_eq!(other.extents(), vec![(400, 10)]);
+
+        match dir.entry_by_name(b"other.dat;2") {
+            Err(Error::EntryNotFound) => (),
+            _ => panic!("Unexpected match"),
+        }
+    }
+
+    #[test]
+    fn bad_records() {
+        let mut dir = Directory { entries: Vec::new() };
+
+        let mut short = record(b"A", 0, 0, 0);
+        short[0] = 20;
+
+        assert!(dir.parse_entries(&short, false).is_err());
+
+        let mut truncated = record(b"A", 0, 0, 0);
+        truncated[0] = 60;
+
+        assert!(dir.parse_entries(&truncated, false).is_err());
+
+        let mut long_name = record(b"A", 0, 0, 0);
+        long_name[32] = 10;
+
+        assert!(dir.parse_entries(&long_name, false).is_err());
+    }
+}
@@ -15,21 +15,26 @@
 //! based on No$'s specs, mednafen's source code and some educated
 //! guesses.
 
+use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
+
 use memory::Addressable;
 use timekeeper::{Peripheral, Cycles};
 use interrupt::Interrupt;
 use shared::SharedState;
-use arrayvec::ArrayVec;
 use cdimage::sector::Sector;
 use cdimage::msf::Msf;
 
 use self::disc::{Disc, Region};
 use self::simple_rand::SimpleRand;
+use self::subchannel::Q;
 
 pub mod disc;
 pub mod iso9660;
+pub mod subchannel;
+pub mod system_cnf;
 
 mod simple_rand;
+mod prefetch;
 
 /// CDROM drive, controller and decoder.
 #[derive(RustcDecodable, RustcEncodable)]
@@ -53,6 +58,8 @@ pub struct CdRom {
     rx_buffer: RxBuffer,
     /// Raw sector read from the disc image
     sector: Sector,
+    /// Q subchannel data of the last sector read
+    subchannel_q: Q,
     /// This bit is set when the program wants to read sector
     /// data. It's automatically cleared when all the sector has been
     /// read but it can also be cleared by writing to the config
@@ -68,6 +75,9 @@ pub struct CdRom {
     read_state: ReadState,
     /// True if a sector has been read but not yet notified
     read_pending: bool,
+    /// True if the last sector read failed. The error is reported
+    /// when the read is notified.
+    read_error: bool,
     /// Currently loaded disc or None if no disc is present
     disc: Option<Disc>,
     /// Target of the next seek command
@@ -80,6 +90,12 @@ pub struct CdRom {
     /// second), otherwise we're in the default 1x (75 sectors per
     /// second).
     double_speed: bool,
+    /// True if the spindle motor is running. If it's not the disc
+    /// has to spin up before we can seek or read.
+    motor_on: bool,
+    /// If true seeks and reads are much faster than on the real
+    /// hardware. Not accurate but useful to speed up loading times.
+    fast_cd: FastCdSetting,
     /// If true Send ADPCM samples to the SPU
     xa_adpcm_to_spu: bool,
     /// If true we read the whole sector except for the sync bytes
@@ -124,17 +140,21 @@ impl CdRom {
             irq_mask: 0,
             rx_buffer: RxBuffer::new(),
             sector: Sector::empty(),
+            subchannel_q: Q::new(),
             rx_active: false,
             sub_cpu: SubCpu::new(),
             rx_index: 0,
             rx_len: 0,
             read_state: ReadState::Idle,
             read_pending: false,
+            read_error: false,
             disc: disc,
             seek_target: Msf::zero(),
             seek_target_pending: false,
             position: Msf::zero(),
             double_speed: false,
+            motor_on: true,
+            fast_cd: FastCdSetting(false),
             xa_adpcm_to_spu: false,
             read_whole_sector: true,
             sector_size_override: false,
@@ -197,12 +217,19 @@ impl CdRom {
 
                     // Read the current sector
                     self.read_sector();
-                    self.maybe_notify_read(shared);
 
-                    // Schedule the next sector read
-                    let next = self.cycles_per_sector() - leftover;
+                    self.read_state =
+                        if self.read_error {
+                            // The drive gives up after a read error
+                            ReadState::Idle
+                        } else {
+                            // Schedule the next sector read
+                            let next = self.cycles_per_sector() - leftover;
 
-                    self.read_state = ReadState::Reading(next);
+                            ReadState::Reading(next)
+                        };
+
+                    self.maybe_notify_read(shared);
                 }
             }
 
@@ -226,6 +253,25 @@ impl CdRom {
         disc
     }
 
+    /// Enable or disable the "fast CD" mode. When enabled seeks and
+    /// sector reads take much less time than on the real hardware
+    /// which speeds up loading times but can break some games.
+    pub fn set_fast_cd(&mut self, fast_cd: bool) {
+        self.fast_cd = FastCdSetting(fast_cd);
+    }
+
+    pub fn fast_cd(&self) -> bool {
+        self.fast_cd.0
+    }
+
+    /// The "fast CD" mode is a frontend setting, it's not stored in
+    /// savestates and a freshly loaded `CdRom` runs at the accurate
+    /// speed. This method should be called on the loaded `CdRom` to
+    /// keep the setting of the `current` instance.
+    pub fn restore_fast_cd(&mut self, current: &CdRom) {
+        self.set_fast_cd(current.fast_cd());
+    }
+
     fn predict_next_sync(&mut self, shared: &mut SharedState) {
         shared.tk().no_sync_needed(Peripheral::CdRom);
 
@@ -551,11 +597,25 @@ impl CdRom {
             if self.irq_flags == 0 && !self.sub_cpu.in_command() {
                 self.sub_cpu.response.clear();
 
-                self.sub_cpu.irq_code = IrqCode::SectorReady;
-
                 let status = self.drive_status();
 
-                self.sub_cpu.response.push(status);
+                if self.read_error {
+                    // Report the error with the "seek error" status
+                    // bit set.
+                    //
+                    // XXX I'm not sure which error code the real
+                    // drive returns for unreadable sectors.
+                    self.sub_cpu.irq_code = IrqCode::Error;
+
+                    self.sub_cpu.response.push(status | 0x05);
+                    self.sub_cpu.response.push(0x04);
+
+                    self.read_error = false;
+                } else {
+                    self.sub_cpu.irq_code = IrqCode::SectorReady;
+
+                    self.sub_cpu.response.push(status);
+                }
 
                 self.sub_cpu.sequence = SubCpuSequence::AsyncRxPush;
                 self.sub_cpu.timer = timings::READ_RX_PUSH;
@@ -688,20 +748,76 @@ impl CdRom {
         // 1x speed: 75 sectors per second
         let cycles_1x = ::cpu::CPU_FREQ_HZ / 75;
 
-        cycles_1x >> (self.double_speed as u32)
+        let cycles = cycles_1x >> (self.double_speed as u32);
+
+        if self.fast_cd.0 {
+            cycles / timings::FAST_READ_FACTOR
+        } else {
+            cycles
+        }
+    }
+
+    /// Start the spindle motor if it's not already running. Returns
+    /// the number of CPU cycles it takes for the disc to reach the
+    /// target speed.
+    fn spin_up(&mut self) -> u32 {
+        if self.motor_on {
+            return 0;
+        }
+
+        self.motor_on = true;
+
+        if self.fast_cd.0 {
+            0
+        } else {
+            timings::SPIN_UP
+        }
     }
 
-    /// Execute a pending seek (if any). On the real console that
-    /// would mean physically moving the read head.
-    fn do_seek(&mut self) {
+    /// Execute a seek to `seek_target`. On the real console that
+    /// means physically moving the read head. Returns the number of
+    /// CPU cycles taken by the seek.
+    fn do_seek(&mut self) -> u32 {
         // Make sure we don't end up in track1's pregap, I don't know
         // if it's ever useful? Needs special handling at least...
         if self.seek_target < Msf::from_bcd(0x00, 0x02, 0x00).unwrap() {
             panic!("Seek to track. 1 pregap: {}", self.seek_target);
         }
 
+        let spin_up = self.spin_up();
+
+        let from = self.position.sector_index();
+        let to = self.seek_target.sector_index();
+
         self.position = self.seek_target;
         self.seek_target_pending = false;
+
+        if self.fast_cd.0 {
+            return timings::FAST_SEEK;
+        }
+
+        let distance =
+            if from > to {
+                from - to
+            } else {
+                to - from
+            };
+
+        // Roughly based on mednafen's heuristic: the sled travel time
+        // is proportional to the distance, with an additional penalty
+        // for long seeks and some pseudo-random variation.
+        let travel = (distance as u64 * timings::SEEK_FULL_DISC as u64)
+            / timings::DISC_SECTORS as u64;
+
+        let mut seek = ::std::cmp::max(travel as u32, timings::SEEK_MIN);
+
+        if distance >= timings::SEEK_LONG_THRESHOLD {
+            seek += timings::SEEK_LONG_PENALTY;
+        }
+
+        seek += self.rand.next() % timings::SEEK_VARIATION;
+
+        spin_up + seek
     }
 
     /// Called when a new sector must be read
@@ -715,9 +831,29 @@ impl CdRom {
         // Read the sector at `position`
         match self.disc {
             Some(ref mut d) =>
-                if let Err(e) = d.image().read_sector(&mut self.sector,
-                                                      position) {
-                    panic!("Couldn't read sector: {}", e);
+                match d.read_sector(position) {
+                    Ok(sector) => {
+                        let q = d.subchannel_q(&sector);
+
+                        // The drive only latches the Q subchannel if
+                        // its CRC is valid, otherwise the last good
+                        // value is kept
+                        if q.crc_valid() {
+                            self.subchannel_q = q;
+                        }
+
+                        self.sector = sector;
+                    }
+                    Err(e) => {
+                        // Report the error to the emulated software
+                        // instead of giving up
+                        warn!("Couldn't read sector {}: {}", position, e);
+
+                        self.read_error = true;
+                        self.read_pending = true;
+
+                        return;
+                    }
                 },
             None => panic!("Sector read without a disc"),
         }
@@ -791,7 +927,7 @@ impl CdRom {
                 let reading = !self.read_state.is_idle();
 
                 // Motor on
-                r |= 1 << 1;
+                r |= (self.motor_on as u8) << 1;
                 r |= (reading as u8) << 5;
 
                 r
@@ -811,6 +947,7 @@ impl CdRom {
                 0x02 => (3, 3, CdRom::cmd_set_loc),
                 // ReadN
                 0x06 => (0, 0, CdRom::cmd_read),
+                0x08 => (0, 0, CdRom::cmd_stop),
                 0x09 => (0, 0, CdRom::cmd_pause),
                 0x0a => (0, 0, CdRom::cmd_init),
                 0x0b => (0, 0, CdRom::cmd_mute),
@@ -881,12 +1018,17 @@ impl CdRom {
             warn!("CDROM READ while we're already reading");
         }
 
-        if self.seek_target_pending {
-            // XXX That should take some time...
-            self.do_seek();
-        }
+        // If a seek is pending we have to move the read head to the
+        // target position before we can start reading. Then we have
+        // to wait for the first sector to pass under the read head.
+        let seek_delay =
+            if self.seek_target_pending {
+                self.do_seek()
+            } else {
+                self.spin_up()
+            };
 
-        let read_delay = self.cycles_per_sector();
+        let read_delay = seek_delay + self.cycles_per_sector();
 
         self.read_state = ReadState::Reading(read_delay);
 
@@ -895,6 +1037,37 @@ impl CdRom {
         self.sub_cpu.response.push(status);
     }
 
+    /// Stop reading and stop the spindle motor
+    fn cmd_stop(&mut self) {
+        let status = self.drive_status();
+
+        self.sub_cpu.response.push(status);
+
+        self.read_state = ReadState::Idle;
+
+        let async_delay =
+            if !self.motor_on {
+                // Already stopped
+                timings::STOP_IDLE_ASYNC
+            } else if self.double_speed {
+                timings::STOP_2X_ASYNC
+            } else {
+                timings::STOP_1X_ASYNC
+            };
+
+        self.motor_on = false;
+
+        self.sub_cpu.schedule_async_response(async_delay, CdRom::async_stop);
+    }
+
+    fn async_stop(&mut self) -> u32 {
+        let status = self.drive_status();
+
+        self.sub_cpu.response.push(status);
+
+        timings::STOP_RX_PUSH
+    }
+
     /// Stop reading sectors but remain at the same position on the
     /// disc
     fn cmd_pause(&mut self) {
@@ -935,6 +1108,7 @@ impl CdRom {
         // XXX I think? Needs testing
         self.read_state = ReadState::Idle;
         self.read_pending = false;
+        self.read_error = false;
 
         self.sub_cpu.schedule_async_response(900_000,
                                              CdRom::async_init);
@@ -949,6 +1123,7 @@ impl CdRom {
         self.seek_target = Msf::zero();
         self.read_state = ReadState::Idle;
         self.double_speed = false;
+        self.motor_on = true;
         self.xa_adpcm_to_spu = false;
         self.read_whole_sector = true;
         self.sector_size_override = false;
@@ -1046,52 +1221,38 @@ impl CdRom {
             panic!("GetLocP while in track1 pregap");
         }
 
-        // Fixme: All this data should be extracted from the
-        // subchannel Q (when available in cdimage).
-
-        let metadata = self.sector.metadata();
-
         // The position returned by get_loc_p seems to be ahead of the
         // currently read sector *sometimes*. Probably because of the
         // way the subchannel data is buffered? Let's not worry about
         // it for now.
-        let abs_msf = metadata.msf;
-
-        // Position within the current track
-        let track_msf = metadata.track_msf;
-
-        let track = metadata.track;
-        let index = metadata.index;
-
-        let (track_m, track_s, track_f) = track_msf.into_bcd();
+        //
+        // `subchannel_q` only contains the last Q with a valid CRC.
+        // That's what LibCrypt checks for: on an original disc the
+        // corrupted sectors report the position of the previous good
+        // sector while on a copy they report their real position.
+        let q = self.subchannel_q;
 
-        let (abs_m, abs_s, abs_f) = abs_msf.into_bcd();
+        let track_msf = q.track_msf();
+        let abs_msf = q.absolute_msf();
 
-        let response_bcd: ArrayVec<[_; 8]> = [track, index,
-                                              track_m, track_s, track_f,
-                                              abs_m, abs_s, abs_f]
-            .iter()
-            .map(|v| v.bcd())
-            .collect();
+        let response_bcd = [q.track(), q.index(),
+                            track_msf[0], track_msf[1], track_msf[2],
+                            abs_msf[0], abs_msf[1], abs_msf[2]];
 
         self.sub_cpu.response.push_slice(&response_bcd);
     }
 
     /// Execute seek. Target is given by previous "set loc" command.
     fn cmd_seek_l(&mut self) {
-        self.do_seek();
+        let seek_delay = self.do_seek();
 
         let status = self.drive_status();
 
         self.sub_cpu.response.push(status);
 
-        // XXX the delay for the async response is tied to the time it
-        // takes for the reading head to physically seek on the
-        // disc. We probably need a heuristic based on the current
-        // head position, target position and probably a bunch of
-        // other factors. For now hardcode a dumb value and hope for
-        // the best.
-        self.sub_cpu.schedule_async_response(1_000_000, CdRom::async_seek_l);
+        // The async response is sent once the read head reached its
+        // destination
+        self.sub_cpu.schedule_async_response(seek_delay, CdRom::async_seek_l);
     }
 
     fn async_seek_l(&mut self) -> u32 {
@@ -1200,6 +1361,26 @@ impl CdRom {
     }
 }
 
+/// "Fast CD" setting selected by the frontend. Like the CPU engine
+/// it's not stored in savestates, it would override the frontend's
+/// choice.
+struct FastCdSetting(bool);
+
+impl Encodable for FastCdSetting {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_nil()
+    }
+}
+
+impl Decodable for FastCdSetting {
+    fn decode<D: Decoder>(d: &mut D) -> Result<FastCdSetting, D::Error> {
+        try!(d.read_nil());
+
+        // Restored by `CdRom::restore_fast_cd`
+        Ok(FastCdSetting(false))
+    }
+}
+
 /// 16byte FIFO used to store command arguments and responses
 #[derive(Copy, Clone, Debug, RustcDecodable, RustcEncodable)]
 struct Fifo {
@@ -1404,6 +1585,7 @@ impl SubCpu {
 }
 
 callback!(struct AsyncResponse(fn (&mut CdRom) -> u32) {
+    CdRom::async_stop,
     CdRom::async_pause,
     CdRom::async_init,
     CdRom::async_seek_l,
@@ -1527,4 +1709,222 @@ mod timings {
     /// Delay between the asynchronous RX_CLEAR and first param push
     /// for the asynchronous Init response
     pub const INIT_RX_PUSH: u32 = 1_700;
+
+    /// Delay between the asynchronous RX_CLEAR and first param push
+    /// for the asynchronous Stop response
+    pub const STOP_RX_PUSH: u32 = 1_700;
+
+    /// Time taken by the spindle motor to stop when running at 1x
+    pub const STOP_1X_ASYNC: u32 = 13_000_000;
+
+    /// Time taken by the spindle motor to stop when running at 2x
+    pub const STOP_2X_ASYNC: u32 = 25_000_000;
+
+    /// Delay of the Stop async response when the motor is already
+    /// stopped
+    pub const STOP_IDLE_ASYNC: u32 = 9_000;
+
+    /// Time taken by the spindle motor to spin up from standby,
+    /// roughly one second.
+    pub const SPIN_UP: u32 = 33_868_800;
+
+    /// Number of sectors on a 72 minute disc
+    pub const DISC_SECTORS: u32 = 72 * 60 * 75;
+
+    /// Approximate time taken by the sled to travel across the entire
+    /// disc (`DISC_SECTORS`). Shorter seeks take a proportional
+    /// amount of time.
+    pub const SEEK_FULL_DISC: u32 = 33_868_800;
+
+    /// Minimum duration of a seek, even if the target is very close
+    /// to the current position.
+    pub const SEEK_MIN: u32 = 20_000;
+
+    /// Seeks over this many sectors (30 seconds) take an additional
+    /// `SEEK_LONG_PENALTY` cycles.
+    pub const SEEK_LONG_THRESHOLD: u32 = 30 * 75;
+
+    /// Additional delay for long seeks (~300ms)
+    pub const SEEK_LONG_PENALTY: u32 = 10_160_640;
+
+    /// Seek durations vary by up to this many cycles
+    pub const SEEK_VARIATION: u32 = 25_000;
+
+    /// Duration of a seek in "fast CD" mode, regardless of the
+    /// distance
+    pub const FAST_SEEK: u32 = SEEK_MIN;
+
+    /// Sector reads are this many times faster in "fast CD" mode
+    pub const FAST_READ_FACTOR: u32 = 4;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CdRom, IrqCode, timings};
+    use shared::SharedState;
+    use cdimage::msf::Msf;
+
+    /// Return the delay of the pending async response
+    fn async_delay(cdrom: &CdRom) -> u32 {
+        match cdrom.sub_cpu.async_response {
+            Some((delay, _)) => delay,
+            None => panic!("No async response scheduled"),
+        }
+    }
+
+    #[test]
+    fn spin_up() {
+        let mut cdrom = CdRom::new(None);
+
+        // Already spinning
+        assert_eq!(cdrom.spin_up(), 0);
+
+        cdrom.motor_on = false;
+
+        assert_eq!(cdrom.spin_up(), timings::SPIN_UP);
+        assert!(cdrom.motor_on);
+        assert_eq!(cdrom.spin_up(), 0);
+
+        cdrom.motor_on = false;
+        cdrom.set_fast_cd(true);
+
+        assert_eq!(cdrom.spin_up(), 0);
+        assert!(cdrom.motor_on);
+    }
+
+    #[test]
+    fn seek() {
+        let mut cdrom = CdRom::new(None);
+
+        let start = Msf::from_bcd(0x00, 0x02, 0x00).unwrap();
+        let near = Msf::from_bcd(0x00, 0x02, 0x01).unwrap();
+        let far = Msf::from_bcd(0x60, 0x00, 0x00).unwrap();
+
+        let min = timings::SEEK_MIN;
+        let var = timings::SEEK_VARIATION;
+
+        // Short seek
+        cdrom.position = start;
+        cdrom.seek_target = near;
+        cdrom.seek_target_pending = true;
+
+        let t = cdrom.do_seek();
+
+        assert!(t >= min && t < min + var);
+        assert!(cdrom.position == near);
+        assert!(!cdrom.seek_target_pending);
+
+        // Long seek, proportional to the distance with an additional
+        // penalty
+        cdrom.seek_target = far;
+
+        let distance = far.sector_index() - near.sector_index();
+        let travel = (distance as u64 * timings::SEEK_FULL_DISC as u64
+                      / timings::DISC_SECTORS as u64) as u32;
+        let long = travel + timings::SEEK_LONG_PENALTY;
+
+        let t = cdrom.do_seek();
+
+        assert!(t >= long && t < long + var);
+
+        // Seeking back takes as long
+        cdrom.seek_target = near;
+
+        let t = cdrom.do_seek();
+
+        assert!(t >= long && t < long + var);
+
+        // Seek with the motor stopped
+        cdrom.motor_on = false;
+        cdrom.seek_target = start;
+
+        let t = cdrom.do_seek();
+
+        assert!(t >= timings::SPIN_UP + min &&
+                t < timings::SPIN_UP + min + var);
+
+        // Fast CD mode
+        cdrom.set_fast_cd(true);
+        cdrom.seek_target = far;
+
+        assert_eq!(cdrom.do_seek(), timings::FAST_SEEK);
+    }
+
+    #[test]
+    fn read_speed() {
+        let mut cdrom = CdRom::new(None);
+
+        let single = cdrom.cycles_per_sector();
+
+        assert_eq!(single, ::cpu::CPU_FREQ_HZ / 75);
+
+        cdrom.double_speed = true;
+
+        assert_eq!(cdrom.cycles_per_sector(), single / 2);
+
+        cdrom.set_fast_cd(true);
+
+        assert_eq!(cdrom.cycles_per_sector(),
+                   single / 2 / timings::FAST_READ_FACTOR);
+    }
+
+    #[test]
+    fn stop() {
+        let mut cdrom = CdRom::new(None);
+
+        cdrom.cmd_stop();
+
+        assert_eq!(async_delay(&cdrom), timings::STOP_1X_ASYNC);
+        assert!(!cdrom.motor_on);
+
+        // Already stopped
+        cdrom.sub_cpu.async_response = None;
+        cdrom.cmd_stop();
+
+        assert_eq!(async_delay(&cdrom), timings::STOP_IDLE_ASYNC);
+
+        cdrom.sub_cpu.async_response = None;
+        cdrom.motor_on = true;
+        cdrom.double_speed = true;
+        cdrom.cmd_stop();
+
+        assert_eq!(async_delay(&cdrom), timings::STOP_2X_ASYNC);
+    }
+
+    #[test]
+    fn read_error() {
+        let mut shared = SharedState::new();
+        let mut cdrom = CdRom::new(None);
+
+        cdrom.read_pending = true;
+        cdrom.read_error = true;
+
+        cdrom.maybe_notify_read(&mut shared);
+
+        match cdrom.sub_cpu.irq_code {
+            IrqCode::Error => (),
+            c => panic!("Unexpected IRQ code {:?}", c),
+        }
+
+        assert_eq!(cdrom.sub_cpu.response.len(), 2);
+        // Status with the error bits set
+        assert_eq!(cdrom.sub_cpu.response.pop() & 0x05, 0x05);
+        assert_eq!(cdrom.sub_cpu.response.pop(), 0x04);
+        assert!(!cdrom.read_pending);
+        assert!(!cdrom.read_error);
+
+        // The next sector is reported normally
+        cdrom.read_pending = true;
+        cdrom.irq_flags = 0;
+        cdrom.sub_cpu.sequence = super::SubCpuSequence::Idle;
+
+        cdrom.maybe_notify_read(&mut shared);
+
+        match cdrom.sub_cpu.irq_code {
+            IrqCode::SectorReady => (),
+            c => panic!("Unexpected IRQ code {:?}", c),
+        }
+
+        assert_eq!(cdrom.sub_cpu.response.len(), 1);
+    }
 }
@@ -0,0 +1,352 @@
+//! Disc read-ahead.
+//!
+//! Reading from the disc image can be arbitrarily slow (compressed
+//! images, network-mounted filesystems...) so in order not to stall
+//! the emulation the sectors following the current read position are
+//! read ahead of time on a worker thread and stored in a bounded LRU
+//! cache.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
+use std::thread;
+
+use cdimage::{Image, CdError};
+use cdimage::msf::Msf;
+use cdimage::sector::Sector;
+
+/// Number of sectors read ahead of the last requested sector. At 2x
+/// speed that's a little more than 200ms worth of data.
+const PREFETCH_DEPTH: u32 = 32;
+
+/// Maximum number of sectors held in the cache
+const CACHE_CAPACITY: usize = 256;
+
+/// Disc image wrapper handling the sector cache and the read-ahead
+/// worker thread
+pub struct Prefetcher {
+    /// Disc image, shared with the worker thread
+    image: Arc<Mutex<Box<Image + Send>>>,
+    /// Sector cache, shared with the worker thread
+    cache: Arc<Mutex<Cache>>,
+    /// Channel used to send read-ahead requests to the worker. Wrapped
+    /// in an option in order to be able to close it when we're
+    /// dropped.
+    requests: Option<Sender<Msf>>,
+    /// Worker thread handle. `None` if we couldn't spawn the thread,
+    /// in which case all reads are synchronous.
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl Prefetcher {
+    pub fn new(image: Box<Image + Send>) -> Prefetcher {
+        let image = Arc::new(Mutex::new(image));
+        let cache = Arc::new(Mutex::new(Cache::new(CACHE_CAPACITY)));
+
+        let (tx, rx) = channel();
+
+        let worker = {
+            let image = image.clone();
+            let cache = cache.clone();
+
+            thread::Builder::new()
+                .name("cdrom read-ahead".into())
+                .spawn(move || run_worker(rx, image, cache))
+        };
+
+        let worker =
+            match worker {
+                Ok(w) => Some(w),
+                Err(e) => {
+                    warn!("Couldn't spawn CD read-ahead thread: {}", e);
+                    None
+                }
+            };
+
+        Prefetcher {
+            image: image,
+            cache: cache,
+            requests: Some(tx),
+            worker: worker,
+        }
+    }
+
+    /// Create a `Prefetcher` without a worker thread, all the reads
+    /// are synchronous. Used for placeholder images where reading
+    /// ahead is pointless.
+    pub fn synchronous(image: Box<Image + Send>) -> Prefetcher {
+        Prefetcher {
+            image: Arc::new(Mutex::new(image)),
+            cache: Arc::new(Mutex::new(Cache::new(CACHE_CAPACITY))),
+            requests: None,
+            worker: None,
+        }
+    }
+
+    /// Lock and return the underlying disc image. The worker thread
+    /// won't be able to read ahead while the lock is held.
+    pub fn image(&self) -> MutexGuard<Box<Image + Send>> {
+        self.image.lock().unwrap()
+    }
+
+    /// Read the sector at `msf`, from the cache if it's available or
+    /// directly from the image otherwise. Schedules a read-ahead of
+    /// the following sectors.
+    pub fn read_sector(&mut self, msf: Msf) -> Result<Sector, CdError> {
+        let index = msf.sector_index();
+
+        let cached = self.cache.lock().unwrap().get(index);
+
+        let sector =
+            match cached {
+                Some(s) => s,
+                None => {
+                    let mut sector = Sector::empty();
+
+                    try!(self.image().read_sector(&mut sector, msf));
+
+                    self.cache.lock().unwrap().insert(index, sector.clone());
+
+                    sector
+                }
+            };
+
+        if let Some(next) = msf.next() {
+            if let Some(ref requests) = self.requests {
+                // If the worker died there's nothing we can do, we'll
+                // just keep reading synchronously
+                let _ = requests.send(next);
+            }
+        }
+
+        Ok(sector)
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        // Closing the channel tells the worker to exit
+        self.requests = None;
+
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Read-ahead worker loop, runs until the request channel is closed
+fn run_worker(requests: Receiver<Msf>,
+              image: Arc<Mutex<Box<Image + Send>>>,
+              cache: Arc<Mutex<Cache>>) {
+    let mut next = requests.recv().ok();
+
+    while let Some(mut msf) = next.take() {
+        // We only care about the most recent request, if the emulator
+        // has moved on there's no point in reading the old sectors.
+        while let Ok(m) = requests.try_recv() {
+            msf = m;
+        }
+
+        for _ in 0..PREFETCH_DEPTH {
+            // Check for new requests between each sector: after a
+            // seek the rest of the read-ahead is stale and we don't
+            // want to hold the image lock while the emulator waits
+            // for the new position.
+            match requests.try_recv() {
+                Ok(m) => {
+                    next = Some(m);
+                    break;
+                }
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Disconnected) => return,
+            }
+
+            let index = msf.sector_index();
+
+            if !cache.lock().unwrap().contains(index) {
+                let mut sector = Sector::empty();
+
+                let res = image.lock().unwrap().read_sector(&mut sector, msf);
+
+                match res {
+                    Ok(()) => cache.lock().unwrap().insert(index, sector),
+                    // We don't cache errors, the emulator will
+                    // attempt to read the sector synchronously and
+                    // handle the error when it gets there.
+                    Err(_) => break,
+                }
+            }
+
+            msf =
+                match msf.next() {
+                    Some(m) => m,
+                    None => break,
+                };
+        }
+
+        if next.is_none() {
+            next = requests.recv().ok();
+        }
+    }
+}
+
+/// Least Recently Used sector cache, indexed by absolute sector
+/// index
+struct Cache {
+    /// Cached sectors along with the date of their last use
+    sectors: HashMap<u32, (Sector, u64)>,
+    /// Maximum number of entries in `sectors`
+    capacity: usize,
+    /// Monotonic counter used to date the accesses
+    date: u64,
+}
+
+impl Cache {
+    fn new(capacity: usize) -> Cache {
+        Cache {
+            sectors: HashMap::with_capacity(capacity),
+            capacity: capacity,
+            date: 0,
+        }
+    }
+
+    fn contains(&self, index: u32) -> bool {
+        self.sectors.contains_key(&index)
+    }
+
+    /// Return a copy of the sector at `index` if it's cached
+    fn get(&mut self, index: u32) -> Option<Sector> {
+        self.date += 1;
+
+        let date = self.date;
+
+        match self.sectors.get_mut(&index) {
+            Some(&mut (ref sector, ref mut last_use)) => {
+                *last_use = date;
+
+                Some(sector.clone())
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, index: u32, sector: Sector) {
+        self.date += 1;
+
+        if self.sectors.len() >= self.capacity &&
+            !self.sectors.contains_key(&index) {
+            // Evict the least recently used entry. The cache is small
+            // enough that a linear search is fine.
+            let lru =
+                self.sectors.iter()
+                .min_by_key(|&(_, &(_, last_use))| last_use)
+                .map(|(&i, _)| i);
+
+            if let Some(lru) = lru {
+                self.sectors.remove(&lru);
+            }
+        }
+
+        self.sectors.insert(index, (sector, self.date));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use cdimage::{Image, CdError};
+    use cdimage::msf::Msf;
+    use cdimage::bcd::Bcd;
+    use cdimage::sector::Sector;
+
+    use super::{Cache, Prefetcher};
+
+    /// Image counting the sector reads, reads past `last` fail
+    struct TestImage {
+        reads: Arc<AtomicUsize>,
+        last: u32,
+    }
+
+    impl Image for TestImage {
+        fn image_format(&self) -> String {
+            "test".into()
+        }
+
+        fn read_sector(&mut self,
+                       _: &mut Sector,
+                       msf: Msf) -> Result<(), CdError> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+
+            if msf.sector_index() > self.last {
+                Err(CdError::BadFormat)
+            } else {
+                Ok(())
+            }
+        }
+
+        fn track_msf(&self, _: Bcd, msf: Msf) -> Result<Msf, CdError> {
+            Ok(msf)
+        }
+    }
+
+    fn msf(index: u32) -> Msf {
+        Msf::from_sector_index(index).unwrap()
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let mut cache = Cache::new(3);
+
+        cache.insert(1, Sector::empty());
+        cache.insert(2, Sector::empty());
+        cache.insert(3, Sector::empty());
+
+        // 1 is now more recent than 2
+        assert!(cache.get(1).is_some());
+
+        cache.insert(4, Sector::empty());
+
+        assert!(cache.contains(1));
+        assert!(!cache.contains(2));
+        assert!(cache.contains(3));
+        assert!(cache.contains(4));
+
+        // Replacing an entry doesn't evict anything
+        cache.insert(4, Sector::empty());
+
+        assert!(cache.contains(1));
+        assert!(cache.contains(3));
+
+        cache.insert(5, Sector::empty());
+
+        assert!(!cache.contains(3));
+        assert!(cache.get(2).is_none());
+    }
+
+    #[test]
+    fn read_errors() {
+        let reads = Arc::new(AtomicUsize::new(0));
+
+        let image = TestImage {
+            reads: reads.clone(),
+            last: 200,
+        };
+
+        let mut reader = Prefetcher::synchronous(Box::new(image));
+
+        assert!(reader.read_sector(msf(150)).is_ok());
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+        // Cache hit
+        assert!(reader.read_sector(msf(150)).is_ok());
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+        // The error is returned to the caller and not cached, the
+        // next attempt hits the image again
+        assert!(reader.read_sector(msf(201)).is_err());
+        assert!(reader.read_sector(msf(201)).is_err());
+        assert_eq!(reads.load(Ordering::SeqCst), 3);
+    }
+}
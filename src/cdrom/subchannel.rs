@@ -0,0 +1,483 @@
+//! Subchannel Q handling.
+//!
+//! Each CD sector comes with 96 bytes of subchannel data spread over
+//! 8 "channels" P to W. The PlayStation only cares about the Q
+//! subchannel which contains the position of the sector on the disc
+//! (track, index, relative and absolute MSF) protected by a CRC.
+//!
+//! LibCrypt-protected discs have the subchannel Q of a few sectors
+//! deliberately corrupted. Since most disc images don't contain any
+//! subchannel data the modified sectors have to be provided in a side
+//! file (.SBI or .LSD) which we use to patch the Q data generated
+//! from the image.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+
+use cdimage::TrackFormat;
+use cdimage::sector::Sector;
+use cdimage::msf::Msf;
+
+/// Raw Q subchannel data for a single sector (12 bytes including the
+/// CRC).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct Q([u8; 12]);
+
+impl Q {
+    /// Create an all-zero Q subchannel. The CRC won't be valid.
+    pub fn new() -> Q {
+        Q([0; 12])
+    }
+
+    /// Create a Q subchannel from its raw representation
+    pub fn from_raw(raw: [u8; 12]) -> Q {
+        Q(raw)
+    }
+
+    /// Build the Q subchannel data from the sector's position
+    /// metadata. The `cdimage` backends don't give us access to the
+    /// raw subchannel so we reconstruct it from the position
+    /// information they provide, which is what the Q subchannel
+    /// encodes for regular (ADR 1) sectors.
+    ///
+    /// XXX Images with subchannel data (.sub files, CloneCD, raw
+    /// 2448 byte sectors) could be used directly once `cdimage`
+    /// exposes it in `Sector`. For now the side files are the only
+    /// way to get the real Q data.
+    pub fn from_sector(sector: &Sector) -> Q {
+        let metadata = sector.metadata();
+
+        let (rel_m, rel_s, rel_f) = metadata.track_msf.into_bcd();
+        let (abs_m, abs_s, abs_f) = metadata.msf.into_bcd();
+
+        let mut q = Q([
+            control_adr(metadata.format),
+            metadata.track.bcd(),
+            metadata.index.bcd(),
+            rel_m.bcd(),
+            rel_s.bcd(),
+            rel_f.bcd(),
+            // Always 0
+            0x00,
+            abs_m.bcd(),
+            abs_s.bcd(),
+            abs_f.bcd(),
+            // CRC placeholder
+            0x00,
+            0x00,
+            ]);
+
+        q.update_crc();
+
+        q
+    }
+
+    /// Return the raw 12 bytes of Q subchannel data
+    pub fn raw(&self) -> &[u8; 12] {
+        &self.0
+    }
+
+    /// Track number (BCD)
+    pub fn track(&self) -> u8 {
+        self.0[1]
+    }
+
+    /// Index number (BCD)
+    pub fn index(&self) -> u8 {
+        self.0[2]
+    }
+
+    /// Position relative to the start of the track as a BCD triplet
+    /// `[m, s, f]`
+    pub fn track_msf(&self) -> [u8; 3] {
+        [self.0[3], self.0[4], self.0[5]]
+    }
+
+    /// Absolute position as a BCD triplet `[m, s, f]`
+    pub fn absolute_msf(&self) -> [u8; 3] {
+        [self.0[7], self.0[8], self.0[9]]
+    }
+
+    /// Return true if the stored CRC matches the payload
+    pub fn crc_valid(&self) -> bool {
+        let crc = crc16(&self.0[0..10]);
+
+        self.0[10] == (crc >> 8) as u8 && self.0[11] == crc as u8
+    }
+
+    /// Recompute the CRC from the payload
+    fn update_crc(&mut self) {
+        let crc = crc16(&self.0[0..10]);
+
+        self.0[10] = (crc >> 8) as u8;
+        self.0[11] = crc as u8;
+    }
+
+    /// Corrupt the CRC. LibCrypt sectors are stored with a bad CRC
+    /// and the .SBI format doesn't bother storing it.
+    fn invalidate_crc(&mut self) {
+        self.update_crc();
+
+        self.0[10] ^= 0xff;
+        self.0[11] ^= 0xff;
+    }
+}
+
+/// Return the first byte of the Q subchannel for a track of type
+/// `format`: the control bits in the high nibble and ADR 1 (position)
+/// in the low nibble
+fn control_adr(format: TrackFormat) -> u8 {
+    let control =
+        match format {
+            // Two channels, no pre-emphasis, copy prohibited
+            TrackFormat::Audio => 0x0,
+            // Data track, copy prohibited
+            _ => 0x4,
+        };
+
+    (control << 4) | 1
+}
+
+/// Collection of subchannel Q patches indexed by absolute BCD MSF
+/// `[m, s, f]`
+pub struct Patches {
+    patches: HashMap<[u8; 3], Patch>,
+}
+
+impl Patches {
+    /// Create an empty patch set
+    pub fn new() -> Patches {
+        Patches {
+            patches: HashMap::new(),
+        }
+    }
+
+    /// Load a patch file, the format (.SBI or .LSD) is detected
+    /// automatically.
+    pub fn load_file(path: &Path) -> Result<Patches, Error> {
+        let mut f = try!(File::open(path));
+
+        let mut data = Vec::new();
+
+        try!(f.read_to_end(&mut data));
+
+        Patches::from_raw(&data)
+    }
+
+    /// Parse a patch file from its raw contents. .SBI files start
+    /// with a "SBI\0" magic, .LSD files have no header at all so we
+    /// assume anything else is LSD.
+    pub fn from_raw(data: &[u8]) -> Result<Patches, Error> {
+        if data.starts_with(b"SBI\0") {
+            Patches::parse_sbi(&data[4..])
+        } else {
+            Patches::parse_lsd(data)
+        }
+    }
+
+    /// SBI entries are made of a 3 byte absolute BCD MSF followed by
+    /// a type byte which describes the patch data.
+    fn parse_sbi(mut data: &[u8]) -> Result<Patches, Error> {
+        let mut patches = Patches::new();
+
+        while !data.is_empty() {
+            if data.len() < 4 {
+                return Err(Error::Truncated);
+            }
+
+            let msf = [data[0], data[1], data[2]];
+            let ty = data[3];
+
+            data = &data[4..];
+
+            let len =
+                match ty {
+                    1 => 10,
+                    2 | 3 => 3,
+                    _ => return Err(Error::BadSbiType(ty)),
+                };
+
+            if data.len() < len {
+                return Err(Error::Truncated);
+            }
+
+            let patch =
+                match ty {
+                    1 => {
+                        let mut q = [0; 10];
+
+                        q.copy_from_slice(&data[0..10]);
+
+                        Patch::Payload(q)
+                    }
+                    2 => Patch::TrackMsf([data[0], data[1], data[2]]),
+                    _ => Patch::AbsoluteMsf([data[0], data[1], data[2]]),
+                };
+
+            try!(patches.insert(msf, patch));
+
+            data = &data[len..];
+        }
+
+        Ok(patches)
+    }
+
+    /// LSD entries are 15 bytes long: a 3 byte absolute BCD MSF
+    /// followed by the full 12 bytes of Q subchannel data (CRC
+    /// included)
+    fn parse_lsd(data: &[u8]) -> Result<Patches, Error> {
+        if data.len() % 15 != 0 {
+            return Err(Error::Truncated);
+        }
+
+        let mut patches = Patches::new();
+
+        for entry in data.chunks(15) {
+            let msf = [entry[0], entry[1], entry[2]];
+
+            let mut q = [0; 12];
+
+            q.copy_from_slice(&entry[3..15]);
+
+            try!(patches.insert(msf, Patch::Raw(q)));
+        }
+
+        Ok(patches)
+    }
+
+    fn insert(&mut self, msf: [u8; 3], patch: Patch) -> Result<(), Error> {
+        if Msf::from_bcd(msf[0], msf[1], msf[2]).is_none() {
+            return Err(Error::BadMsf(msf));
+        }
+
+        self.patches.insert(msf, patch);
+
+        Ok(())
+    }
+
+    /// Return the number of patched sectors
+    pub fn len(&self) -> usize {
+        self.patches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patches.is_empty()
+    }
+
+    /// Apply the patch for sector `msf` (if any) to `q`
+    pub fn apply(&self, msf: Msf, q: &mut Q) {
+        let (m, s, f) = msf.into_bcd();
+
+        if let Some(patch) = self.patches.get(&[m.bcd(), s.bcd(), f.bcd()]) {
+            patch.apply(q);
+        }
+    }
+}
+
+/// A single subchannel Q patch
+enum Patch {
+    /// Replace the whole Q payload, the CRC is invalidated
+    Payload([u8; 10]),
+    /// Replace the relative MSF, the CRC is invalidated
+    TrackMsf([u8; 3]),
+    /// Replace the absolute MSF, the CRC is invalidated
+    AbsoluteMsf([u8; 3]),
+    /// Replace the full Q subchannel including the CRC
+    Raw([u8; 12]),
+}
+
+impl Patch {
+    fn apply(&self, q: &mut Q) {
+        match *self {
+            Patch::Payload(ref p) => {
+                q.0[0..10].copy_from_slice(p);
+                q.invalidate_crc();
+            }
+            Patch::TrackMsf(ref msf) => {
+                q.0[3..6].copy_from_slice(msf);
+                q.invalidate_crc();
+            }
+            Patch::AbsoluteMsf(ref msf) => {
+                q.0[7..10].copy_from_slice(msf);
+                q.invalidate_crc();
+            }
+            Patch::Raw(ref raw) => q.0 = *raw,
+        }
+    }
+}
+
+/// CRC-16-CCITT as used by the Q subchannel: polynomial 0x1021, 0
+/// initial value and inverted output.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+
+    for &b in data {
+        crc ^= (b as u16) << 8;
+
+        for _ in 0..8 {
+            crc =
+                if crc & 0x8000 != 0 {
+                    (crc << 1) ^ 0x1021
+                } else {
+                    crc << 1
+                };
+        }
+    }
+
+    !crc
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Error while reading the patch file
+    IoError(io::Error),
+    /// The patch file ends in the middle of an entry
+    Truncated,
+    /// Unknown SBI entry type
+    BadSbiType(u8),
+    /// Invalid BCD MSF in a patch entry
+    BadMsf([u8; 3]),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::IoError(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cdimage::TrackFormat;
+    use cdimage::msf::Msf;
+
+    use super::{Q, Patches, Error, crc16, control_adr};
+
+    /// Track 01, index 01, relative 00:00:00, absolute 00:02:00
+    const TRACK1_START: [u8; 10] = [
+        0x41, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00
+    ];
+
+    fn q_at(abs: [u8; 3]) -> Q {
+        let mut raw = [0; 12];
+
+        raw[0..10].copy_from_slice(&TRACK1_START);
+        raw[7..10].copy_from_slice(&abs);
+
+        let mut q = Q::from_raw(raw);
+
+        q.update_crc();
+
+        q
+    }
+
+    fn msf(m: u8, s: u8, f: u8) -> Msf {
+        Msf::from_bcd(m, s, f).unwrap()
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc16(&TRACK1_START), 0x2832);
+
+        let mut raw = [0; 12];
+
+        raw[0..10].copy_from_slice(&TRACK1_START);
+        raw[10] = 0x28;
+        raw[11] = 0x32;
+
+        assert!(Q::from_raw(raw).crc_valid());
+
+        raw[11] ^= 1;
+
+        assert!(!Q::from_raw(raw).crc_valid());
+    }
+
+    #[test]
+    fn control() {
+        assert_eq!(control_adr(TrackFormat::Audio), 0x01);
+        assert_eq!(control_adr(TrackFormat::Mode2Xa), 0x41);
+    }
+
+    #[test]
+    fn sbi() {
+        let mut sbi = b"SBI\0".to_vec();
+
+        // Type 1: full payload
+        sbi.extend_from_slice(&[0x03, 0x08, 0x05, 0x01]);
+        sbi.extend_from_slice(&[0x41, 0x01, 0x01, 0x07, 0x06, 0x05,
+                                0x00, 0x23, 0x08, 0x05]);
+        // Type 2: relative MSF
+        sbi.extend_from_slice(&[0x03, 0x08, 0x10, 0x02, 0x01, 0x02, 0x03]);
+        // Type 3: absolute MSF
+        sbi.extend_from_slice(&[0x03, 0x08, 0x20, 0x03, 0x04, 0x05, 0x06]);
+
+        let patches = Patches::from_raw(&sbi).unwrap();
+
+        assert_eq!(patches.len(), 3);
+
+        let mut q = q_at([0x03, 0x08, 0x05]);
+        patches.apply(msf(0x03, 0x08, 0x05), &mut q);
+        assert_eq!(q.track_msf(), [0x07, 0x06, 0x05]);
+        assert_eq!(q.absolute_msf(), [0x23, 0x08, 0x05]);
+        assert!(!q.crc_valid());
+
+        let mut q = q_at([0x03, 0x08, 0x10]);
+        patches.apply(msf(0x03, 0x08, 0x10), &mut q);
+        assert_eq!(q.track_msf(), [0x01, 0x02, 0x03]);
+        assert_eq!(q.absolute_msf(), [0x03, 0x08, 0x10]);
+        assert!(!q.crc_valid());
+
+        let mut q = q_at([0x03, 0x08, 0x20]);
+        patches.apply(msf(0x03, 0x08, 0x20), &mut q);
+        assert_eq!(q.track_msf(), [0x00, 0x00, 0x00]);
+        assert_eq!(q.absolute_msf(), [0x04, 0x05, 0x06]);
+        assert!(!q.crc_valid());
+
+        // Sectors without a patch are left untouched
+        let mut q = q_at([0x03, 0x08, 0x21]);
+        let orig = q;
+        patches.apply(msf(0x03, 0x08, 0x21), &mut q);
+        assert_eq!(q, orig);
+        assert!(q.crc_valid());
+    }
+
+    #[test]
+    fn sbi_errors() {
+        match Patches::from_raw(b"SBI\0\x03\x08\x05\x04") {
+            Err(Error::BadSbiType(4)) => (),
+            r => panic!("Unexpected result: {:?}", r.map(|p| p.len())),
+        }
+
+        match Patches::from_raw(b"SBI\0\x03\x08\x05\x02\x01") {
+            Err(Error::Truncated) => (),
+            r => panic!("Unexpected result: {:?}", r.map(|p| p.len())),
+        }
+    }
+
+    #[test]
+    fn lsd() {
+        let raw = [0x41, 0x01, 0x01, 0x07, 0x06, 0x05,
+                   0x00, 0x23, 0x08, 0x05, 0x12, 0x34];
+
+        let mut lsd = vec![0x03, 0x08, 0x05];
+        lsd.extend_from_slice(&raw);
+
+        let patches = Patches::from_raw(&lsd).unwrap();
+
+        assert_eq!(patches.len(), 1);
+
+        let mut q = q_at([0x03, 0x08, 0x05]);
+        patches.apply(msf(0x03, 0x08, 0x05), &mut q);
+        assert_eq!(q.raw(), &raw);
+
+        // Entries are always 15 bytes long
+        lsd.pop();
+
+        match Patches::from_raw(&lsd) {
+            Err(Error::Truncated) => (),
+            r => panic!("Unexpected result: {:?}", r.map(|p| p.len())),
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! SYSTEM.CNF parser.
+//!
+//! SYSTEM.CNF is a small text file at the root of PlayStation discs
+//! which tells the BIOS which executable to boot and how to configure
+//! the kernel before running it. It looks like:
+//!
+//! ```text
+//! BOOT = cdrom:\SCUS_945.03;1
+//! TCB = 4
+//! EVENT = 10
+//! STACK = 801FFFF0
+//! ```
+//!
+//! All numeric values are in hexadecimal.
+
+use std::fmt;
+
+/// Path of the executable booted by the BIOS when the disc doesn't
+/// have a SYSTEM.CNF
+pub const DEFAULT_BOOT: &'static [u8] = b"cdrom:\\PSX.EXE;1";
+
+/// Default number of Task Control Blocks
+pub const DEFAULT_TCB: u32 = 4;
+
+/// Default number of Event Control Blocks
+pub const DEFAULT_EVENT: u32 = 0x10;
+
+/// Default initial stack pointer
+pub const DEFAULT_STACK: u32 = 0x801fff00;
+
+/// Parsed contents of a SYSTEM.CNF file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SystemCnf {
+    /// Path of the boot executable, for instance
+    /// `cdrom:\SCUS_945.03;1`
+    pub boot: Vec<u8>,
+    /// Optional arguments following the boot path
+    pub boot_args: Vec<u8>,
+    /// Number of Task Control Blocks to allocate
+    pub tcb: u32,
+    /// Number of Event Control Blocks to allocate
+    pub event: u32,
+    /// Initial stack pointer
+    pub stack: u32,
+}
+
+impl SystemCnf {
+    /// Parse the raw contents of a SYSTEM.CNF file. The BOOT line is
+    /// mandatory, the other values fall back to the BIOS defaults if
+    /// they're missing or invalid.
+    pub fn parse(raw: &[u8]) -> Result<SystemCnf, Error> {
+        let mut cnf = SystemCnf {
+            boot: Vec::new(),
+            boot_args: Vec::new(),
+            tcb: DEFAULT_TCB,
+            event: DEFAULT_EVENT,
+            stack: DEFAULT_STACK,
+        };
+
+        let mut have_boot = false;
+
+        for line in raw.split(|&b| b == b'\n' || b == b'\r') {
+            let eq =
+                match line.iter().position(|&b| b == b'=') {
+                    Some(p) => p,
+                    // Blank line or garbage, ignore it
+                    None => continue,
+                };
+
+            let key = trim(&line[..eq]);
+            let value = trim(&line[eq + 1..]);
+
+            match key {
+                b"BOOT" => {
+                    // The path can be followed by arguments
+                    let (path, args) =
+                        match value.iter().position(|&b| b == b' ' || b == b'\t') {
+                            Some(p) => (&value[..p], trim(&value[p..])),
+                            None => (value, &[][..]),
+                        };
+
+                    if path.is_empty() {
+                        return Err(Error::EmptyBootPath);
+                    }
+
+                    cnf.boot = path.into();
+                    cnf.boot_args = args.into();
+                    have_boot = true;
+                }
+                b"TCB" => parse_hex(key, value, &mut cnf.tcb),
+                b"EVENT" => parse_hex(key, value, &mut cnf.event),
+                b"STACK" => parse_hex(key, value, &mut cnf.stack),
+                _ => warn!("Unknown SYSTEM.CNF key: {}",
+                           String::from_utf8_lossy(key)),
+            }
+        }
+
+        if !have_boot {
+            return Err(Error::MissingBoot);
+        }
+
+        Ok(cnf)
+    }
+
+    /// Return the name of the boot executable without the device,
+    /// directories and version number. For instance
+    /// `cdrom:\FOO\SCUS_945.03;1` returns `SCUS_945.03`.
+    pub fn boot_file_name(&self) -> &[u8] {
+        let start =
+            match self.boot.iter().rposition(|&b| b == b'\\' || b == b':') {
+                Some(p) => p + 1,
+                None => 0,
+            };
+
+        let name = &self.boot[start..];
+
+        match name.iter().position(|&b| b == b';') {
+            Some(p) => &name[..p],
+            None => name,
+        }
+    }
+}
+
+impl fmt::Display for SystemCnf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BOOT={} TCB={:x} EVENT={:x} STACK={:08x}",
+               String::from_utf8_lossy(&self.boot),
+               self.tcb,
+               self.event,
+               self.stack)
+    }
+}
+
+/// Remove leading and trailing whitespaces. The file is sometimes
+/// padded with NULs or terminated by a DOS EOF character (0x1a)
+/// without a final newline, those are removed as well.
+fn trim(s: &[u8]) -> &[u8] {
+    let is_space =
+        |b: &u8| *b == b' ' || *b == b'\t' || *b == 0 || *b == 0x1a;
+
+    let start =
+        match s.iter().position(|b| !is_space(b)) {
+            Some(p) => p,
+            None => return &[],
+        };
+
+    let end = s.iter().rposition(|b| !is_space(b)).unwrap() + 1;
+
+    &s[start..end]
+}
+
+/// Parse the hexadecimal `value` of `key` into `out`. If the value is
+/// invalid a warning is displayed and `out` is left untouched.
+fn parse_hex(key: &[u8], value: &[u8], out: &mut u32) {
+    match parse_hex_value(key, value) {
+        Ok(v) => *out = v,
+        Err(e) => warn!("Invalid SYSTEM.CNF value, using default 0x{:x}: {:?}",
+                        *out, e),
+    }
+}
+
+fn parse_hex_value(key: &[u8], value: &[u8]) -> Result<u32, Error> {
+    let bad_value =
+        || Error::BadValue(String::from_utf8_lossy(key).into_owned(),
+                           String::from_utf8_lossy(value).into_owned());
+
+    let s =
+        match ::std::str::from_utf8(value) {
+            Ok(s) => s,
+            Err(_) => return Err(bad_value()),
+        };
+
+    // Some discs use an explicit "0x" prefix
+    let s =
+        if s.starts_with("0x") || s.starts_with("0X") {
+            &s[2..]
+        } else {
+            s
+        };
+
+    u32::from_str_radix(s, 16).map_err(|_| bad_value())
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// SYSTEM.CNF doesn't contain a BOOT line
+    MissingBoot,
+    /// The BOOT line doesn't contain a path
+    EmptyBootPath,
+    /// Couldn't parse the value of a key: `(key, value)`
+    BadValue(String, String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SystemCnf, Error, DEFAULT_TCB, DEFAULT_EVENT, DEFAULT_STACK};
+
+    #[test]
+    fn boot_with_args() {
+        let raw = b"BOOT = cdrom:\\FOO\\SLUS_123.45;1 arg1 arg2\r\n\
+                    TCB = 4\r\n\
+                    EVENT = 10\r\n\
+                    STACK = 801FFFF0\r\n";
+
+        let cnf = SystemCnf::parse(raw).unwrap();
+
+        assert_eq!(cnf.boot, b"cdrom:\\FOO\\SLUS_123.45;1".to_vec());
+        assert_eq!(cnf.boot_args, b"arg1 arg2".to_vec());
+        assert_eq!(cnf.boot_file_name(), b"SLUS_123.45");
+        assert_eq!(cnf.tcb, 4);
+        assert_eq!(cnf.event, 0x10);
+        assert_eq!(cnf.stack, 0x801ffff0);
+    }
+
+    #[test]
+    fn hex_prefix() {
+        let cnf = SystemCnf::parse(b"BOOT=cdrom:\\PSX.EXE;1\n\
+                                     EVENT=0x20\n\
+                                     STACK=0X801FFFF0").unwrap();
+
+        assert!(cnf.boot_args.is_empty());
+        assert_eq!(cnf.tcb, DEFAULT_TCB);
+        assert_eq!(cnf.event, 0x20);
+        assert_eq!(cnf.stack, 0x801ffff0);
+    }
+
+    #[test]
+    fn missing_boot() {
+        match SystemCnf::parse(b"TCB = 4\nEVENT = 10\n") {
+            Err(Error::MissingBoot) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        match SystemCnf::parse(b"BOOT = \nTCB = 4\n") {
+            Err(Error::EmptyBootPath) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn bad_hex() {
+        // Invalid values keep the defaults, the trailing padding is
+        // ignored
+        let cnf = SystemCnf::parse(b"BOOT = cdrom:\\SCES_000.01;1\n\
+                                     TCB = 4 garbage\n\
+                                     EVENT = XYZ\n\
+                                     STACK = 801FFFF0\x1a\x00\x00").unwrap();
+
+        assert_eq!(cnf.tcb, DEFAULT_TCB);
+        assert_eq!(cnf.event, DEFAULT_EVENT);
+        assert_eq!(cnf.stack, 0x801ffff0);
+        assert!(cnf.stack != DEFAULT_STACK);
+    }
+}
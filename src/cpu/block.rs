@@ -0,0 +1,425 @@
+//! Block cache used by the cached interpreter.
+//!
+//! Instead of decoding every instruction from scratch we pre-decode
+//! basic blocks (a run of instructions ending with a jump or branch
+//! and its delay slot) into a list of handlers.
+//!
+//! Instructions with a handler can't trigger a sync, modify the
+//! interrupt state or write to memory. Like the dynarec we run a
+//! sequence of those without checking for syncs and interrupts or
+//! fetching them through the instruction cache as long as we know
+//! that no sync will be needed, that no interrupt is pending and that
+//! the instruction fetch timings are deterministic. The other
+//! instructions go through the regular interpreter and the
+//! conditions are checked again afterwards. The timings are exactly
+//! the same as the interpreter's.
+//!
+//! Each cached instruction is checked against the word actually
+//! fetched by the CPU: if the memory or the instruction cache changed
+//! under our feet (DMA transfer, cache isolation...) the block is
+//! discarded and we fall back to the regular decoder. Blocks are also
+//! invalidated eagerly when the CPU writes to the RAM pages they
+//! cover or when the cache is isolated.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
+
+use memory::{Interconnect, map};
+use shared::SharedState;
+use debugger::Debugger;
+use timekeeper::Cycles;
+
+use super::{Cpu, Instruction};
+
+/// Maximum number of instructions in a block
+const MAX_BLOCK_LEN: usize = 64;
+
+/// log2 of the size of the RAM pages used to track block
+/// invalidation
+const PAGE_SHIFT: u32 = 10;
+
+/// Number of invalidation pages in the 2MB of RAM
+const PAGE_COUNT: usize = (2 * 1024 * 1024) >> PAGE_SHIFT;
+
+/// Instruction handler which doesn't need access to the rest of the
+/// system. Only the GTE and HI/LO instructions use the `SharedState`,
+/// to stall until the result is ready.
+pub type Handler = fn(&mut Cpu, Instruction, &mut SharedState);
+
+/// Wrap a `Cpu` method which doesn't need the `SharedState` in a
+/// `Handler`
+macro_rules! pure {
+    ($op:ident) => {{
+        fn handler(cpu: &mut Cpu,
+                   instruction: Instruction,
+                   _: &mut SharedState) {
+            cpu.$op(instruction)
+        }
+
+        handler
+    }}
+}
+
+/// Pre-decoded instruction
+#[derive(Clone, Copy)]
+pub struct CachedOp {
+    /// Raw instruction word, used to validate the cache
+    pub word: u32,
+    /// Handler for this instruction. `None` means that the
+    /// instruction must go through `decode_and_execute` (memory
+    /// accesses, COP0, breakpoints...)
+    pub handler: Option<Handler>,
+}
+
+pub struct Block {
+    /// Instructions in the block, starting at the block's address
+    pub ops: Vec<CachedOp>,
+}
+
+impl Block {
+    /// Pre-decode the block starting at `pc`. Only code in RAM and
+    /// BIOS can be cached, for other addresses an empty block is
+    /// returned.
+    pub fn build(inter: &Interconnect, pc: u32) -> Block {
+        let mut ops = Vec::new();
+        let mut pc = pc;
+        let mut delay_slot = false;
+
+        while ops.len() < MAX_BLOCK_LEN {
+            let word =
+                match inter.peek_instruction(pc) {
+                    Some(w) => w,
+                    None => break,
+                };
+
+            let instruction = Instruction(word);
+
+            ops.push(CachedOp {
+                word: word,
+                handler: handler(instruction),
+            });
+
+            if delay_slot {
+                break;
+            }
+
+            delay_slot = ends_block(instruction);
+
+            pc = pc.wrapping_add(4);
+        }
+
+        Block {
+            ops: ops,
+        }
+    }
+}
+
+impl Cpu {
+    /// Run the instructions at the beginning of `ops` (starting at
+    /// `pc`) that have a handler without checking for syncs,
+    /// interrupts and breakpoints in between. Returns the number of
+    /// instructions run, 0 if the conditions to do so aren't met.
+    pub fn run_cached_ops<D>(&mut self,
+                             debugger: &mut D,
+                             shared: &mut SharedState,
+                             pc: u32,
+                             ops: &[CachedOp]) -> usize
+        where D: Debugger {
+        let len = ops.iter().take_while(|op| op.handler.is_some()).count();
+
+        if len == 0 ||
+            pc % 4 != 0 ||
+            shared.tk().sync_pending() ||
+            self.cop0.code_breakpoints_enabled() ||
+            self.cop0.irq_active(*shared.irq_state()) {
+            return 0;
+        }
+
+        let words = ops[..len].iter().map(|op| op.word);
+
+        let cycles =
+            match self.fetch_cycles_per_instruction(pc, words) {
+                Some(c) => c,
+                None => return 0,
+            };
+
+        // Stop before the first instruction that would need a sync
+        let until_sync = shared.tk().cycles_until_sync();
+        let max_len = (until_sync + cycles - 1) / cycles;
+
+        let len =
+            if (len as Cycles) < max_len {
+                len
+            } else {
+                max_len as usize
+            };
+
+        for (i, op) in ops[..len].iter().enumerate() {
+            let pc = pc.wrapping_add((i as u32) << 2);
+
+            if self.pc != pc {
+                // Exception or jump
+                return i;
+            }
+
+            // Same as `run_instruction` when the fetch hits
+            self.current_pc = pc;
+
+            debugger.pc_change(self);
+
+            self.pc         = self.next_pc;
+            self.next_pc    = self.pc.wrapping_add(4);
+            self.delay_slot = self.branch;
+            self.branch     = false;
+
+            shared.tk().tick(cycles);
+
+            let date = shared.tk().now();
+
+            if let Some(handler) = op.handler {
+                handler(self, Instruction(op.word), shared);
+            }
+
+            if shared.tk().now() != date {
+                // The instruction stalled, the sync might be closer
+                // than we thought
+                return i + 1;
+            }
+        }
+
+        len
+    }
+
+    /// Return the number of cycles taken by each instruction of the
+    /// sequence starting at `start` if the instruction fetch timings
+    /// are deterministic and the contents of the memory match
+    /// `words`. Must match `fetch_instruction`.
+    pub fn fetch_cycles_per_instruction<I>(&self,
+                                           start: u32,
+                                           words: I) -> Option<Cycles>
+        where I: IntoIterator<Item = u32> {
+        let cc = self.inter.cache_control();
+
+        if start < 0xa0000000 && cc.icache_enabled() {
+            // Cached code, every fetch must hit
+            for (i, w) in words.into_iter().enumerate() {
+                let pc = start.wrapping_add((i as u32) << 2);
+
+                let tag = pc & 0x7ffff000;
+                let line = &self.icache[((pc >> 4) & 0xff) as usize];
+                let index = (pc >> 2) & 3;
+
+                if line.tag() != tag ||
+                    !line.valid(index) ||
+                    line.instruction(index).0 != w {
+                    return None;
+                }
+            }
+
+            // Only the execution time
+            Some(1)
+        } else {
+            for (i, w) in words.into_iter().enumerate() {
+                let pc = start.wrapping_add((i as u32) << 2);
+
+                if self.inter.peek_instruction(pc) != Some(w) {
+                    return None;
+                }
+            }
+
+            // Uncached fetch + execution time. The memory timings
+            // can't change while running instructions that don't
+            // access the rest of the system.
+            let (first, _) = self.inter.fetch_cycles(start);
+
+            Some(first + 1)
+        }
+    }
+}
+
+/// Cache of pre-decoded (or recompiled) blocks indexed by their
+/// address. It's not stored in savestates, it'll be rebuilt as
+/// needed.
+pub struct BlockCache<B> {
+    blocks: HashMap<u32, Arc<B>>,
+    /// Address of the blocks overlapping each page of RAM
+    pages: Vec<Vec<u32>>,
+}
+
+impl<B> BlockCache<B> {
+    pub fn new() -> BlockCache<B> {
+        BlockCache {
+            blocks: HashMap::new(),
+            pages: vec![Vec::new(); PAGE_COUNT],
+        }
+    }
+
+    pub fn get(&self, pc: u32) -> Option<Arc<B>> {
+        self.blocks.get(&pc).cloned()
+    }
+
+    pub fn contains(&self, pc: u32) -> bool {
+        self.blocks.contains_key(&pc)
+    }
+
+    /// Insert `block` covering the `len` instructions starting at
+    /// `pc`
+    pub fn insert(&mut self, pc: u32, len: usize, block: Arc<B>) {
+        let len = len as u32;
+
+        if len == 0 {
+            return;
+        }
+
+        let first = ram_page(pc);
+        let last = ram_page(pc.wrapping_add((len - 1) * 4));
+
+        if let (Some(first), Some(last)) = (first, last) {
+            // Blocks can't wrap around the end of RAM since they'd
+            // run into the next region first
+            for page in first..(last + 1) {
+                let page = &mut self.pages[page];
+
+                if !page.contains(&pc) {
+                    page.push(pc);
+                }
+            }
+        }
+
+        self.blocks.insert(pc, block);
+    }
+
+    /// Remove the block starting at `pc`
+    pub fn invalidate(&mut self, pc: u32) {
+        self.blocks.remove(&pc);
+    }
+
+    /// Called when the CPU writes to `addr`, invalidates the blocks
+    /// overlapping the target RAM page
+    pub fn invalidate_ram(&mut self, addr: u32) {
+        let page =
+            match ram_page(addr) {
+                Some(p) => p,
+                None => return,
+            };
+
+        for pc in self.pages[page].drain(..) {
+            self.blocks.remove(&pc);
+        }
+    }
+
+    /// Discard all the blocks
+    pub fn clear(&mut self) {
+        if self.blocks.is_empty() {
+            return;
+        }
+
+        self.blocks.clear();
+
+        for page in &mut self.pages {
+            page.clear();
+        }
+    }
+}
+
+impl<B> Encodable for BlockCache<B> {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        // The cache is rebuilt on demand, no need to store it
+        s.emit_nil()
+    }
+}
+
+impl<B> Decodable for BlockCache<B> {
+    fn decode<D: Decoder>(d: &mut D) -> Result<BlockCache<B>, D::Error> {
+        try!(d.read_nil());
+
+        Ok(BlockCache::new())
+    }
+}
+
+/// Return the index of the invalidation page containing `addr` if it
+/// points to RAM
+fn ram_page(addr: u32) -> Option<usize> {
+    let abs_addr = map::mask_region(addr);
+
+    map::RAM.contains(abs_addr).map(|offset| {
+        // The RAM is mirrored four times
+        ((offset & 0x1fffff) >> PAGE_SHIFT) as usize
+    })
+}
+
+/// Return true if `instruction` is a jump or branch, in which case
+/// the block ends after the following delay slot
+fn ends_block(instruction: Instruction) -> bool {
+    match instruction.function() {
+        0b000000 => match instruction.subfunction() {
+            // JR, JALR
+            0b001000 | 0b001001 => true,
+            _ => false,
+        },
+        // BXX, J, JAL, BEQ, BNE, BLEZ, BGTZ
+        0b000001...0b000111 => true,
+        _ => false,
+    }
+}
+
+/// Return the handler for `instruction` if it can be executed without
+/// accessing the rest of the system. Must match `decode_and_execute`.
+pub fn handler(instruction: Instruction) -> Option<Handler> {
+    let handler: Handler =
+        match instruction.function() {
+            0b000000 => match instruction.subfunction() {
+                0b000000 => pure!(op_sll),
+                0b000010 => pure!(op_srl),
+                0b000011 => pure!(op_sra),
+                0b000100 => pure!(op_sllv),
+                0b000110 => pure!(op_srlv),
+                0b000111 => pure!(op_srav),
+                0b001000 => pure!(op_jr),
+                0b001001 => pure!(op_jalr),
+                0b001100 => pure!(op_syscall),
+                0b010000 => Cpu::op_mfhi,
+                0b010001 => pure!(op_mthi),
+                0b010010 => Cpu::op_mflo,
+                0b010011 => pure!(op_mtlo),
+                0b011000 => Cpu::op_mult,
+                0b011001 => Cpu::op_multu,
+                0b011010 => Cpu::op_div,
+                0b011011 => Cpu::op_divu,
+                0b100000 => pure!(op_add),
+                0b100001 => pure!(op_addu),
+                0b100010 => pure!(op_sub),
+                0b100011 => pure!(op_subu),
+                0b100100 => pure!(op_and),
+                0b100101 => pure!(op_or),
+                0b100110 => pure!(op_xor),
+                0b100111 => pure!(op_nor),
+                0b101010 => pure!(op_slt),
+                0b101011 => pure!(op_sltu),
+                // BREAK needs the debugger
+                _ => return None,
+            },
+            0b000001 => pure!(op_bxx),
+            0b000010 => pure!(op_j),
+            0b000011 => pure!(op_jal),
+            0b000100 => pure!(op_beq),
+            0b000101 => pure!(op_bne),
+            0b000110 => pure!(op_blez),
+            0b000111 => pure!(op_bgtz),
+            0b001000 => pure!(op_addi),
+            0b001001 => pure!(op_addiu),
+            0b001010 => pure!(op_slti),
+            0b001011 => pure!(op_sltiu),
+            0b001100 => pure!(op_andi),
+            0b001101 => pure!(op_ori),
+            0b001110 => pure!(op_xori),
+            0b001111 => pure!(op_lui),
+            0b010010 => Cpu::op_cop2,
+            // COP0 and memory accesses need the rest of the system
+            _ => return None,
+        };
+
+    Some(handler)
+}
@@ -9,6 +9,18 @@ pub struct Cop0 {
     cause:  u32,
     /// Cop0 register 14: Exception PC
     epc: u32,
+    /// Cop0 register 8: Bad Virtual Address, set by address errors
+    bad_vaddr: u32,
+    /// Cop0 register 3: Breakpoint on execute address
+    bpc: u32,
+    /// Cop0 register 11: Breakpoint on execute mask
+    bpcm: u32,
+    /// Cop0 register 5: Breakpoint on data access address
+    bda: u32,
+    /// Cop0 register 9: Breakpoint on data access mask
+    bdam: u32,
+    /// Cop0 register 7: Breakpoint control
+    dcic: Dcic,
 }
 
 impl Cop0 {
@@ -18,6 +30,12 @@ impl Cop0 {
             sr:    0,
             cause: 0,
             epc:   0,
+            bad_vaddr: 0,
+            bpc:   0,
+            bpcm:  0,
+            bda:   0,
+            bdam:  0,
+            dcic:  Dcic(0),
         }
     }
 
@@ -48,6 +66,105 @@ impl Cop0 {
         self.epc
     }
 
+    pub fn bad_vaddr(&self) -> u32 {
+        self.bad_vaddr
+    }
+
+    /// Latch the address which caused an address error
+    /// exception. Bus errors don't modify this register.
+    pub fn set_bad_vaddr(&mut self, addr: u32) {
+        self.bad_vaddr = addr;
+    }
+
+    pub fn bpc(&self) -> u32 {
+        self.bpc
+    }
+
+    pub fn set_bpc(&mut self, bpc: u32) {
+        self.bpc = bpc;
+    }
+
+    pub fn bpcm(&self) -> u32 {
+        self.bpcm
+    }
+
+    pub fn set_bpcm(&mut self, bpcm: u32) {
+        self.bpcm = bpcm;
+    }
+
+    pub fn bda(&self) -> u32 {
+        self.bda
+    }
+
+    pub fn set_bda(&mut self, bda: u32) {
+        self.bda = bda;
+    }
+
+    pub fn bdam(&self) -> u32 {
+        self.bdam
+    }
+
+    pub fn set_bdam(&mut self, bdam: u32) {
+        self.bdam = bdam;
+    }
+
+    pub fn dcic(&self) -> u32 {
+        self.dcic.0
+    }
+
+    pub fn set_dcic(&mut self, dcic: u32) {
+        self.dcic = Dcic(dcic);
+
+        if self.dcic.jump_break_enabled() {
+            // XXX Jump breaks would require tracing all the taken
+            // branches and jumps in all the engines
+            warn!("Unimplemented DCIC jump break: 0x{:08x}", dcic);
+        }
+    }
+
+    /// Return true if execution breakpoints are enabled. The native
+    /// code doesn't check them so it can't be used when they are.
+    pub fn code_breakpoints_enabled(&self) -> bool {
+        self.dcic.code_break_enabled()
+    }
+
+    /// Check for an execution breakpoint at `pc`. If it matches the
+    /// status bits are updated in DCIC and true is returned, the
+    /// caller must then trigger a debug exception.
+    pub fn code_breakpoint(&mut self, pc: u32) -> bool {
+        if !self.dcic.code_break_enabled() ||
+            (pc ^ self.bpc) & self.bpcm != 0 {
+            return false;
+        }
+
+        // Set the "any break" and "code break" status bits
+        self.dcic.0 |= 0x3;
+
+        true
+    }
+
+    /// Check for a data breakpoint on an access to `addr`. Works like
+    /// `code_breakpoint`.
+    pub fn data_breakpoint(&mut self, addr: u32, write: bool) -> bool {
+        if !self.dcic.data_break_enabled(write) ||
+            (addr ^ self.bda) & self.bdam != 0 {
+            return false;
+        }
+
+        // Set the "any break", "data break" and "data read" or "data
+        // write" status bits
+        let status =
+            if write {
+                0x15
+            } else {
+                0xd
+            };
+
+        self.dcic.0 |= status;
+
+        true
+    }
+
     pub fn cache_isolated(&self) -> bool {
         self.sr & 0x10000 != 0
     }
@@ -93,6 +210,20 @@ impl Cop0 {
         }
     }
 
+    /// Enter the exception triggered by the breakpoint hardware. It
+    /// works like the BREAK instruction but uses a dedicated handler.
+    /// Returns the address of the handler.
+    pub fn enter_debug_exception(&mut self,
+                                 pc: u32,
+                                 in_delay_slot: bool) -> u32 {
+        self.enter_exception(Exception::Break, pc, in_delay_slot);
+
+        match self.sr & (1 << 22) != 0 {
+            true  => 0xbfc00140,
+            false => 0x80000040,
+        }
+    }
+
     /// The counterpart to "enter_exception": shift SR's mode back
     /// into place. Doesn't touch CAUSE or EPC however.
     pub fn return_from_exception(&mut self) {
@@ -137,6 +268,10 @@ pub enum Exception {
     LoadAddressError = 0x4,
     /// Address error on store
     StoreAddressError = 0x5,
+    /// Bus error on instruction fetch
+    BusErrorInstruction = 0x6,
+    /// Bus error on data load or store
+    BusErrorData = 0x7,
     /// System call (caused by the SYSCALL opcode)
     SysCall = 0x8,
     /// Breakpoint (caused by the BREAK opcode)
@@ -148,3 +283,42 @@ pub enum Exception {
     /// Arithmetic overflow
     Overflow = 0xc,
 }
+
+/// Breakpoint control register. Bits [5:0] are status flags set by
+/// the hardware when a breakpoint is hit, they're never cleared by
+/// the hardware. The upper bits are the enable flags. Bit
+/// descriptions from No$.
+#[derive(Clone, Copy, RustcDecodable, RustcEncodable)]
+struct Dcic(u32);
+
+impl Dcic {
+    /// Return true if the breakpoints are enabled globally: bits 23,
+    /// 30 and 31 must all be set
+    fn master_enabled(self) -> bool {
+        self.0 & 0xc0800000 == 0xc0800000
+    }
+
+    /// Return true if the breakpoint on execute (BPC/BPCM) is enabled
+    fn code_break_enabled(self) -> bool {
+        self.master_enabled() && self.0 & (1 << 24) != 0
+    }
+
+    /// Return true if the breakpoint on data access (BDA/BDAM) is
+    /// enabled for reads or writes
+    fn data_break_enabled(self, write: bool) -> bool {
+        let access =
+            if write {
+                1 << 27
+            } else {
+                1 << 26
+            };
+
+        self.master_enabled() && self.0 & (1 << 25) != 0 && self.0 & access != 0
+    }
+
+    /// Return true if the "break on any jump" is enabled. Only needs
+    /// bits 23, 28, 29 and 31.
+    fn jump_break_enabled(self) -> bool {
+        self.0 & 0xb0800000 == 0xb0800000
+    }
+}
@@ -0,0 +1,860 @@
+//! x86-64 dynamic recompiler.
+//!
+//! The recompiler translates the blocks built by the `block` module
+//! into native code. The semantics must be exactly the same as the
+//! interpreter's so we only recompile what we can emulate precisely:
+//!
+//! * Simple ALU instructions are translated directly. The MIPS
+//!   registers are kept in the `Cpu` structure, they're loaded and
+//!   stored around every instruction.
+//! * Instructions that don't access the rest of the system (GTE,
+//!   multiplications and divisions, HI/LO moves, SYSCALL...) call
+//!   back into the interpreter's handler.
+//! * Loads and stores call back into the interpreter if they target
+//!   the RAM or the scratchpad. Other accesses exit the recompiled
+//!   code and are left to the interpreter.
+//! * Jumps and branches are supported at the end of a block if their
+//!   delay slot is a simple ALU instruction.
+//! * Everything else (COP0, BREAK...) ends the block and is left to
+//!   the interpreter.
+//!
+//! The instruction following a load (or a MFC2/CFC2) always goes
+//! through the interpreter so that the load delay slot is handled by
+//! `delayed_load`.
+//!
+//! Since those instructions can't modify the interrupt state or
+//! trigger a sync we only enter a recompiled block when we know that
+//! no interrupt is pending and that no sync will be needed until the
+//! end of the block. The instruction fetch timings are deterministic
+//! if all the instructions hit the instruction cache (or if we run
+//! from uncached memory), we can then compute the date of every
+//! instruction. The timekeeper is only brought up to date before the
+//! callbacks, which can stall the CPU (memory accesses, GTE and HI/LO
+//! interlocks), and when we exit the block. If any of those
+//! conditions isn't met we fall back to the cached interpreter.
+//!
+//! Instructions that can raise an exception (ADD, ADDI and SUB
+//! overflows, callbacks to the interpreter) exit the recompiled code
+//! so that the exception can be handled by `Cop0::enter_exception`
+//! exactly like in the interpreter.
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+compile_error!("The dynarec is only supported on x86-64 Linux");
+
+mod x86_64;
+
+use std::any::Any;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
+
+use memory::Interconnect;
+use shared::SharedState;
+use gpu::renderer::Renderer;
+use debugger::Debugger;
+use timekeeper::Cycles;
+
+use super::{Cpu, Instruction, RegisterIndex};
+use super::block::{self, Block, BlockCache, Handler};
+use self::x86_64::{Emitter, CodeBuffer, Reg, Alu, Shift, Cond};
+
+/// Size of the buffer holding the recompiled code. When it's full
+/// all the blocks are discarded.
+const CODE_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+
+/// Status returned by the recompiled code: the whole block has been
+/// executed
+const EXIT_END: u32 = 0;
+/// Status returned by the recompiled code: the instruction whose
+/// index is in bits [31:8] has not been executed, either because it
+/// overflowed or because it accesses something else than the RAM or
+/// the scratchpad. The interpreter must run it.
+const EXIT_TRAP: u32 = 1;
+/// Status returned by the recompiled code: the interpreter handler
+/// called by the instruction whose index is in bits [31:8] changed the
+/// control flow (exception, stall...), the CPU state has already been
+/// updated
+const EXIT_CALLOUT: u32 = 2;
+
+/// Execution context shared between `run_next_native_block`, the
+/// recompiled code and the callouts
+#[repr(C)]
+struct Context {
+    /// Address of the instruction following the delay slot, written
+    /// by the recompiled code
+    pc: u32,
+    /// 1 if the branch was taken, 0 otherwise. Written by the
+    /// recompiled code.
+    taken: u32,
+    shared: *mut SharedState,
+    /// Points to the caller's `Debugger`
+    debugger: *mut u8,
+    /// Points to the caller's `&mut Renderer`
+    renderer: *mut u8,
+    /// `memory_op` instance for the caller's `Debugger`
+    memory_op: unsafe fn(&mut Cpu, Instruction, &mut Context),
+    /// Address of the first instruction of the block
+    start: u32,
+    /// Number of instructions in the block
+    len: u32,
+    /// Duration of every instruction
+    cycles: Cycles,
+    /// Number of instructions already accounted for in the timekeeper
+    ticked: u32,
+    /// Panic caught in a callout. It can't unwind through the
+    /// recompiled code so it's resumed once we're out of it.
+    panic: Option<Box<Any + Send>>,
+}
+
+impl Context {
+    /// Bring the timekeeper to the end of the first `n` instructions
+    /// of the block
+    fn tick(&mut self, shared: &mut SharedState, n: u32) {
+        if n > self.ticked {
+            shared.tk().tick((n - self.ticked) as Cycles * self.cycles);
+            self.ticked = n;
+        }
+    }
+
+    /// Run `f` for the instruction at position `index` in the block
+    /// with the CPU in the state the interpreter would be in. Returns
+    /// 0 if the recompiled code can continue, otherwise the status it
+    /// must return.
+    fn run<F>(&mut self, cpu: &mut Cpu, index: u32, f: F) -> u32
+        where F: FnOnce(&mut Cpu, &mut Context) {
+        let shared = unsafe { &mut *self.shared };
+
+        self.tick(shared, index + 1);
+
+        let pc = self.start.wrapping_add(index << 2);
+        let next = pc.wrapping_add(4);
+
+        cpu.current_pc = pc;
+        cpu.pc = next;
+        cpu.next_pc = next.wrapping_add(4);
+        cpu.delay_slot = false;
+        cpu.branch = false;
+
+        let stop = EXIT_CALLOUT | (index << 8);
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| f(cpu, self)));
+
+        if let Err(e) = res {
+            self.panic = Some(e);
+            return stop;
+        }
+
+        // If the instruction stalled a sync might be needed before
+        // the end of the block
+        let remaining = (self.len - index - 1) as Cycles;
+
+        let sync =
+            remaining > 0 &&
+            shared.tk().cycles_until_sync() <= (remaining - 1) * self.cycles;
+
+        if cpu.pc != next || cpu.branch || sync {
+            stop
+        } else {
+            0
+        }
+    }
+}
+
+/// Signature of the recompiled functions
+type NativeFn = extern "C" fn(*mut u32, *mut Cpu, *mut Context) -> u32;
+
+struct NativeBlock {
+    /// Recompiled code, `None` if the first instruction can't be
+    /// recompiled
+    code: Option<NativeFn>,
+    /// Instructions covered by the block, used to validate it
+    words: Vec<u32>,
+    /// True if the block ends with a branch and its delay slot
+    branch: bool,
+}
+
+pub struct Dynarec {
+    blocks: BlockCache<NativeBlock>,
+    /// Allocated on the first recompilation
+    buffer: Option<CodeBuffer>,
+}
+
+impl Dynarec {
+    pub fn new() -> Dynarec {
+        Dynarec {
+            blocks: BlockCache::new(),
+            buffer: None,
+        }
+    }
+
+    /// Discard all the recompiled blocks
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+
+        if let Some(ref mut b) = self.buffer {
+            b.reset();
+        }
+    }
+
+    /// Called when the CPU writes to `addr`
+    pub fn invalidate_ram(&mut self, addr: u32) {
+        self.blocks.invalidate_ram(addr);
+    }
+
+    /// Recompile the block starting at `pc`
+    fn compile(&mut self, inter: &Interconnect, pc: u32) -> NativeBlock {
+        let block = Block::build(inter, pc);
+
+        let (emitter, words, branch) = translate(&block, pc);
+
+        if words.is_empty() {
+            return NativeBlock {
+                code: None,
+                words: words,
+                branch: false,
+            };
+        }
+
+        if self.buffer.is_none() {
+            self.buffer = Some(CodeBuffer::new(CODE_BUFFER_SIZE));
+        }
+
+        let mut code = self.buffer.as_mut().unwrap().push(emitter.code());
+
+        if code.is_none() {
+            // The buffer is full, start over
+            self.clear();
+
+            code = self.buffer.as_mut().unwrap().push(emitter.code());
+        }
+
+        let code = code.expect("Recompiled block too big");
+
+        NativeBlock {
+            code: Some(unsafe { mem::transmute::<*const u8, NativeFn>(code) }),
+            words: words,
+            branch: branch,
+        }
+    }
+}
+
+impl Encodable for Dynarec {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        // The blocks are recompiled on demand, no need to store them
+        s.emit_nil()
+    }
+}
+
+impl Decodable for Dynarec {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Dynarec, D::Error> {
+        try!(d.read_nil());
+
+        Ok(Dynarec::new())
+    }
+}
+
+impl Cpu {
+    /// Run the recompiled block starting at the current PC. Falls
+    /// back to the cached interpreter when the code can't be
+    /// recompiled or when the conditions to run native code aren't
+    /// met.
+    pub fn run_next_native_block<D>(&mut self,
+                                    debugger: &mut D,
+                                    shared: &mut SharedState,
+                                    mut renderer: &mut Renderer)
+        where D: Debugger {
+        let start = self.pc;
+
+        // Interrupts and syncs are handled by the interpreter, as
+        // well as the instructions following a branch or a load.
+        // The native code doesn't check for execution breakpoints.
+        let can_enter =
+            !shared.tk().sync_pending() &&
+            !self.cop0.code_breakpoints_enabled() &&
+            start % 4 == 0 &&
+            !self.branch &&
+            self.next_pc == start.wrapping_add(4) &&
+            self.load.0 == RegisterIndex(0) &&
+            !self.cop0.irq_active(*shared.irq_state());
+
+        if !can_enter {
+            self.run_next_block(debugger, shared, renderer);
+            return;
+        }
+
+        let block =
+            match self.dynarec.blocks.get(start) {
+                Some(b) => b,
+                None => {
+                    let b = Arc::new(self.dynarec.compile(&self.inter, start));
+
+                    self.dynarec.blocks.insert(start,
+                                               b.words.len().max(1),
+                                               b.clone());
+
+                    b
+                }
+            };
+
+        let code =
+            match block.code {
+                Some(c) => c,
+                None => {
+                    self.run_next_block(debugger, shared, renderer);
+                    return;
+                }
+            };
+
+        let cycles =
+            match self.fetch_cycles_per_instruction(start,
+                                                    block.words.iter()
+                                                    .cloned()) {
+                Some(c) => c,
+                None => {
+                    // Either the code isn't in the instruction cache
+                    // yet or it changed since it was recompiled
+                    let stale =
+                        block.words.iter().enumerate().any(|(i, &w)| {
+                            let pc = start.wrapping_add((i as u32) << 2);
+
+                            self.inter.peek_instruction(pc) != Some(w)
+                        });
+
+                    if stale {
+                        self.dynarec.blocks.invalidate(start);
+                    }
+
+                    self.run_next_block(debugger, shared, renderer);
+                    return;
+                }
+            };
+
+        let len = block.words.len() as u32;
+
+        // Make sure no sync will be needed before the last instruction
+        if shared.tk().cycles_until_sync() <= (len - 1) as Cycles * cycles {
+            self.run_next_block(debugger, shared, renderer);
+            return;
+        }
+
+        let mut ctx = Context {
+            pc: 0,
+            taken: 0,
+            shared: shared,
+            debugger: debugger as *mut D as *mut u8,
+            renderer: &mut renderer as *mut &mut Renderer as *mut u8,
+            memory_op: memory_op::<D>,
+            start: start,
+            len: len,
+            cycles: cycles,
+            ticked: 0,
+            panic: None,
+        };
+
+        let status = {
+            let cpu: *mut Cpu = self;
+
+            unsafe {
+                let regs = (*cpu).regs.as_mut_ptr();
+
+                code(regs, cpu, &mut ctx)
+            }
+        };
+
+        if let Some(p) = ctx.panic.take() {
+            panic::resume_unwind(p);
+        }
+
+        let index = status >> 8;
+        let pc = start.wrapping_add(index << 2);
+
+        match status & 0xff {
+            EXIT_END => {
+                ctx.tick(shared, len);
+
+                let last = start.wrapping_add((len - 1) << 2);
+
+                self.current_pc = last;
+
+                if block.branch {
+                    self.pc = ctx.pc;
+                    self.delay_slot = ctx.taken != 0;
+                } else {
+                    self.pc = last.wrapping_add(4);
+                    self.delay_slot = false;
+                }
+
+                self.next_pc = self.pc.wrapping_add(4);
+                self.branch = false;
+            }
+            EXIT_TRAP => {
+                // Let the interpreter run the instruction and raise
+                // the exception (or perform the memory access)
+                ctx.tick(shared, index);
+
+                self.pc = pc;
+
+                if block.branch && index == len - 1 {
+                    // We're in the delay slot
+                    self.next_pc = ctx.pc;
+                    self.branch = ctx.taken != 0;
+                } else {
+                    self.next_pc = pc.wrapping_add(4);
+                    self.branch = false;
+                }
+
+                if index > 0 {
+                    self.current_pc = pc.wrapping_sub(4);
+                    self.delay_slot = false;
+                }
+
+                self.run_instruction(debugger, shared, renderer, None);
+            }
+            EXIT_CALLOUT => {
+                // The CPU state and the timekeeper have been updated
+                // by the callout
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Called by the recompiled code to run the interpreter `handler` for
+/// the instruction `word` at position `index` in the block. Returns 0
+/// if the recompiled code can continue, otherwise the status it must
+/// return.
+extern "C" fn callout(cpu: *mut Cpu,
+                      ctx: *mut Context,
+                      handler: usize,
+                      word: u32,
+                      index: u32) -> u32 {
+    let (cpu, ctx) = unsafe { (&mut *cpu, &mut *ctx) };
+    let handler = unsafe { mem::transmute::<usize, Handler>(handler) };
+
+    ctx.run(cpu, index, |cpu, ctx| {
+        let shared = unsafe { &mut *ctx.shared };
+
+        handler(cpu, Instruction(word), shared)
+    })
+}
+
+/// Called by the recompiled code for the load or store `word` at
+/// position `index` in the block. Only accesses to the RAM and the
+/// scratchpad are run here, the other ones are left to the
+/// interpreter.
+extern "C" fn memory_callout(cpu: *mut Cpu,
+                             ctx: *mut Context,
+                             _: usize,
+                             word: u32,
+                             index: u32) -> u32 {
+    let (cpu, ctx) = unsafe { (&mut *cpu, &mut *ctx) };
+    let instruction = Instruction(word);
+
+    let addr = cpu.reg(instruction.s()).wrapping_add(instruction.imm_se());
+
+    // When the cache is isolated the stores go to the instruction
+    // cache and discard all the recompiled code
+    if !cpu.inter.is_memory(addr) || cpu.cop0.cache_isolated() {
+        return EXIT_TRAP | (index << 8);
+    }
+
+    let status =
+        ctx.run(cpu, index, |cpu, ctx| unsafe {
+            (ctx.memory_op)(cpu, instruction, ctx)
+        });
+
+    // Stop if we overwrote the block we're running
+    if status == 0 && !cpu.dynarec.blocks.contains(ctx.start) {
+        EXIT_CALLOUT | (index << 8)
+    } else {
+        status
+    }
+}
+
+/// Run the load or store `instruction` through the interpreter using
+/// the caller's debugger and renderer
+unsafe fn memory_op<D>(cpu: &mut Cpu,
+                       instruction: Instruction,
+                       ctx: &mut Context)
+    where D: Debugger {
+    let shared = &mut *ctx.shared;
+    let debugger = &mut *(ctx.debugger as *mut D);
+    let renderer = &mut **(ctx.renderer as *mut &mut Renderer);
+
+    match instruction.function() {
+        0b100000 => cpu.op_lb(instruction, debugger, shared),
+        0b100001 => cpu.op_lh(instruction, debugger, shared),
+        0b100010 => cpu.op_lwl(instruction, debugger, shared),
+        0b100011 => cpu.op_lw(instruction, debugger, shared),
+        0b100100 => cpu.op_lbu(instruction, debugger, shared),
+        0b100101 => cpu.op_lhu(instruction, debugger, shared),
+        0b100110 => cpu.op_lwr(instruction, debugger, shared),
+        0b101000 => cpu.op_sb(instruction, debugger, shared, renderer),
+        0b101001 => cpu.op_sh(instruction, debugger, shared, renderer),
+        0b101010 => cpu.op_swl(instruction, debugger, shared, renderer),
+        0b101011 => cpu.op_sw(instruction, debugger, shared, renderer),
+        0b101110 => cpu.op_swr(instruction, debugger, shared, renderer),
+        0b110010 => cpu.op_lwc2(instruction, debugger, shared),
+        0b111010 => cpu.op_swc2(instruction, debugger, shared, renderer),
+        _ => unreachable!(),
+    }
+}
+
+/// How an instruction is handled by the recompiler
+enum Kind {
+    /// Translated to native code
+    Native,
+    /// Jump or branch
+    Branch,
+    /// Call back into the interpreter
+    Callout(Handler),
+    /// Load or store, calls back into the interpreter
+    Memory,
+    /// Can't be recompiled, ends the block
+    Unsupported,
+}
+
+fn kind(instruction: Instruction) -> Kind {
+    match instruction.function() {
+        0b000000 => match instruction.subfunction() {
+            0b000000 | 0b000010 | 0b000011 |
+            0b000100 | 0b000110 | 0b000111 => Kind::Native,
+            0b001000 | 0b001001 => Kind::Branch,
+            0b100000...0b100111 | 0b101010 | 0b101011 => Kind::Native,
+            _ => callout_kind(instruction),
+        },
+        // J, JAL, BEQ, BNE, BLEZ, BGTZ
+        0b000010...0b000111 => Kind::Branch,
+        // ADDI to LUI
+        0b001000...0b001111 => Kind::Native,
+        // LB to LWR, SB, SH, SWL, SW, SWR, LWC2, SWC2
+        0b100000...0b100110 |
+        0b101000...0b101011 | 0b101110 |
+        0b110010 | 0b111010 => Kind::Memory,
+        _ => callout_kind(instruction),
+    }
+}
+
+fn callout_kind(instruction: Instruction) -> Kind {
+    match block::handler(instruction) {
+        Some(h) => Kind::Callout(h),
+        None => Kind::Unsupported,
+    }
+}
+
+/// Return true if `instruction` can leave a pending load in
+/// `Cpu::load`
+fn sets_load(instruction: Instruction) -> bool {
+    match instruction.function() {
+        // LB to LWR
+        0b100000...0b100110 => true,
+        // MFC2, CFC2
+        0b010010 => match instruction.cop_opcode() {
+            0b00000 | 0b00010 => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Translate `block` starting at `pc`. Returns the emitter containing
+/// the native code, the instructions covered and whether the block
+/// ends with a branch.
+fn translate(block: &Block, pc: u32) -> (Emitter, Vec<u32>, bool) {
+    let mut e = Emitter::new();
+    let mut words = Vec::new();
+    let mut branch = false;
+    // True if the previous instruction can leave a pending load
+    let mut load = false;
+
+    e.prologue();
+
+    let ops = &block.ops;
+
+    for (i, op) in ops.iter().enumerate() {
+        let instruction = Instruction(op.word);
+        let addr = pc.wrapping_add((i as u32) << 2);
+        let index = i as u32;
+
+        let k =
+            match kind(instruction) {
+                // The interpreter handler will apply the pending load
+                // after reading the operands
+                Kind::Native if load => callout_kind(instruction),
+                // We don't want to deal with a load in the branch
+                // delay slot
+                Kind::Branch if load => Kind::Unsupported,
+                k => k,
+            };
+
+        match k {
+            Kind::Native => emit_native(&mut e, instruction, index),
+            Kind::Callout(h) => {
+                e.call(callout as usize as u64,
+                       h as usize as u64,
+                       instruction.0,
+                       index);
+                e.exit_if_nonzero();
+            }
+            Kind::Memory => {
+                e.call(memory_callout as usize as u64,
+                       0,
+                       instruction.0,
+                       index);
+                e.exit_if_nonzero();
+            }
+            Kind::Branch => {
+                // The delay slot must be recompiled along with the
+                // branch
+                let slot =
+                    match ops.get(i + 1) {
+                        Some(s) => Instruction(s.word),
+                        None => break,
+                    };
+
+                if let Kind::Native = kind(slot) {
+                    emit_branch(&mut e, instruction, addr);
+                    emit_native(&mut e, slot, index + 1);
+
+                    words.push(instruction.0);
+                    words.push(slot.0);
+                    branch = true;
+                }
+
+                break;
+            }
+            Kind::Unsupported => break,
+        }
+
+        load = sets_load(instruction);
+
+        words.push(instruction.0);
+    }
+
+    e.mov_imm(Reg::Eax, EXIT_END);
+    e.epilogue();
+
+    (e, words, branch)
+}
+
+/// Emit a simple ALU instruction. `index` is the position of the
+/// instruction in the block.
+fn emit_native(e: &mut Emitter, instruction: Instruction, index: u32) {
+    let s = instruction.s().0;
+    let t = instruction.t().0;
+    let d = instruction.d().0;
+
+    let trap = EXIT_TRAP | (index << 8);
+
+    match instruction.function() {
+        0b000000 => match instruction.subfunction() {
+            0b000000 => shift_imm(e, Shift::Shl, t, d, instruction.shift()),
+            0b000010 => shift_imm(e, Shift::Shr, t, d, instruction.shift()),
+            0b000011 => shift_imm(e, Shift::Sar, t, d, instruction.shift()),
+            0b000100 => shift_var(e, Shift::Shl, s, t, d),
+            0b000110 => shift_var(e, Shift::Shr, s, t, d),
+            0b000111 => shift_var(e, Shift::Sar, s, t, d),
+            0b100000 => alu_trap(e, Alu::Add, s, t, d, trap),
+            0b100001 => alu(e, Alu::Add, s, t, d, false),
+            0b100010 => alu_trap(e, Alu::Sub, s, t, d, trap),
+            0b100011 => alu(e, Alu::Sub, s, t, d, false),
+            0b100100 => alu(e, Alu::And, s, t, d, false),
+            0b100101 => alu(e, Alu::Or, s, t, d, false),
+            0b100110 => alu(e, Alu::Xor, s, t, d, false),
+            0b100111 => alu(e, Alu::Or, s, t, d, true),
+            0b101010 => set(e, Cond::Less, s, t, d),
+            0b101011 => set(e, Cond::Below, s, t, d),
+            _ => unreachable!(),
+        },
+        0b001000 => {
+            // ADDI
+            e.load_reg(Reg::Eax, s);
+            e.alu_imm(Alu::Add, instruction.imm_se());
+            e.skip_if(Cond::NoOverflow, 10);
+            e.exit(trap);
+
+            if t != 0 {
+                e.store_reg(t, Reg::Eax);
+            }
+        }
+        0b001001 => alu_imm(e, Alu::Add, s, t, instruction.imm_se()),
+        0b001010 => set_imm(e, Cond::Less, s, t, instruction.imm_se()),
+        0b001011 => set_imm(e, Cond::Below, s, t, instruction.imm_se()),
+        0b001100 => alu_imm(e, Alu::And, s, t, instruction.imm()),
+        0b001101 => alu_imm(e, Alu::Or, s, t, instruction.imm()),
+        0b001110 => alu_imm(e, Alu::Xor, s, t, instruction.imm()),
+        0b001111 =>
+            // LUI
+            if t != 0 {
+                e.mov_imm(Reg::Eax, instruction.imm() << 16);
+                e.store_reg(t, Reg::Eax);
+            },
+        _ => unreachable!(),
+    }
+}
+
+/// Emit a jump or branch at address `pc`. The address of the
+/// instruction following the delay slot is stored in `Context::pc`.
+fn emit_branch(e: &mut Emitter, instruction: Instruction, pc: u32) {
+    let s = instruction.s().0;
+    let t = instruction.t().0;
+
+    // Address of the instruction following the delay slot
+    let link = pc.wrapping_add(8);
+
+    match instruction.function() {
+        0b000000 => {
+            // JR, JALR
+            e.load_reg(Reg::Eax, s);
+            e.store_ctx(0);
+            e.store_ctx_imm(4, 1);
+
+            let d = instruction.d().0;
+
+            if instruction.subfunction() == 0b001001 && d != 0 {
+                e.mov_imm(Reg::Eax, link);
+                e.store_reg(d, Reg::Eax);
+            }
+        }
+        f @ 0b000010...0b000011 => {
+            // J, JAL
+            let target =
+                (pc.wrapping_add(4) & 0xf0000000) | (instruction.imm_jump() << 2);
+
+            e.store_ctx_imm(0, target);
+            e.store_ctx_imm(4, 1);
+
+            if f == 0b000011 {
+                e.mov_imm(Reg::Eax, link);
+                e.store_reg(31, Reg::Eax);
+            }
+        }
+        f => {
+            let target =
+                pc.wrapping_add(4).wrapping_add(instruction.imm_se() << 2);
+
+            let not_taken =
+                match f {
+                    0b000100 | 0b000101 => {
+                        e.load_reg(Reg::Eax, s);
+                        e.load_reg(Reg::Ecx, t);
+                        e.alu(Alu::Cmp);
+
+                        if f == 0b000100 {
+                            // BEQ
+                            Cond::NotEqual
+                        } else {
+                            // BNE
+                            Cond::Equal
+                        }
+                    }
+                    _ => {
+                        e.load_reg(Reg::Eax, s);
+                        e.test();
+
+                        if f == 0b000110 {
+                            // BLEZ
+                            Cond::Greater
+                        } else {
+                            // BGTZ
+                            Cond::LessEqual
+                        }
+                    }
+                };
+
+            // The stores don't modify the flags
+            e.store_ctx_imm(0, link);
+            e.store_ctx_imm(4, 0);
+            e.skip_if(not_taken, 16);
+            e.store_ctx_imm(0, target);
+            e.store_ctx_imm(4, 1);
+        }
+    }
+}
+
+fn shift_imm(e: &mut Emitter, op: Shift, t: u32, d: u32, amount: u32) {
+    if d == 0 {
+        return;
+    }
+
+    e.load_reg(Reg::Eax, t);
+    e.shift_imm(op, amount);
+    e.store_reg(d, Reg::Eax);
+}
+
+fn shift_var(e: &mut Emitter, op: Shift, s: u32, t: u32, d: u32) {
+    if d == 0 {
+        return;
+    }
+
+    e.load_reg(Reg::Eax, t);
+    // The x86 shifts truncate the amount to 5 bits like the MIPS
+    e.load_reg(Reg::Ecx, s);
+    e.shift_cl(op);
+    e.store_reg(d, Reg::Eax);
+}
+
+fn alu(e: &mut Emitter, op: Alu, s: u32, t: u32, d: u32, not: bool) {
+    if d == 0 {
+        return;
+    }
+
+    e.load_reg(Reg::Eax, s);
+    e.load_reg(Reg::Ecx, t);
+    e.alu(op);
+
+    if not {
+        e.not();
+    }
+
+    e.store_reg(d, Reg::Eax);
+}
+
+/// ALU operation raising an exception on signed overflow. Even if the
+/// target is R0 the overflow must be checked.
+fn alu_trap(e: &mut Emitter, op: Alu, s: u32, t: u32, d: u32, trap: u32) {
+    e.load_reg(Reg::Eax, s);
+    e.load_reg(Reg::Ecx, t);
+    e.alu(op);
+    e.skip_if(Cond::NoOverflow, 10);
+    e.exit(trap);
+
+    if d != 0 {
+        e.store_reg(d, Reg::Eax);
+    }
+}
+
+fn set(e: &mut Emitter, cond: Cond, s: u32, t: u32, d: u32) {
+    if d == 0 {
+        return;
+    }
+
+    e.load_reg(Reg::Eax, s);
+    e.load_reg(Reg::Ecx, t);
+    e.alu(Alu::Cmp);
+    e.set_cond(cond);
+    e.store_reg(d, Reg::Eax);
+}
+
+fn alu_imm(e: &mut Emitter, op: Alu, s: u32, t: u32, imm: u32) {
+    if t == 0 {
+        return;
+    }
+
+    e.load_reg(Reg::Eax, s);
+    e.alu_imm(op, imm);
+    e.store_reg(t, Reg::Eax);
+}
+
+fn set_imm(e: &mut Emitter, cond: Cond, s: u32, t: u32, imm: u32) {
+    if t == 0 {
+        return;
+    }
+
+    e.load_reg(Reg::Eax, s);
+    e.alu_imm(Alu::Cmp, imm);
+    e.set_cond(cond);
+    e.store_reg(t, Reg::Eax);
+}
@@ -0,0 +1,394 @@
+//! Minimal x86-64 code emitter, only supports the handful of
+//! instructions used by the recompiler.
+//!
+//! The generated functions use the System V calling convention. While
+//! running recompiled code the following registers are reserved:
+//!
+//! * `rbx`: pointer to the CPU general purpose registers
+//! * `r12`: pointer to the `Cpu`
+//! * `r13`: pointer to the `Context` structure
+//!
+//! `eax` and `ecx` are used as scratch registers.
+
+use std::ptr;
+
+/// Scratch registers
+#[derive(Clone, Copy)]
+pub enum Reg {
+    Eax = 0,
+    Ecx = 1,
+}
+
+/// Two-operand ALU operations. The value is the opcode of the
+/// `op r/m32, r32` form, the `op eax, imm32` form is always 4 bytes
+/// further.
+#[derive(Clone, Copy)]
+pub enum Alu {
+    Add = 0x01,
+    Or = 0x09,
+    And = 0x21,
+    Sub = 0x29,
+    Xor = 0x31,
+    Cmp = 0x39,
+}
+
+/// Shift operations, the value is the ModRM "reg" field
+#[derive(Clone, Copy)]
+pub enum Shift {
+    Shl = 4,
+    Shr = 5,
+    Sar = 7,
+}
+
+/// Condition codes
+#[derive(Clone, Copy)]
+pub enum Cond {
+    NoOverflow = 0x1,
+    Below = 0x2,
+    Equal = 0x4,
+    NotEqual = 0x5,
+    Less = 0xc,
+    LessEqual = 0xe,
+    Greater = 0xf,
+}
+
+pub struct Emitter {
+    code: Vec<u8>,
+    /// Positions of the `rel32` displacements that must point to the
+    /// epilogue
+    exits: Vec<usize>,
+}
+
+impl Emitter {
+    pub fn new() -> Emitter {
+        Emitter {
+            code: Vec::new(),
+            exits: Vec::new(),
+        }
+    }
+
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    fn byte(&mut self, b: u8) {
+        self.code.push(b);
+    }
+
+    fn dword(&mut self, v: u32) {
+        for i in 0..4 {
+            self.byte((v >> (i * 8)) as u8);
+        }
+    }
+
+    fn qword(&mut self, v: u64) {
+        self.dword(v as u32);
+        self.dword((v >> 32) as u32);
+    }
+
+    /// Save the callee-saved registers we use and load the arguments
+    /// in the reserved registers
+    pub fn prologue(&mut self) {
+        // push rbx
+        self.byte(0x53);
+        // push r12
+        self.byte(0x41);
+        self.byte(0x54);
+        // push r13
+        self.byte(0x41);
+        self.byte(0x55);
+        // mov rbx, rdi
+        self.byte(0x48);
+        self.byte(0x89);
+        self.byte(0xfb);
+        // mov r12, rsi
+        self.byte(0x49);
+        self.byte(0x89);
+        self.byte(0xf4);
+        // mov r13, rdx
+        self.byte(0x49);
+        self.byte(0x89);
+        self.byte(0xd5);
+    }
+
+    /// Emit the epilogue and patch all the pending exit jumps to
+    /// point to it. The return value must be in `eax`.
+    pub fn epilogue(&mut self) {
+        let target = self.code.len();
+
+        for &pos in &self.exits {
+            let rel = (target as isize - (pos + 4) as isize) as i32;
+
+            for i in 0..4 {
+                self.code[pos + i] = (rel >> (i * 8)) as u8;
+            }
+        }
+
+        self.exits.clear();
+
+        // pop r13
+        self.byte(0x41);
+        self.byte(0x5d);
+        // pop r12
+        self.byte(0x41);
+        self.byte(0x5c);
+        // pop rbx
+        self.byte(0x5b);
+        // ret
+        self.byte(0xc3);
+    }
+
+    /// mov reg, [rbx + index * 4]
+    pub fn load_reg(&mut self, dst: Reg, index: u32) {
+        self.byte(0x8b);
+        self.byte(0x43 | ((dst as u8) << 3));
+        self.byte((index * 4) as u8);
+    }
+
+    /// mov [rbx + index * 4], reg
+    pub fn store_reg(&mut self, index: u32, src: Reg) {
+        self.byte(0x89);
+        self.byte(0x43 | ((src as u8) << 3));
+        self.byte((index * 4) as u8);
+    }
+
+    /// mov reg, imm32
+    pub fn mov_imm(&mut self, dst: Reg, imm: u32) {
+        self.byte(0xb8 + dst as u8);
+        self.dword(imm);
+    }
+
+    /// op eax, ecx
+    pub fn alu(&mut self, op: Alu) {
+        self.byte(op as u8);
+        self.byte(0xc8);
+    }
+
+    /// op eax, imm32
+    pub fn alu_imm(&mut self, op: Alu, imm: u32) {
+        self.byte(op as u8 + 4);
+        self.dword(imm);
+    }
+
+    /// not eax
+    pub fn not(&mut self) {
+        self.byte(0xf7);
+        self.byte(0xd0);
+    }
+
+    /// test eax, eax
+    pub fn test(&mut self) {
+        self.byte(0x85);
+        self.byte(0xc0);
+    }
+
+    /// shift eax, imm8
+    pub fn shift_imm(&mut self, op: Shift, amount: u32) {
+        self.byte(0xc1);
+        self.byte(0xc0 | ((op as u8) << 3));
+        self.byte(amount as u8);
+    }
+
+    /// shift eax, cl
+    pub fn shift_cl(&mut self, op: Shift) {
+        self.byte(0xd3);
+        self.byte(0xc0 | ((op as u8) << 3));
+    }
+
+    /// setcc al; movzx eax, al
+    pub fn set_cond(&mut self, cond: Cond) {
+        self.byte(0x0f);
+        self.byte(0x90 | cond as u8);
+        self.byte(0xc0);
+
+        self.byte(0x0f);
+        self.byte(0xb6);
+        self.byte(0xc0);
+    }
+
+    /// jcc rel8, `skip` is the number of bytes to skip if the
+    /// condition is true
+    pub fn skip_if(&mut self, cond: Cond, skip: u8) {
+        self.byte(0x70 | cond as u8);
+        self.byte(skip);
+    }
+
+    /// mov eax, `status`; jmp epilogue. Always 10 bytes long.
+    pub fn exit(&mut self, status: u32) {
+        self.mov_imm(Reg::Eax, status);
+
+        // jmp rel32, patched in `epilogue`
+        self.byte(0xe9);
+        self.exits.push(self.code.len());
+        self.dword(0);
+    }
+
+    /// mov dword [r13 + offset], imm32. Always 8 bytes long.
+    pub fn store_ctx_imm(&mut self, offset: u8, imm: u32) {
+        self.byte(0x41);
+        self.byte(0xc7);
+        self.byte(0x45);
+        self.byte(offset);
+        self.dword(imm);
+    }
+
+    /// mov dword [r13 + offset], eax
+    pub fn store_ctx(&mut self, offset: u8) {
+        self.byte(0x41);
+        self.byte(0x89);
+        self.byte(0x45);
+        self.byte(offset);
+    }
+
+    /// test eax, eax; jnz epilogue. The status is returned as-is.
+    pub fn exit_if_nonzero(&mut self) {
+        self.test();
+
+        // jnz rel32, patched in `epilogue`
+        self.byte(0x0f);
+        self.byte(0x85);
+        self.exits.push(self.code.len());
+        self.dword(0);
+    }
+
+    /// Call `f(cpu, ctx, a, b, c)` where `cpu` and `ctx` are the
+    /// pointers held in `r12` and `r13`. The return value ends up in
+    /// `eax`.
+    pub fn call(&mut self, f: u64, a: u64, b: u32, c: u32) {
+        // mov rdi, r12
+        self.byte(0x4c);
+        self.byte(0x89);
+        self.byte(0xe7);
+        // mov rsi, r13
+        self.byte(0x4c);
+        self.byte(0x89);
+        self.byte(0xee);
+        // mov rdx, imm64
+        self.byte(0x48);
+        self.byte(0xba);
+        self.qword(a);
+        // mov ecx, imm32
+        self.byte(0xb9);
+        self.dword(b);
+        // mov r8d, imm32
+        self.byte(0x41);
+        self.byte(0xb8);
+        self.dword(c);
+        // mov rax, imm64
+        self.byte(0x48);
+        self.byte(0xb8);
+        self.qword(f);
+        // call rax. The stack is 16-byte aligned since we pushed
+        // three registers in the prologue.
+        self.byte(0xff);
+        self.byte(0xd0);
+    }
+}
+
+/// Executable memory region holding the recompiled code. The pages
+/// are never writable and executable at the same time: they're made
+/// writable only while we copy new code into them.
+pub struct CodeBuffer {
+    base: *mut u8,
+    size: usize,
+    used: usize,
+}
+
+// The buffer is only ever accessed through the `Cpu` that owns it
+unsafe impl Send for CodeBuffer {}
+
+impl CodeBuffer {
+    pub fn new(size: usize) -> CodeBuffer {
+        let base = unsafe {
+            mmap(ptr::null_mut(),
+                 size,
+                 PROT_READ | PROT_WRITE,
+                 MAP_PRIVATE | MAP_ANONYMOUS,
+                 -1,
+                 0)
+        };
+
+        if base == MAP_FAILED {
+            panic!("Couldn't allocate the dynarec code buffer");
+        }
+
+        CodeBuffer {
+            base: base,
+            size: size,
+            used: 0,
+        }
+    }
+
+    /// Copy `code` into the buffer and return its address or `None`
+    /// if the buffer is full
+    pub fn push(&mut self, code: &[u8]) -> Option<*const u8> {
+        // Keep the functions 16-byte aligned
+        let start = (self.used + 15) & !15;
+
+        if start + code.len() > self.size {
+            return None;
+        }
+
+        // Pages touched by the copy
+        let first = start & !(PAGE_SIZE - 1);
+        let end = start + code.len();
+        let len = ((end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)) - first;
+
+        unsafe {
+            let page = self.base.offset(first as isize);
+            let dst = self.base.offset(start as isize);
+
+            // Nothing is running from the buffer while we recompile
+            self.protect(page, len, PROT_READ | PROT_WRITE);
+
+            ptr::copy_nonoverlapping(code.as_ptr(), dst, code.len());
+
+            self.protect(page, len, PROT_READ | PROT_EXEC);
+
+            self.used = end;
+
+            Some(dst)
+        }
+    }
+
+    unsafe fn protect(&self, addr: *mut u8, len: usize, prot: i32) {
+        if mprotect(addr, len, prot) != 0 {
+            panic!("Couldn't change the dynarec code buffer protection");
+        }
+    }
+
+    /// Discard all the code in the buffer. The caller must make sure
+    /// that none of it is still referenced.
+    pub fn reset(&mut self) {
+        self.used = 0;
+    }
+}
+
+impl Drop for CodeBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.base, self.size);
+        }
+    }
+}
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 0x02;
+const MAP_ANONYMOUS: i32 = 0x20;
+const MAP_FAILED: *mut u8 = !0usize as *mut u8;
+const PAGE_SIZE: usize = 4096;
+
+extern "C" {
+    fn mmap(addr: *mut u8,
+            len: usize,
+            prot: i32,
+            flags: i32,
+            fd: i32,
+            offset: i64) -> *mut u8;
+
+    fn munmap(addr: *mut u8, len: usize) -> i32;
+
+    fn mprotect(addr: *mut u8, len: usize, prot: i32) -> i32;
+}
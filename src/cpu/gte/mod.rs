@@ -7,6 +7,38 @@ mod divider;
 #[cfg(test)]
 mod tests;
 
+use timekeeper::Cycles;
+
+/// Return the number of CPU cycles taken by the GTE `command`
+pub fn command_cycles(command: u32) -> Cycles {
+    match command & 0x3f {
+        0x01 => 15, // RTPS
+        0x06 => 8,  // NCLIP
+        0x0c => 6,  // OP
+        0x10 => 8,  // DPCS
+        0x11 => 8,  // INTPL
+        0x12 => 8,  // MVMVA
+        0x13 => 19, // NCDS
+        0x14 => 13, // CDP
+        0x16 => 44, // NCDT
+        0x1b => 17, // NCCS
+        0x1c => 11, // CC
+        0x1e => 14, // NCS
+        0x20 => 30, // NCT
+        0x28 => 5,  // SQR
+        0x29 => 8,  // DCPL
+        0x2a => 17, // DPCT
+        0x2d => 5,  // AVSZ3
+        0x2e => 6,  // AVSZ4
+        0x30 => 23, // RTPT
+        0x3d => 5,  // GPF
+        0x3e => 5,  // GPL
+        0x3f => 39, // NCCT
+        // Invalid command, `Gte::command` will complain
+        _ => 1,
+    }
+}
+
 #[derive(Debug, RustcDecodable, RustcEncodable)]
 pub struct Gte {
     // Control registers
@@ -0,0 +1,630 @@
+//! Differential testing harness for the CPU execution engines.
+//!
+//! `Lockstep` runs two `Cpu` instances side by side: a reference one
+//! using the plain interpreter and a candidate one using any other
+//! `Engine`. After every step of the candidate (a single instruction
+//! or a whole block depending on the engine) the reference is run
+//! until it reaches the same date and the two states are compared:
+//! general purpose registers, HI/LO, PC, pending load, COP0, GTE
+//! registers and the memory writes performed during the step. Since
+//! all the engines are supposed to be cycle-accurate any difference,
+//! including timing, is reported as a `Divergence`.
+//!
+//! The harness is meant to be used from `cargo test` with small PS-X
+//! EXE programs, typically generated with the `assembler` module and
+//! packaged with `exe_loader::build_exe`.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+
+use bios::Bios;
+use gpu::{Gpu, VideoClock};
+use gpu::renderer::{Renderer, PrimitiveAttributes, Vertex};
+use memory::{Interconnect, Byte, Word, map};
+use parallel_io::exe_loader::{self, ExeLoader};
+use shared::SharedState;
+use debugger::Debugger;
+use disassembler::{disassemble, REGISTER_NAMES};
+
+use super::{Cpu, Engine, RegisterIndex};
+
+/// Number of reference instructions kept for the divergence report
+const TRACE_LEN: usize = 16;
+
+/// Two CPUs running the same program in lockstep
+pub struct Lockstep {
+    reference: Side,
+    candidate: Side,
+    /// Date of the candidate CPU after the last step
+    date: u64,
+}
+
+impl Lockstep {
+    /// Create a new harness from two CPUs in the same state. The
+    /// engine of `reference` is forced to `Engine::Interpreter`.
+    pub fn new(mut reference: Cpu, candidate: Cpu) -> Lockstep {
+        reference.set_engine(Engine::Interpreter);
+
+        Lockstep {
+            reference: Side::new(reference),
+            candidate: Side::new(candidate),
+            date: 0,
+        }
+    }
+
+    /// Create a harness running the PS-X EXE `exe` with a dummy BIOS,
+    /// the candidate CPU uses `engine`. The executable is loaded
+    /// directly in RAM and the CPUs start at its entry point.
+    pub fn from_exe(exe: &[u8],
+                    engine: Engine) -> Result<Lockstep, exe_loader::Error> {
+        let exe = try!(ExeLoader::load(&mut io::Cursor::new(exe)));
+
+        let reference = exe_cpu(&exe);
+        let mut candidate = exe_cpu(&exe);
+
+        candidate.set_engine(engine);
+
+        Ok(Lockstep::new(reference, candidate))
+    }
+
+    pub fn reference(&self) -> &Cpu {
+        &self.reference.cpu
+    }
+
+    pub fn candidate(&self) -> &Cpu {
+        &self.candidate.cpu
+    }
+
+    /// Run a single step of the candidate CPU, then bring the
+    /// reference to the same date and compare them
+    pub fn step(&mut self) -> Result<(), Divergence> {
+        self.candidate.step();
+
+        self.date = self.candidate.now();
+
+        while self.reference.now() < self.date {
+            self.reference.step();
+        }
+
+        let reference_date = self.reference.now();
+
+        let result =
+            if reference_date != self.date {
+                Err(self.divergence(
+                    format!("date: reference {}, candidate {}",
+                            reference_date, self.date)))
+            } else {
+                self.compare()
+            };
+
+        self.reference.debugger.writes.clear();
+        self.candidate.debugger.writes.clear();
+
+        result
+    }
+
+    /// Run until both CPUs reach `end_pc` or the candidate runs for
+    /// more than `max_cycles`. Returns `Ok(false)` on timeout.
+    pub fn run(&mut self,
+               end_pc: u32,
+               max_cycles: u64) -> Result<bool, Divergence> {
+        while self.candidate.now() < max_cycles {
+            if self.candidate.cpu.pc == end_pc &&
+                self.reference.cpu.pc == end_pc {
+                return Ok(true);
+            }
+
+            try!(self.step());
+        }
+
+        Ok(false)
+    }
+
+    fn compare(&mut self) -> Result<(), Divergence> {
+        let r_irq = *self.reference.shared.irq_state();
+        let c_irq = *self.candidate.shared.irq_state();
+
+        let (r, c) = (&self.reference.cpu, &self.candidate.cpu);
+
+        try!(self.check("PC", r.pc, c.pc));
+        try!(self.check("next PC", r.next_pc, c.next_pc));
+
+        for i in 0..32 {
+            try!(self.check(REGISTER_NAMES[i], r.regs[i], c.regs[i]));
+        }
+
+        try!(self.check("HI", r.hi, c.hi));
+        try!(self.check("LO", r.lo, c.lo));
+
+        let (RegisterIndex(rl), RegisterIndex(cl)) = (r.load.0, c.load.0);
+
+        try!(self.check("load delay target", rl, cl));
+        try!(self.check("load delay value", r.load.1, c.load.1));
+
+        try!(self.check("COP0 SR", r.cop0.sr(), c.cop0.sr()));
+
+        try!(self.check("COP0 CAUSE",
+                        r.cop0.cause(r_irq),
+                        c.cop0.cause(c_irq)));
+        try!(self.check("COP0 EPC", r.cop0.epc(), c.cop0.epc()));
+        try!(self.check("COP0 BadVaddr",
+                        r.cop0.bad_vaddr(),
+                        c.cop0.bad_vaddr()));
+        try!(self.check("COP0 DCIC", r.cop0.dcic(), c.cop0.dcic()));
+
+        for reg in 0..32 {
+            try!(self.check_gte("data",
+                                reg,
+                                r.gte.data(reg),
+                                c.gte.data(reg)));
+            try!(self.check_gte("control",
+                                reg,
+                                r.gte.control(reg),
+                                c.gte.control(reg)));
+        }
+
+        let (r_writes, c_writes) = (&self.reference.debugger.writes,
+                                    &self.candidate.debugger.writes);
+
+        for i in 0..r_writes.len().max(c_writes.len()) {
+            let (ra, ca) = (r_writes.get(i), c_writes.get(i));
+
+            if ra != ca {
+                return Err(self.divergence(
+                    format!("memory write #{}: reference {:?}, candidate {:?}",
+                            i, ra.map(Hex), ca.map(Hex))));
+            }
+
+            let addr = *ra.unwrap();
+
+            // XXX We only check the values written to RAM, writes to
+            // the other devices are only compared by address
+            if map::RAM.contains(map::mask_region(addr)).is_some() {
+                let addr = addr & !3;
+
+                let rv = r.inter.ram().load::<Word>(addr);
+                let cv = c.inter.ram().load::<Word>(addr);
+
+                if rv != cv {
+                    return Err(self.divergence(
+                        format!("RAM @ 0x{:08x}: reference 0x{:08x}, \
+                                 candidate 0x{:08x}",
+                                addr, rv, cv)));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check(&self, what: &str, r: u32, c: u32) -> Result<(), Divergence> {
+        if r == c {
+            Ok(())
+        } else {
+            Err(self.divergence(
+                format!("{}: reference 0x{:08x}, candidate 0x{:08x}",
+                        what, r, c)))
+        }
+    }
+
+    fn check_gte(&self,
+                 kind: &str,
+                 reg: u32,
+                 r: u32,
+                 c: u32) -> Result<(), Divergence> {
+        if r == c {
+            Ok(())
+        } else {
+            Err(self.divergence(
+                format!("GTE {} register {}: reference 0x{:08x}, \
+                         candidate 0x{:08x}",
+                        kind, reg, r, c)))
+        }
+    }
+
+    fn divergence(&self, what: String) -> Divergence {
+        Divergence {
+            date: self.date,
+            what: what,
+            trace: self.reference.debugger.trace.iter().cloned().collect(),
+        }
+    }
+}
+
+/// First difference found between the two CPUs
+#[derive(Debug)]
+pub struct Divergence {
+    /// Date of the divergence in CPU cycles
+    pub date: u64,
+    /// Description of the difference
+    pub what: String,
+    /// Last instructions executed by the reference CPU (address and
+    /// instruction word if it could be read)
+    pub trace: Vec<(u32, Option<u32>)>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f,
+                      "CPU divergence at cycle {}: {}",
+                      self.date,
+                      self.what));
+        try!(writeln!(f, "Last instructions executed by the reference:"));
+
+        for &(pc, word) in &self.trace {
+            match word {
+                Some(w) =>
+                    try!(writeln!(f,
+                                  "  0x{:08x}: {:08x}  {}",
+                                  pc,
+                                  w,
+                                  disassemble(w, pc))),
+                None =>
+                    try!(writeln!(f, "  0x{:08x}: ????????", pc)),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Helper to format optional addresses in hexadecimal
+struct Hex<'a>(&'a u32);
+
+impl<'a> fmt::Debug for Hex<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:08x}", self.0)
+    }
+}
+
+/// One of the two CPUs with its own state
+struct Side {
+    cpu: Cpu,
+    shared: SharedState,
+    debugger: Recorder,
+}
+
+impl Side {
+    fn new(cpu: Cpu) -> Side {
+        Side {
+            cpu: cpu,
+            shared: SharedState::new(),
+            debugger: Recorder::new(),
+        }
+    }
+
+    fn now(&mut self) -> u64 {
+        self.shared.tk().now()
+    }
+
+    fn step(&mut self) {
+        self.cpu.run_next_step(&mut self.debugger,
+                               &mut self.shared,
+                               &mut DummyRenderer);
+    }
+}
+
+/// Debugger recording the instructions executed and the memory
+/// writes
+struct Recorder {
+    trace: VecDeque<(u32, Option<u32>)>,
+    writes: Vec<u32>,
+}
+
+impl Recorder {
+    fn new() -> Recorder {
+        Recorder {
+            trace: VecDeque::with_capacity(TRACE_LEN),
+            writes: Vec::new(),
+        }
+    }
+}
+
+impl Debugger for Recorder {
+    fn trigger_break(&mut self) {
+    }
+
+    fn pc_change(&mut self, cpu: &mut Cpu) {
+        // The recompiled code doesn't call us so the trace is only
+        // complete for the reference CPU
+        if self.trace.len() == TRACE_LEN {
+            self.trace.pop_front();
+        }
+
+        let pc = cpu.current_pc;
+
+        self.trace.push_back((pc, cpu.inter.peek_instruction(pc)));
+    }
+
+    fn memory_read(&mut self, _: &mut Cpu, _: u32) {
+    }
+
+    fn memory_write(&mut self, _: &mut Cpu, addr: u32) {
+        self.writes.push(addr);
+    }
+}
+
+/// The harness doesn't care about the GPU output
+struct DummyRenderer;
+
+impl Renderer for DummyRenderer {
+    fn set_draw_offset(&mut self, _: i16, _: i16) {
+    }
+
+    fn set_draw_area(&mut self, _: (u16, u16), _: (u16, u16)) {
+    }
+
+    fn set_display_mode(&mut self,
+                        _: (u16, u16),
+                        _: (u16, u16),
+                        _: bool) {
+    }
+
+    fn push_line(&mut self, _: &PrimitiveAttributes, _: &[Vertex; 2]) {
+    }
+
+    fn push_triangle(&mut self, _: &PrimitiveAttributes, _: &[Vertex; 3]) {
+    }
+
+    fn push_quad(&mut self, _: &PrimitiveAttributes, _: &[Vertex; 4]) {
+    }
+
+    fn fill_rect(&mut self,
+                 _: [u8; 3],
+                 _: (u16, u16),
+                 _: (u16, u16)) {
+    }
+
+    fn load_image(&mut self,
+                  _: (u16, u16),
+                  _: (u16, u16),
+                  _: &[u16]) {
+    }
+}
+
+/// Build a CPU with a dummy BIOS and `exe` loaded in RAM, ready to
+/// run from the entry point
+fn exe_cpu(exe: &ExeLoader) -> Cpu {
+    let bios = Bios::dummy();
+    let gpu = Gpu::new(VideoClock::Ntsc);
+    let inter = Interconnect::new(bios, gpu, None);
+    let mut cpu = Cpu::new(inter);
+
+    {
+        let ram = cpu.interconnect_mut().ram_mut();
+
+        let (fill_base, fill_len) = exe.memfill();
+
+        for i in 0..fill_len {
+            ram.store::<Byte>(fill_base.wrapping_add(i), 0);
+        }
+
+        for (i, &b) in exe.text().iter().enumerate() {
+            ram.store::<Byte>(exe.base().wrapping_add(i as u32),
+                                        b as u32);
+        }
+    }
+
+    let (gp, sp) = exe.initial_gp_sp();
+
+    // Same default stack as the BIOS
+    let sp = if sp == 0 { 0x801ffff0 } else { sp };
+
+    cpu.set_reg(RegisterIndex(28), gp);
+    cpu.set_reg(RegisterIndex(29), sp);
+    cpu.set_reg(RegisterIndex(30), sp);
+
+    cpu.set_pc(exe.entry());
+
+    cpu
+}
+
+#[cfg(test)]
+mod tests {
+    use assembler::Assembler;
+    use assembler::syntax::*;
+    use parallel_io::exe_loader::build_exe;
+
+    use std::io;
+    use std::time::Instant;
+
+    use parallel_io::exe_loader::ExeLoader;
+    use shared::SharedState;
+
+    use super::super::Engine;
+    use super::{Lockstep, DummyRenderer, exe_cpu};
+
+    const BASE: u32 = 0x80010000;
+
+    /// Assemble a small program exercising ALU ops, multiplications,
+    /// divisions, branches, delayed loads and stores. Returns the
+    /// EXE and the address of the final infinite loop.
+    fn test_exe() -> (Vec<u8>, u32) {
+        let mut asm = Assembler::from_base(BASE);
+
+        asm.assemble(&[
+            Li(T0, 0x80020000),
+            Li(T1, 100),
+            Li(T2, 0x12345678),
+            Li(S0, 0),
+
+            Local("loop"),
+            Addu(S0, S0, T1),
+            Mult(S0, T2),
+            Mflo(T3),
+            Sra(T4, T3, 3),
+            Xor(T3, T3, T4),
+            Sw(T3, T0, 0),
+            Lw(T5, T0, 0),
+            // Load delay slot
+            Addiu(T5, T5, 1),
+            Sh(T5, T0, 6),
+            Lbu(T6, T0, 6),
+            Divu(T2, T1),
+            Mfhi(T7),
+            Sltu(T7, T7, T6),
+            Bnez(T7, Label::Local("skip", 'f')),
+            Sb(T7, T0, 9),
+            Nor(S1, T6, T3),
+            Local("skip"),
+            Addiu(T0, T0, 16),
+            Addiu(T1, T1, -1),
+            Bgtz(T1, Label::Local("loop", 'b')),
+            Nop,
+
+            Global("end"),
+            B(Label::Global("end")),
+            Nop,
+        ]).unwrap();
+
+        let (mc, _) = asm.machine_code();
+
+        // The final loop is 2 instructions long
+        let end = BASE + mc.len() as u32 - 8;
+
+        (build_exe(BASE, BASE, &mc), end)
+    }
+
+    fn run_lockstep(engine: Engine) {
+        let (exe, end) = test_exe();
+
+        let mut lockstep = Lockstep::from_exe(&exe, engine).unwrap();
+
+        match lockstep.run(end, 1_000_000) {
+            Ok(true) => (),
+            Ok(false) => panic!("Lockstep test timed out"),
+            Err(d) => panic!("{}", d),
+        }
+    }
+
+    /// Jump to the misaligned address `target` and run until the
+    /// exception handler is reached
+    fn run_misaligned_jump(engine: Engine, target: u32) {
+        let mut asm = Assembler::from_base(BASE);
+
+        asm.assemble(&[
+            Li(T0, target),
+            Jr(T0),
+            Nop,
+        ]).unwrap();
+
+        let (mc, _) = asm.machine_code();
+
+        let exe = build_exe(BASE, BASE, &mc);
+
+        let mut lockstep = Lockstep::from_exe(&exe, engine).unwrap();
+
+        match lockstep.run(0x80000080, 1_000) {
+            Ok(true) => (),
+            Ok(false) => panic!("Misaligned jump test timed out"),
+            Err(d) => panic!("{}", d),
+        }
+
+        let cpu = lockstep.candidate();
+
+        assert_eq!(cpu.cop0.epc(), target);
+        assert_eq!(cpu.bad(), target);
+    }
+
+    fn run_misaligned_jumps(engine: Engine) {
+        // Last halfword of the RAM and of the BIOS
+        for &target in &[0x801ffffe, 0xbfc7fffe] {
+            run_misaligned_jump(engine, target);
+        }
+    }
+
+    #[test]
+    fn test_cached_interpreter() {
+        run_lockstep(Engine::CachedInterpreter);
+    }
+
+    #[test]
+    fn test_cached_interpreter_misaligned_jump() {
+        run_misaligned_jumps(Engine::CachedInterpreter);
+    }
+
+    #[cfg(feature = "dynarec")]
+    #[test]
+    fn test_dynarec() {
+        run_lockstep(Engine::Dynarec);
+    }
+
+    #[cfg(feature = "dynarec")]
+    #[test]
+    fn test_dynarec_misaligned_jump() {
+        run_misaligned_jumps(Engine::Dynarec);
+    }
+
+    /// Run an ALU-heavy loop with `engine` and return the final value
+    /// of the accumulator along with the emulated and elapsed times
+    fn time_engine(engine: Engine) -> (u32, u64, f64) {
+        let mut asm = Assembler::from_base(BASE);
+
+        asm.assemble(&[
+            Li(T0, 2_000_000),
+            Li(S0, 0),
+            Li(T1, 0x9e3779b9),
+
+            Local("loop"),
+            Addu(S0, S0, T1),
+            Sll(T2, S0, 5),
+            Xor(S0, S0, T2),
+            Srl(T2, S0, 7),
+            Xor(S0, S0, T2),
+            Addiu(T0, T0, -1),
+            Bgtz(T0, Label::Local("loop", 'b')),
+            Nop,
+
+            Global("end"),
+            B(Label::Global("end")),
+            Nop,
+        ]).unwrap();
+
+        let (mc, _) = asm.machine_code();
+        let end = BASE + mc.len() as u32 - 8;
+
+        let exe = build_exe(BASE, BASE, &mc);
+        let exe = ExeLoader::load(&mut io::Cursor::new(exe)).unwrap();
+
+        let mut cpu = exe_cpu(&exe);
+        let mut shared = SharedState::new();
+
+        cpu.set_engine(engine);
+
+        let start = Instant::now();
+
+        while cpu.pc != end {
+            cpu.run_next_step(&mut (), &mut shared, &mut DummyRenderer);
+        }
+
+        let elapsed = start.elapsed();
+        let elapsed = elapsed.as_secs() as f64 +
+                      elapsed.subsec_nanos() as f64 * 1e-9;
+
+        (cpu.regs[16], shared.tk().now(), elapsed)
+    }
+
+    /// Compare the speed of the engines. Run with
+    /// `cargo test --release -- --ignored --nocapture engine_speed`
+    #[test]
+    #[ignore]
+    fn engine_speed() {
+        let (reference, cycles, base) = time_engine(Engine::Interpreter);
+
+        let check = |engine: Engine| {
+            let (result, c, elapsed) = time_engine(engine);
+
+            assert_eq!(result, reference);
+            assert_eq!(c, cycles);
+
+            println!("{:?}: {} cycles in {:.3}s ({:.2}x)",
+                     engine, c, elapsed, base / elapsed);
+        };
+
+        check(Engine::Interpreter);
+        check(Engine::CachedInterpreter);
+        #[cfg(feature = "dynarec")]
+        check(Engine::Dynarec);
+    }
+}
@@ -1,21 +1,33 @@
 mod cop0;
 mod gte;
+mod block;
+
+pub mod lockstep;
+
+#[cfg(feature = "dynarec")]
+mod dynarec;
 
 #[cfg(test)]
 mod tests;
 
 use std::fmt::{Display, Formatter, Error};
 use std::default::Default;
+use std::sync::Arc;
+
+use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
 
-use memory::{Interconnect, Addressable, Byte, HalfWord, Word};
+use memory::{self, Interconnect, Addressable, Byte, HalfWord, Word};
+use memory::timings::WriteQueue;
 use shared::SharedState;
 use gpu::renderer::Renderer;
 use interrupt::InterruptState;
 use debugger::Debugger;
 use tracer::module_tracer;
+use timekeeper::Cycles;
 
 use self::cop0::{Cop0, Exception};
 use self::gte::Gte;
+use self::block::{Block, BlockCache, CachedOp};
 
 /// This struct contains the CPU state, including the `Interconnect`
 /// instance which owns most of the peripherals.
@@ -36,6 +48,14 @@ pub struct Cpu {
     /// LO register for division quotient and multiplication low
     /// result
     lo: u32,
+    /// Date at which the result of the last multiplication or
+    /// division will be available in HI/LO
+    hilo_ready: Cycles,
+    /// Date at which the result of the last GTE command will be
+    /// available
+    gte_ready: Cycles,
+    /// Queue of the stores waiting to be written to the bus
+    write_queue: WriteQueue,
     /// Instruction Cache (256 4-word cachelines)
     icache: ICacheLines,
     /// Memory interface
@@ -55,6 +75,14 @@ pub struct Cpu {
     /// If `true` break instructions will trigger the debugger instead
     /// of generating an exception.
     debug_on_break: bool,
+    /// Execution engine used by `run_until_next_frame`. Not stored
+    /// in savestates.
+    engine: EngineSetting,
+    /// Pre-decoded blocks used by the cached interpreter
+    block_cache: BlockCache<Block>,
+    /// Recompiler state
+    #[cfg(feature = "dynarec")]
+    dynarec: dynarec::Dynarec,
 }
 
 impl Cpu {
@@ -76,6 +104,9 @@ impl Cpu {
             regs:           regs,
             hi:             0xdeadbeef,
             lo:             0xdeadbeef,
+            hilo_ready:     0,
+            gte_ready:      0,
+            write_queue:    WriteQueue::new(),
             icache:         ICacheLines::new(),
             inter:          inter,
             cop0:           Cop0::new(),
@@ -84,6 +115,10 @@ impl Cpu {
             branch:         false,
             delay_slot:     false,
             debug_on_break: false,
+            engine:         EngineSetting(Engine::Interpreter),
+            block_cache:    BlockCache::new(),
+            #[cfg(feature = "dynarec")]
+            dynarec:        dynarec::Dynarec::new(),
         }
     }
 
@@ -91,6 +126,36 @@ impl Cpu {
         self.debug_on_break = enabled
     }
 
+    /// Select the execution engine used by `run_until_next_frame`.
+    /// All engines produce the exact same results.
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = EngineSetting(engine);
+
+        // Free the caches we won't be using anymore
+        if engine == Engine::Interpreter {
+            self.block_cache.clear();
+        }
+
+        #[cfg(feature = "dynarec")]
+        {
+            if engine != Engine::Dynarec {
+                self.dynarec.clear();
+            }
+        }
+    }
+
+    pub fn engine(&self) -> Engine {
+        self.engine.0
+    }
+
+    /// The engine is a frontend setting, it's not stored in
+    /// savestates and a freshly loaded `Cpu` uses the interpreter.
+    /// This method should be called on the loaded `Cpu` to keep using
+    /// the engine of the `current` instance.
+    pub fn restore_engine(&mut self, current: &Cpu) {
+        self.set_engine(current.engine());
+    }
+
     /// Return a reference to the interconnect
     pub fn interconnect(&self) -> &Interconnect {
         &self.inter
@@ -110,7 +175,24 @@ impl Cpu {
         let frame = shared.counters().frame.get();
 
         while frame == shared.counters().frame.get() {
-            self.run_next_instruction(debugger, shared, renderer);
+            self.run_next_step(debugger, shared, renderer);
+        }
+    }
+
+    /// Run the next instruction or block using the current engine
+    fn run_next_step<D>(&mut self,
+                        debugger: &mut D,
+                        shared: &mut SharedState,
+                        renderer: &mut Renderer)
+        where D: Debugger {
+        match self.engine.0 {
+            Engine::Interpreter =>
+                self.run_next_instruction(debugger, shared, renderer),
+            Engine::CachedInterpreter =>
+                self.run_next_block(debugger, shared, renderer),
+            #[cfg(feature = "dynarec")]
+            Engine::Dynarec =>
+                self.run_next_native_block(debugger, shared, renderer),
         }
     }
 
@@ -120,6 +202,84 @@ impl Cpu {
                                    shared: &mut SharedState,
                                    renderer: &mut Renderer)
         where D: Debugger {
+        self.run_instruction(debugger, shared, renderer, None);
+    }
+
+    /// Run the instructions of the block starting at the current PC
+    /// using the block cache. Returns early if an exception or jump
+    /// takes us out of the block or if a new frame starts.
+    pub fn run_next_block<D>(&mut self,
+                             debugger: &mut D,
+                             shared: &mut SharedState,
+                             renderer: &mut Renderer)
+        where D: Debugger {
+        let start = self.pc;
+
+        let block =
+            match self.block_cache.get(start) {
+                Some(b) => b,
+                None => {
+                    let b = Arc::new(Block::build(&self.inter, start));
+
+                    if b.ops.is_empty() {
+                        // Can't cache code at this address
+                        self.run_next_instruction(debugger, shared, renderer);
+                        return;
+                    }
+
+                    self.block_cache.insert(start, b.ops.len(), b.clone());
+
+                    b
+                }
+            };
+
+        let frame = shared.counters().frame.get();
+
+        let mut i = 0;
+
+        while i < block.ops.len() {
+            let pc = start.wrapping_add((i as u32) << 2);
+
+            if self.pc != pc || frame != shared.counters().frame.get() {
+                break;
+            }
+
+            let ops = &block.ops[i..];
+
+            // Run as many instructions as possible in one go
+            let run = self.run_cached_ops(debugger, shared, pc, ops);
+
+            if run > 0 {
+                i += run;
+                continue;
+            }
+
+            let valid = self.run_instruction(debugger,
+                                             shared,
+                                             renderer,
+                                             Some((pc, ops[0])));
+
+            if !valid {
+                // The code changed since the block was built
+                self.block_cache.invalidate(start);
+                break;
+            }
+
+            i += 1;
+        }
+    }
+
+    /// Run the instruction at PC. If `cached` contains the address
+    /// and pre-decoded version of the instruction it's used to skip
+    /// the decoding, provided the instruction fetched from memory
+    /// matches. Returns false if `cached` was provided but didn't
+    /// match.
+    fn run_instruction<D>(&mut self,
+                          debugger: &mut D,
+                          shared: &mut SharedState,
+                          renderer: &mut Renderer,
+                          cached: Option<(u32, CachedOp)>) -> bool
+        where D: Debugger {
 
         // Synchronize the peripherals
         if shared.tk().sync_pending() {
@@ -136,12 +296,49 @@ impl Cpu {
 
         if self.current_pc % 4 != 0 {
             // PC is not correctly aligned!
-            self.exception(Exception::LoadAddressError);
-            return;
+            let pc = self.current_pc;
+
+            self.address_error(Exception::LoadAddressError, pc);
+            return cached.is_none();
+        }
+
+        if self.cop0.code_breakpoint(self.current_pc) {
+            // The breakpoint triggers before the instruction is
+            // fetched. If the last instruction was a branch then
+            // we're in its delay slot.
+            self.delay_slot = self.branch;
+            self.branch = false;
+
+            self.debug_exception();
+            return true;
         }
 
         // Fetch instruction at PC
-        let instruction = self.fetch_instruction(shared);
+        let instruction =
+            match self.fetch_instruction(shared) {
+                Some(instruction) => instruction,
+                None => {
+                    // Nothing answered on the bus. If the last
+                    // instruction was a branch then we're in its
+                    // delay slot.
+                    self.delay_slot = self.branch;
+                    self.branch = false;
+
+                    self.exception(Exception::BusErrorInstruction);
+                    return cached.is_none();
+                }
+            };
+
+        let (valid, handler) =
+            match cached {
+                Some((pc, op)) =>
+                    if pc == self.current_pc && op.word == instruction.0 {
+                        (true, op.handler)
+                    } else {
+                        (false, None)
+                    },
+                None => (true, None),
+            };
 
         // Increment PC to point to the next instruction. and
         // `next_pc` to the one after that. Both values can be
@@ -186,8 +383,22 @@ impl Cpu {
             self.exception(Exception::Interrupt);
         } else {
             // No interrupt pending, run the current instruction
-            self.decode_and_execute(debugger, instruction, shared, renderer);
+            match handler {
+                Some(handler) => {
+                    // Same as `decode_and_execute`
+                    shared.tk().tick(1);
+
+                    handler(self, instruction, shared);
+                }
+                None =>
+                    self.decode_and_execute(debugger,
+                                            instruction,
+                                            shared,
+                                            renderer),
+            }
         }
+
+        valid
     }
 
     /// Force the value of the PC
@@ -197,11 +408,15 @@ impl Cpu {
     }
 
     /// Fetch the instruction at `current_pc` through the instruction
-    /// cache
-    fn fetch_instruction(&mut self, shared: &mut SharedState) -> Instruction {
+    /// cache. Returns `None` if the fetch caused a bus error.
+    fn fetch_instruction(&mut self,
+                         shared: &mut SharedState) -> Option<Instruction> {
         let pc = self.current_pc;
         let cc = self.inter.cache_control();
 
+        // Duration of the first and subsequent bus accesses
+        let (first, seq) = self.inter.fetch_cycles(pc);
+
         // KUSEG and KSEG0 regions are cached. KSEG1 is uncached and
         // KSEG2 doesn't contain any code
         let cached = pc < 0xa0000000;
@@ -224,34 +439,34 @@ impl Cpu {
             let line = &mut self.icache[line as usize];
 
             // Check the tag and validity
-            if line.tag() != tag || line.valid_index() > index {
+            if line.tag() != tag || !line.valid(index) {
                 // Cache miss. Fetch the cacheline starting at the
                 // current index. If the index is not 0 then some
                 // words are going to remain invalid in the cacheline.
                 let mut cpc = pc;
 
-                // XXX Fetch timings from mednafen, on my console it
-                // seems a bit faster than that, need to review those
-                // timings when I decide to implement CPU pipelining
-                // and whatnot
-                shared.tk().tick(3);
+                // The rest of the line is fetched in burst mode
+                shared.tk().tick(first + (3 - index) as Cycles * seq);
 
-                for i in index..4 {
-                    shared.tk().tick(1);
+                // Set the tag and valid bits
+                line.set_tag_valid(pc);
 
-                    let instruction =
-                        Instruction(self.inter.load_instruction(shared, cpc));
+                for i in index..4 {
+                    match self.inter.load_instruction(shared, cpc) {
+                        Ok(word) => line.set_instruction(i, Instruction(word)),
+                        // Bus error, the word remains invalid
+                        Err(_) => line.invalidate_word(i),
+                    }
 
-                    line.set_instruction(i, instruction);
                     cpc += 4;
                 }
 
-                // Set the tag and valid bits
-                line.set_tag_valid(pc);
+                if !line.valid(index) {
+                    return None;
+                }
             }
 
-            // Cache line is now guaranteed to be valid
-            line.instruction(index)
+            Some(line.instruction(index))
         } else {
             // XXX Apparently pointing the PC to KSEG2 causes a bus
             // error no matter what, even if you point it at some
@@ -260,33 +475,63 @@ impl Cpu {
             // nowhere to put code in KSEG2, only a bunch of
             // registers.
 
-            // Cache disabled, fetch directly from memory. Takes 4 to
-            // 5 cycles on average.
-            shared.tk().tick(4);
+            // Cache disabled, fetch directly from memory
+            shared.tk().tick(first);
 
-            Instruction(self.inter.load_instruction(shared, pc))
+            self.inter.load_instruction(shared, pc).ok().map(Instruction)
         }
     }
 
-    /// Memory read
+    /// Memory read. If nothing answers on the bus a bus error
+    /// exception is triggered and `None` is returned, in which case
+    /// the instruction must not modify the target register. Same
+    /// thing if the access hits a data breakpoint.
     fn load<A, D>(&mut self,
                   debugger: &mut D,
                   shared: &mut SharedState,
-                  addr: u32) -> u32
+                  addr: u32) -> Option<u32>
     where A: Addressable, D: Debugger {
         debugger.memory_read(self, addr);
 
-        self.inter.load::<A>(shared, addr)
+        if self.cop0.data_breakpoint(addr, false) {
+            self.delayed_load();
+            self.debug_exception();
+            return None;
+        }
+
+        if self.cop0.cache_isolated() && !self.inter.is_scratch_pad(addr) {
+            return Some(self.isolated_load::<A>(addr));
+        }
+
+        // The CPU stalls until the data comes back from the bus. Reads
+        // have to wait for the pending writes to complete first.
+        if let Some(cycles) = self.inter.access_cycles::<A>(addr, false) {
+            self.write_queue.flush(shared);
+
+            shared.tk().tick(cycles);
+        }
+
+        match self.inter.load::<A>(shared, addr) {
+            Ok(v) => Some(v),
+            Err(_) => {
+                self.delayed_load();
+                self.exception(Exception::BusErrorData);
+                None
+            }
+        }
     }
 
     /// Memory read with as little side-effect as possible. Used for
-    /// debugging.
+    /// debugging. Bus errors return the open bus value.
     pub fn examine<A: Addressable>(&mut self, addr: u32) -> u32 {
 
         self.inter.load::<A>(&mut SharedState::new(), addr)
+            .unwrap_or(memory::open_bus::<A>())
     }
 
-    /// Memory write
+    /// Memory write. Triggers a bus error exception if nothing
+    /// answers on the bus or a debug exception if the access hits a
+    /// data breakpoint.
     ///
     /// We always pass around 32bit values even for Byte and HalfWord
     /// access because some devices ignore the requested width when
@@ -305,47 +550,122 @@ impl Cpu {
     where A: Addressable, D: Debugger {
         debugger.memory_write(self, addr);
 
-        if self.cop0.cache_isolated() {
+        if self.cop0.data_breakpoint(addr, true) {
+            self.debug_exception();
+            return;
+        }
+
+        if self.cop0.cache_isolated() && !self.inter.is_scratch_pad(addr) {
             self.cache_maintenance::<A>(addr, val);
         } else {
-            self.inter.store::<A>(shared, renderer, addr, val);
+            if let Some(cycles) = self.inter.access_cycles::<A>(addr, true) {
+                self.write_queue.push(shared, cycles);
+            }
+
+            if self.inter.store::<A>(shared, renderer, addr, val).is_err() {
+                self.exception(Exception::BusErrorData);
+                return;
+            }
+
+            // Discard any cached code we might have overwritten
+            self.block_cache.invalidate_ram(addr);
+
+            #[cfg(feature = "dynarec")]
+            self.dynarec.invalidate_ram(addr);
         }
     }
 
-    /// Handle writes when the cache is isolated
-    pub fn cache_maintenance<A: Addressable>(&mut self, addr: u32, val: u32) {
-        // Implementing full cache emulation requires handling many
-        // corner cases. For now I'm just going to add support for
-        // cache invalidation which is the only use case for cache
-        // isolation as far as I know.
+    /// Return the current value of the word at `addr` for SWL and
+    /// SWR. The hardware only drives the relevant byte lanes, it
+    /// doesn't read the memory: this has no timing, breakpoint or
+    /// debugger side effect.
+    fn unaligned_store_base(&self, addr: u32) -> u32 {
+        if self.cop0.cache_isolated() && !self.inter.is_scratch_pad(addr) {
+            self.isolated_load::<Word>(addr)
+        } else {
+            // XXX The devices get the whole word, the bytes we can't
+            // peek at are sent as 0
+            self.inter.peek_word(addr).unwrap_or(0)
+        }
+    }
 
+    /// Handle writes when the cache is isolated. The PlayStation BIOS
+    /// uses cache isolation to flush the instruction cache, the
+    /// writes end up in the instruction cache instead of the bus.
+    ///
+    /// The data cache is permanently used as the scratchpad which
+    /// doesn't have any tag, so the isolation and the tag test mode
+    /// only concern the instruction cache. Scratchpad accesses are not
+    /// affected and never get here.
+    pub fn cache_maintenance<A: Addressable>(&mut self, addr: u32, val: u32) {
         let cc = self.inter.cache_control();
 
+        // The cached blocks might not match the icache contents
+        // anymore
+        self.block_cache.clear();
+
+        #[cfg(feature = "dynarec")]
+        self.dynarec.clear();
+
         if !cc.icache_enabled() {
-            panic!("Cache maintenance while instruction cache is disabled");
+            // The cache is isolated from the bus, the write is lost
+            debug!("Isolated write with the instruction cache disabled: \
+                    0x{:08x} @ 0x{:08x}", val, addr);
+            return;
         }
 
-        if A::size() != 4 || val != 0 {
-            panic!("Unsupported write while cache is isolated: {:08x}",
-                   val);
-        }
+        let index = (addr >> 2) & 3;
 
-        let line = (addr >> 4) & 0xff;
+        // Byte and halfword writes put the value in the corresponding
+        // byte lanes
+        let val = val << ((addr & 3) * 8);
 
         // Fetch the cacheline for this address
-        let line = &mut self.icache[line as usize];
+        let line = &mut self.icache[((addr >> 4) & 0xff) as usize];
 
         if cc.tag_test_mode() {
-            // In tag test mode the write invalidates the entire
-            // targeted cacheline
-            line.invalidate();
-        } else {
+            // In tag test mode the write sets the tag of the line and
+            // its valid bits: bit N for word N. The BIOS writes 0 to
+            // invalidate the entire line.
+            line.set_tag_test(addr, val);
+        } else if A::size() == 4 {
             // Otherwise the write ends up directly in the cache.
-            let index = (addr >> 2) & 3;
+            line.set_instruction(index, Instruction(val));
+        } else {
+            // Partial writes can't update the cache, they invalidate
+            // the targeted word instead
+            line.invalidate_word(index);
+        }
+    }
 
-            let instruction = Instruction(val);
+    /// Handle reads when the cache is isolated, the value comes from
+    /// the instruction cache instead of the bus
+    fn isolated_load<A: Addressable>(&self, addr: u32) -> u32 {
+        let cc = self.inter.cache_control();
 
-            line.set_instruction(index, instruction);
+        if !cc.icache_enabled() {
+            // Nothing drives the bus
+            debug!("Isolated read with the instruction cache disabled: \
+                    0x{:08x}", addr);
+            return memory::open_bus::<A>();
+        }
+
+        let line = &self.icache[((addr >> 4) & 0xff) as usize];
+
+        let word =
+            if cc.tag_test_mode() {
+                line.tag_valid()
+            } else {
+                line.instruction((addr >> 2) & 3).0
+            };
+
+        // Byte and halfword reads return the corresponding byte lanes
+        let v = word >> ((addr & 3) * 8);
+
+        match A::size() {
+            1 => v & 0xff,
+            2 => v & 0xffff,
+            _ => v,
         }
     }
 
@@ -376,6 +696,24 @@ impl Cpu {
         self.next_pc = self.pc.wrapping_add(4);
     }
 
+    /// Trigger the exception of the breakpoint hardware (BPC, BDA)
+    fn debug_exception(&mut self) {
+        let handler_addr =
+            self.cop0.enter_debug_exception(self.current_pc,
+                                            self.delay_slot);
+
+        self.pc      = handler_addr;
+        self.next_pc = self.pc.wrapping_add(4);
+    }
+
+    /// Trigger an address error exception `cause` for the bad
+    /// address `addr`
+    fn address_error(&mut self, cause: Exception, addr: u32) {
+        self.cop0.set_bad_vaddr(addr);
+
+        self.exception(cause);
+    }
+
     /// Retrieve the value of a general purpose register
     fn reg(&self, index: RegisterIndex) -> u32 {
         self.regs[index.0 as usize]
@@ -445,9 +783,7 @@ impl Cpu {
     }
 
     pub fn bad(&self) -> u32 {
-        // XXX we don't emulate the "BAD" cop0 register yet. It's
-        // almost useless in the PSX anyway since there's no MMU.
-        0
+        self.cop0.bad_vaddr()
     }
 
     /// Force PC address. Meant to be used from the debugger. Use at
@@ -480,14 +816,14 @@ impl Cpu {
                 0b001001 => self.op_jalr(instruction),
                 0b001100 => self.op_syscall(instruction),
                 0b001101 => self.op_break(instruction, debugger),
-                0b010000 => self.op_mfhi(instruction),
+                0b010000 => self.op_mfhi(instruction, shared),
                 0b010001 => self.op_mthi(instruction),
-                0b010010 => self.op_mflo(instruction),
+                0b010010 => self.op_mflo(instruction, shared),
                 0b010011 => self.op_mtlo(instruction),
-                0b011000 => self.op_mult(instruction),
-                0b011001 => self.op_multu(instruction),
-                0b011010 => self.op_div(instruction),
-                0b011011 => self.op_divu(instruction),
+                0b011000 => self.op_mult(instruction, shared),
+                0b011001 => self.op_multu(instruction, shared),
+                0b011010 => self.op_div(instruction, shared),
+                0b011011 => self.op_divu(instruction, shared),
                 0b100000 => self.op_add(instruction),
                 0b100001 => self.op_addu(instruction),
                 0b100010 => self.op_sub(instruction),
@@ -517,7 +853,7 @@ impl Cpu {
             0b001111 => self.op_lui(instruction),
             0b010000 => self.op_cop0(instruction, shared),
             0b010001 => self.op_cop1(instruction),
-            0b010010 => self.op_cop2(instruction),
+            0b010010 => self.op_cop2(instruction, shared),
             0b010011 => self.op_cop3(instruction),
             0b100000 => self.op_lb(instruction, debugger, shared),
             0b100001 => self.op_lh(instruction, debugger, shared),
@@ -724,10 +1060,22 @@ impl Cpu {
         }
     }
 
+    /// Stall until the result of the last multiplication or division
+    /// is available
+    fn wait_hilo(&mut self, shared: &mut SharedState) {
+        let now = shared.tk().now();
+
+        if self.hilo_ready > now {
+            shared.tk().tick(self.hilo_ready - now);
+        }
+    }
+
     /// Move From HI
-    fn op_mfhi(&mut self, instruction: Instruction) {
+    fn op_mfhi(&mut self, instruction: Instruction, shared: &mut SharedState) {
         let d = instruction.d();
 
+        self.wait_hilo(shared);
+
         let hi = self.hi;
 
         self.delayed_load();
@@ -745,9 +1093,11 @@ impl Cpu {
     }
 
     /// Move From LO
-    fn op_mflo(&mut self, instruction: Instruction) {
+    fn op_mflo(&mut self, instruction: Instruction, shared: &mut SharedState) {
         let d = instruction.d();
 
+        self.wait_hilo(shared);
+
         let lo = self.lo;
 
         self.delayed_load();
@@ -765,7 +1115,7 @@ impl Cpu {
     }
 
     /// Multiply (signed)
-    fn op_mult(&mut self, instruction: Instruction) {
+    fn op_mult(&mut self, instruction: Instruction, shared: &mut SharedState) {
         let s = instruction.s();
         let t = instruction.t();
 
@@ -774,6 +1124,11 @@ impl Cpu {
 
         self.delayed_load();
 
+        // Negative values take as long as their complement
+        let magnitude = if a < 0 { !a } else { a };
+
+        self.hilo_ready = shared.tk().now() + mult_cycles(magnitude as u32);
+
         let v = (a * b) as u64;
 
         self.hi = (v >> 32) as u32;
@@ -781,7 +1136,7 @@ impl Cpu {
     }
 
     /// Multiply Unsigned
-    fn op_multu(&mut self, instruction: Instruction) {
+    fn op_multu(&mut self, instruction: Instruction, shared: &mut SharedState) {
         let s = instruction.s();
         let t = instruction.t();
 
@@ -790,6 +1145,8 @@ impl Cpu {
 
         self.delayed_load();
 
+        self.hilo_ready = shared.tk().now() + mult_cycles(a as u32);
+
         let v = a * b;
 
         self.hi = (v >> 32) as u32;
@@ -797,7 +1154,7 @@ impl Cpu {
     }
 
     /// Divide (signed)
-    fn op_div(&mut self, instruction: Instruction) {
+    fn op_div(&mut self, instruction: Instruction, shared: &mut SharedState) {
         let s = instruction.s();
         let t = instruction.t();
 
@@ -806,6 +1163,8 @@ impl Cpu {
 
         self.delayed_load();
 
+        self.hilo_ready = shared.tk().now() + DIV_CYCLES;
+
         if d == 0 {
             // Division by zero, results are bogus
             self.hi = n as u32;
@@ -826,7 +1185,7 @@ impl Cpu {
     }
 
     /// Divide Unsigned
-    fn op_divu(&mut self, instruction: Instruction) {
+    fn op_divu(&mut self, instruction: Instruction, shared: &mut SharedState) {
         let s = instruction.s();
         let t = instruction.t();
 
@@ -835,6 +1194,8 @@ impl Cpu {
 
         self.delayed_load();
 
+        self.hilo_ready = shared.tk().now() + DIV_CYCLES;
+
         if d == 0 {
             // Division by zero, results are bogus
             self.hi = n;
@@ -1194,20 +1555,12 @@ impl Cpu {
                 warn!("Unhandled read from JUMP_DEST (cop0r6)");
                 0
             }
-            7 => {
-                // DCIC: breakpoint control
-                warn!("Unhandled read from DCIC (cop0r7)");
-                0
-            }
-            8 => {
-                // This register should be mostly useless on the
-                // PlayStation since it doesn't have virtual memory,
-                // however some exceptions do write to this register
-                // so maybe we'll have to implement this correctly
-                // some day.
-                warn!("Unhandled read from BAD_VADDR (cop0r8)");
-                0
-            }
+            3 => self.cop0.bpc(),
+            5 => self.cop0.bda(),
+            7 => self.cop0.dcic(),
+            8 => self.cop0.bad_vaddr(),
+            9 => self.cop0.bdam(),
+            11 => self.cop0.bpcm(),
             12 => self.cop0.sr(),
             13 => self.cop0.cause(*shared.irq_state()),
             14 => self.cop0.epc(),
@@ -1228,10 +1581,13 @@ impl Cpu {
         self.delayed_load();
 
         match cop_r {
-            3 | 5 | 6 | 7 | 9 | 11  => // Breakpoints registers
-                if v != 0 {
-                    panic!("Unhandled write to cop0r{}: {:08x}", cop_r, v)
-                },
+            3 => self.cop0.set_bpc(v),
+            5 => self.cop0.set_bda(v),
+            // JUMP_DEST is read-only
+            6 => (),
+            7 => self.cop0.set_dcic(v),
+            9 => self.cop0.set_bdam(v),
+            11 => self.cop0.set_bpcm(v),
             12 => self.cop0.set_sr(v),
             13 => self.cop0.set_cause(v),
             _  => panic!("Unhandled cop0 register {}", cop_r),
@@ -1261,7 +1617,7 @@ impl Cpu {
     }
 
     /// Coprocessor 2 opcode (GTE)
-    fn op_cop2(&mut self, instruction: Instruction) {
+    fn op_cop2(&mut self, instruction: Instruction, shared: &mut SharedState) {
         // XXX: we should check that the GTE is enabled in cop0's
         // status register, otherwise the cop2 instructions seem to
         // freeze the CPU (or maybe raise an exception?). Furthermore
@@ -1271,13 +1627,26 @@ impl Cpu {
         let cop_opcode = instruction.cop_opcode();
 
         if cop_opcode & 0x10 != 0 {
-            // GTE command
-            // XXX handle GTE command duration
+            self.delayed_load();
+
+            // GTE command. The GTE can only run one command at a time
+            // so we have to wait for the previous one to complete.
+            self.wait_gte(shared);
+
+            // The CPU doesn't wait for the command to complete, it
+            // only stalls when it attempts to read the result. We
+            // compute the result immediately but make it available
+            // only after the duration of the command (the first
+            // cycle has already been accounted for).
+            let cycles = gte::command_cycles(instruction.0);
+
+            self.gte_ready = shared.tk().now() + cycles - 1;
+
             self.gte.command(instruction.0);
         } else {
             match cop_opcode {
-                0b00000 => self.op_mfc2(instruction),
-                0b00010 => self.op_cfc2(instruction),
+                0b00000 => self.op_mfc2(instruction, shared),
+                0b00010 => self.op_cfc2(instruction, shared),
                 0b00100 => self.op_mtc2(instruction),
                 0b00110 => self.op_ctc2(instruction),
                 _       => panic!("unhandled GTE instruction {}", instruction),
@@ -1285,21 +1654,34 @@ impl Cpu {
         }
     }
 
+    /// Stall until the result of the last GTE command is available
+    fn wait_gte(&mut self, shared: &mut SharedState) {
+        let now = shared.tk().now();
+
+        if self.gte_ready > now {
+            shared.tk().tick(self.gte_ready - now);
+        }
+    }
+
     /// Move From Coprocessor 2 Data register
-    fn op_mfc2(&mut self, instruction: Instruction) {
+    fn op_mfc2(&mut self, instruction: Instruction, shared: &mut SharedState) {
         let cpu_r = instruction.t();
         let cop_r = instruction.d().0;
 
+        self.wait_gte(shared);
+
         let v = self.gte.data(cop_r);
 
         self.delayed_load_chain(cpu_r, v);
     }
 
     /// Move From Coprocessor 2 Control register
-    fn op_cfc2(&mut self, instruction: Instruction) {
+    fn op_cfc2(&mut self, instruction: Instruction, shared: &mut SharedState) {
         let cpu_r = instruction.t();
         let cop_r = instruction.d().0;
 
+        self.wait_gte(shared);
+
         let v = self.gte.control(cop_r);
 
         self.delayed_load_chain(cpu_r, v);
@@ -1350,7 +1732,12 @@ impl Cpu {
         let addr = self.reg(s).wrapping_add(i);
 
         // Cast as i8 to force sign extension
-        let v = self.load::<Byte, D>(debugger, shared, addr) as i8;
+        let v =
+            match self.load::<Byte, D>(debugger, shared, addr) {
+                Some(v) => v as i8,
+                // Bus error
+                None => return,
+            };
 
         self.delayed_load_chain(t, v as u32);
     }
@@ -1370,12 +1757,17 @@ impl Cpu {
         // Address must be 16bit aligned
         if addr % 2 == 0 {
             // Cast as i16 to force sign extension
-            let v = self.load::<HalfWord, D>(debugger, shared, addr) as i16;
+            let v =
+                match self.load::<HalfWord, D>(debugger, shared, addr) {
+                    Some(v) => v as i16,
+                    // Bus error
+                    None => return,
+                };
 
             self.delayed_load_chain(t, v as u32);
         } else {
             self.delayed_load();
-            self.exception(Exception::LoadAddressError);
+            self.address_error(Exception::LoadAddressError, addr);
         }
     }
 
@@ -1406,7 +1798,12 @@ impl Cpu {
         // Next we load the *aligned* word containing the first
         // addressed byte
         let aligned_addr = addr & !3;
-        let aligned_word = self.load::<Word, D>(debugger, shared, aligned_addr);
+        let aligned_word =
+            match self.load::<Word, D>(debugger, shared, aligned_addr) {
+                Some(v) => v,
+                // Bus error
+                None => return,
+            };
 
         // Depending on the address alignment we fetch the 1, 2, 3 or
         // 4 *most* significant bytes and put them in the target
@@ -1436,12 +1833,17 @@ impl Cpu {
 
         // Address must be 32bit aligned
         if addr % 4 == 0 {
-            let v = self.load::<Word, D>(debugger, shared, addr);
+            let v =
+                match self.load::<Word, D>(debugger, shared, addr) {
+                    Some(v) => v,
+                    // Bus error
+                    None => return,
+                };
 
             self.delayed_load_chain(t, v);
         } else {
             self.delayed_load();
-            self.exception(Exception::LoadAddressError);
+            self.address_error(Exception::LoadAddressError, addr);
         }
     }
 
@@ -1457,7 +1859,12 @@ impl Cpu {
 
         let addr = self.reg(s).wrapping_add(i);
 
-        let v = self.load::<Byte, D>(debugger, shared, addr);
+        let v =
+            match self.load::<Byte, D>(debugger, shared, addr) {
+                Some(v) => v,
+                // Bus error
+                None => return,
+            };
 
         self.delayed_load_chain(t, v as u32);
     }
@@ -1476,12 +1883,17 @@ impl Cpu {
 
         // Address must be 16bit aligned
         if addr % 2 == 0 {
-            let v = self.load::<HalfWord, D>(debugger, shared, addr);
+            let v =
+                match self.load::<HalfWord, D>(debugger, shared, addr) {
+                    Some(v) => v,
+                    // Bus error
+                    None => return,
+                };
 
             self.delayed_load_chain(t, v);
         } else {
             self.delayed_load();
-            self.exception(Exception::LoadAddressError);
+            self.address_error(Exception::LoadAddressError, addr);
         }
     }
 
@@ -1512,7 +1924,12 @@ impl Cpu {
         // Next we load the *aligned* word containing the first
         // addressed byte
         let aligned_addr = addr & !3;
-        let aligned_word = self.load::<Word, D>(debugger, shared, aligned_addr);
+        let aligned_word =
+            match self.load::<Word, D>(debugger, shared, aligned_addr) {
+                Some(v) => v,
+                // Bus error
+                None => return,
+            };
 
         // Depending on the address alignment we fetch the 1, 2, 3 or
         // 4 *least* significant bytes and put them in the target
@@ -1568,7 +1985,7 @@ impl Cpu {
         if addr % 2 == 0 {
             self.store::<HalfWord, D>(debugger, shared, renderer, addr, v);
         } else {
-            self.exception(Exception::StoreAddressError);
+            self.address_error(Exception::StoreAddressError, addr);
         }
     }
 
@@ -1587,9 +2004,7 @@ impl Cpu {
         let v    = self.reg(t);
 
         let aligned_addr = addr & !3;
-        // Load the current value for the aligned word at the target
-        // address
-        let cur_mem = self.load::<Word, D>(debugger, shared, aligned_addr);
+        let cur_mem = self.unaligned_store_base(aligned_addr);
 
         let mem =
             match addr & 3 {
@@ -1625,7 +2040,7 @@ impl Cpu {
         if addr % 4 == 0 {
             self.store::<Word, D>(debugger, shared, renderer, addr, v);
         } else {
-            self.exception(Exception::StoreAddressError);
+            self.address_error(Exception::StoreAddressError, addr);
         }
     }
 
@@ -1644,9 +2059,7 @@ impl Cpu {
         let v    = self.reg(t);
 
         let aligned_addr = addr & !3;
-        // Load the current value for the aligned word at the target
-        // address
-        let cur_mem = self.load::<Word, D>(debugger, shared, aligned_addr);
+        let cur_mem = self.unaligned_store_base(aligned_addr);
 
         let mem =
             match addr & 3 {
@@ -1694,12 +2107,17 @@ impl Cpu {
 
         // Address must be 32bit aligned
         if addr % 4 == 0 {
-            let v = self.load::<Word, D>(debugger, shared, addr);
+            let v =
+                match self.load::<Word, D>(debugger, shared, addr) {
+                    Some(v) => v,
+                    // Bus error
+                    None => return,
+                };
 
             // Send to coprocessor
             self.gte.set_data(cop_r, v);
         } else {
-            self.exception(Exception::LoadAddressError);
+            self.address_error(Exception::LoadAddressError, addr);
         }
     }
 
@@ -1738,6 +2156,9 @@ impl Cpu {
         let s = instruction.s();
 
         let addr = self.reg(s).wrapping_add(i);
+
+        self.wait_gte(shared);
+
         let v = self.gte.data(cop_r);
 
         self.delayed_load();
@@ -1746,7 +2167,7 @@ impl Cpu {
         if addr % 4 == 0 {
             self.store::<Word, D>(debugger, shared, renderer, addr, v);
         } else {
-            self.exception(Exception::LoadAddressError);
+            self.address_error(Exception::LoadAddressError, addr);
         }
     }
 
@@ -1759,6 +2180,57 @@ impl Cpu {
     }
 }
 
+/// Number of cycles between a multiplication and the availability of
+/// its result, depending on the magnitude of the first operand
+fn mult_cycles(a: u32) -> Cycles {
+    if a < 0x800 {
+        5
+    } else if a < 0x100000 {
+        8
+    } else {
+        12
+    }
+}
+
+/// Number of cycles between a division and the availability of its
+/// result
+const DIV_CYCLES: Cycles = 35;
+
+/// CPU execution engines
+#[derive(Clone, Copy, PartialEq, Eq, Debug, RustcDecodable, RustcEncodable)]
+pub enum Engine {
+    /// Decode and execute every instruction individually
+    Interpreter,
+    /// Execute pre-decoded blocks of instructions (see the `block`
+    /// module)
+    CachedInterpreter,
+    /// Recompile blocks of instructions to native x86-64 code. Debugger
+    /// breakpoints and watchpoints are not checked for the recompiled
+    /// code.
+    #[cfg(feature = "dynarec")]
+    Dynarec,
+}
+
+/// Engine selected by the frontend. It's not stored in savestates:
+/// it would override the frontend's choice and the `Dynarec` variant
+/// is not available in all builds.
+struct EngineSetting(Engine);
+
+impl Encodable for EngineSetting {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_nil()
+    }
+}
+
+impl Decodable for EngineSetting {
+    fn decode<D: Decoder>(d: &mut D) -> Result<EngineSetting, D::Error> {
+        try!(d.read_nil());
+
+        // Restored by `Cpu::restore_engine`
+        Ok(EngineSetting(Engine::Interpreter))
+    }
+}
+
 /// Simple wrapper around an instruction word to provide type-safety.
 #[derive(Clone, Copy, RustcDecodable, RustcEncodable)]
 struct Instruction(u32);
@@ -1859,7 +2331,7 @@ struct RegisterIndex(u32);
 #[derive(Clone, Copy, RustcDecodable, RustcEncodable)]
 struct ICacheLine {
     /// Tag: high 22bits of the address associated with this cacheline
-    /// Valid bits: 3 bit index of the first valid word in line.
+    /// Valid bits: bits [3:0], one per word in the line.
     tag_valid: u32,
     /// Four words per line
     line: [Instruction; 4],
@@ -1871,7 +2343,7 @@ impl ICacheLine {
         // missbehaving software we fill them with "trap" values
         ICacheLine {
             // Tag is 0, all line valid
-            tag_valid: 0x0,
+            tag_valid: 0xf,
             // BREAK opcode
             //line: [Instruction(0xbadc0de5); 4],
             line: [Instruction(0); 4],
@@ -1883,27 +2355,35 @@ impl ICacheLine {
         self.tag_valid & 0xfffff000
     }
 
-    /// Return the cacheline's first valid word
-    fn valid_index(&self) -> u32 {
-        // We store the valid bits in bits [4:2], this way we can just
-        // mask the PC value in `set_tag_valid` without having to
-        // shuffle the bits around
-        (self.tag_valid >> 2) & 0x7
+    /// Return the raw tag and valid bits, as read in tag test mode
+    fn tag_valid(&self) -> u32 {
+        self.tag_valid
     }
 
-    /// Set the cacheline's tag and valid bits. `pc` is the first
-    /// valid PC in the cacheline.
+    /// Return true if the word at `index` is valid
+    fn valid(&self, index: u32) -> bool {
+        self.tag_valid & (1 << index) != 0
+    }
+
+    /// Set the cacheline's tag and valid bits after a refill. `pc`
+    /// is the first valid PC in the cacheline, the words before it
+    /// are invalid.
     fn set_tag_valid(&mut self, pc: u32) {
-        self.tag_valid =  pc & 0x7ffff00c;
+        let index = (pc >> 2) & 3;
+
+        self.tag_valid = (pc & 0x7ffff000) | ((0xf << index) & 0xf);
+    }
+
+    /// Set the tag from `addr` and the valid bits from bits [3:0] of
+    /// `val`. Used in tag test mode.
+    fn set_tag_test(&mut self, addr: u32, val: u32) {
+        self.tag_valid = (addr & 0x7ffff000) | (val & 0xf);
     }
 
-    /// Invalidate the entire cacheline by pushing the index out of
-    /// range. Doesn't change the tag or contents of the line.
-    fn invalidate(&mut self) {
-        // Setting bit 4 means that the value returned by valid_index
-        // will be in the range [4, 7] which is outside the valid
-        // cacheline index range [0, 3].
-        self.tag_valid |= 0x10;
+    /// Invalidate the word at `index`. Doesn't change the tag or
+    /// contents of the line.
+    fn invalidate_word(&mut self, index: u32) {
+        self.tag_valid &= !(1 << index);
     }
 
     fn instruction(&self, index: u32) -> Instruction {
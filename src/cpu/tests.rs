@@ -739,6 +739,477 @@ fn test_lh_and_lb_sign_extension() {
     assert!(cpu.regs[4] == 0x80);
 }
 
+#[test]
+fn test_jump_to_unmapped_bus_error() {
+    let bios = Bios::dummy();
+    let gpu = Gpu::new(VideoClock::Ntsc);
+    let inter = Interconnect::new(bios, gpu, None);
+    let mut cpu = Cpu::new(inter);
+    let mut shared = SharedState::new();
+    let mut renderer = DummyRenderer;
+
+    for r in 0..31 {
+        cpu.set_reg(RegisterIndex(r), 0);
+    }
+
+    // lui t0, 0xbe00
+    // jr t0
+    // nop
+    write_blob(&mut cpu, 0x80100000,
+               &[0x3c08be00,
+                 0x01000008,
+                 0x00000000]);
+
+    cpu.set_pc(0x80100000);
+
+    // The 4th instruction fetch targets unmapped memory
+    for _ in 0..4 {
+        cpu.run_next_instruction(&mut (), &mut shared, &mut renderer);
+    }
+
+    let cause = cpu.cause(*shared.irq_state());
+
+    assert!(cpu.pc == 0x80000080);
+    assert!(cpu.cop0.epc() == 0xbe000000);
+    // IBE exception, not in a delay slot
+    assert!(cause & 0x7c == 6 << 2);
+    assert!(cause & (1 << 31) == 0);
+}
+
+#[test]
+fn test_delay_slot_bus_error() {
+    let bios = Bios::dummy();
+    let gpu = Gpu::new(VideoClock::Ntsc);
+    let inter = Interconnect::new(bios, gpu, None);
+    let mut cpu = Cpu::new(inter);
+    let mut shared = SharedState::new();
+    let mut renderer = DummyRenderer;
+
+    for r in 0..31 {
+        cpu.set_reg(RegisterIndex(r), 0);
+    }
+
+    // lui t0, 0x807f
+    // ori t0, t0, 0xfffc
+    // jr t0
+    // nop
+    write_blob(&mut cpu, 0x80100000,
+               &[0x3c08807f,
+                 0x3508fffc,
+                 0x01000008,
+                 0x00000000]);
+
+    // Last word of the RAM mirrors: j 0x80100000. The delay slot is
+    // past the end of the RAM.
+    write_blob(&mut cpu, 0x807ffffc, &[0x08040000]);
+
+    cpu.set_pc(0x80100000);
+
+    for _ in 0..6 {
+        cpu.run_next_instruction(&mut (), &mut shared, &mut renderer);
+    }
+
+    let cause = cpu.cause(*shared.irq_state());
+
+    assert!(cpu.pc == 0x80000080);
+    // EPC points at the jump
+    assert!(cpu.cop0.epc() == 0x807ffffc);
+    // IBE exception in a delay slot
+    assert!(cause & 0x7c == 6 << 2);
+    assert!(cause & (1 << 31) != 0);
+}
+
+#[test]
+fn test_mult_div_latency() {
+    let bios = Bios::dummy();
+    let gpu = Gpu::new(VideoClock::Ntsc);
+    let inter = Interconnect::new(bios, gpu, None);
+    let mut cpu = Cpu::new(inter);
+    let mut shared = SharedState::new();
+    let mut renderer = DummyRenderer;
+
+    for r in 0..31 {
+        cpu.set_reg(RegisterIndex(r), 0);
+    }
+
+    cpu.set_reg(RegisterIndex(1), 0x12345678);
+    cpu.set_reg(RegisterIndex(2), 3);
+
+    // mult r1, r2
+    // mflo r3
+    // div r1, r2
+    // mflo r4
+    // mult r2, r1
+    // mflo r5
+    write_blob(&mut cpu, 0x80100000,
+               &[0x00220018,
+                 0x00001812,
+                 0x0022001a,
+                 0x00002012,
+                 0x00410018,
+                 0x00002812]);
+
+    cpu.set_pc(0x80100000);
+
+    {
+        // Run a multiplication or division followed by MFLO and
+        // return the time elapsed between the end of both
+        // instructions
+        let mut latency = || {
+            cpu.run_next_instruction(&mut (), &mut shared, &mut renderer);
+
+            let start = shared.tk().now();
+
+            cpu.run_next_instruction(&mut (), &mut shared, &mut renderer);
+
+            shared.tk().now() - start
+        };
+
+        // MFLO stalls until the result is available. Large first
+        // operand: 12 cycles.
+        assert!(latency() == 12);
+        // Division: 35 cycles
+        assert!(latency() == 35);
+        // Small first operand: 5 cycles (`mult_cycles(3)`), MFLO
+        // still stalls for 4 cycles after its own
+        assert!(latency() == 5);
+    }
+
+    assert!(cpu.regs[3] == 0x369d0368);
+    assert!(cpu.regs[4] == 0x06117228);
+    assert!(cpu.regs[5] == 0x369d0368);
+}
+
+/// Write `cc` to the cache control register
+fn set_cache_control(cpu: &mut Cpu, shared: &mut SharedState, cc: u32) {
+    cpu.interconnect_mut()
+        .store::<memory::Word>(shared, &mut DummyRenderer, 0xfffe0130, cc)
+        .unwrap();
+}
+
+#[test]
+fn test_isolated_cache() {
+    let bios = Bios::dummy();
+    let gpu = Gpu::new(VideoClock::Ntsc);
+    let inter = Interconnect::new(bios, gpu, None);
+    let mut cpu = Cpu::new(inter);
+    let mut shared = SharedState::new();
+
+    // Instruction cache enabled, normal mode: word writes end up in
+    // the cacheline and partial reads return the byte lanes
+    set_cache_control(&mut cpu, &mut shared, 0x800);
+
+    cpu.cache_maintenance::<memory::Word>(0x124, 0x12345678);
+
+    assert!(cpu.isolated_load::<memory::Word>(0x124) == 0x12345678);
+    assert!(cpu.isolated_load::<memory::HalfWord>(0x126) == 0x1234);
+    assert!(cpu.isolated_load::<memory::Byte>(0x125) == 0x56);
+
+    // Partial writes invalidate the word instead
+    cpu.cache_maintenance::<memory::Byte>(0x125, 0xab);
+
+    assert!(cpu.isolated_load::<memory::Word>(0x124) == 0x12345678);
+
+    // Tag test mode: reads return the tag and the valid bits, word 1
+    // has been invalidated
+    set_cache_control(&mut cpu, &mut shared, 0x804);
+
+    assert!(cpu.isolated_load::<memory::Word>(0x120) == 0x0000000d);
+
+    // Writes set the tag from the address and the valid bits from the
+    // value
+    cpu.cache_maintenance::<memory::Word>(0x80011120, 0x3);
+
+    assert!(cpu.isolated_load::<memory::Word>(0x120) == 0x00011003);
+
+    // The BIOS writes 0 to invalidate the line
+    cpu.cache_maintenance::<memory::Word>(0x120, 0);
+
+    assert!(cpu.isolated_load::<memory::Word>(0x120) == 0);
+
+    // Instruction cache disabled: the writes are lost and nothing
+    // drives the bus on reads
+    set_cache_control(&mut cpu, &mut shared, 0);
+
+    cpu.cache_maintenance::<memory::Word>(0x130, 0xdeadbeef);
+
+    assert!(cpu.isolated_load::<memory::Word>(0x130) == 0xffffffff);
+
+    set_cache_control(&mut cpu, &mut shared, 0x800);
+
+    assert!(cpu.isolated_load::<memory::Word>(0x130) == 0);
+}
+
+#[test]
+fn test_isolated_scratch_pad() {
+    let bios = Bios::dummy();
+    let gpu = Gpu::new(VideoClock::Ntsc);
+    let inter = Interconnect::new(bios, gpu, None);
+    let mut cpu = Cpu::new(inter);
+    let mut shared = SharedState::new();
+    let mut renderer = DummyRenderer;
+
+    // Isolate the cache
+    cpu.cop0.set_sr(0x10000);
+
+    // Instruction cache and scratchpad enabled: the scratchpad isn't
+    // affected by the isolation
+    set_cache_control(&mut cpu, &mut shared, 0x888);
+
+    cpu.store::<memory::Word, ()>(&mut (),
+                                  &mut shared,
+                                  &mut renderer,
+                                  0x1f800010,
+                                  0xcafef00d);
+
+    assert!(cpu.load::<memory::Word, ()>(&mut (), &mut shared, 0x1f800010)
+            == Some(0xcafef00d));
+    assert!(cpu.isolated_load::<memory::Word>(0x1f800010) == 0);
+
+    // Scratchpad disabled: the same address targets the instruction
+    // cache
+    set_cache_control(&mut cpu, &mut shared, 0x800);
+
+    cpu.store::<memory::Word, ()>(&mut (),
+                                  &mut shared,
+                                  &mut renderer,
+                                  0x1f800010,
+                                  0x0badc0de);
+
+    assert!(cpu.load::<memory::Word, ()>(&mut (), &mut shared, 0x1f800010)
+            == Some(0x0badc0de));
+
+    // The scratchpad contents haven't changed
+    cpu.cop0.set_sr(0);
+    set_cache_control(&mut cpu, &mut shared, 0x888);
+
+    assert!(cpu.load::<memory::Word, ()>(&mut (), &mut shared, 0x1f800010)
+            == Some(0xcafef00d));
+}
+
+#[test]
+fn test_partial_register_access() {
+    let bios = Bios::dummy();
+    let gpu = Gpu::new(VideoClock::Ntsc);
+    let inter = Interconnect::new(bios, gpu, None);
+    let mut cpu = Cpu::new(inter);
+    let mut shared = SharedState::new();
+    let mut renderer = DummyRenderer;
+
+    set_cache_control(&mut cpu, &mut shared, 0x804);
+
+    let inter = cpu.interconnect_mut();
+
+    // Partial reads return the corresponding byte lanes
+    let cc = inter.load::<memory::HalfWord>(&mut shared, 0xfffe0130);
+    assert!(cc.unwrap() == 0x804);
+    let cc = inter.load::<memory::Byte>(&mut shared, 0xfffe0131);
+    assert!(cc.unwrap() as u8 == 0x08);
+
+    // Partial writes to the cache control are ignored
+    inter.store::<memory::Byte>(&mut shared, &mut renderer, 0xfffe0130, 0)
+        .unwrap();
+
+    assert!(inter.cache_control().tag_test_mode());
+
+    inter.store::<memory::Word>(&mut shared, &mut renderer, 0x1f801074, 0x7ff)
+        .unwrap();
+
+    let mask = inter.load::<memory::HalfWord>(&mut shared, 0x1f801074);
+    assert!(mask.unwrap() == 0x7ff);
+
+    // The IRQ registers are only 16bit wide
+    let high = inter.load::<memory::HalfWord>(&mut shared, 0x1f801076);
+    assert!(high.unwrap() == 0);
+
+    inter.store::<memory::HalfWord>(&mut shared,
+                                    &mut renderer,
+                                    0x1f801076,
+                                    0xffff)
+        .unwrap();
+
+    assert!(shared.irq_state().mask() == 0x7ff);
+}
+
+#[test]
+fn test_code_breakpoint_in_delay_slot() {
+    let bios = Bios::dummy();
+    let gpu = Gpu::new(VideoClock::Ntsc);
+    let inter = Interconnect::new(bios, gpu, None);
+    let mut cpu = Cpu::new(inter);
+    let mut shared = SharedState::new();
+    let mut renderer = DummyRenderer;
+
+    cpu.set_reg(RegisterIndex(1), 0);
+
+    // beq r0, r0, +2
+    // addiu r1, r0, 1
+    // addiu r2, r0, 2
+    // nop
+    write_blob(&mut cpu, 0x80100000,
+               &[0x10000002,
+                 0x24010001,
+                 0x24020002,
+                 0x00000000]);
+
+    cpu.set_pc(0x80100000);
+
+    // Break on the delay slot
+    cpu.cop0.set_bpc(0x80100004);
+    cpu.cop0.set_bpcm(0xffffffff);
+    cpu.cop0.set_dcic(0xc1800000);
+
+    cpu.run_next_instruction(&mut (), &mut shared, &mut renderer);
+    cpu.run_next_instruction(&mut (), &mut shared, &mut renderer);
+
+    // The exception triggers before the delay slot runs
+    assert!(cpu.regs[1] == 0);
+    assert!(cpu.pc == 0x80000040);
+
+    // EPC points to the branch, BD is set and the code is BREAK
+    let cause = cpu.cop0.cause(*shared.irq_state());
+
+    assert!(cpu.cop0.epc() == 0x80100000);
+    assert!(cause & (1 << 31) != 0);
+    assert!((cause >> 2) & 0x1f == 9);
+
+    // "Any break" and "code break" status bits
+    assert!(cpu.cop0.dcic() & 0x3f == 0x03);
+}
+
+#[test]
+fn test_data_breakpoints() {
+    let bios = Bios::dummy();
+    let gpu = Gpu::new(VideoClock::Ntsc);
+    let inter = Interconnect::new(bios, gpu, None);
+    let mut cpu = Cpu::new(inter);
+    let mut shared = SharedState::new();
+    let mut renderer = DummyRenderer;
+
+    cpu.set_reg(RegisterIndex(2), 0);
+
+    // lui r1, 0x8010
+    // swl r0, 0x100(r1)
+    // lw r2, 0x100(r1)
+    // sw r0, 0x100(r1)
+    write_blob(&mut cpu, 0x80100000,
+               &[0x3c018010,
+                 0xa8200100,
+                 0x8c220100,
+                 0xac200100]);
+
+    write_blob(&mut cpu, 0x80100100, &[0x12345678]);
+
+    // BEV set, the handler is in the BIOS
+    cpu.cop0.set_sr(1 << 22);
+    cpu.cop0.set_bda(0x80100100);
+    cpu.cop0.set_bdam(0xffffffff);
+
+    // Break on reads only: SWL doesn't read the memory, it only
+    // replaces the low byte
+    cpu.cop0.set_dcic(0xc6800000);
+
+    cpu.set_pc(0x80100000);
+
+    for _ in 0..3 {
+        cpu.run_next_instruction(&mut (), &mut shared, &mut renderer);
+    }
+
+    assert!(cpu.pc == 0xbfc00140);
+    assert!(cpu.cop0.epc() == 0x80100008);
+    // "Any break", "data break" and "data read" status bits
+    assert!(cpu.cop0.dcic() & 0x3f == 0x0d);
+
+    // The load never completes
+    assert!(cpu.regs[2] == 0);
+    assert!(cpu.load.0 == RegisterIndex(0));
+
+    // Break on writes only
+    cpu.cop0.set_dcic(0xca800000);
+
+    cpu.set_pc(0x80100008);
+
+    cpu.run_next_instruction(&mut (), &mut shared, &mut renderer);
+    assert!(cpu.pc == 0x8010000c);
+
+    cpu.run_next_instruction(&mut (), &mut shared, &mut renderer);
+    cpu.run_next_instruction(&mut (), &mut shared, &mut renderer);
+
+    assert!(cpu.pc == 0xbfc00140);
+    assert!(cpu.cop0.epc() == 0x8010000c);
+    // "Any break", "data break" and "data write" status bits
+    assert!(cpu.cop0.dcic() & 0x3f == 0x15);
+
+    // The pending load completed but the store never happened
+    assert!(cpu.regs[2] == 0x12345600);
+    assert!(cpu.examine::<memory::Word>(0x80100100) == 0x12345600);
+}
+
+#[test]
+fn test_gte_latency() {
+    let bios = Bios::dummy();
+    let gpu = Gpu::new(VideoClock::Ntsc);
+    let inter = Interconnect::new(bios, gpu, None);
+    let mut cpu = Cpu::new(inter);
+    let mut shared = SharedState::new();
+    let mut renderer = DummyRenderer;
+
+    // nct
+    // mfc2 t0, mac0
+    // mfc2 t1, mac0
+    // nct
+    // cfc2 t1, flag
+    // nct
+    // swc2 mac0, 0x1000(r0)
+    // nct
+    // nclip
+    // mfc2 t0, mac0
+    write_blob(&mut cpu, 0x80100000,
+               &[0x4a000020,
+                 0x4808c000,
+                 0x4809c000,
+                 0x4a000020,
+                 0x4849f800,
+                 0x4a000020,
+                 0xe8181000,
+                 0x4a000020,
+                 0x4a000006,
+                 0x4808c000]);
+
+    cpu.set_pc(0x80100000);
+
+    // Run the next instruction and return its duration. The cache is
+    // disabled so every instruction takes at least 5 cycles (4 for
+    // the fetch, 1 for the execution).
+    let mut step = || {
+        let start = shared.tk().now();
+
+        cpu.run_next_instruction(&mut (), &mut shared, &mut renderer);
+
+        shared.tk().now() - start
+    };
+
+    // The CPU doesn't wait for the commands to complete
+    assert!(step() == 5);
+    // MFC2 stalls until the end of NCT (30 cycles, the first one
+    // overlaps with the command's execution)
+    assert!(step() == 29);
+    // No stall when the GTE is idle
+    assert!(step() == 5);
+
+    // CFC2 and SWC2 stall as well
+    assert!(step() == 5);
+    assert!(step() == 29);
+    assert!(step() == 5);
+    assert!(step() == 29);
+
+    // The GTE runs one command at a time: NCLIP waits for NCT to
+    // complete and MFC2 for NCLIP (8 cycles)
+    assert!(step() == 5);
+    assert!(step() == 29);
+    assert!(step() == 7);
+}
+
 /// Number of CPU cycles after which we consider the test to be a
 /// failure
 const TIMEOUT: usize = 1_000_000;
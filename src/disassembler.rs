@@ -0,0 +1,451 @@
+//! MIPS R3000A disassembler, the counterpart of the `assembler`
+//! module. Decodes all the CPU, COP0 and GTE (COP2) opcodes into a
+//! human readable string using the PlayStation register names.
+//!
+//! The pseudo-instructions generated by the assembler (`nop`, `move`,
+//! `b`, `beqz` and `bnez`) are recognized so that assembled code
+//! reads back the way it was written.
+
+use std::collections::HashMap;
+
+/// General purpose register names
+pub const REGISTER_NAMES: [&'static str; 32] = [
+    "r0", "at", "v0", "v1", "a0", "a1", "a2", "a3",
+    "t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7",
+    "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7",
+    "t8", "t9", "k0", "k1", "gp", "sp", "fp", "ra",
+];
+
+/// GTE data register names (MFC2/MTC2/LWC2/SWC2)
+pub const GTE_DATA_NAMES: [&'static str; 32] = [
+    "vxy0", "vz0",  "vxy1", "vz1",  "vxy2", "vz2",  "rgbc", "otz",
+    "ir0",  "ir1",  "ir2",  "ir3",  "sxy0", "sxy1", "sxy2", "sxyp",
+    "sz0",  "sz1",  "sz2",  "sz3",  "rgb0", "rgb1", "rgb2", "res1",
+    "mac0", "mac1", "mac2", "mac3", "irgb", "orgb", "lzcs", "lzcr",
+];
+
+/// GTE control register names (CFC2/CTC2)
+pub const GTE_CONTROL_NAMES: [&'static str; 32] = [
+    "rt11rt12", "rt13rt21", "rt22rt23", "rt31rt32",
+    "rt33",     "trx",      "try",      "trz",
+    "l11l12",   "l13l21",   "l22l23",   "l31l32",
+    "l33",      "rbk",      "gbk",      "bbk",
+    "lr1lr2",   "lr3lg1",   "lg2lg3",   "lb1lb2",
+    "lb3",      "rfc",      "gfc",      "bfc",
+    "ofx",      "ofy",      "h",        "dqa",
+    "dqb",      "zsf3",     "zsf4",     "flag",
+];
+
+/// Symbol table used to annotate jump and branch targets
+pub trait Symbols {
+    /// Return the name of the symbol located at `addr`, if any
+    fn symbol(&self, addr: u32) -> Option<&str>;
+}
+
+/// No symbols
+impl Symbols for () {
+    fn symbol(&self, _: u32) -> Option<&str> {
+        None
+    }
+}
+
+impl Symbols for HashMap<u32, String> {
+    fn symbol(&self, addr: u32) -> Option<&str> {
+        self.get(&addr).map(|s| &**s)
+    }
+}
+
+/// Disassemble the instruction `word` located at address `pc`
+pub fn disassemble(word: u32, pc: u32) -> String {
+    disassemble_with_symbols(word, pc, &())
+}
+
+/// Disassemble the instruction `word` located at address `pc`, jump
+/// and branch targets are resolved using `symbols`
+pub fn disassemble_with_symbols<S>(word: u32,
+                                   pc: u32,
+                                   symbols: &S) -> String
+    where S: Symbols {
+    let (mnemonic, operands) = decode(word, pc, symbols);
+
+    if operands.is_empty() {
+        mnemonic.to_owned()
+    } else {
+        format!("{:<7} {}", mnemonic, operands)
+    }
+}
+
+/// Return the mnemonic and the formatted operands for `word`
+fn decode<S>(word: u32, pc: u32, symbols: &S) -> (&'static str, String)
+    where S: Symbols {
+    let s = REGISTER_NAMES[((word >> 21) & 0x1f) as usize];
+    let t = REGISTER_NAMES[((word >> 16) & 0x1f) as usize];
+    let d = REGISTER_NAMES[((word >> 11) & 0x1f) as usize];
+    let shift = (word >> 6) & 0x1f;
+    let imm = word & 0xffff;
+    let imm_se = imm as i16;
+
+    // Jumps and branches are relative to the delay slot
+    let delay_slot = pc.wrapping_add(4);
+
+    let branch_target =
+        target(delay_slot.wrapping_add((imm_se as u32) << 2), symbols);
+
+    let jump_target =
+        target((delay_slot & 0xf0000000) | ((word & 0x3ffffff) << 2),
+               symbols);
+
+    let rd_rs_rt = || format!("{}, {}, {}", d, s, t);
+    let rd_rt_rs = || format!("{}, {}, {}", d, t, s);
+    let rd_rt_shift = || format!("{}, {}, {}", d, t, shift);
+    let rs_rt = || format!("{}, {}", s, t);
+    let rt_rs_se = || format!("{}, {}, {}", t, s, imm_se);
+    let rt_rs_imm = || format!("{}, {}, 0x{:x}", t, s, imm);
+    let rt_mem = || format!("{}, {}({})", t, imm_se, s);
+    let code = || format!("0x{:x}", (word >> 6) & 0xfffff);
+
+    match word >> 26 {
+        0b000000 => match word & 0x3f {
+            0b000000 =>
+                if word == 0 {
+                    ("nop", String::new())
+                } else {
+                    ("sll", rd_rt_shift())
+                },
+            0b000010 => ("srl", rd_rt_shift()),
+            0b000011 => ("sra", rd_rt_shift()),
+            0b000100 => ("sllv", rd_rt_rs()),
+            0b000110 => ("srlv", rd_rt_rs()),
+            0b000111 => ("srav", rd_rt_rs()),
+            0b001000 => ("jr", s.to_owned()),
+            0b001001 =>
+                if d == "ra" {
+                    ("jalr", s.to_owned())
+                } else {
+                    ("jalr", format!("{}, {}", d, s))
+                },
+            0b001100 => ("syscall", code()),
+            0b001101 => ("break", code()),
+            0b010000 => ("mfhi", d.to_owned()),
+            0b010001 => ("mthi", s.to_owned()),
+            0b010010 => ("mflo", d.to_owned()),
+            0b010011 => ("mtlo", s.to_owned()),
+            0b011000 => ("mult", rs_rt()),
+            0b011001 => ("multu", rs_rt()),
+            0b011010 => ("div", rs_rt()),
+            0b011011 => ("divu", rs_rt()),
+            0b100000 => ("add", rd_rs_rt()),
+            0b100001 =>
+                if t == "r0" {
+                    ("move", format!("{}, {}", d, s))
+                } else {
+                    ("addu", rd_rs_rt())
+                },
+            0b100010 => ("sub", rd_rs_rt()),
+            0b100011 => ("subu", rd_rs_rt()),
+            0b100100 => ("and", rd_rs_rt()),
+            0b100101 => ("or", rd_rs_rt()),
+            0b100110 => ("xor", rd_rs_rt()),
+            0b100111 => ("nor", rd_rs_rt()),
+            0b101010 => ("slt", rd_rs_rt()),
+            0b101011 => ("sltu", rd_rs_rt()),
+            _ => illegal(word),
+        },
+        0b000001 => {
+            // Same decoding as the CPU: the link only takes place if
+            // bits [20:17] are 0b1000
+            let is_bgez = (word >> 16) & 1 != 0;
+            let is_link = (word >> 17) & 0xf == 0x8;
+
+            let mnemonic =
+                match (is_bgez, is_link) {
+                    (false, false) => "bltz",
+                    (true, false) => "bgez",
+                    (false, true) => "bltzal",
+                    (true, true) => "bgezal",
+                };
+
+            (mnemonic, format!("{}, {}", s, branch_target))
+        }
+        0b000010 => ("j", jump_target),
+        0b000011 => ("jal", jump_target),
+        0b000100 =>
+            if s == "r0" && t == "r0" {
+                ("b", branch_target)
+            } else if t == "r0" {
+                ("beqz", format!("{}, {}", s, branch_target))
+            } else {
+                ("beq", format!("{}, {}", rs_rt(), branch_target))
+            },
+        0b000101 =>
+            if t == "r0" {
+                ("bnez", format!("{}, {}", s, branch_target))
+            } else {
+                ("bne", format!("{}, {}", rs_rt(), branch_target))
+            },
+        0b000110 => ("blez", format!("{}, {}", s, branch_target)),
+        0b000111 => ("bgtz", format!("{}, {}", s, branch_target)),
+        0b001000 => ("addi", rt_rs_se()),
+        0b001001 => ("addiu", rt_rs_se()),
+        0b001010 => ("slti", rt_rs_se()),
+        0b001011 => ("sltiu", rt_rs_se()),
+        0b001100 => ("andi", rt_rs_imm()),
+        0b001101 => ("ori", rt_rs_imm()),
+        0b001110 => ("xori", rt_rs_imm()),
+        0b001111 => ("lui", format!("{}, 0x{:x}", t, imm)),
+        0b010000 => {
+            let cop_r = (word >> 11) & 0x1f;
+
+            match (word >> 21) & 0x1f {
+                0b00000 => ("mfc0", format!("{}, {}", t, cop0_name(cop_r))),
+                0b00100 => ("mtc0", format!("{}, {}", t, cop0_name(cop_r))),
+                0b10000 if word & 0x3f == 0b010000 => ("rfe", String::new()),
+                _ => illegal(word),
+            }
+        }
+        0b010001 => ("cop1", format!("0x{:07x}", word & 0x1ffffff)),
+        0b010010 => {
+            let cop_r = ((word >> 11) & 0x1f) as usize;
+
+            match (word >> 21) & 0x1f {
+                0b00000 =>
+                    ("mfc2", format!("{}, {}", t, GTE_DATA_NAMES[cop_r])),
+                0b00010 =>
+                    ("cfc2", format!("{}, {}", t, GTE_CONTROL_NAMES[cop_r])),
+                0b00100 =>
+                    ("mtc2", format!("{}, {}", t, GTE_DATA_NAMES[cop_r])),
+                0b00110 =>
+                    ("ctc2", format!("{}, {}", t, GTE_CONTROL_NAMES[cop_r])),
+                op if op & 0x10 != 0 => gte_command(word),
+                _ => illegal(word),
+            }
+        }
+        0b010011 => ("cop3", format!("0x{:07x}", word & 0x1ffffff)),
+        0b100000 => ("lb", rt_mem()),
+        0b100001 => ("lh", rt_mem()),
+        0b100010 => ("lwl", rt_mem()),
+        0b100011 => ("lw", rt_mem()),
+        0b100100 => ("lbu", rt_mem()),
+        0b100101 => ("lhu", rt_mem()),
+        0b100110 => ("lwr", rt_mem()),
+        0b101000 => ("sb", rt_mem()),
+        0b101001 => ("sh", rt_mem()),
+        0b101010 => ("swl", rt_mem()),
+        0b101011 => ("sw", rt_mem()),
+        0b101110 => ("swr", rt_mem()),
+        0b110000 => ("lwc0", cop_mem(word)),
+        0b110001 => ("lwc1", cop_mem(word)),
+        0b110010 => ("lwc2", gte_mem(word)),
+        0b110011 => ("lwc3", cop_mem(word)),
+        0b111000 => ("swc0", cop_mem(word)),
+        0b111001 => ("swc1", cop_mem(word)),
+        0b111010 => ("swc2", gte_mem(word)),
+        0b111011 => ("swc3", cop_mem(word)),
+        _ => illegal(word),
+    }
+}
+
+fn illegal(word: u32) -> (&'static str, String) {
+    ("illegal", format!("0x{:08x}", word))
+}
+
+/// Format a jump or branch target, with the symbol name if there's
+/// one
+fn target<S: Symbols>(addr: u32, symbols: &S) -> String {
+    match symbols.symbol(addr) {
+        Some(name) => format!("0x{:08x} <{}>", addr, name),
+        None => format!("0x{:08x}", addr),
+    }
+}
+
+fn cop0_name(reg: u32) -> String {
+    let name =
+        match reg {
+            3 => "bpc",
+            5 => "bda",
+            6 => "jumpdest",
+            7 => "dcic",
+            8 => "badvaddr",
+            9 => "bdam",
+            11 => "bpcm",
+            12 => "sr",
+            13 => "cause",
+            14 => "epc",
+            15 => "prid",
+            _ => return format!("cop0r{}", reg),
+        };
+
+    name.to_owned()
+}
+
+/// Operands of the LWCn/SWCn instructions for the coprocessors
+/// without named registers
+fn cop_mem(word: u32) -> String {
+    format!("cop{}r{}, {}({})",
+            (word >> 26) & 3,
+            (word >> 16) & 0x1f,
+            word as i16,
+            REGISTER_NAMES[((word >> 21) & 0x1f) as usize])
+}
+
+/// Operands of LWC2/SWC2
+fn gte_mem(word: u32) -> String {
+    format!("{}, {}({})",
+            GTE_DATA_NAMES[((word >> 16) & 0x1f) as usize],
+            word as i16,
+            REGISTER_NAMES[((word >> 21) & 0x1f) as usize])
+}
+
+/// Decode a GTE command, the configuration bits are listed as
+/// operands when they're relevant
+fn gte_command(word: u32) -> (&'static str, String) {
+    let mnemonic =
+        match word & 0x3f {
+            0x01 => "rtps",
+            0x06 => "nclip",
+            0x0c => "op",
+            0x10 => "dpcs",
+            0x11 => "intpl",
+            0x12 => "mvmva",
+            0x13 => "ncds",
+            0x14 => "cdp",
+            0x16 => "ncdt",
+            0x1b => "nccs",
+            0x1c => "cc",
+            0x1e => "ncs",
+            0x20 => "nct",
+            0x28 => "sqr",
+            0x29 => "dcpl",
+            0x2a => "dpct",
+            0x2d => "avsz3",
+            0x2e => "avsz4",
+            0x30 => "rtpt",
+            0x3d => "gpf",
+            0x3e => "gpl",
+            0x3f => "ncct",
+            _ => return ("cop2", format!("0x{:07x}", word & 0x1ffffff)),
+        };
+
+    let mut operands = Vec::new();
+
+    if word & 0x3f == 0x12 {
+        let matrix = ["rt", "ll", "lc", "??"][((word >> 17) & 3) as usize];
+        let vector = ["v0", "v1", "v2", "ir"][((word >> 15) & 3) as usize];
+        let add = ["tr", "bk", "fc", "none"][((word >> 13) & 3) as usize];
+
+        operands.push(format!("mx={}", matrix));
+        operands.push(format!("v={}", vector));
+        operands.push(format!("cv={}", add));
+    }
+
+    if word & (1 << 19) != 0 {
+        operands.push("sf".to_owned());
+    }
+
+    if word & (1 << 10) != 0 {
+        operands.push("lm".to_owned());
+    }
+
+    (mnemonic, operands.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use assembler::Assembler;
+    use assembler::syntax::*;
+
+    use super::{disassemble, disassemble_with_symbols};
+
+    #[test]
+    fn disassemble_assembled() {
+        let mut asm = Assembler::from_base(0x80010000);
+
+        asm.assemble(&[
+            Global("start"),
+            Nop,
+            Addiu(SP, SP, -24),
+            Sw(RA, SP, 20),
+            Move(FP, SP),
+            Lui(T0, 0x1f80),
+            Bnez(T0, Label::Global("start")),
+            Mfc0(T1, 12),
+            Jal(Label::Global("start")),
+            Jalr(RA, T0),
+            Sllv(T2, T3, T4),
+        ]).unwrap();
+
+        let (mc, base) = asm.machine_code();
+
+        let mut symbols = HashMap::new();
+
+        symbols.insert(0x80010000, "start".to_owned());
+
+        let expected = [
+            "nop",
+            "addiu   sp, sp, -24",
+            "sw      ra, 20(sp)",
+            "move    fp, sp",
+            "lui     t0, 0x1f80",
+            "bnez    t0, 0x80010000 <start>",
+            "mfc0    t1, sr",
+            "jal     0x80010000 <start>",
+            "jalr    t0",
+            "sllv    t2, t3, t4",
+        ];
+
+        for (i, expected) in expected.iter().enumerate() {
+            let pc = base + (i as u32) * 4;
+            let b = &mc[i * 4..];
+            let word = b[0] as u32
+                | ((b[1] as u32) << 8)
+                | ((b[2] as u32) << 16)
+                | ((b[3] as u32) << 24);
+
+            assert_eq!(disassemble_with_symbols(word, pc, &symbols), *expected);
+        }
+
+        // RTPS with the shift flag set
+        assert_eq!(disassemble(0x4a180001, 0), "rtps    sf");
+    }
+
+    #[test]
+    fn regimm_branches() {
+        // The link only takes place if bits [20:17] are 0b1000
+        assert_eq!(disassemble(0x04800001, 0), "bltz    a0, 0x00000008");
+        assert_eq!(disassemble(0x04810001, 0), "bgez    a0, 0x00000008");
+        assert_eq!(disassemble(0x04900001, 0), "bltzal  a0, 0x00000008");
+        assert_eq!(disassemble(0x04910001, 0), "bgezal  a0, 0x00000008");
+        assert_eq!(disassemble(0x04930001, 0), "bgez    a0, 0x00000008");
+    }
+
+    #[test]
+    fn coprocessors() {
+        assert_eq!(disassemble(0x42000010, 0), "rfe");
+        assert_eq!(disassemble(0x48083800, 0), "mfc2    t0, otz");
+        assert_eq!(disassemble(0x4848f800, 0), "cfc2    t0, flag");
+        assert_eq!(disassemble(0x48894000, 0), "mtc2    t1, ir0");
+        assert_eq!(disassemble(0x48c9c000, 0), "ctc2    t1, ofx");
+
+        // MVMVA with the light matrix, V2, no translation, sf and lm
+        assert_eq!(disassemble(0x4a0b6412, 0),
+                   "mvmva   mx=ll, v=v2, cv=none, sf, lm");
+        // MVMVA with the rotation matrix, IR and the translation
+        assert_eq!(disassemble(0x4a018012, 0),
+                   "mvmva   mx=rt, v=ir, cv=tr");
+
+        // Unknown GTE command
+        assert_eq!(disassemble(0x4a000000, 0), "cop2    0x2000000");
+        assert_eq!(disassemble(0x4c000123, 0), "cop3    0x0000123");
+        assert_eq!(disassemble(0xcc4a0010, 0), "lwc3    cop3r10, 16(v0)");
+    }
+
+    #[test]
+    fn illegal() {
+        assert_eq!(disassemble(0x0000003f, 0), "illegal 0x0000003f");
+        assert_eq!(disassemble(0xfc000000, 0), "illegal 0xfc000000");
+        // There is no CTC0
+        assert_eq!(disassemble(0x40c00000, 0), "illegal 0x40c00000");
+    }
+}
@@ -249,6 +249,14 @@ impl Gpu {
         // Conwert delta back to integer
         let delta = delta >> 16;
 
+        if let Some(dist) = self.ticks_to_lightgun_target(shared) {
+            if dist <= delta {
+                // The beam went past the point the lightgun is aimed
+                // at, the gun's photodiode triggers IRQ10.
+                shared.irq_state_mut().assert(Interrupt::Lightgun);
+            }
+        }
+
         // Compute the current line and position within the line.
 
         let (ticks_per_line, lines_per_frame) = self.vmode_timings();
@@ -341,6 +349,13 @@ impl Gpu {
             delta += (display_line_end - 1 - cur_line) * ticks_per_line;
         }
 
+        // Make sure we don't miss the lightgun interrupt
+        if let Some(dist) = self.ticks_to_lightgun_target(shared) {
+            if dist < delta {
+                delta = dist;
+            }
+        }
+
         // Convert delta in CPU clock periods.
         delta <<= FracCycles::frac_bits();
         // Remove the current fractional cycle to be more accurate
@@ -358,6 +373,50 @@ impl Gpu {
         (self.display_vram_x_start, self.display_vram_y_start)
     }
 
+    /// Return the current display configuration, used to convert
+    /// screen coordinates into beam positions for the lightguns
+    pub fn display_info(&self) -> DisplayInfo {
+        DisplayInfo {
+            vram_start: self.display_vram_start(),
+            width: self.hres.width(),
+            height: self.vres.height(),
+            dotclock_divider: self.hres.dotclock_divider(),
+            horiz_start: self.display_horiz_start,
+            line_start: self.display_line_start,
+        }
+    }
+
+    /// Return the number of GPU clock ticks between the current
+    /// position of the beam and the lightgun target set in `shared`,
+    /// if any. The result is always in the range `[1, frame_len]`.
+    fn ticks_to_lightgun_target(&self,
+                                shared: &mut SharedState) -> Option<Cycles> {
+        let (dot, line) =
+            match shared.lightgun_target() {
+                Some(t) => t,
+                None => return None,
+            };
+
+        let (ticks_per_line, lines_per_frame) = self.vmode_timings();
+
+        let ticks_per_line = ticks_per_line as Cycles;
+        let frame_len = ticks_per_line * lines_per_frame as Cycles;
+
+        let tick = dot as Cycles * self.hres.dotclock_divider() as Cycles;
+
+        let target = line as Cycles * ticks_per_line + tick;
+        let current = self.display_line as Cycles * ticks_per_line +
+                      self.display_line_tick as Cycles;
+
+        let dist = (target % frame_len + frame_len - current) % frame_len;
+
+        if dist == 0 {
+            Some(frame_len)
+        } else {
+            Some(dist)
+        }
+    }
+
     /// Return true if we're currently in the video blanking period
     fn in_vblank(&self) -> bool {
         self.display_line < self.display_line_start ||
@@ -1238,6 +1297,7 @@ impl Gpu {
 
                 timers.video_timings_changed(shared, self);
                 self.update_display_mode(renderer);
+                self.update_display_info(shared);
                 self.update_draw_area(renderer);
                 renderer.set_draw_offset(0, 0);
             },
@@ -1250,13 +1310,21 @@ impl Gpu {
                 // that the game is done rendering the previous frame.
                 shared.counters_mut().framebuffer_swap.increment();
                 self.gp1_display_vram_start(val);
+                self.update_display_info(shared);
+            }
+            0x06 => {
+                self.gp1_display_horizontal_range(val);
+                self.update_display_info(shared);
+            }
+            0x07 => {
+                self.gp1_display_vertical_range(shared,val);
+                self.update_display_info(shared);
             }
-            0x06 => self.gp1_display_horizontal_range(val),
-            0x07 => self.gp1_display_vertical_range(shared,val),
             0x08 => {
                 self.gp1_display_mode(shared, val);
                 timers.video_timings_changed(shared, self);
                 self.update_display_mode(renderer);
+                self.update_display_info(shared);
             }
             0x10 => self.gp1_get_info(val),
             _    => panic!("Unhandled GP1 command {:08x}", val),
@@ -1272,6 +1340,11 @@ impl Gpu {
         renderer.set_display_mode(top_left, resolution, depth_24bpp);
     }
 
+    /// Forward the new display configuration to the lightguns
+    fn update_display_info(&self, shared: &mut SharedState) {
+        shared.set_display_info(self.display_info());
+    }
+
     /// GP1(0x00): Soft Reset
     fn gp1_reset(&mut self,
                  shared: &mut SharedState) {
@@ -1561,6 +1634,65 @@ impl VerticalRes {
     }
 }
 
+/// Display configuration as seen by the lightguns
+#[derive(Clone, Copy, Debug, RustcDecodable, RustcEncodable)]
+pub struct DisplayInfo {
+    /// Top-left corner of the display area in VRAM
+    pub vram_start: (u16, u16),
+    /// Approximate width of the displayed image in pixels
+    pub width: u16,
+    /// Height of the displayed image in pixels
+    pub height: u16,
+    /// Number of GPU clock ticks per dotclock
+    pub dotclock_divider: u8,
+    /// Display output horizontal start relative to HSYNC, in GPU
+    /// clock ticks
+    pub horiz_start: u16,
+    /// First displayed line relative to VSYNC
+    pub line_start: u16,
+}
+
+impl DisplayInfo {
+    pub fn new() -> DisplayInfo {
+        DisplayInfo {
+            vram_start: (0, 0),
+            width: 320,
+            height: 240,
+            dotclock_divider: 8,
+            horiz_start: 0x200,
+            line_start: 0x10,
+        }
+    }
+
+    /// Convert a VRAM position into screen coordinates (relative to
+    /// the top-left of the displayed image)
+    pub fn vram_to_screen(&self, x: u16, y: u16) -> (i32, i32) {
+        (x as i32 - self.vram_start.0 as i32,
+         y as i32 - self.vram_start.1 as i32)
+    }
+
+    /// Convert the screen coordinates `(x, y)` into the position of
+    /// the beam `(dotclock, line)` relative to HSYNC and VSYNC
+    /// respectively. Returns `None` if the position is off-screen.
+    pub fn screen_to_beam(&self, x: i32, y: i32) -> Option<(u16, u16)> {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            return None;
+        }
+
+        let dot = self.horiz_start / self.dotclock_divider as u16 + x as u16;
+
+        // In 480 line mode each field only contains every other line
+        let line =
+            if self.height > 240 {
+                y / 2
+            } else {
+                y
+            };
+
+        Some((dot, self.line_start + line as u16))
+    }
+}
+
 /// Video Modes
 #[derive(Clone, Copy, RustcDecodable, RustcEncodable)]
 enum VMode {
@@ -1934,3 +2066,110 @@ pub enum VideoClock {
     Ntsc,
     Pal,
 }
+
+#[cfg(test)]
+mod tests {
+    use shared::SharedState;
+    use timekeeper::{Peripheral, Cycles, FracCycles};
+
+    use super::{Gpu, VideoClock};
+
+    fn lightgun_irq(shared: &mut SharedState) -> bool {
+        shared.irq_state().status() & (1 << 10) != 0
+    }
+
+    /// Tick until the GPU requests a sync and return the number of
+    /// CPU cycles elapsed
+    fn cycles_to_sync(shared: &mut SharedState) -> Cycles {
+        let mut cycles = 0;
+
+        while !shared.tk().needs_sync(Peripheral::Gpu) {
+            shared.tk().tick(1);
+            cycles += 1;
+        }
+
+        cycles
+    }
+
+    /// Return the number of CPU cycles needed for `ticks` GPU ticks,
+    /// rounded up
+    fn gpu_ticks_to_cpu(gpu: &Gpu, ticks: Cycles) -> Cycles {
+        let ratio = gpu.gpu_to_cpu_clock_ratio().get_fp();
+
+        ((ticks << FracCycles::frac_bits()) + ratio - 1) / ratio
+    }
+
+    #[test]
+    fn lightgun_target_distance() {
+        let mut shared = SharedState::new();
+        let mut gpu = Gpu::new(VideoClock::Ntsc);
+
+        assert_eq!(gpu.ticks_to_lightgun_target(&mut shared), None);
+
+        // Default mode: 256 pixels (dotclock divider 10), 3412 ticks
+        // per line, 263 lines
+        shared.set_lightgun_target(Some((64, 5)));
+        assert_eq!(gpu.ticks_to_lightgun_target(&mut shared),
+                   Some(5 * 3412 + 64 * 10));
+
+        // Target behind the beam: we have to wait for the next frame
+        gpu.display_line = 6;
+        gpu.display_line_tick = 0;
+        assert_eq!(gpu.ticks_to_lightgun_target(&mut shared),
+                   Some(263 * 3412 - 3412 + 64 * 10));
+
+        // Beam right on the target: next frame
+        gpu.display_line = 5;
+        gpu.display_line_tick = 640;
+        assert_eq!(gpu.ticks_to_lightgun_target(&mut shared),
+                   Some(263 * 3412));
+    }
+
+    #[test]
+    fn lightgun_irq_timing() {
+        // Aim before the end of the vertical blanking so that the
+        // lightgun is the next event
+        let target = 5 * 3412 + 64 * 10;
+
+        let expected = {
+            let mut shared = SharedState::new();
+            let mut gpu = Gpu::new(VideoClock::Ntsc);
+
+            shared.set_lightgun_target(Some((64, 5)));
+            gpu.sync(&mut shared);
+
+            let cycles = cycles_to_sync(&mut shared);
+
+            assert_eq!(cycles, gpu_ticks_to_cpu(&gpu, target));
+
+            cycles
+        };
+
+        let mut shared = SharedState::new();
+        let mut gpu = Gpu::new(VideoClock::Ntsc);
+
+        shared.set_lightgun_target(Some((64, 5)));
+        gpu.sync(&mut shared);
+
+        // One cycle early: no interrupt yet
+        shared.tk().tick(expected - 1);
+        gpu.sync(&mut shared);
+        assert!(!lightgun_irq(&mut shared));
+
+        shared.tk().tick(1);
+        gpu.sync(&mut shared);
+        assert!(lightgun_irq(&mut shared));
+
+        // The gun doesn't trigger again before the next frame
+        shared.irq_state_mut().ack(!(1 << 10));
+        shared.tk().tick(expected);
+        gpu.sync(&mut shared);
+        assert!(!lightgun_irq(&mut shared));
+
+        // No target, no interrupt
+        shared.set_lightgun_target(None);
+        shared.tk().tick(263 * 3412);
+        gpu.sync(&mut shared);
+        assert!(!lightgun_irq(&mut shared));
+    }
+}
@@ -1,4 +1,4 @@
-/// The PlayStation supports 10 interrupts
+/// The PlayStation supports 11 interrupts
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 #[derive(RustcDecodable, RustcEncodable)]
 pub enum Interrupt {
@@ -16,6 +16,9 @@ pub enum Interrupt {
     Timer2 = 6,
     /// Gamepad and Memory Card controller interrupt
     PadMemCard = 7,
+    /// Lightgun interrupt, triggered by the gun's photodiode when the
+    /// beam passes over the aimed point
+    Lightgun = 10,
 }
 
 #[derive(Clone, Copy, RustcDecodable, RustcEncodable)]
@@ -63,7 +66,8 @@ impl InterruptState {
                           Interrupt::Timer0,
                           Interrupt::Timer1,
                           Interrupt::Timer2,
-                          Interrupt::PadMemCard];
+                          Interrupt::PadMemCard,
+                          Interrupt::Lightgun];
 
         let rem = supported.iter().fold(mask,
                                         |mask, &it| mask & !(1 << it as u16));
@@ -25,8 +25,10 @@ pub mod shared;
 pub mod padmemcard;
 pub mod debugger;
 pub mod assembler;
+pub mod disassembler;
 pub mod parallel_io;
 pub mod debug_uart;
+pub mod movie;
 
 mod interrupt;
 mod timekeeper;
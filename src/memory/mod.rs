@@ -1,4 +1,5 @@
 pub mod timers;
+pub mod timings;
 mod ram;
 mod dma;
 
@@ -8,7 +9,7 @@ use self::timers::Timers;
 
 use shared::SharedState;
 use bios::Bios;
-use timekeeper::Peripheral;
+use timekeeper::{Peripheral, Cycles};
 use gpu::Gpu;
 use gpu::renderer::Renderer;
 use spu::Spu;
@@ -145,126 +146,253 @@ impl Interconnect {
         &mut self.parallel_io
     }
 
-    /// Interconnect: load instruction at `PC`. Only the RAM and BIOS
-    /// are supported, would it make sense to fetch instructions from
-    /// anything else?
+    /// Interconnect: load instruction at `PC`. Only the RAM, BIOS and
+    /// expansion 1 are supported, would it make sense to fetch
+    /// instructions from anything else? Returns a `BusError` if
+    /// nothing answers at this address.
     pub fn load_instruction(&mut self,
                             shared: &mut SharedState,
-                            pc: u32) -> u32 {
+                            pc: u32) -> Result<u32, BusError> {
         let abs_addr = map::mask_region(pc);
 
         if let Some(offset) = map::RAM.contains(abs_addr) {
-            return self.ram.load::<Word>(offset);
+            return Ok(self.ram.load::<Word>(offset));
         }
 
         if let Some(offset) = map::BIOS.contains(abs_addr) {
-            return self.bios.load::<Word>(offset);
+            return Ok(self.bios.load::<Word>(offset));
         }
 
         if let Some(offset) = map::EXPANSION_1.contains(abs_addr) {
-            return self.parallel_io.load::<Word>(shared, offset);
+            return Ok(self.parallel_io.load::<Word>(shared, offset));
         }
 
-        panic!("unhandled instruction load at address {:08x}", pc);
+        warn!("Instruction bus error at 0x{:08x}", pc);
+
+        Err(BusError)
+    }
+
+    /// Return the instruction word at `pc` without any side effect
+    /// if it's in RAM or BIOS, `None` otherwise. Used to pre-decode
+    /// code. Misaligned addresses also return `None`, the CPU will
+    /// raise an address error when it attempts to run them.
+    pub fn peek_instruction(&self, pc: u32) -> Option<u32> {
+        if pc % 4 != 0 {
+            return None;
+        }
+
+        let abs_addr = map::mask_region(pc);
+
+        if let Some(offset) = map::RAM.contains(abs_addr) {
+            return Some(self.ram.load::<Word>(offset));
+        }
+
+        if let Some(offset) = map::BIOS.contains(abs_addr) {
+            return Some(self.bios.load::<Word>(offset));
+        }
+
+        None
+    }
+
+    /// Return the word at `addr` if it's in RAM, in the scratchpad or
+    /// in the BIOS, without any side effect. `addr` must be aligned.
+    pub fn peek_word(&self, addr: u32) -> Option<u32> {
+        let abs_addr = map::mask_region(addr);
+
+        if let Some(offset) = map::RAM.contains(abs_addr) {
+            return Some(self.ram.load::<Word>(offset));
+        }
+
+        if let Some(offset) = self.scratch_pad_offset(addr) {
+            return Some(self.scratch_pad.load::<Word>(offset));
+        }
+
+        if let Some(offset) = map::BIOS.contains(abs_addr) {
+            return Some(self.bios.load::<Word>(offset));
+        }
+
+        None
+    }
+
+    /// Return the number of cycles taken by an instruction fetch at
+    /// `pc`: the duration of the first access and of the subsequent
+    /// sequential accesses when filling a cacheline.
+    pub fn fetch_cycles(&self, pc: u32) -> (Cycles, Cycles) {
+        let abs_addr = map::mask_region(pc);
+
+        if map::RAM.contains(abs_addr).is_some() {
+            // XXX Fetch timings from mednafen, on my console it
+            // seems a bit faster than that
+            (timings::RAM_ACCESS_CYCLES, 1)
+        } else {
+            let cycles = self.device_access_cycles::<Word>(abs_addr, false);
+
+            (cycles, cycles)
+        }
+    }
+
+    /// Return the number of cycles taken by a bus access to `addr`,
+    /// `None` if the access doesn't go through the bus (scratchpad)
+    pub fn access_cycles<A: Addressable>(&self,
+                                         addr: u32,
+                                         write: bool) -> Option<Cycles> {
+        if self.scratch_pad_offset(addr).is_some() {
+            return None;
+        }
+
+        let abs_addr = map::mask_region(addr);
+
+        Some(self.device_access_cycles::<A>(abs_addr, write))
+    }
+
+    /// Return the number of cycles taken by an access to `abs_addr`
+    /// (which must be masked with `map::mask_region`)
+    fn device_access_cycles<A: Addressable>(&self,
+                                            abs_addr: u32,
+                                            write: bool) -> Cycles {
+        if map::RAM.contains(abs_addr).is_some() {
+            return timings::RAM_ACCESS_CYCLES;
+        }
+
+        // Index of the delay/size register in `mem_control`
+        let delay_size =
+            if map::EXPANSION_1.contains(abs_addr).is_some() {
+                2
+            } else if map::BIOS.contains(abs_addr).is_some() {
+                4
+            } else if map::SPU.contains(abs_addr).is_some() {
+                5
+            } else if map::CDROM.contains(abs_addr).is_some() {
+                6
+            } else if map::EXPANSION_2.contains(abs_addr).is_some() {
+                7
+            } else {
+                return timings::IO_ACCESS_CYCLES;
+            };
+
+        timings::device_access_cycles(self.mem_control[delay_size],
+                                      self.mem_control[8],
+                                      A::size(),
+                                      write)
+    }
+
+    /// Return the offset of `addr` in the scratchpad if it targets
+    /// the scratchpad. The scratchpad is the CPU's data cache, it's
+    /// only reachable through the cached regions (KUSEG and KSEG0)
+    /// and when it's enabled in the cache control register. Otherwise
+    /// the access goes through the bus where nothing is mapped at
+    /// this address. Since the scratchpad doesn't use tags it's not
+    /// affected by the cache isolation or tag test mode.
+    fn scratch_pad_offset(&self, addr: u32) -> Option<u32> {
+        if addr >= 0xa0000000 || !self.cache_control.scratch_pad_enabled() {
+            return None;
+        }
+
+        map::SCRATCH_PAD.contains(map::mask_region(addr))
+    }
+
+    /// Return true if `addr` targets the scratchpad
+    pub fn is_scratch_pad(&self, addr: u32) -> bool {
+        self.scratch_pad_offset(addr).is_some()
+    }
+
+    /// Return true if `addr` targets the RAM or the scratchpad. Those
+    /// accesses don't have any side effect on the rest of the system.
+    pub fn is_memory(&self, addr: u32) -> bool {
+        map::RAM.contains(map::mask_region(addr)).is_some() ||
+            self.is_scratch_pad(addr)
     }
 
     /// Interconnect: load value at `addr`
     pub fn load<A: Addressable>(&mut self,
                                 shared: &mut SharedState,
-                                addr: u32) -> u32 {
-        // XXX Since I don't implement CPU pipelining correctly for
-        // now I just pretend the memory is pretty fast. In reality it
-        // will depend on the device being accessed and then it could
-        // be pipelined in the CPU to reduce stalling.
-        shared.tk().tick(2);
-
+                                addr: u32) -> Result<u32, BusError> {
         let abs_addr = map::mask_region(addr);
 
         if let Some(offset) = map::RAM.contains(abs_addr) {
-            return self.ram.load::<A>(offset);
+            return Ok(self.ram.load::<A>(offset));
         }
 
-        if let Some(offset) = map::SCRATCH_PAD.contains(abs_addr) {
-            if addr > 0xa0000000 {
-                panic!("ScratchPad access through uncached memory");
-            }
-
-            return self.scratch_pad.load::<A>(offset);
+        if let Some(offset) = self.scratch_pad_offset(addr) {
+            return Ok(self.scratch_pad.load::<A>(offset));
         }
 
         if let Some(offset) = map::BIOS.contains(abs_addr) {
-            return self.bios.load::<A>(offset);
+            return Ok(self.bios.load::<A>(offset));
         }
 
         if let Some(offset) = map::IRQ_CONTROL.contains(abs_addr) {
-            return
-                match offset {
+            let v =
+                match offset & !3 {
                     0 => shared.irq_state().status() as u32,
-                    4 => shared.irq_state().mask() as u32,
-                    _ => panic!("Unhandled IRQ load at address {:08x}", addr),
+                    _ => shared.irq_state().mask() as u32,
                 };
+
+            return Ok(byte_lanes(v, offset));
         }
 
         if let Some(offset) = map::DMA.contains(abs_addr) {
-            return self.dma_reg::<A>(offset);
+            return Ok(self.dma_reg::<A>(offset));
         }
 
         if let Some(offset) = map::GPU.contains(abs_addr) {
-            return self.gpu.load::<A>(shared, offset);
+            return Ok(self.gpu.load::<A>(shared, offset));
         }
 
         if let Some(offset) = map::TIMERS.contains(abs_addr) {
-            return self.timers.load::<A>(shared, offset);
+            return Ok(self.timers.load::<A>(shared, offset));
         }
 
         if let Some(offset) = map::CDROM.contains(abs_addr) {
-            return self.cdrom.load::<A>(shared, offset);
+            return Ok(self.cdrom.load::<A>(shared, offset));
         }
 
         if let Some(offset) = map::MDEC.contains(abs_addr) {
-            return self.mdec.load::<A>(shared, offset);
+            return Ok(self.mdec.load::<A>(shared, offset));
         }
 
         if let Some(offset) = map::SPU.contains(abs_addr) {
-            return self.spu.load::<A>(offset);
+            return Ok(self.spu.load::<A>(offset));
         }
 
         if let Some(offset) = map::PAD_MEMCARD.contains(abs_addr) {
-            return self.pad_memcard.load::<A>(shared, offset);
+            return Ok(self.pad_memcard.load::<A>(shared, offset));
         }
 
         if let Some(offset) = map::EXPANSION_1.contains(abs_addr) {
-            return self.parallel_io.load::<A>(shared, offset);
+            return Ok(self.parallel_io.load::<A>(shared, offset));
         }
 
-        if let Some(_) = map::RAM_SIZE.contains(abs_addr) {
-            return self.ram_size;
+        if let Some(offset) = map::RAM_SIZE.contains(abs_addr) {
+            return Ok(byte_lanes(self.ram_size, offset));
         }
 
         if let Some(offset) = map::MEM_CONTROL.contains(abs_addr) {
 
-            if A::size() != 4 {
-                panic!("Unhandled MEM_CONTROL access ({})", A::size());
-            }
-
             let index = (offset >> 2) as usize;
 
-            return self.mem_control[index];
+            return Ok(byte_lanes(self.mem_control[index], offset));
         }
 
-        if let Some(_) = map::CACHE_CONTROL.contains(abs_addr) {
-            if A::size() != 4 {
-                panic!("Unhandled cache control access ({})", A::size());
-            }
-
-            return self.cache_control.0;
+        if let Some(offset) = map::CACHE_CONTROL.contains(abs_addr) {
+            return Ok(byte_lanes(self.cache_control.0, offset));
         }
 
         if let Some(offset) = map::EXPANSION_2.contains(abs_addr) {
-            return self.debug_uart.load::<A>(shared, offset);
+            return Ok(self.debug_uart.load::<A>(shared, offset));
         }
 
-        panic!("unhandled load at address {:08x}", addr);
+        if map::IO.contains(abs_addr).is_some() {
+            // Nothing answers within the I/O region (this includes
+            // the scratchpad when it's not mapped), we get whatever
+            // is floating on the bus
+            warn!("Load from unmapped I/O at 0x{:08x}", addr);
+            return Ok(open_bus::<A>());
+        }
+
+        warn!("Data bus error on load at 0x{:08x}", addr);
+
+        Err(BusError)
     }
 
     /// Interconnect: store `val` into `addr`
@@ -272,35 +400,35 @@ impl Interconnect {
                                  shared: &mut SharedState,
                                  renderer: &mut Renderer,
                                  addr: u32,
-                                 val: u32) {
+                                 val: u32) -> Result<(), BusError> {
 
         let abs_addr = map::mask_region(addr);
 
         if let Some(offset) = map::RAM.contains(abs_addr) {
             self.ram.store::<A>(offset, val);
-            return;
+            return Ok(());
         }
 
-        if let Some(offset) = map::SCRATCH_PAD.contains(abs_addr) {
-            if addr > 0xa0000000 {
-                panic!("ScratchPad access through uncached memory");
-            }
-
-            return self.scratch_pad.store::<A>(offset, val);
+        if let Some(offset) = self.scratch_pad_offset(addr) {
+            self.scratch_pad.store::<A>(offset, val);
+            return Ok(());
         }
 
         if let Some(offset) = map::IRQ_CONTROL.contains(abs_addr) {
             match offset {
                 0 => shared.irq_state_mut().ack(val as u16),
                 4 => shared.irq_state_mut().set_mask(val as u16),
-                _ => panic!("Unhandled IRQ store at address {:08x}"),
+                // XXX The registers are only 16bit wide, we ignore
+                // the writes targeting the upper bytes
+                _ => warn!("Unhandled IRQ store at 0x{:08x}: 0x{:08x}",
+                           addr, val),
             }
-            return;
+            return Ok(());
         }
 
         if let Some(offset) = map::DMA.contains(abs_addr) {
             self.set_dma_reg::<A>(shared, renderer, offset, val);
-            return;
+            return Ok(());
         }
 
         if let Some(offset) = map::GPU.contains(abs_addr) {
@@ -309,7 +437,7 @@ impl Interconnect {
                                 &mut self.timers,
                                 offset,
                                 val);
-            return;
+            return Ok(());
         }
 
         if let Some(offset) = map::TIMERS.contains(abs_addr) {
@@ -317,45 +445,52 @@ impl Interconnect {
                                    &mut self.gpu,
                                    offset,
                                    val);
-            return;
+            return Ok(());
         }
 
         if let Some(offset) = map::CDROM.contains(abs_addr) {
-            return self.cdrom.store::<A>(shared, offset, val);
+            self.cdrom.store::<A>(shared, offset, val);
+            return Ok(());
         }
 
         if let Some(offset) = map::MDEC.contains(abs_addr) {
-            return self.mdec.store::<A>(shared, offset, val);
+            self.mdec.store::<A>(shared, offset, val);
+            return Ok(());
         }
 
         if let Some(offset) = map::SPU.contains(abs_addr) {
             self.spu.store::<A>(offset, val);
-            return;
+            return Ok(());
         }
 
         if let Some(offset) = map::PAD_MEMCARD.contains(abs_addr) {
             self.pad_memcard.store::<A>(shared, offset, val);
-            return;
+            return Ok(());
         }
 
         if let Some(_) = map::CACHE_CONTROL.contains(abs_addr) {
             if A::size() != 4 {
-                panic!("Unhandled cache control access");
+                // XXX Not sure how the register behaves with partial
+                // writes, ignore them
+                warn!("Unhandled cache control store ({}): 0x{:08x}",
+                      A::size(), val);
+                return Ok(());
             }
 
             self.cache_control = CacheControl(val);
 
-            return;
+            return Ok(());
         }
 
         if let Some(offset) = map::MEM_CONTROL.contains(abs_addr) {
 
             if A::size() != 4 {
-                panic!("Unhandled MEM_CONTROL access ({})", A::size());
+                warn!("Unhandled MEM_CONTROL store ({}) at 0x{:08x}: \
+                       0x{:08x}",
+                      A::size(), addr, val);
+                return Ok(());
             }
 
-            let val = val;
-
             match offset {
                 0 => // Expansion 1 base address
                     if val != 0x1f000000 {
@@ -375,26 +510,36 @@ impl Interconnect {
 
             self.mem_control[index] = val;
 
-            return;
+            return Ok(());
         }
 
         if let Some(_) = map::RAM_SIZE.contains(abs_addr) {
 
             if A::size() != 4 {
-                panic!("Unhandled RAM_SIZE access");
+                warn!("Unhandled RAM_SIZE store ({}): 0x{:08x}",
+                      A::size(), val);
+                return Ok(());
             }
 
             self.ram_size = val;
-            return;
+            return Ok(());
         }
 
         if let Some(offset) = map::EXPANSION_2.contains(abs_addr) {
             self.debug_uart.store::<A>(shared, offset, val);
-            return;
+            return Ok(());
+        }
+
+        if map::IO.contains(abs_addr).is_some() {
+            // Nothing answers within the I/O region (this includes
+            // the scratchpad when it's not mapped), the write is lost
+            warn!("Store to unmapped I/O at 0x{:08x}: 0x{:08x}", addr, val);
+            return Ok(());
         }
 
-        panic!("unhandled store into address {:08x}: {:08x}",
-               addr, val);
+        warn!("Data bus error on store at 0x{:08x}: 0x{:08x}", addr, val);
+
+        Err(BusError)
     }
 
     /// DMA register read
@@ -663,6 +808,37 @@ impl CacheControl {
     pub fn tag_test_mode(self) -> bool {
         self.0 & 4 != 0
     }
+
+    /// Return true if the scratchpad is enabled, which requires both
+    /// bits 3 and 7 to be set
+    pub fn scratch_pad_enabled(self) -> bool {
+        self.0 & 0x88 == 0x88
+    }
+}
+
+/// Error returned when nothing answers an access on the bus. The CPU
+/// turns it into a bus error exception (IBE or DBE).
+#[derive(Clone, Copy, Debug)]
+pub struct BusError;
+
+/// Value returned by a read when no device drives the data bus.
+///
+/// XXX On the real hardware we'd get whatever was left floating on the
+/// bus by the previous transfer, it's not really predictable. The
+/// lines seem to be pulled up most of the time so we return all ones.
+pub fn open_bus<A: Addressable>() -> u32 {
+    match A::size() {
+        1 => 0xff,
+        2 => 0xffff,
+        _ => 0xffffffff,
+    }
+}
+
+/// Return the byte lanes of the 32bit register `reg` targeted by an
+/// access at `offset`, for the registers that don't care about the
+/// access width
+fn byte_lanes(reg: u32, offset: u32) -> u32 {
+    reg >> ((offset & 3) * 8)
 }
 
 /// Trait representing the attributes of a memory access
@@ -777,6 +953,11 @@ pub mod map {
     /// Expansion region 2
     pub const EXPANSION_2: Range = Range(0x1f802000, 66);
 
+    /// Region containing the scratchpad, the I/O registers and the
+    /// expansion 2. Accessing an unmapped address in there doesn't
+    /// trigger a bus error.
+    pub const IO: Range = Range(0x1f800000, 0x3000);
+
     /// Cache control register. Full address since it's in KSEG2
     pub const CACHE_CONTROL: Range = Range(0xfffe0130, 4);
 }
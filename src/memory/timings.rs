@@ -0,0 +1,266 @@
+//! Bus access timings.
+//!
+//! The CPU stalls on loads and instruction fetches until the data
+//! comes back from the bus. RAM and the internal registers have fixed
+//! timings while the BIOS, SPU, CDROM and expansion regions are
+//! configured through the MEM_CONTROL "delay/size" registers.
+//!
+//! Stores don't stall the CPU directly: they're pushed into a 4-entry
+//! write queue which is drained in the background. The CPU only
+//! stalls when it attempts to store while the queue is full or when
+//! it needs to read from the bus while writes are still pending.
+
+use shared::SharedState;
+use timekeeper::Cycles;
+
+/// Number of cycles taken by a RAM access
+pub const RAM_ACCESS_CYCLES: Cycles = 4;
+
+/// Number of cycles taken by an access to the internal registers (DMA,
+/// GPU, timers, IRQ control...)
+pub const IO_ACCESS_CYCLES: Cycles = 2;
+
+/// Number of cycles to complete an access of `size` bytes to a device
+/// configured with the MEM_CONTROL `delay_size` register and the
+/// `com_delay` common delay register. Formula from No$.
+pub fn device_access_cycles(delay_size: u32,
+                            com_delay: u32,
+                            size: u8,
+                            write: bool) -> Cycles {
+    // Bits [3:0] are the write delay, [7:4] the read delay
+    let access =
+        if write {
+            (delay_size & 0xf) as Cycles
+        } else {
+            ((delay_size >> 4) & 0xf) as Cycles
+        };
+
+    let com0 = (com_delay & 0xf) as Cycles;
+    let com2 = ((com_delay >> 8) & 0xf) as Cycles;
+    let com3 = ((com_delay >> 12) & 0xf) as Cycles;
+
+    let use_com0 = delay_size & (1 << 8) != 0;
+    let use_com2 = delay_size & (1 << 10) != 0;
+    let use_com3 = delay_size & (1 << 11) != 0;
+    let bus_16bits = delay_size & (1 << 12) != 0;
+
+    // Duration of the first access and of the subsequent sequential
+    // accesses
+    let mut first = 0;
+    let mut seq = 0;
+    let mut min = 0;
+
+    if use_com0 {
+        first += com0.saturating_sub(1);
+        seq += com0.saturating_sub(1);
+    }
+
+    if use_com2 {
+        first += com2;
+        seq += com2;
+    }
+
+    if use_com3 {
+        min = com3;
+    }
+
+    if first < 6 {
+        first += 1;
+    }
+
+    first = (first + access + 2).max(min + 6);
+    seq = (seq + access + 2).max(min + 2);
+
+    // Wide accesses are split into multiple bus cycles
+    match (size, bus_16bits) {
+        (1, _) => first,
+        (2, true) => first,
+        (2, false) => first + seq,
+        (_, true) => first + seq,
+        (_, false) => first + 3 * seq,
+    }
+}
+
+/// Size of the CPU write queue
+const WRITE_QUEUE_LEN: usize = 4;
+
+/// CPU write queue
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct WriteQueue {
+    /// Completion date of the pending writes, in order
+    pending: [Cycles; WRITE_QUEUE_LEN],
+    /// Number of entries in `pending`
+    len: usize,
+}
+
+impl WriteQueue {
+    pub fn new() -> WriteQueue {
+        WriteQueue {
+            pending: [0; WRITE_QUEUE_LEN],
+            len: 0,
+        }
+    }
+
+    /// Queue a write taking `cycles` to complete. Stalls the CPU if
+    /// the queue is full.
+    pub fn push(&mut self, shared: &mut SharedState, cycles: Cycles) {
+        let mut now = shared.tk().now();
+
+        self.retire(now);
+
+        if self.len == WRITE_QUEUE_LEN {
+            // Wait for the oldest write to complete
+            let wait = self.pending[0] - now;
+
+            shared.tk().tick(wait);
+            now += wait;
+
+            self.retire(now);
+        }
+
+        // Writes are performed one after the other
+        let start =
+            match self.len {
+                0 => now,
+                n => self.pending[n - 1].max(now),
+            };
+
+        self.pending[self.len] = start + cycles;
+        self.len += 1;
+    }
+
+    /// Stall the CPU until all the pending writes are done
+    pub fn flush(&mut self, shared: &mut SharedState) {
+        if self.len == 0 {
+            return;
+        }
+
+        let now = shared.tk().now();
+        let done = self.pending[self.len - 1];
+
+        if done > now {
+            shared.tk().tick(done - now);
+        }
+
+        self.len = 0;
+    }
+
+    /// Remove the writes completed at date `now`
+    fn retire(&mut self, now: Cycles) {
+        let done =
+            self.pending[..self.len].iter().take_while(|&&d| d <= now).count();
+
+        for i in done..self.len {
+            self.pending[i - done] = self.pending[i];
+        }
+
+        self.len -= done;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::SharedState;
+
+    use super::{device_access_cycles, WriteQueue};
+
+    /// Value of the COM_DELAY register set by the BIOS
+    const COM_DELAY: u32 = 0x00031125;
+
+    #[test]
+    fn bios_timings() {
+        // 8bit bus, read delay 3, uses COM2
+        let delay_size = 0x0013243f;
+
+        let read =
+            |size| device_access_cycles(delay_size, COM_DELAY, size, false);
+        let write =
+            |size| device_access_cycles(delay_size, COM_DELAY, size, true);
+
+        assert_eq!(read(1), 7);
+        assert_eq!(read(2), 13);
+        assert_eq!(read(4), 25);
+
+        assert_eq!(write(1), 19);
+        assert_eq!(write(2), 37);
+        assert_eq!(write(4), 73);
+    }
+
+    #[test]
+    fn spu_timings() {
+        // 16bit bus, read delay 14, write delay 1, uses COM0
+        let delay_size = 0x200931e1;
+
+        let read =
+            |size| device_access_cycles(delay_size, COM_DELAY, size, false);
+        let write =
+            |size| device_access_cycles(delay_size, COM_DELAY, size, true);
+
+        assert_eq!(read(1), 21);
+        assert_eq!(read(2), 21);
+        assert_eq!(read(4), 41);
+
+        assert_eq!(write(1), 8);
+        assert_eq!(write(2), 8);
+        assert_eq!(write(4), 15);
+    }
+
+    #[test]
+    fn write_queue_full() {
+        let mut shared = SharedState::new();
+        let mut queue = WriteQueue::new();
+
+        // The writes are performed one after the other and complete
+        // at dates 10, 20, 30 and 40
+        for _ in 0..4 {
+            queue.push(&mut shared, 10);
+        }
+
+        assert_eq!(shared.tk().now(), 0);
+
+        // The queue is full, we have to wait for the first write to
+        // complete
+        queue.push(&mut shared, 10);
+
+        assert_eq!(shared.tk().now(), 10);
+
+        // One write completed in the meantime, there's room left
+        shared.tk().tick(15);
+        queue.push(&mut shared, 10);
+
+        assert_eq!(shared.tk().now(), 25);
+
+        // All the writes are done at date 60
+        queue.flush(&mut shared);
+
+        assert_eq!(shared.tk().now(), 60);
+    }
+
+    #[test]
+    fn write_queue_flush() {
+        let mut shared = SharedState::new();
+        let mut queue = WriteQueue::new();
+
+        // Flushing an empty queue doesn't stall
+        queue.flush(&mut shared);
+
+        assert_eq!(shared.tk().now(), 0);
+
+        queue.push(&mut shared, 4);
+        queue.push(&mut shared, 4);
+
+        shared.tk().tick(3);
+
+        // A read must wait for both writes to complete
+        queue.flush(&mut shared);
+
+        assert_eq!(shared.tk().now(), 8);
+
+        // Writes completed in the past don't stall
+        queue.push(&mut shared, 4);
+        shared.tk().tick(10);
+        queue.flush(&mut shared);
+
+        assert_eq!(shared.tk().now(), 18);
+    }
+}
@@ -0,0 +1,491 @@
+//! Deterministic input recording and playback.
+//!
+//! A `Movie` stores every input change sent to the two controller
+//! ports (as well as disc swaps) along with the emulated frame
+//! (`Counters::frame`) at which it occured. Since the emulator is
+//! deterministic replaying those inputs from the same starting point
+//! must reproduce the exact same emulation, which makes movies a
+//! convenient way to share bug reports.
+//!
+//! The frontend is in charge of the actual file and disc image I/O:
+//! `Movie` implements `Encodable` and `Decodable` like the rest of the
+//! emulator state. If the movie doesn't start from power-on it embeds
+//! a savestate which is opaque to this module, the frontend is
+//! expected to serialize the console state when it starts recording
+//! and to load it back before starting playback.
+//!
+//! Both the `Recorder` and the `Player` expect the frontend to call
+//! `start_frame` once per emulated frame, before feeding the inputs
+//! for that frame.
+
+use bios::db::Metadata;
+use cdrom::disc::SerialNumber;
+use padmemcard::gamepad::{GamePad, Button, ButtonState, Axis};
+use shared::SharedState;
+
+/// Number of controller ports recorded in movies
+pub const PORTS: usize = 2;
+
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+pub struct Movie {
+    /// SHA-256 of the BIOS used to record the movie
+    bios_sha256: [u8; 32],
+    /// Serial number of the disc inserted at the beginning of the
+    /// movie, if any
+    disc: Option<SerialNumber>,
+    /// Starting point of the movie
+    start: Start,
+    /// Recorded frames, in chronological order. Frames without any
+    /// input change are omitted.
+    frames: Vec<Frame>,
+    /// Value of `Counters::frame` when the recording started
+    first_frame: u32,
+    /// Value of `Counters::frame` for the last frame recorded
+    last_frame: u32,
+}
+
+impl Movie {
+    /// Return the SHA-256 of the BIOS used to record the movie
+    pub fn bios_sha256(&self) -> &[u8; 32] {
+        &self.bios_sha256
+    }
+
+    /// Return the serial number of the disc inserted at the beginning
+    /// of the movie
+    pub fn disc(&self) -> Option<SerialNumber> {
+        self.disc
+    }
+
+    pub fn start(&self) -> &Start {
+        &self.start
+    }
+
+    /// Return the number of frames in the movie
+    pub fn len(&self) -> u32 {
+        self.last_frame.wrapping_sub(self.first_frame)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Check that the movie was recorded with the BIOS `bios` and the
+    /// disc `disc`
+    pub fn check_environment(&self,
+                             bios: &Metadata,
+                             disc: Option<SerialNumber>) -> Result<(), Error> {
+        if bios.sha256 != self.bios_sha256 {
+            return Err(Error::BiosMismatch(self.bios_sha256));
+        }
+
+        if disc != self.disc {
+            return Err(Error::DiscMismatch(self.disc));
+        }
+
+        Ok(())
+    }
+}
+
+/// Starting point of a movie
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+pub enum Start {
+    /// The movie starts when the console is switched on
+    PowerOn,
+    /// The movie starts from a savestate. The contents are encoded by
+    /// the frontend.
+    Savestate(Vec<u8>),
+}
+
+/// Inputs for a single emulated frame
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+struct Frame {
+    /// Value of `Counters::frame` for this frame
+    frame: u32,
+    /// Value of `Counters::cpu_interrupt` at the beginning of the
+    /// frame, used to detect desyncs
+    cpu_interrupt: u32,
+    /// Input events, in the order they were received
+    events: Vec<Event>,
+}
+
+/// Recorded input event
+#[derive(Clone, Copy, RustcDecodable, RustcEncodable)]
+pub enum Event {
+    /// Change of button state on a controller port
+    Button(u8, Button, ButtonState),
+    /// Change of analog axis position on a controller port
+    Axis(u8, Axis, u8),
+    /// Disc swap. `None` means that the disc was ejected.
+    DiscSwap(Option<SerialNumber>),
+}
+
+/// Last input state sent on a controller port, used to only record
+/// changes since the frontends usually refresh the full controller
+/// state every frame.
+#[derive(Clone, Copy)]
+struct PortState {
+    /// One bit per `Button`, set when pressed
+    buttons: u32,
+    /// Axis positions, 0x80 is centered
+    axes: [u8; 4],
+}
+
+impl PortState {
+    fn new() -> PortState {
+        PortState {
+            buttons: 0,
+            axes: [0x80; 4],
+        }
+    }
+
+    /// Update the state with `button`. Returns true if it changed.
+    fn set_button_state(&mut self, button: Button, state: ButtonState) -> bool {
+        let mask = 1 << (button as u32);
+
+        let buttons =
+            match state {
+                ButtonState::Pressed => self.buttons | mask,
+                ButtonState::Released => self.buttons & !mask,
+            };
+
+        let changed = buttons != self.buttons;
+
+        self.buttons = buttons;
+
+        changed
+    }
+
+    /// Update the state with `axis`. Returns true if it changed.
+    fn set_axis_state(&mut self, axis: Axis, value: u8) -> bool {
+        let changed = self.axes[axis as usize] != value;
+
+        self.axes[axis as usize] = value;
+
+        changed
+    }
+}
+
+/// Movie recorder. All the inputs sent to the controllers must go
+/// through the recorder.
+pub struct Recorder {
+    movie: Movie,
+    ports: [PortState; PORTS],
+}
+
+impl Recorder {
+    /// Start a new recording. `bios` is the BIOS being used, `disc`
+    /// the serial number of the disc currently inserted and `start`
+    /// the starting point of the movie.
+    ///
+    /// The controllers are assumed to be in their default state (all
+    /// buttons released and sticks centered) when the recording
+    /// starts.
+    pub fn new(bios: &Metadata,
+               disc: Option<SerialNumber>,
+               start: Start,
+               shared: &SharedState) -> Recorder {
+        Recorder {
+            movie: Movie {
+                bios_sha256: bios.sha256,
+                disc: disc,
+                start: start,
+                frames: Vec::new(),
+                first_frame: shared.counters().frame.get(),
+                last_frame: shared.counters().frame.get(),
+            },
+            ports: [PortState::new(); PORTS],
+        }
+    }
+
+    /// Must be called by the frontend at the beginning of every
+    /// emulated frame, before any input is sent
+    pub fn start_frame(&mut self, shared: &SharedState) {
+        let counters = shared.counters();
+
+        self.movie.last_frame = counters.frame.get();
+
+        self.movie.frames.push(Frame {
+            frame: counters.frame.get(),
+            cpu_interrupt: counters.cpu_interrupt.get(),
+            events: Vec::new(),
+        });
+    }
+
+    /// Set the state of `button` on the gamepad plugged in `port` (0
+    /// or 1) and record the change
+    pub fn set_button_state(&mut self,
+                            mut pads: [&mut GamePad; PORTS],
+                            port: usize,
+                            button: Button,
+                            state: ButtonState) {
+        if self.ports[port].set_button_state(button, state) {
+            self.record(Event::Button(port as u8, button, state));
+        }
+
+        pads[port].profile_mut().set_button_state(button, state);
+    }
+
+    /// Set the position of `axis` on the gamepad plugged in `port` (0
+    /// or 1) and record the change
+    pub fn set_axis_state(&mut self,
+                          mut pads: [&mut GamePad; PORTS],
+                          port: usize,
+                          axis: Axis,
+                          value: u8) {
+        if self.ports[port].set_axis_state(axis, value) {
+            self.record(Event::Axis(port as u8, axis, value));
+        }
+
+        pads[port].profile_mut().set_axis_state(axis, value);
+    }
+
+    /// Record a disc swap. The frontend is in charge of actually
+    /// changing the disc.
+    pub fn disc_swap(&mut self, disc: Option<SerialNumber>) {
+        self.record(Event::DiscSwap(disc));
+    }
+
+    /// Stop the recording and return the movie
+    pub fn finish(mut self) -> Movie {
+        // Remove the frames without any input change
+        self.movie.frames.retain(|f| !f.events.is_empty());
+
+        self.movie
+    }
+
+    fn record(&mut self, event: Event) {
+        match self.movie.frames.last_mut() {
+            Some(f) => f.events.push(event),
+            None => panic!("Movie event recorded before start_frame"),
+        }
+    }
+}
+
+/// Movie player
+pub struct Player {
+    movie: Movie,
+    /// Position of the next frame to be played in `movie.frames`
+    pos: usize,
+    /// First frame where a desync was detected, if any
+    desync: Option<u32>,
+}
+
+impl Player {
+    /// Start playing back `movie`. The frontend is expected to reset
+    /// the console (or load the movie's savestate) and insert the
+    /// right disc before starting the playback.
+    pub fn new(movie: Movie) -> Player {
+        Player {
+            movie: movie,
+            pos: 0,
+            desync: None,
+        }
+    }
+
+    /// Must be called by the frontend at the beginning of every
+    /// emulated frame. Sends the inputs recorded for this frame to
+    /// the controllers. Returns the disc swaps that have to be
+    /// performed by the frontend, if any.
+    pub fn start_frame(&mut self,
+                       shared: &SharedState,
+                       mut pads: [&mut GamePad; PORTS]) -> Vec<Option<SerialNumber>> {
+        let mut swaps = Vec::new();
+
+        let counters = shared.counters();
+        let frame = counters.frame.get();
+
+        let f =
+            match self.movie.frames.get(self.pos) {
+                Some(f) => f,
+                None => return swaps,
+            };
+
+        // Frames are stored in chronological order, if we missed one
+        // the emulation diverged
+        if (f.frame.wrapping_sub(frame) as i32) < 0 {
+            self.flag_desync(f.frame);
+            self.pos += 1;
+            return swaps;
+        }
+
+        if f.frame != frame {
+            // Nothing to do for this frame
+            return swaps;
+        }
+
+        let desync = f.cpu_interrupt != counters.cpu_interrupt.get();
+
+        for &event in &f.events {
+            match event {
+                Event::Button(port, button, state) =>
+                    pads[port as usize].profile_mut().set_button_state(button, state),
+                Event::Axis(port, axis, value) =>
+                    pads[port as usize].profile_mut().set_axis_state(axis, value),
+                Event::DiscSwap(disc) => swaps.push(disc),
+            }
+        }
+
+        if desync {
+            self.flag_desync(frame);
+        }
+
+        self.pos += 1;
+
+        swaps
+    }
+
+    /// Return true if all the recorded frames have been played back
+    pub fn is_finished(&self) -> bool {
+        self.pos >= self.movie.frames.len()
+    }
+
+    /// Return the first frame at which the playback diverged from
+    /// the recording, if any
+    pub fn desync(&self) -> Option<u32> {
+        self.desync
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    fn flag_desync(&mut self, frame: u32) {
+        if self.desync.is_none() {
+            warn!("Movie desync at frame {}", frame);
+            self.desync = Some(frame);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The movie was recorded with a different BIOS, contains the
+    /// expected SHA-256
+    BiosMismatch([u8; 32]),
+    /// The movie was recorded with a different disc, contains the
+    /// expected serial number
+    DiscMismatch(Option<SerialNumber>),
+}
+
+#[cfg(test)]
+mod tests {
+    use bios::db::DATABASE;
+    use cdrom::disc::SerialNumber;
+    use padmemcard::gamepad::{GamePad, DigitalProfile, Button, ButtonState};
+    use shared::SharedState;
+
+    use super::{Recorder, Player, Start, Error};
+
+    fn digital_pad() -> GamePad {
+        let mut pad = GamePad::disconnected();
+
+        pad.set_profile(Box::new(DigitalProfile::new()));
+
+        pad
+    }
+
+    /// Poll the button state word of a digital `pad`. Buttons are
+    /// active low.
+    fn buttons(pad: &mut GamePad) -> u16 {
+        pad.select();
+
+        pad.send_command(0x01);
+        pad.send_command(0x42);
+        pad.send_command(0x00);
+
+        let lo = pad.send_command(0x00).0 as u16;
+        let hi = pad.send_command(0x00).0 as u16;
+
+        lo | (hi << 8)
+    }
+
+    #[test]
+    fn record_replay() {
+        let cross = 1 << (Button::Cross as u16);
+
+        let mut shared = SharedState::new();
+        let mut pad0 = digital_pad();
+        let mut pad1 = digital_pad();
+
+        let mut recorder = Recorder::new(&DATABASE[0],
+                                         Some(SerialNumber::dummy()),
+                                         Start::PowerOn,
+                                         &shared);
+
+        for frame in 0..10 {
+            recorder.start_frame(&shared);
+
+            match frame {
+                // Pressing twice only records one event
+                2 | 3 =>
+                    recorder.set_button_state([&mut pad0, &mut pad1],
+                                              1,
+                                              Button::Cross,
+                                              ButtonState::Pressed),
+                5 =>
+                    recorder.set_button_state([&mut pad0, &mut pad1],
+                                              1,
+                                              Button::Cross,
+                                              ButtonState::Released),
+                7 => recorder.disc_swap(None),
+                _ => (),
+            }
+
+            shared.counters_mut().frame.increment();
+        }
+
+        let movie = recorder.finish();
+
+        assert_eq!(movie.len(), 9);
+
+        let mut shared = SharedState::new();
+        let mut pad0 = digital_pad();
+        let mut pad1 = digital_pad();
+
+        let mut player = Player::new(movie);
+
+        for frame in 0..10 {
+            let swaps = player.start_frame(&shared, [&mut pad0, &mut pad1]);
+
+            let pressed = frame >= 2 && frame < 5;
+
+            assert_eq!(buttons(&mut pad0), 0xffff);
+            assert_eq!(buttons(&mut pad1) & cross == 0, pressed);
+
+            if frame == 7 {
+                assert_eq!(swaps, vec![None]);
+            } else {
+                assert!(swaps.is_empty());
+            }
+
+            shared.counters_mut().frame.increment();
+        }
+
+        assert!(player.is_finished());
+        assert_eq!(player.desync(), None);
+    }
+
+    #[test]
+    fn environment_mismatch() {
+        let shared = SharedState::new();
+        let serial = SerialNumber::dummy();
+
+        let movie = Recorder::new(&DATABASE[0],
+                                  Some(serial),
+                                  Start::PowerOn,
+                                  &shared).finish();
+
+        assert!(movie.check_environment(&DATABASE[0], Some(serial)).is_ok());
+
+        match movie.check_environment(&DATABASE[1], Some(serial)) {
+            Err(Error::BiosMismatch(sha)) =>
+                assert_eq!(sha, DATABASE[0].sha256),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        match movie.check_environment(&DATABASE[0], None) {
+            Err(Error::DiscMismatch(disc)) => assert_eq!(disc, Some(serial)),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+}
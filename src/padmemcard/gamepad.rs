@@ -1,7 +1,13 @@
 use rustc_serialize::{Decodable, Encodable, Decoder, Encoder};
 
+use gpu::DisplayInfo;
+
+use super::mouse::MouseProfile;
+use super::negcon::NeGconProfile;
+use super::lightgun::{GunConProfile, JustifierProfile};
+
 pub struct GamePad {
-    /// Gamepad profile. *Not* stored in the savestate.
+    /// Gamepad profile. Serialized through `ProfileState`.
     profile: Box<Profile>,
     /// Counter keeping track of the current position in the reply
     /// sequence
@@ -48,6 +54,11 @@ impl GamePad {
         (resp, dsr)
     }
 
+    /// Return a reference to the underlying gamepad Profile
+    pub fn profile(&self) -> &Profile {
+        &*self.profile
+    }
+
     /// Return a mutable reference to the underlying gamepad Profile
     pub fn profile_mut(&mut self) -> &mut Profile {
         &mut *self.profile
@@ -61,15 +72,15 @@ impl GamePad {
 impl Encodable for GamePad {
     fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
 
-        // We don't store the Profile in the serialized data, we'll
-        // let the frontend reset it
-        s.emit_struct("GamePad", 2, |s| {
+        s.emit_struct("GamePad", 3, |s| {
 
             try!(s.emit_struct_field("seq", 0,
                                      |s| self.seq.encode(s)));
             try!(s.emit_struct_field("active", 1,
                                      |s| self.active.encode(s)));
-            
+            try!(s.emit_struct_field("profile", 2,
+                                     |s| self.profile.state().encode(s)));
+
             Ok(())
         })
     }
@@ -78,7 +89,7 @@ impl Encodable for GamePad {
 impl Decodable for GamePad {
     fn decode<D: Decoder>(d: &mut D) -> Result<GamePad, D::Error> {
 
-        d.read_struct("GamePad", 2, |d| {
+        d.read_struct("GamePad", 3, |d| {
             let mut pad = GamePad::disconnected();
 
             pad.seq =
@@ -87,6 +98,11 @@ impl Decodable for GamePad {
             pad.active =
                 try!(d.read_struct_field("active", 1, Decodable::decode));
 
+            let profile: ProfileState =
+                try!(d.read_struct_field("profile", 2, Decodable::decode));
+
+            pad.profile = profile.into_profile();
+
             Ok(pad)
         })
     }
@@ -95,7 +111,7 @@ impl Decodable for GamePad {
 /// Digital buttons on a PlayStation controller. The value assigned to
 /// each button is the bit position in the 16bit word returned in the
 /// serial protocol
-#[derive(Clone,Copy,Debug)]
+#[derive(Clone,Copy,Debug,RustcDecodable,RustcEncodable)]
 pub enum Button {
     Select = 0,
     Start = 3,
@@ -111,9 +127,13 @@ pub enum Button {
     Circle = 13,
     Cross = 14,
     Square = 15,
+    /// "Analog" mode button found on analog controllers. It's not
+    /// reported in the button state word, it toggles the analog mode
+    /// instead.
+    Analog = 16,
 }
 
-#[derive(Clone,Copy,Debug)]
+#[derive(Clone,Copy,Debug,RustcDecodable,RustcEncodable)]
 pub enum ButtonState {
     Pressed,
     Released,
@@ -135,6 +155,58 @@ pub trait Profile {
     /// in a row with the same button and the same state, it should be
     /// idempotent.
     fn set_button_state(&mut self, button: Button, state: ButtonState);
+
+    /// Return a serializable snapshot of the profile's type and
+    /// internal state, used to store the profile in savestates.
+    ///
+    /// `ProfileState` can only describe the controllers emulated by
+    /// this crate, by default the profile is saved as an empty slot.
+    fn state(&self) -> ProfileState {
+        ProfileState::Disconnected
+    }
+
+    /// Set the position of an analog stick axis. 0x00 is left/up,
+    /// 0xff is right/down and 0x80 is centered. Profiles without
+    /// analog sticks ignore it.
+    fn set_axis_state(&mut self, _axis: Axis, _value: u8) {
+    }
+
+    /// Set the pressure applied to a button, from 0x00 (released) to
+    /// 0xff (fully pressed). Profiles without pressure sensitive
+    /// buttons treat any non-zero pressure as a press.
+    fn set_button_pressure(&mut self, button: Button, pressure: u8) {
+        let state =
+            if pressure > 0 {
+                ButtonState::Pressed
+            } else {
+                ButtonState::Released
+            };
+
+        self.set_button_state(button, state);
+    }
+
+    /// Report a relative motion of a pointing device like the
+    /// mouse. Ignored by other controllers.
+    fn add_relative_motion(&mut self, _dx: i32, _dy: i32) {
+    }
+
+    /// Set the point a lightgun is aimed at in screen coordinates
+    /// (pixels relative to the top-left of the displayed image, see
+    /// `DisplayInfo::vram_to_screen`). `None` means that the gun
+    /// points away from the screen. Ignored by other controllers.
+    fn set_aim(&mut self, _aim: Option<(i32, i32)>) {
+    }
+
+    /// Called by the controller interface at the beginning of every
+    /// transaction with the current display configuration
+    fn set_display_info(&mut self, _info: &DisplayInfo) {
+    }
+
+    /// Beam position `(dotclock, line)` at which the controller
+    /// wants the lightgun interrupt (IRQ10) to be triggered, if any
+    fn lightgun_target(&self) -> Option<(u16, u16)> {
+        None
+    }
 }
 
 /// Dummy profile emulating an empty pad slot
@@ -150,9 +222,46 @@ impl Profile for DisconnectedProfile {
     }
 }
 
+/// Serializable state of a `Profile`: the variant identifies the kind
+/// of controller, the payload holds its internal state.
+///
+/// Frontend callbacks (such as the DualShock rumble callback) can't be
+/// serialized, they have to be registered again after a savestate is
+/// loaded.
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+pub enum ProfileState {
+    Disconnected,
+    Digital(DigitalProfile),
+    DualShock(DualShockState),
+    NeGcon(NeGconProfile),
+    Mouse(MouseProfile),
+    GunCon(GunConProfile),
+    Justifier(JustifierProfile),
+}
+
+impl ProfileState {
+    /// Rebuild the profile described by this state
+    pub fn into_profile(self) -> Box<Profile> {
+        match self {
+            ProfileState::Disconnected => Box::new(DisconnectedProfile),
+            ProfileState::Digital(p) => Box::new(p),
+            ProfileState::DualShock(state) =>
+                Box::new(DualShockProfile {
+                    state: state,
+                    rumble_callback: None,
+                }),
+            ProfileState::NeGcon(p) => Box::new(p),
+            ProfileState::Mouse(p) => Box::new(p),
+            ProfileState::GunCon(p) => Box::new(p),
+            ProfileState::Justifier(p) => Box::new(p),
+        }
+    }
+}
+
 /// SCPH-1080: Digital gamepad.
 /// Full state is only two bytes since we only need one bit per
 /// button.
+#[derive(Clone, RustcDecodable, RustcEncodable)]
 pub struct DigitalProfile(u16);
 
 impl DigitalProfile {
@@ -186,6 +295,11 @@ impl Profile for DigitalProfile {
     }
 
     fn set_button_state(&mut self, button: Button, state: ButtonState) {
+        if let Button::Analog = button {
+            // No analog button on this controller
+            return;
+        }
+
         let s = self.0;
 
         let mask = 1 << (button as usize);
@@ -196,4 +310,580 @@ impl Profile for DigitalProfile {
                 ButtonState::Released => s | mask,
             };
     }
+
+    fn state(&self) -> ProfileState {
+        ProfileState::Digital(self.clone())
+    }
+}
+
+/// Analog stick axes
+#[derive(Clone,Copy,Debug,RustcDecodable,RustcEncodable)]
+pub enum Axis {
+    RightX = 0,
+    RightY = 1,
+    LeftX = 2,
+    LeftY = 3,
+}
+
+/// Callback used to notify the frontend when the state of the rumble
+/// motors changes. The first parameter is the state of the small
+/// motor (on/off), the second the speed of the large one.
+pub type RumbleCallback = Box<FnMut(bool, u8)>;
+
+/// SCPH-1200: DualShock analog controller. Starts in digital mode
+/// like the real thing, the analog mode can be toggled with the
+/// "analog" button or by the game through the config mode.
+pub struct DualShockProfile {
+    /// Controller state, stored in savestates
+    state: DualShockState,
+    /// Frontend rumble callback. *Not* stored in the savestate.
+    rumble_callback: Option<RumbleCallback>,
+}
+
+/// Serializable part of the `DualShockProfile`
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+pub struct DualShockState {
+    /// Button state, same format as `DigitalProfile`
+    buttons: u16,
+    /// Analog stick positions in the order they're sent on the bus
+    /// (see `Axis`), 0x80 is centered
+    axes: [u8; 4],
+    /// True if the controller is in analog mode (LED on)
+    analog: bool,
+    /// If true the analog button is ignored, only the game can change
+    /// the mode
+    locked: bool,
+    /// State of the analog button, used to detect presses
+    analog_pressed: bool,
+    /// True if we're in config mode
+    config: bool,
+    /// Config mode state requested by the current 0x43 command, it's
+    /// only applied once the command completes
+    next_config: bool,
+    /// Command being processed
+    command: u8,
+    /// Response payload (following the 0x5a byte) for the current
+    /// command
+    response: [u8; 6],
+    /// Number of valid bytes in `response`
+    response_len: u8,
+    /// Rumble configuration set by command 0x4d: for each byte of
+    /// the 0x42 command payload 0x00 means "small motor", 0x01 means
+    /// "large motor" and 0xff means "unused"
+    rumble_map: [u8; 6],
+    /// Small motor state being received
+    small_motor: bool,
+    /// Large motor speed being received
+    large_motor: u8,
+    /// Last motor state sent to the frontend
+    rumble_state: (bool, u8),
+}
+
+impl DualShockProfile {
+    pub fn new() -> DualShockProfile {
+        DualShockProfile {
+            state: DualShockState {
+                buttons: 0xffff,
+                axes: [0x80; 4],
+                analog: false,
+                locked: false,
+                analog_pressed: false,
+                config: false,
+                next_config: false,
+                command: 0,
+                response: [0; 6],
+                response_len: 0,
+                rumble_map: [0xff; 6],
+                small_motor: false,
+                large_motor: 0,
+                rumble_state: (false, 0),
+            },
+            rumble_callback: None,
+        }
+    }
+
+    /// Return the state of the analog mode LED
+    pub fn led(&self) -> bool {
+        self.state.analog
+    }
+
+    /// Register the callback notified when the rumble motors change
+    /// state
+    pub fn set_rumble_callback(&mut self, callback: RumbleCallback) {
+        self.rumble_callback = Some(callback);
+    }
+
+    /// Controller ID returned in the 2nd reply byte
+    fn id(&self) -> u8 {
+        if self.state.config {
+            0xf3
+        } else if self.state.analog {
+            0x73
+        } else {
+            0x41
+        }
+    }
+
+    /// Prepare the response for command `cmd`. Returns false if the
+    /// command is not supported in the current mode.
+    fn start_command(&mut self, cmd: u8) -> bool {
+        self.state.command = cmd;
+        self.state.response = [0; 6];
+
+        if !self.state.config {
+            // Only "read buttons" and "enter config" are available
+            // outside of config mode, they both return the pad state.
+            if cmd != 0x42 && cmd != 0x43 {
+                return false;
+            }
+
+            self.state.next_config = false;
+            self.state.small_motor = false;
+            self.state.large_motor = 0;
+
+            self.state.response[0] = self.state.buttons as u8;
+            self.state.response[1] = (self.state.buttons >> 8) as u8;
+
+            self.state.response_len =
+                if self.state.analog {
+                    self.state.response[2..6].copy_from_slice(&self.state.axes);
+                    6
+                } else {
+                    2
+                };
+
+            return true;
+        }
+
+        // In config mode all replies are 6 bytes long
+        self.state.response_len = 6;
+
+        match cmd {
+            0x42 => {
+                // Still works in config mode, always in analog format
+                self.state.small_motor = false;
+                self.state.large_motor = 0;
+                self.state.response[0] = self.state.buttons as u8;
+                self.state.response[1] = (self.state.buttons >> 8) as u8;
+                self.state.response[2..6].copy_from_slice(&self.state.axes);
+            }
+            // Exit config mode (or stay if the payload is 0x01)
+            0x43 => self.state.next_config = true,
+            // Set analog mode, no reply
+            0x44 => (),
+            // Get status
+            0x45 => {
+                // Controller type: DualShock (SCPH-1200). The
+                // DualShock 2 replies 0x03 here.
+                self.state.response[0] = 0x01;
+                self.state.response[1] = 0x02;
+                self.state.response[2] = self.state.analog as u8;
+                self.state.response[3] = 0x02;
+                self.state.response[4] = 0x01;
+                self.state.response[5] = 0x00;
+            }
+            // Unknown constants, the contents depend on the index
+            // sent in the first payload byte and are filled in
+            // `handle_payload`
+            0x46 | 0x4c => (),
+            // Unknown constant
+            0x47 => {
+                self.state.response[2] = 0x02;
+                self.state.response[4] = 0x01;
+            }
+            // Rumble configuration, reply with the previous config
+            0x4d => self.state.response = self.state.rumble_map,
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// Handle the command byte `cmd` received at position `index` in
+    /// the payload
+    fn handle_payload(&mut self, index: usize, cmd: u8) {
+        match (self.state.command, index) {
+            (0x42, _) => {
+                match self.state.rumble_map[index] {
+                    0x00 => self.state.small_motor = cmd & 1 != 0,
+                    0x01 => self.state.large_motor = cmd,
+                    _ => (),
+                }
+            }
+            (0x43, 0) => self.state.next_config = cmd == 0x01,
+            (0x44, 0) => self.state.analog = cmd == 0x01,
+            (0x44, 1) => self.state.locked = cmd == 0x03,
+            (0x46, 0) => {
+                let constants: [u8; 4] =
+                    if cmd == 0x01 {
+                        [0x01, 0x01, 0x01, 0x14]
+                    } else {
+                        [0x01, 0x02, 0x00, 0x0a]
+                    };
+
+                self.state.response[2..6].copy_from_slice(&constants);
+            }
+            (0x4c, 0) => {
+                self.state.response[3] =
+                    if cmd == 0x01 {
+                        0x07
+                    } else {
+                        0x04
+                    };
+            }
+            (0x4d, _) => self.state.rumble_map[index] = cmd,
+            _ => (),
+        }
+    }
+
+    /// Called once the last byte of a command has been transferred
+    fn end_command(&mut self) {
+        match self.state.command {
+            0x42 => self.update_rumble(),
+            0x43 => self.state.config = self.state.next_config,
+            _ => (),
+        }
+    }
+
+    fn update_rumble(&mut self) {
+        let state = (self.state.small_motor, self.state.large_motor);
+
+        if state != self.state.rumble_state {
+            self.state.rumble_state = state;
+
+            if let Some(ref mut cb) = self.rumble_callback {
+                cb(state.0, state.1);
+            }
+        }
+    }
+}
+
+impl Profile for DualShockProfile {
+    fn handle_command(&mut self, seq: u8, cmd: u8) -> (u8, bool) {
+        match seq {
+            0 => (0xff, (cmd == 0x01)),
+            1 => {
+                let id = self.id();
+
+                (id, self.start_command(cmd))
+            }
+            2 => (0x5a, true),
+            n => {
+                let index = (n - 3) as usize;
+
+                if index >= self.state.response_len as usize {
+                    // Shouldn't be reached
+                    return (0xff, false);
+                }
+
+                let response = self.state.response[index];
+
+                self.handle_payload(index, cmd);
+
+                // No DSR for the last byte
+                let last = index + 1 == self.state.response_len as usize;
+
+                if last {
+                    self.end_command();
+                }
+
+                (response, !last)
+            }
+        }
+    }
+
+    fn set_button_state(&mut self, button: Button, state: ButtonState) {
+        if let Button::Analog = button {
+            let pressed =
+                match state {
+                    ButtonState::Pressed => true,
+                    ButtonState::Released => false,
+                };
+
+            // The analog mode is toggled when the button is pressed,
+            // unless the game locked it.
+            if pressed && !self.state.analog_pressed && !self.state.locked {
+                self.state.analog = !self.state.analog;
+            }
+
+            self.state.analog_pressed = pressed;
+
+            return;
+        }
+
+        let s = self.state.buttons;
+
+        let mask = 1 << (button as usize);
+
+        self.state.buttons =
+            match state {
+                ButtonState::Pressed  => s & !mask,
+                ButtonState::Released => s | mask,
+            };
+    }
+
+    fn set_axis_state(&mut self, axis: Axis, value: u8) {
+        self.state.axes[axis as usize] = value;
+    }
+
+    fn state(&self) -> ProfileState {
+        ProfileState::DualShock(self.state.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    use rustc_serialize::json;
+
+    use super::{Profile, DualShockProfile, Button, ButtonState, Axis};
+    use super::GamePad;
+
+    /// Run a full transaction and return the response bytes. Checks
+    /// that DSR is asserted for every byte but the last one.
+    fn transfer(profile: &mut Profile, cmd: &[u8]) -> Vec<u8> {
+        let mut reply = Vec::new();
+
+        for (seq, &b) in cmd.iter().enumerate() {
+            let (r, dsr) = profile.handle_command(seq as u8, b);
+
+            reply.push(r);
+
+            assert_eq!(dsr, seq + 1 < cmd.len());
+        }
+
+        reply
+    }
+
+    /// Send a config mode command with a 6 byte payload
+    fn config_cmd(pad: &mut DualShockProfile,
+                  cmd: u8,
+                  payload: [u8; 6]) -> Vec<u8> {
+        let mut bytes = vec![0x01, cmd, 0x00];
+        bytes.extend_from_slice(&payload);
+
+        transfer(pad, &bytes)
+    }
+
+    #[test]
+    fn dualshock_config_mode() {
+        let mut pad = DualShockProfile::new();
+
+        pad.set_button_state(Button::Start, ButtonState::Pressed);
+
+        // Starts in digital mode
+        assert_eq!(transfer(&mut pad, &[0x01, 0x42, 0x00, 0x00, 0x00]),
+                   vec![0xff, 0x41, 0x5a, 0xf7, 0xff]);
+
+        // Config mode isn't available outside of config mode
+        assert_eq!(pad.handle_command(0, 0x01), (0xff, true));
+        assert_eq!(pad.handle_command(1, 0x45), (0x41, false));
+
+        // Enter config mode
+        assert_eq!(transfer(&mut pad, &[0x01, 0x43, 0x00, 0x01, 0x00]),
+                   vec![0xff, 0x41, 0x5a, 0xf7, 0xff]);
+
+        // Get status
+        assert_eq!(config_cmd(&mut pad, 0x45, [0; 6]),
+                   vec![0xff, 0xf3, 0x5a, 0x01, 0x02, 0x00, 0x02, 0x01, 0x00]);
+
+        // Switch to analog mode and lock it
+        assert_eq!(config_cmd(&mut pad, 0x44, [0x01, 0x03, 0, 0, 0, 0]),
+                   vec![0xff, 0xf3, 0x5a, 0, 0, 0, 0, 0, 0]);
+
+        assert!(pad.led());
+        assert_eq!(config_cmd(&mut pad, 0x45, [0; 6])[5], 0x01);
+
+        // Unknown constants
+        assert_eq!(&config_cmd(&mut pad, 0x46, [0x00, 0, 0, 0, 0, 0])[3..],
+                   &[0x00, 0x00, 0x01, 0x02, 0x00, 0x0a]);
+        assert_eq!(&config_cmd(&mut pad, 0x46, [0x01, 0, 0, 0, 0, 0])[3..],
+                   &[0x00, 0x00, 0x01, 0x01, 0x01, 0x14]);
+        assert_eq!(&config_cmd(&mut pad, 0x47, [0; 6])[3..],
+                   &[0x00, 0x00, 0x02, 0x00, 0x01, 0x00]);
+        assert_eq!(&config_cmd(&mut pad, 0x4c, [0x00, 0, 0, 0, 0, 0])[3..],
+                   &[0x00, 0x00, 0x00, 0x04, 0x00, 0x00]);
+        assert_eq!(&config_cmd(&mut pad, 0x4c, [0x01, 0, 0, 0, 0, 0])[3..],
+                   &[0x00, 0x00, 0x00, 0x07, 0x00, 0x00]);
+
+        // Polling still works in config mode, in the analog format
+        assert_eq!(config_cmd(&mut pad, 0x42, [0; 6]),
+                   vec![0xff, 0xf3, 0x5a, 0xf7, 0xff,
+                        0x80, 0x80, 0x80, 0x80]);
+
+        // Unsupported command
+        assert_eq!(pad.handle_command(0, 0x01), (0xff, true));
+        assert_eq!(pad.handle_command(1, 0x40), (0xf3, false));
+
+        // Staying in config mode
+        config_cmd(&mut pad, 0x43, [0x01, 0, 0, 0, 0, 0]);
+
+        assert_eq!(pad.handle_command(0, 0x01), (0xff, true));
+        assert_eq!(pad.handle_command(1, 0x42), (0xf3, true));
+
+        // Exit config mode
+        config_cmd(&mut pad, 0x43, [0; 6]);
+
+        pad.set_axis_state(Axis::LeftX, 0x00);
+        pad.set_axis_state(Axis::RightY, 0xff);
+
+        assert_eq!(transfer(&mut pad, &[0x01, 0x42, 0, 0, 0, 0, 0, 0, 0]),
+                   vec![0xff, 0x73, 0x5a, 0xf7, 0xff,
+                        0x80, 0xff, 0x00, 0x80]);
+
+        // The mode is locked, the analog button is ignored
+        pad.set_button_state(Button::Analog, ButtonState::Pressed);
+
+        assert!(pad.led());
+    }
+
+    #[test]
+    fn dualshock_analog_button() {
+        let mut pad = DualShockProfile::new();
+
+        pad.set_button_state(Button::Analog, ButtonState::Pressed);
+
+        assert!(pad.led());
+
+        // Holding the button doesn't toggle the mode again
+        pad.set_button_state(Button::Analog, ButtonState::Pressed);
+        pad.set_button_state(Button::Analog, ButtonState::Released);
+
+        assert!(pad.led());
+        assert_eq!(pad.handle_command(1, 0x42), (0x73, true));
+
+        pad.set_button_state(Button::Analog, ButtonState::Pressed);
+
+        assert!(!pad.led());
+
+        // The analog button isn't reported in the button state
+        assert_eq!(transfer(&mut pad, &[0x01, 0x42, 0x00, 0x00, 0x00]),
+                   vec![0xff, 0x41, 0x5a, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn dualshock_rumble() {
+        let mut pad = DualShockProfile::new();
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+
+        {
+            let events = events.clone();
+
+            pad.set_rumble_callback(Box::new(move |small, large| {
+                events.borrow_mut().push((small, large))
+            }));
+        }
+
+        let poll = [0x01, 0x42, 0x00, 0x01, 0xc0, 0x00, 0x00, 0x00, 0x00];
+
+        pad.set_button_state(Button::Analog, ButtonState::Pressed);
+
+        // Motors are unmapped by default
+        transfer(&mut pad, &poll);
+
+        assert!(events.borrow().is_empty());
+
+        // Map the small motor to the first payload byte and the large
+        // one to the second
+        transfer(&mut pad, &[0x01, 0x43, 0x00, 0x01, 0x00, 0, 0, 0, 0]);
+
+        assert_eq!(config_cmd(&mut pad, 0x4d, [0x00, 0x01, 0xff, 0xff,
+                                               0xff, 0xff]),
+                   vec![0xff, 0xf3, 0x5a, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+
+        // The previous mapping is returned
+        assert_eq!(&config_cmd(&mut pad, 0x4d, [0x00, 0x01, 0xff, 0xff,
+                                                0xff, 0xff])[3..],
+                   &[0x00, 0x01, 0xff, 0xff, 0xff, 0xff]);
+
+        config_cmd(&mut pad, 0x43, [0; 6]);
+
+        transfer(&mut pad, &poll);
+
+        assert_eq!(*events.borrow(), vec![(true, 0xc0)]);
+
+        // The callback is only called when the state changes
+        transfer(&mut pad, &poll);
+
+        assert_eq!(events.borrow().len(), 1);
+
+        transfer(&mut pad, &[0x01, 0x42, 0, 0, 0, 0, 0, 0, 0]);
+
+        assert_eq!(*events.borrow(), vec![(true, 0xc0), (false, 0x00)]);
+    }
+
+    #[test]
+    fn savestate_round_trip() {
+        let mut pad = GamePad::disconnected();
+
+        pad.set_profile(Box::new(DualShockProfile::new()));
+
+        {
+            let profile = pad.profile_mut();
+
+            profile.set_button_state(Button::Start, ButtonState::Pressed);
+            profile.set_axis_state(Axis::LeftX, 0x00);
+
+            // Enter config mode, switch to analog mode and lock it,
+            // map the large motor to the first payload byte
+            transfer(profile, &[0x01, 0x43, 0x00, 0x01, 0x00]);
+            transfer(profile, &[0x01, 0x44, 0x00, 0x01, 0x03, 0, 0, 0, 0]);
+            transfer(profile, &[0x01, 0x4d, 0x00, 0x01, 0xff,
+                                0xff, 0xff, 0xff, 0xff]);
+        }
+
+        let saved = json::encode(&pad).unwrap();
+
+        let mut pad: GamePad = json::decode(&saved).unwrap();
+
+        let profile = pad.profile_mut();
+
+        // Still in config mode, still in analog mode
+        assert_eq!(transfer(profile, &[0x01, 0x45, 0, 0, 0, 0, 0, 0, 0]),
+                   vec![0xff, 0xf3, 0x5a, 0x01, 0x02, 0x01, 0x02, 0x01, 0x00]);
+
+        // The rumble configuration is preserved
+        assert_eq!(&transfer(profile, &[0x01, 0x4d, 0x00, 0x01, 0xff,
+                                        0xff, 0xff, 0xff, 0xff])[3..],
+                   &[0x01, 0xff, 0xff, 0xff, 0xff, 0xff]);
+
+        // Leave config mode
+        transfer(profile, &[0x01, 0x43, 0x00, 0x00, 0, 0, 0, 0, 0]);
+
+        // The analog mode is still locked
+        profile.set_button_state(Button::Analog, ButtonState::Pressed);
+        profile.set_button_state(Button::Analog, ButtonState::Released);
+
+        assert_eq!(transfer(profile, &[0x01, 0x42, 0, 0, 0, 0, 0, 0, 0]),
+                   vec![0xff, 0x73, 0x5a, 0xf7, 0xff,
+                        0x80, 0x80, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn unknown_profile_state() {
+        struct Custom;
+
+        impl Profile for Custom {
+            fn handle_command(&mut self, _: u8, _: u8) -> (u8, bool) {
+                (0x00, true)
+            }
+
+            fn set_button_state(&mut self, _: Button, _: ButtonState) {
+            }
+        }
+
+        let mut pad = GamePad::disconnected();
+
+        pad.set_profile(Box::new(Custom));
+
+        let saved = json::encode(&pad).unwrap();
+
+        let mut pad: GamePad = json::decode(&saved).unwrap();
+
+        // Restored as an empty slot
+        assert_eq!(pad.profile_mut().handle_command(0, 0x01), (0xff, false));
+    }
 }
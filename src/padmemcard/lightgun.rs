@@ -0,0 +1,307 @@
+//! Lightgun controllers.
+//!
+//! Lightguns contain a photodiode which detects the moment the CRT
+//! beam passes over the point the gun is aimed at. The GunCon
+//! measures the beam position itself and reports it through the
+//! serial protocol while the Justifier pulses the lightgun interrupt
+//! (IRQ10) and lets the game read the timers to figure out the
+//! position.
+//!
+//! The frontend sets the aim point in screen coordinates with
+//! `Profile::set_aim`, we convert it into a beam position using the
+//! display configuration received through
+//! `Profile::set_display_info`.
+//!
+//! Both guns use the same button mapping:
+//!
+//! * Trigger: `Button::Circle`
+//! * A/Auxiliary: `Button::Cross`
+//! * B/Start: `Button::Start`
+
+use gpu::DisplayInfo;
+
+use super::gamepad::{Profile, ProfileState, Button, ButtonState};
+
+/// Buttons actually present on the lightguns
+const BUTTON_MASK: u16 =
+    (1 << Button::Circle as u16) |
+    (1 << Button::Cross as u16) |
+    (1 << Button::Start as u16);
+
+/// Button state and aim shared by both lightgun profiles
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+struct Gun {
+    /// Button state, active low
+    buttons: u16,
+    /// Aim point in screen coordinates
+    aim: Option<(i32, i32)>,
+    /// Last display configuration received
+    display: DisplayInfo,
+}
+
+impl Gun {
+    fn new() -> Gun {
+        Gun {
+            buttons: 0xffff,
+            aim: None,
+            display: DisplayInfo::new(),
+        }
+    }
+
+    /// Return the beam position the gun is aimed at
+    fn beam_position(&self) -> Option<(u16, u16)> {
+        self.aim.and_then(|(x, y)| self.display.screen_to_beam(x, y))
+    }
+
+    fn set_button_state(&mut self, button: Button, state: ButtonState) {
+        if let Button::Analog = button {
+            return;
+        }
+
+        let mask = 1 << (button as usize);
+
+        if mask & BUTTON_MASK == 0 {
+            return;
+        }
+
+        let s = self.buttons;
+
+        self.buttons =
+            match state {
+                ButtonState::Pressed  => s & !mask,
+                ButtonState::Released => s | mask,
+            };
+    }
+}
+
+/// NPC-103: Namco GunCon
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+pub struct GunConProfile {
+    gun: Gun,
+    /// Beam position latched at the beginning of the transaction
+    position: (u16, u16),
+}
+
+impl GunConProfile {
+    pub fn new() -> GunConProfile {
+        GunConProfile {
+            gun: Gun::new(),
+            position: (0, 0),
+        }
+    }
+}
+
+impl Profile for GunConProfile {
+    fn handle_command(&mut self, seq: u8, cmd: u8) -> (u8, bool) {
+        match seq {
+            0 => (0xff, (cmd == 0x01)),
+            1 => {
+                // When the gun doesn't see the beam it reports X=1,
+                // Y=10
+                self.position =
+                    self.gun.beam_position().unwrap_or((0x01, 0x0a));
+
+                // The GunCon only supports "read"
+                (0x63, (cmd == 0x42))
+            }
+            2 => (0x5a, true),
+            3 => (self.gun.buttons as u8, true),
+            4 => ((self.gun.buttons >> 8) as u8, true),
+            // X position in dotclocks relative to HSYNC
+            5 => (self.position.0 as u8, true),
+            6 => ((self.position.0 >> 8) as u8, true),
+            // Y position in scanlines relative to VSYNC
+            7 => (self.position.1 as u8, true),
+            // No DSR for the last byte
+            8 => ((self.position.1 >> 8) as u8, false),
+            _ => (0xff, false),
+        }
+    }
+
+    fn set_button_state(&mut self, button: Button, state: ButtonState) {
+        self.gun.set_button_state(button, state);
+    }
+
+    fn set_aim(&mut self, aim: Option<(i32, i32)>) {
+        self.gun.aim = aim;
+    }
+
+    fn set_display_info(&mut self, info: &DisplayInfo) {
+        self.gun.display = *info;
+    }
+
+    fn state(&self) -> ProfileState {
+        ProfileState::GunCon(self.clone())
+    }
+}
+
+/// SLUH-00017: Konami Justifier
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+pub struct JustifierProfile {
+    gun: Gun,
+    /// True if the game enabled the lightgun interrupt
+    irq_enabled: bool,
+}
+
+impl JustifierProfile {
+    pub fn new() -> JustifierProfile {
+        JustifierProfile {
+            gun: Gun::new(),
+            irq_enabled: false,
+        }
+    }
+}
+
+impl Profile for JustifierProfile {
+    fn handle_command(&mut self, seq: u8, cmd: u8) -> (u8, bool) {
+        match seq {
+            0 => (0xff, (cmd == 0x01)),
+            1 => (0x31, (cmd == 0x42)),
+            2 => (0x5a, true),
+            3 => {
+                // XXX The game uses this byte to enable the lightgun
+                // interrupt, I'm not sure the other bits mean
+                // anything.
+                self.irq_enabled = cmd & 0x10 != 0;
+
+                (self.gun.buttons as u8, true)
+            }
+            // No DSR for the last byte
+            4 => ((self.gun.buttons >> 8) as u8, false),
+            _ => (0xff, false),
+        }
+    }
+
+    fn set_button_state(&mut self, button: Button, state: ButtonState) {
+        self.gun.set_button_state(button, state);
+    }
+
+    fn set_aim(&mut self, aim: Option<(i32, i32)>) {
+        self.gun.aim = aim;
+    }
+
+    fn set_display_info(&mut self, info: &DisplayInfo) {
+        self.gun.display = *info;
+    }
+
+    fn state(&self) -> ProfileState {
+        ProfileState::Justifier(self.clone())
+    }
+
+    fn lightgun_target(&self) -> Option<(u16, u16)> {
+        if self.irq_enabled {
+            self.gun.beam_position()
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gpu::DisplayInfo;
+
+    use super::{GunConProfile, JustifierProfile};
+    use super::super::gamepad::{Profile, Button, ButtonState};
+
+    /// Run a full transaction and return the response bytes. Checks
+    /// that DSR is asserted for every byte but the last one.
+    fn transfer(profile: &mut Profile, cmd: &[u8]) -> Vec<u8> {
+        let mut reply = Vec::new();
+
+        for (seq, &b) in cmd.iter().enumerate() {
+            let (r, dsr) = profile.handle_command(seq as u8, b);
+
+            reply.push(r);
+
+            assert_eq!(dsr, seq + 1 < cmd.len());
+        }
+
+        reply
+    }
+
+    #[test]
+    fn screen_to_beam() {
+        let mut info = DisplayInfo::new();
+
+        // 320x240, display starts at GPU tick 0x200 with a divider
+        // of 8 (dotclock 64) and line 16
+        assert_eq!(info.screen_to_beam(0, 0), Some((64, 16)));
+        assert_eq!(info.screen_to_beam(10, 20), Some((74, 36)));
+        assert_eq!(info.screen_to_beam(319, 239), Some((383, 255)));
+
+        assert_eq!(info.screen_to_beam(-1, 0), None);
+        assert_eq!(info.screen_to_beam(0, -1), None);
+        assert_eq!(info.screen_to_beam(320, 0), None);
+        assert_eq!(info.screen_to_beam(0, 240), None);
+
+        // 640x480 interlaced, each field only has every other line
+        info.width = 640;
+        info.height = 480;
+        info.dotclock_divider = 4;
+
+        assert_eq!(info.screen_to_beam(10, 20), Some((138, 26)));
+        assert_eq!(info.screen_to_beam(10, 21), Some((138, 26)));
+        assert_eq!(info.screen_to_beam(0, 479), Some((128, 255)));
+    }
+
+    #[test]
+    fn guncon_position() {
+        let mut gun = GunConProfile::new();
+
+        let cmd = [0x01, 0x42, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+        // Not aimed at the screen
+        assert_eq!(transfer(&mut gun, &cmd),
+                   vec![0xff, 0x63, 0x5a, 0xff, 0xff,
+                        0x01, 0x00, 0x0a, 0x00]);
+
+        gun.set_display_info(&DisplayInfo::new());
+        gun.set_aim(Some((250, 20)));
+        gun.set_button_state(Button::Circle, ButtonState::Pressed);
+
+        // The position is in dotclocks (64 + 250 = 0x13a) and lines
+        // (16 + 20 = 0x24)
+        assert_eq!(transfer(&mut gun, &cmd),
+                   vec![0xff, 0x63, 0x5a, 0xff, 0xdf,
+                        0x3a, 0x01, 0x24, 0x00]);
+
+        // Buttons not present on the gun are ignored
+        gun.set_button_state(Button::Square, ButtonState::Pressed);
+        gun.set_button_state(Button::Circle, ButtonState::Released);
+        gun.set_aim(Some((320, 20)));
+
+        assert_eq!(transfer(&mut gun, &cmd),
+                   vec![0xff, 0x63, 0x5a, 0xff, 0xff,
+                        0x01, 0x00, 0x0a, 0x00]);
+
+        // The GunCon doesn't support anything but "read"
+        assert_eq!(gun.handle_command(0, 0x01), (0xff, true));
+        assert_eq!(gun.handle_command(1, 0x43), (0x63, false));
+    }
+
+    #[test]
+    fn justifier_target() {
+        let mut gun = JustifierProfile::new();
+
+        gun.set_display_info(&DisplayInfo::new());
+        gun.set_aim(Some((10, 20)));
+
+        // No IRQ10 until the game enables it
+        assert_eq!(gun.lightgun_target(), None);
+
+        assert_eq!(transfer(&mut gun, &[0x01, 0x42, 0x00, 0x10, 0x00]),
+                   vec![0xff, 0x31, 0x5a, 0xff, 0xff]);
+
+        assert_eq!(gun.lightgun_target(), Some((74, 36)));
+
+        // Off-screen: no interrupt
+        gun.set_aim(None);
+        assert_eq!(gun.lightgun_target(), None);
+
+        gun.set_aim(Some((10, 20)));
+        transfer(&mut gun, &[0x01, 0x42, 0x00, 0x00, 0x00]);
+
+        assert_eq!(gun.lightgun_target(), None);
+    }
+}
@@ -0,0 +1,633 @@
+//! Memory card filesystem.
+//!
+//! The card is split into 16 8KiB blocks. The first block contains
+//! the header frame followed by 15 directory frames (one per save
+//! block) and the broken sector list. The remaining 15 blocks hold
+//! the saves, a save spanning several blocks is a linked list of
+//! directory frames.
+//!
+//! The first frame of the first block of every save is the title
+//! frame: it contains the "SC" magic, the icon animation type, the
+//! title in Shift-JIS and the icon palette. It's followed by one to
+//! three 16x16 4bpp icon frames.
+
+use super::memcard::{self, CARD_SIZE, SECTOR_SIZE};
+
+/// Size of a memory card block in bytes
+pub const BLOCK_SIZE: usize = 8 * 1024;
+
+/// Number of blocks available for saves
+pub const SAVE_BLOCKS: usize = 15;
+
+/// Maximum length of a save file name, not including the NUL
+pub const NAME_LEN: usize = 20;
+
+/// Width and height of the save icons in pixels
+pub const ICON_SIZE: usize = 16;
+
+/// Directory frame state: first block of a save
+pub const BLOCK_FIRST: u8 = 0x51;
+/// Directory frame state: middle block of a save
+pub const BLOCK_MIDDLE: u8 = 0x52;
+/// Directory frame state: last block of a save
+pub const BLOCK_LAST: u8 = 0x53;
+/// Directory frame state: free block. The low nibble contains the
+/// previous state if the block was deleted.
+pub const BLOCK_FREE: u8 = 0xa0;
+
+/// Offset of the file name in a directory frame
+const NAME_OFFSET: usize = 0x0a;
+
+/// A save listed in the card directory
+#[derive(Clone, Debug)]
+pub struct Save {
+    /// Blocks used by the save, in order
+    blocks: Vec<usize>,
+    /// Size in bytes stored in the directory
+    size: u32,
+    /// File name, for instance `BASCUS-94163FF7`
+    file_name: Vec<u8>,
+    /// Copy of the title frame and icon frames
+    header: Vec<u8>,
+}
+
+impl Save {
+    /// Return the first block of the save (0-based, not counting the
+    /// directory block). It's the value used to identify the save in
+    /// `delete` and `copy`.
+    pub fn first_block(&self) -> usize {
+        self.blocks[0]
+    }
+
+    /// Return the chain of blocks used by the save
+    pub fn blocks(&self) -> &[usize] {
+        &self.blocks
+    }
+
+    /// Size of the save in bytes, as stored in the directory
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Raw file name
+    pub fn file_name(&self) -> &[u8] {
+        &self.file_name
+    }
+
+    /// Region code: `BI` (Japan), `BA` (America) or `BE` (Europe)
+    pub fn region(&self) -> &[u8] {
+        let end = ::std::cmp::min(2, self.file_name.len());
+
+        &self.file_name[..end]
+    }
+
+    /// Product code of the game that created the save, for instance
+    /// `SCUS-94163`
+    pub fn product_code(&self) -> &[u8] {
+        let start = ::std::cmp::min(2, self.file_name.len());
+        let end = ::std::cmp::min(12, self.file_name.len());
+
+        &self.file_name[start..end]
+    }
+
+    /// Game-specific part of the file name following the product
+    /// code
+    pub fn identifier(&self) -> &[u8] {
+        let start = ::std::cmp::min(12, self.file_name.len());
+
+        &self.file_name[start..]
+    }
+
+    /// Return true if the title frame has a valid "SC" magic
+    pub fn has_title(&self) -> bool {
+        self.header.starts_with(b"SC")
+    }
+
+    /// Decode the Shift-JIS save title
+    pub fn title(&self) -> String {
+        if !self.has_title() {
+            return String::new();
+        }
+
+        decode_shift_jis(&self.header[4..0x44])
+    }
+
+    /// Number of icon frames, 0 if the save doesn't have a title
+    /// frame. Animated icons cycle through 2 or 3 frames.
+    pub fn icon_frame_count(&self) -> usize {
+        if !self.has_title() {
+            return 0;
+        }
+
+        match self.header[2] {
+            0x12 => 2,
+            0x13 => 3,
+            _ => 1,
+        }
+    }
+
+    /// Decode the icon frames into 16x16 RGBA images (4 bytes per
+    /// pixel, rows from top to bottom)
+    pub fn icon_frames(&self) -> Vec<Vec<u8>> {
+        let mut palette = [[0u8; 4]; 16];
+
+        for (i, p) in palette.iter_mut().enumerate() {
+            let off = 0x60 + i * 2;
+            let color = self.header[off] as u16 | ((self.header[off + 1] as u16) << 8);
+
+            *p = bgr555_to_rgba(color);
+        }
+
+        (0..self.icon_frame_count()).map(|frame| {
+            let start = (frame + 1) * SECTOR_SIZE;
+            let pixels = &self.header[start..start + SECTOR_SIZE];
+
+            let mut rgba = Vec::with_capacity(ICON_SIZE * ICON_SIZE * 4);
+
+            for &b in pixels {
+                // Low nibble is the leftmost pixel
+                rgba.extend_from_slice(&palette[(b & 0xf) as usize]);
+                rgba.extend_from_slice(&palette[(b >> 4) as usize]);
+            }
+
+            rgba
+        }).collect()
+    }
+}
+
+/// Convert a 16bit PlayStation color to RGBA. Like on the GPU the
+/// color 0x0000 is fully transparent.
+fn bgr555_to_rgba(color: u16) -> [u8; 4] {
+    let expand = |c: u16| {
+        let c = (c & 0x1f) as u8;
+
+        (c << 3) | (c >> 2)
+    };
+
+    let alpha =
+        if color == 0 {
+            0
+        } else {
+            0xff
+        };
+
+    [expand(color), expand(color >> 5), expand(color >> 10), alpha]
+}
+
+/// List the saves on the card
+pub fn saves(memory: &[u8]) -> Result<Vec<Save>, Error> {
+    if memory.len() != CARD_SIZE {
+        return Err(Error::BadCardSize(memory.len()));
+    }
+
+    let mut saves = Vec::new();
+
+    for block in 0..SAVE_BLOCKS {
+        if directory_frame(memory, block)[0] == BLOCK_FIRST {
+            saves.push(try!(save(memory, block)));
+        }
+    }
+
+    Ok(saves)
+}
+
+/// Return the save starting at `block`
+pub fn save(memory: &[u8], block: usize) -> Result<Save, Error> {
+    if memory.len() != CARD_SIZE {
+        return Err(Error::BadCardSize(memory.len()));
+    }
+
+    if block >= SAVE_BLOCKS || directory_frame(memory, block)[0] != BLOCK_FIRST {
+        return Err(Error::NoSuchSave(block));
+    }
+
+    let first = directory_frame(memory, block);
+
+    let mut blocks = vec![block];
+
+    let mut b = block;
+
+    loop {
+        let frame = directory_frame(memory, b);
+        let next = frame[8] as usize | ((frame[9] as usize) << 8);
+
+        if next == 0xffff {
+            break;
+        }
+
+        if next >= SAVE_BLOCKS || blocks.len() >= SAVE_BLOCKS ||
+            blocks.contains(&next) {
+            return Err(Error::BadBlockChain(block));
+        }
+
+        blocks.push(next);
+
+        b = next;
+    }
+
+    let data = block_data(memory, block);
+
+    Ok(Save {
+        blocks: blocks,
+        size: get_u32(&first[4..]),
+        file_name: c_string(&first[NAME_OFFSET..NAME_OFFSET + NAME_LEN + 1]).into(),
+        header: data[0..4 * SECTOR_SIZE].into(),
+    })
+}
+
+/// Return the contents of all the blocks of `save`
+pub fn save_data(memory: &[u8], save: &Save) -> Vec<u8> {
+    let mut data = Vec::with_capacity(save.blocks.len() * BLOCK_SIZE);
+
+    for &b in &save.blocks {
+        data.extend_from_slice(block_data(memory, b));
+    }
+
+    data
+}
+
+/// Write a new save named `name` containing `data` in the free blocks
+/// of the card. Returns the first block of the new save.
+pub fn insert(memory: &mut [u8], name: &[u8], data: &[u8]) -> Result<usize, Error> {
+    if memory.len() != CARD_SIZE {
+        return Err(Error::BadCardSize(memory.len()));
+    }
+
+    if name.is_empty() || name.len() > NAME_LEN {
+        return Err(Error::BadSaveName);
+    }
+
+    if data.is_empty() || data.len() % BLOCK_SIZE != 0 {
+        return Err(Error::BadSaveSize(data.len()));
+    }
+
+    let nblocks = data.len() / BLOCK_SIZE;
+
+    // Make sure the save isn't already on the card and look for free
+    // blocks
+    let mut free = Vec::new();
+
+    for block in 0..SAVE_BLOCKS {
+        let frame = directory_frame(memory, block);
+
+        if frame[0] == BLOCK_FIRST &&
+            c_string(&frame[NAME_OFFSET..NAME_OFFSET + NAME_LEN + 1]) == name {
+            return Err(Error::DuplicateSave(String::from_utf8_lossy(name)
+                                            .into_owned()));
+        }
+
+        if frame[0] & 0xf0 == BLOCK_FREE {
+            free.push(block);
+        }
+    }
+
+    if free.len() < nblocks {
+        return Err(Error::CardFull);
+    }
+
+    let blocks = &free[..nblocks];
+
+    for (i, &block) in blocks.iter().enumerate() {
+        block_data_mut(memory, block)
+            .copy_from_slice(&data[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE]);
+
+        let next =
+            match blocks.get(i + 1) {
+                Some(&n) => n as u16,
+                None => 0xffff,
+            };
+
+        let frame = directory_frame_mut(memory, block);
+
+        for b in frame.iter_mut() {
+            *b = 0;
+        }
+
+        frame[0] =
+            if i == 0 {
+                BLOCK_FIRST
+            } else if i == nblocks - 1 {
+                BLOCK_LAST
+            } else {
+                BLOCK_MIDDLE
+            };
+
+        if i == 0 {
+            put_u32(&mut frame[4..], data.len() as u32);
+            frame[NAME_OFFSET..NAME_OFFSET + name.len()].copy_from_slice(name);
+        }
+
+        frame[8] = next as u8;
+        frame[9] = (next >> 8) as u8;
+
+        memcard::update_frame_checksum(frame);
+    }
+
+    Ok(blocks[0])
+}
+
+/// Delete the save starting at `block`. Like the BIOS we only mark
+/// the blocks as free in the directory, the data is left untouched.
+pub fn delete(memory: &mut [u8], block: usize) -> Result<(), Error> {
+    let entry = try!(save(memory, block));
+
+    for &b in &entry.blocks {
+        let frame = directory_frame_mut(memory, b);
+
+        // 0x51/0x52/0x53 become 0xa1/0xa2/0xa3
+        frame[0] = BLOCK_FREE | (frame[0] & 0xf);
+
+        memcard::update_frame_checksum(frame);
+    }
+
+    Ok(())
+}
+
+/// Copy the save starting at `block` in `from` to the card `to`.
+/// Returns the first block of the copy.
+pub fn copy(from: &[u8], block: usize, to: &mut [u8]) -> Result<usize, Error> {
+    let entry = try!(save(from, block));
+
+    let data = save_data(from, &entry);
+
+    insert(to, &entry.file_name, &data)
+}
+
+/// Return the directory frame describing `block`
+pub fn directory_frame(memory: &[u8], block: usize) -> &[u8] {
+    let start = (block + 1) * SECTOR_SIZE;
+
+    &memory[start..start + SECTOR_SIZE]
+}
+
+fn directory_frame_mut(memory: &mut [u8], block: usize) -> &mut [u8] {
+    let start = (block + 1) * SECTOR_SIZE;
+
+    &mut memory[start..start + SECTOR_SIZE]
+}
+
+fn block_data(memory: &[u8], block: usize) -> &[u8] {
+    let start = (block + 1) * BLOCK_SIZE;
+
+    &memory[start..start + BLOCK_SIZE]
+}
+
+fn block_data_mut(memory: &mut [u8], block: usize) -> &mut [u8] {
+    let start = (block + 1) * BLOCK_SIZE;
+
+    &mut memory[start..start + BLOCK_SIZE]
+}
+
+/// Return `s` up to the first NUL
+pub fn c_string(s: &[u8]) -> &[u8] {
+    match s.iter().position(|&b| b == 0) {
+        Some(p) => &s[..p],
+        None => s,
+    }
+}
+
+fn get_u32(b: &[u8]) -> u32 {
+    b[0] as u32 | ((b[1] as u32) << 8) | ((b[2] as u32) << 16) | ((b[3] as u32) << 24)
+}
+
+fn put_u32(b: &mut [u8], v: u32) {
+    b[0] = v as u8;
+    b[1] = (v >> 8) as u8;
+    b[2] = (v >> 16) as u8;
+    b[3] = (v >> 24) as u8;
+}
+
+/// Decode a NUL-terminated Shift-JIS string.
+///
+/// XXX Only ASCII, the half-width katakana and the first rows of the
+/// JIS X 0208 table (punctuation, full-width alphanumerics, kana) are
+/// supported, which covers the vast majority of save titles. Kanji are
+/// replaced with U+FFFD.
+pub fn decode_shift_jis(s: &[u8]) -> String {
+    let mut out = String::new();
+
+    let mut bytes = c_string(s).iter();
+
+    while let Some(&b) = bytes.next() {
+        let c =
+            match b {
+                0x00...0x7f => b as u32,
+                // Half-width katakana
+                0xa1...0xdf => 0xff61 + (b - 0xa1) as u32,
+                0x81...0x9f | 0xe0...0xfc => {
+                    let lo =
+                        match bytes.next() {
+                            Some(&lo) => lo,
+                            None => break,
+                        };
+
+                    decode_jis_char(((b as u16) << 8) | lo as u16)
+                }
+                _ => 0xfffd,
+            };
+
+        out.push(::std::char::from_u32(c).unwrap_or('\u{fffd}'));
+    }
+
+    out
+}
+
+/// Convert a double byte Shift-JIS character to an Unicode code point
+fn decode_jis_char(c: u16) -> u32 {
+    match c {
+        0x8140...0x8197 => SJIS_PUNCTUATION[(c - 0x8140) as usize] as u32,
+        // Full-width digits
+        0x824f...0x8258 => 0xff10 + (c - 0x824f) as u32,
+        // Full-width uppercase
+        0x8260...0x8279 => 0xff21 + (c - 0x8260) as u32,
+        // Full-width lowercase
+        0x8281...0x829a => 0xff41 + (c - 0x8281) as u32,
+        // Hiragana
+        0x829f...0x82f1 => 0x3041 + (c - 0x829f) as u32,
+        // Katakana, 0x837f is unused
+        0x8340...0x837e => 0x30a1 + (c - 0x8340) as u32,
+        0x8380...0x8396 => 0x30e0 + (c - 0x8380) as u32,
+        _ => 0xfffd,
+    }
+}
+
+/// Unicode code points for the Shift-JIS characters 0x8140 to 0x8197
+static SJIS_PUNCTUATION: [u16; 0x58] = [
+    0x3000, 0x3001, 0x3002, 0xff0c, 0xff0e, 0x30fb, 0xff1a, 0xff1b,
+    0xff1f, 0xff01, 0x309b, 0x309c, 0x00b4, 0xff40, 0x00a8, 0xff3e,
+    0xffe3, 0xff3f, 0x30fd, 0x30fe, 0x309d, 0x309e, 0x3003, 0x4edd,
+    0x3005, 0x3006, 0x3007, 0x30fc, 0x2015, 0x2010, 0xff0f, 0xff3c,
+    0xff5e, 0x2225, 0xff5c, 0x2026, 0x2025, 0x2018, 0x2019, 0x201c,
+    0x201d, 0xff08, 0xff09, 0x3014, 0x3015, 0xff3b, 0xff3d, 0xff5b,
+    0xff5d, 0x3008, 0x3009, 0x300a, 0x300b, 0x300c, 0x300d, 0x300e,
+    0x300f, 0x3010, 0x3011, 0xff0b, 0xff0d, 0x00b1, 0x00d7, 0xfffd,
+    0x00f7, 0xff1d, 0x2260, 0xff1c, 0xff1e, 0x2266, 0x2267, 0x221e,
+    0x2234, 0x2642, 0x2640, 0x00b0, 0x2032, 0x2033, 0x2103, 0xffe5,
+    0xff04, 0xffe0, 0xffe1, 0xff05, 0xff03, 0xff06, 0xff0a, 0xff20,
+];
+
+#[derive(Debug)]
+pub enum Error {
+    /// The card image isn't 128KiB
+    BadCardSize(usize),
+    /// The save data isn't a whole number of blocks
+    BadSaveSize(usize),
+    /// There's no save starting at this block
+    NoSuchSave(usize),
+    /// The block chain of the save starting at this block is broken
+    BadBlockChain(usize),
+    /// The save file name is empty or too long
+    BadSaveName,
+    /// A save with the same file name is already on the card
+    DuplicateSave(String),
+    /// Not enough free blocks on the card
+    CardFull,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memcard::{self, CARD_SIZE, SECTOR_SIZE};
+
+    fn formatted() -> Vec<u8> {
+        let mut memory = vec![0; CARD_SIZE];
+
+        memcard::format(&mut memory);
+
+        memory
+    }
+
+    /// Return true if all the directory frames have a valid checksum
+    fn checksums_ok(memory: &[u8]) -> bool {
+        (0..SAVE_BLOCKS).all(|b| {
+            directory_frame(memory, b).iter().fold(0, |c, &v| c ^ v) == 0
+        })
+    }
+
+    #[test]
+    fn directory_frames() {
+        let mut memory = formatted();
+
+        assert!(checksums_ok(&memory));
+
+        let first = insert(&mut memory, b"BIFOO", &[0; BLOCK_SIZE]).unwrap();
+        let second =
+            insert(&mut memory, b"BIBAR", &[0; 3 * BLOCK_SIZE]).unwrap();
+
+        assert_eq!(first, 0);
+        assert_eq!(second, 1);
+        assert!(checksums_ok(&memory));
+
+        let states: Vec<u8> =
+            (0..4).map(|b| directory_frame(&memory, b)[0]).collect();
+
+        assert_eq!(states,
+                   vec![BLOCK_FIRST, BLOCK_FIRST, BLOCK_MIDDLE, BLOCK_LAST]);
+
+        let s = save(&memory, second).unwrap();
+
+        assert_eq!(s.blocks(), &[1, 2, 3]);
+        assert_eq!(s.size(), 3 * BLOCK_SIZE as u32);
+        assert_eq!(s.file_name(), b"BIBAR");
+
+        delete(&mut memory, first).unwrap();
+
+        assert_eq!(directory_frame(&memory, 0)[0], BLOCK_FREE | 1);
+        assert!(checksums_ok(&memory));
+        assert_eq!(saves(&memory).unwrap().len(), 1);
+
+        // The freed block is reused
+        assert_eq!(insert(&mut memory, b"BIBAZ", &[0; BLOCK_SIZE]).unwrap(),
+                   0);
+    }
+
+    #[test]
+    fn insert_errors() {
+        let mut memory = formatted();
+
+        insert(&mut memory, b"BIFOO", &[0; 14 * BLOCK_SIZE]).unwrap();
+
+        match insert(&mut memory, b"BIFOO", &[0; BLOCK_SIZE]) {
+            Err(Error::DuplicateSave(_)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        match insert(&mut memory, b"BIBAR", &[0; 2 * BLOCK_SIZE]) {
+            Err(Error::CardFull) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        match insert(&mut memory, b"BIBAR", &[0; 100]) {
+            Err(Error::BadSaveSize(100)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        match insert(&mut memory, b"", &[0; BLOCK_SIZE]) {
+            Err(Error::BadSaveName) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn broken_chain() {
+        let mut memory = formatted();
+
+        insert(&mut memory, b"BIFOO", &[0; 2 * BLOCK_SIZE]).unwrap();
+
+        // Make the last block point back to the first one
+        {
+            let start = 2 * SECTOR_SIZE;
+            let frame = &mut memory[start..start + SECTOR_SIZE];
+
+            frame[8] = 0;
+            frame[9] = 0;
+        }
+
+        match save(&memory, 0) {
+            Err(Error::BadBlockChain(0)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn title() {
+        let mut memory = formatted();
+
+        let mut data = vec![0; BLOCK_SIZE];
+
+        data[0] = b'S';
+        data[1] = b'C';
+        // Animated icon, 3 frames
+        data[2] = 0x13;
+        data[3] = 1;
+
+        let title = [0x82, 0x60, 0x82, 0x61, 0x81, 0x40, 0x82, 0xa0,
+                     0x83, 0x41, 0xb1, b'!', 0x88, 0x9f];
+
+        data[4..4 + title.len()].copy_from_slice(&title);
+
+        let block = insert(&mut memory, b"BISLPS-00000", &data).unwrap();
+
+        let s = save(&memory, block).unwrap();
+
+        assert!(s.has_title());
+        assert_eq!(s.title(), "\u{ff21}\u{ff22}\u{3000}\u{3042}\u{30a2}\
+                               \u{ff71}!\u{fffd}");
+        assert_eq!(s.icon_frame_count(), 3);
+        assert_eq!(s.region(), b"BI");
+        assert_eq!(s.product_code(), b"SLPS-00000");
+    }
+
+    #[test]
+    fn shift_jis() {
+        assert_eq!(decode_shift_jis(b"ABC\0DEF"), "ABC");
+        // Full-width digits and punctuation
+        assert_eq!(decode_shift_jis(&[0x82, 0x4f, 0x82, 0x58, 0x81, 0x49]),
+                   "\u{ff10}\u{ff19}\u{ff01}");
+        // Katakana after the 0x837f hole
+        assert_eq!(decode_shift_jis(&[0x83, 0x80, 0x83, 0x96]),
+                   "\u{30e0}\u{30f6}");
+        // Truncated double byte character
+        assert_eq!(decode_shift_jis(&[b'A', 0x82]), "A");
+    }
+}
@@ -0,0 +1,528 @@
+//! Memory card emulation.
+//!
+//! Memory cards share the serial bus with the gamepads, they're
+//! addressed by sending `0x81` as the first byte of the transaction
+//! (gamepads use `0x01`). The card is made of 1024 128-byte sectors
+//! (also called "frames") for a total of 128KiB.
+
+use timekeeper::Cycles;
+
+/// Size of a memory card in bytes
+pub const CARD_SIZE: usize = 128 * 1024;
+
+/// Size of a single sector in bytes
+pub const SECTOR_SIZE: usize = 128;
+
+/// Number of sectors in a memory card
+pub const SECTOR_COUNT: u16 = (CARD_SIZE / SECTOR_SIZE) as u16;
+
+/// Delay between the end of a byte transfer and the DSR pulse, in
+/// CPU cycles. Memory cards are noticeably slower to acknowledge
+/// bytes than gamepads.
+pub const DSR_DELAY: Cycles = 170;
+
+/// FLAG bit set when the card has just been inserted (or the console
+/// just booted). It's cleared by the first succesful write.
+const FLAG_NEW_CARD: u8 = 0x08;
+
+/// FLAG bit set when the last write failed
+const FLAG_WRITE_ERROR: u8 = 0x04;
+
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct MemoryCard {
+    /// Card contents, `CARD_SIZE` bytes long. Empty if no card is
+    /// plugged in.
+    memory: Vec<u8>,
+    /// Status flags returned in the 2nd reply byte of every command
+    flag: u8,
+    /// Command being processed
+    command: Command,
+    /// Counter keeping track of the current position in the reply
+    /// sequence
+    seq: u8,
+    /// False if the card is done processing the current command
+    active: bool,
+    /// Sector being accessed by the current command
+    sector: u16,
+    /// Running checksum for the current read or write
+    checksum: u8,
+    /// Last byte received, some replies echo it back one byte later
+    last_rx: u8,
+    /// Data received during a write command
+    write_buffer: SectorBuffer,
+    /// True if the contents of the card have been modified since the
+    /// last call to `clear_dirty`
+    dirty: bool,
+}
+
+impl MemoryCard {
+    /// Create an empty memory card slot
+    pub fn disconnected() -> MemoryCard {
+        MemoryCard {
+            memory: Vec::new(),
+            flag: FLAG_NEW_CARD,
+            command: Command::None,
+            seq: 0,
+            active: false,
+            sector: 0,
+            checksum: 0,
+            last_rx: 0,
+            write_buffer: SectorBuffer::new(),
+            dirty: false,
+        }
+    }
+
+    /// Create a memory card containing `memory` which must be exactly
+    /// `CARD_SIZE` bytes long. Returns `None` if the size doesn't
+    /// match.
+    pub fn new(memory: Vec<u8>) -> Option<MemoryCard> {
+        if memory.len() != CARD_SIZE {
+            return None;
+        }
+
+        let mut card = MemoryCard::disconnected();
+
+        card.memory = memory;
+
+        Some(card)
+    }
+
+    /// Create a new freshly formatted memory card
+    pub fn formatted() -> MemoryCard {
+        let mut memory = vec![0; CARD_SIZE];
+
+        format(&mut memory);
+
+        MemoryCard::new(memory).unwrap()
+    }
+
+    /// Return true if a card is plugged in
+    pub fn is_connected(&self) -> bool {
+        !self.memory.is_empty()
+    }
+
+    /// Return the raw contents of the card. The slice is empty if no
+    /// card is plugged in.
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Return true if the card has been written to since the last
+    /// call to `clear_dirty`. Can be used by the frontend to know when
+    /// the card needs to be saved.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn clear_dirty(&mut self) {
+        self.dirty = false;
+    }
+
+    /// Called when the "select" line goes down.
+    pub fn select(&mut self) {
+        // Prepare for incomming command
+        self.active = true;
+        self.seq = 0;
+        self.command = Command::None;
+    }
+
+    /// The first return value is the response byte. The 2nd return
+    /// value is true if the card issues a DSR pulse after the byte is
+    /// read to notify the controller that more data can be read.
+    pub fn send_command(&mut self, cmd: u8) -> (u8, bool) {
+        if !self.active || !self.is_connected() {
+            return (0xff, false);
+        }
+
+        let (resp, dsr) =
+            match self.seq {
+                // First byte must be 0x81 to address the memory card
+                0 => (0xff, cmd == 0x81),
+                1 => {
+                    self.command =
+                        match cmd {
+                            b'R' => Command::Read,
+                            b'W' => Command::Write,
+                            b'S' => Command::GetId,
+                            _ => Command::None,
+                        };
+
+                    (self.flag, self.command != Command::None)
+                }
+                // Card ID
+                2 => (0x5a, true),
+                3 => (0x5d, true),
+                n =>
+                    match self.command {
+                        Command::Read => self.read(n, cmd),
+                        Command::Write => self.write(n, cmd),
+                        Command::GetId => self.get_id(n),
+                        Command::None => unreachable!(),
+                    },
+            };
+
+        self.last_rx = cmd;
+
+        // If we're not asserting DSR it either means that we've
+        // encountered an error or that we have nothing else to
+        // reply. In either case we won't be handling any more command
+        // bytes in this transaction.
+        self.active = dsr;
+
+        self.seq += 1;
+
+        (resp, dsr)
+    }
+
+    /// Read sector sequence (command 0x52 'R'), starting at byte 4.
+    fn read(&mut self, seq: u8, cmd: u8) -> (u8, bool) {
+        match seq {
+            // Sector MSB
+            4 => {
+                self.sector = (cmd as u16) << 8;
+                (0x00, true)
+            }
+            // Sector LSB, reply with the previous byte
+            5 => {
+                self.sector |= cmd as u16;
+                (self.last_rx, true)
+            }
+            // Command acknowledge
+            6 => (0x5c, true),
+            7 => (0x5d, true),
+            // Confirmed sector MSB. If the sector is out of range
+            // the card replies 0xffff and stops.
+            8 =>
+                if self.sector < SECTOR_COUNT {
+                    ((self.sector >> 8) as u8, true)
+                } else {
+                    (0xff, true)
+                },
+            9 =>
+                if self.sector < SECTOR_COUNT {
+                    self.checksum = (self.sector >> 8) as u8 ^ self.sector as u8;
+
+                    (self.sector as u8, true)
+                } else {
+                    (0xff, false)
+                },
+            // Sector data
+            10...137 => {
+                let b = self.memory[self.sector_offset() + (seq - 10) as usize];
+
+                self.checksum ^= b;
+
+                (b, true)
+            }
+            138 => (self.checksum, true),
+            // End byte: 'G' for "good". No DSR for the last byte
+            139 => (b'G', false),
+            _ => (0xff, false),
+        }
+    }
+
+    /// Write sector sequence (command 0x57 'W'), starting at byte 4.
+    fn write(&mut self, seq: u8, cmd: u8) -> (u8, bool) {
+        match seq {
+            // Sector MSB
+            4 => {
+                self.sector = (cmd as u16) << 8;
+                (0x00, true)
+            }
+            // Sector LSB, from now on every reply is the previous
+            // byte received.
+            5 => {
+                self.sector |= cmd as u16;
+                self.checksum = (self.sector >> 8) as u8 ^ self.sector as u8;
+                (self.last_rx, true)
+            }
+            // Sector data
+            6...133 => {
+                self.write_buffer[(seq - 6) as usize] = cmd;
+                self.checksum ^= cmd;
+                (self.last_rx, true)
+            }
+            // Checksum
+            134 => {
+                // Store the result of the checksum comparison in
+                // `checksum`, 0 if it matches
+                self.checksum ^= cmd;
+                (self.last_rx, true)
+            }
+            // Command acknowledge
+            135 => (0x5c, true),
+            136 => (0x5d, true),
+            // End byte: 'G' for "good", 'N' for bad checksum, 0xff
+            // for bad sector. No DSR for the last byte
+            137 => {
+                let status =
+                    if self.sector >= SECTOR_COUNT {
+                        0xff
+                    } else if self.checksum != 0 {
+                        b'N'
+                    } else {
+                        let offset = self.sector_offset();
+
+                        self.memory[offset..offset + SECTOR_SIZE]
+                            .copy_from_slice(&self.write_buffer[..]);
+
+                        self.dirty = true;
+
+                        b'G'
+                    };
+
+                if status == b'G' {
+                    // The "new card" flag is cleared by the first
+                    // succesful write
+                    self.flag = 0;
+                } else {
+                    self.flag |= FLAG_WRITE_ERROR;
+                }
+
+                (status, false)
+            }
+            _ => (0xff, false),
+        }
+    }
+
+    /// Get ID sequence (command 0x53 'S'), starting at byte 4. Only
+    /// supported by the official Sony cards. The reply looks like
+    /// it's describing the card geometry.
+    fn get_id(&mut self, seq: u8) -> (u8, bool) {
+        match seq {
+            4 => (0x5c, true),
+            5 => (0x5d, true),
+            6 => (0x04, true),
+            7 => (0x00, true),
+            8 => (0x00, true),
+            9 => (0x80, false),
+            _ => (0xff, false),
+        }
+    }
+
+    fn sector_offset(&self) -> usize {
+        self.sector as usize * SECTOR_SIZE
+    }
+}
+
+/// Memory card command being processed
+#[derive(Clone, Copy, PartialEq, Eq, Debug, RustcDecodable, RustcEncodable)]
+enum Command {
+    None,
+    Read,
+    Write,
+    GetId,
+}
+
+/// Serializable buffer used to hold a sector's worth of data
+buffer!(struct SectorBuffer([u8; SECTOR_SIZE]));
+
+/// Format the raw memory card image `memory`: write the header, mark
+/// all the blocks as free and clear the broken sector list.
+pub fn format(memory: &mut [u8]) {
+    assert!(memory.len() == CARD_SIZE);
+
+    for b in memory.iter_mut() {
+        *b = 0;
+    }
+
+    // Header frame
+    memory[0] = b'M';
+    memory[1] = b'C';
+    update_frame_checksum(&mut memory[0..SECTOR_SIZE]);
+
+    // Directory frames: one per block, all free
+    for frame in 1..16 {
+        let f = &mut memory[frame * SECTOR_SIZE..(frame + 1) * SECTOR_SIZE];
+
+        // Block allocation state: free, never used
+        f[0] = 0xa0;
+        // Next block pointer: none
+        f[8] = 0xff;
+        f[9] = 0xff;
+
+        update_frame_checksum(f);
+    }
+
+    // Broken sector list: no broken sector
+    for frame in 16..36 {
+        let f = &mut memory[frame * SECTOR_SIZE..(frame + 1) * SECTOR_SIZE];
+
+        f[0] = 0xff;
+        f[1] = 0xff;
+        f[2] = 0xff;
+        f[3] = 0xff;
+        f[8] = 0xff;
+        f[9] = 0xff;
+
+        update_frame_checksum(f);
+    }
+
+    // The last frame of the first block is a copy of the header,
+    // used by the BIOS to test writes.
+    let (header, rest) = memory.split_at_mut(SECTOR_SIZE);
+
+    rest[62 * SECTOR_SIZE..63 * SECTOR_SIZE].copy_from_slice(header);
+}
+
+/// Compute the checksum of the system frame `frame` and store it in
+/// the last byte. The checksum is the XOR of all the other bytes.
+pub fn update_frame_checksum(frame: &mut [u8]) {
+    let last = SECTOR_SIZE - 1;
+
+    let checksum = frame[0..last].iter().fold(0, |c, &b| c ^ b);
+
+    frame[last] = checksum;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryCard, CARD_SIZE, SECTOR_SIZE};
+
+    /// Run a full transaction and return the `(response, dsr)` pairs
+    fn transfer(card: &mut MemoryCard, cmd: &[u8]) -> Vec<(u8, bool)> {
+        card.select();
+
+        cmd.iter().map(|&b| card.send_command(b)).collect()
+    }
+
+    /// Card whose contents are a simple pattern
+    fn card() -> MemoryCard {
+        let memory = (0..CARD_SIZE).map(|i| (i ^ (i >> 8)) as u8).collect();
+
+        MemoryCard::new(memory).unwrap()
+    }
+
+    #[test]
+    fn read() {
+        let mut card = card();
+
+        let mut cmd = vec![0x81, b'R', 0, 0, 0x01, 0x23, 0, 0, 0, 0];
+        cmd.extend_from_slice(&[0; SECTOR_SIZE + 2]);
+
+        let reply = transfer(&mut card, &cmd);
+
+        let offset = 0x123 * SECTOR_SIZE;
+        let data = card.memory()[offset..offset + SECTOR_SIZE].to_vec();
+
+        let checksum = data.iter().fold(0x01 ^ 0x23, |c, &b| c ^ b);
+
+        let mut expected = vec![0xff, 0x08, 0x5a, 0x5d, 0x00, 0x01,
+                                0x5c, 0x5d, 0x01, 0x23];
+        expected.extend_from_slice(&data);
+        expected.push(checksum);
+        expected.push(b'G');
+
+        let bytes: Vec<u8> = reply.iter().map(|&(b, _)| b).collect();
+
+        assert_eq!(bytes, expected);
+
+        // DSR is asserted for every byte but the last one
+        assert!(reply[..reply.len() - 1].iter().all(|&(_, dsr)| dsr));
+        assert!(!reply[reply.len() - 1].1);
+
+        // Reads don't clear the "new card" flag
+        assert_eq!(transfer(&mut card, &[0x81, b'R'])[1], (0x08, true));
+    }
+
+    #[test]
+    fn read_out_of_range() {
+        let mut card = card();
+
+        let reply = transfer(&mut card,
+                             &[0x81, b'R', 0, 0, 0x04, 0x00, 0, 0, 0, 0, 0]);
+
+        assert_eq!(&reply[8..],
+                   &[(0xff, true), (0xff, false), (0xff, false)]);
+    }
+
+    /// Build a write command for `sector`
+    fn write_cmd(sector: u16, data: &[u8], checksum: u8) -> Vec<u8> {
+        let mut cmd = vec![0x81, b'W', 0, 0,
+                           (sector >> 8) as u8, sector as u8];
+        cmd.extend_from_slice(data);
+        cmd.extend_from_slice(&[checksum, 0, 0, 0]);
+
+        cmd
+    }
+
+    #[test]
+    fn write() {
+        let mut card = card();
+
+        let data: Vec<u8> = (0..SECTOR_SIZE).map(|i| 0xff - i as u8).collect();
+        let checksum = data.iter().fold(0x05, |c, &b| c ^ b);
+
+        let before = card.memory().to_vec();
+
+        // Bad checksum
+        let reply = transfer(&mut card, &write_cmd(5, &data, !checksum));
+
+        assert_eq!(reply.len(), 138);
+        assert_eq!(&reply[..6],
+                   &[(0xff, true), (0x08, true), (0x5a, true),
+                     (0x5d, true), (0x00, true), (0x00, true)]);
+        // The data is echoed back one byte late
+        assert_eq!(reply[6], (0x05, true));
+        assert_eq!(reply[7], (data[0], true));
+        assert_eq!(reply[134], (data[SECTOR_SIZE - 1], true));
+        assert_eq!(&reply[135..],
+                   &[(0x5c, true), (0x5d, true), (b'N', false)]);
+
+        assert!(card.memory() == &before[..]);
+        assert!(!card.is_dirty());
+
+        // The error is reported in FLAG along with the "new card" bit
+        assert_eq!(transfer(&mut card, &[0x81, b'W'])[1], (0x0c, true));
+
+        // Good checksum
+        let reply = transfer(&mut card, &write_cmd(5, &data, checksum));
+
+        assert_eq!(reply[137], (b'G', false));
+
+        let offset = 5 * SECTOR_SIZE;
+
+        assert_eq!(&card.memory()[offset..offset + SECTOR_SIZE], &data[..]);
+        assert!(card.is_dirty());
+
+        // Both flags are cleared by the succesful write
+        assert_eq!(transfer(&mut card, &[0x81, b'R'])[1], (0x00, true));
+
+        // Bad sector
+        let reply = transfer(&mut card, &write_cmd(0x400, &data, checksum));
+
+        assert_eq!(reply[137], (0xff, false));
+    }
+
+    #[test]
+    fn get_id() {
+        let mut card = card();
+
+        let reply = transfer(&mut card, &[0x81, b'S', 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        assert_eq!(reply,
+                   vec![(0xff, true), (0x08, true), (0x5a, true),
+                        (0x5d, true), (0x5c, true), (0x5d, true),
+                        (0x04, true), (0x00, true), (0x00, true),
+                        (0x80, false)]);
+    }
+
+    #[test]
+    fn bad_command() {
+        let mut card = card();
+
+        // Unknown command
+        assert_eq!(transfer(&mut card, &[0x81, b'X', 0]),
+                   vec![(0xff, true), (0x08, false), (0xff, false)]);
+
+        // Gamepad address
+        assert_eq!(transfer(&mut card, &[0x01, 0x42]),
+                   vec![(0xff, false), (0xff, false)]);
+
+        // No card
+        let mut empty = MemoryCard::disconnected();
+
+        assert_eq!(transfer(&mut empty, &[0x81, b'R']),
+                   vec![(0xff, false), (0xff, false)]);
+    }
+}
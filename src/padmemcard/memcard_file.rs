@@ -0,0 +1,512 @@
+//! Memory card image import and export.
+//!
+//! Supported full card formats:
+//!
+//! * Raw 128KiB dumps (.mcr, .mcd, .srm...)
+//! * DexDrive (.gme): 3904 byte header with a comment per block
+//! * PSP (.vmp): 128 byte header with a signature
+//!
+//! Supported single save formats:
+//!
+//! * .mcs: the save's directory frame followed by the data
+//! * Action Replay/GameShark (.psx): 54 byte header with the file
+//!   name and a comment
+//! * PS3 (.psv): 132 byte header with a signature
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::memcard::{self, MemoryCard, CARD_SIZE, SECTOR_SIZE};
+use super::mcfs::{self, BLOCK_SIZE, SAVE_BLOCKS, NAME_LEN, BLOCK_FIRST};
+use super::mcfs::{c_string, directory_frame};
+
+/// DexDrive header magic
+const GME_MAGIC: &'static [u8] = b"123-456-STD";
+/// DexDrive header size
+const GME_HEADER_LEN: usize = 0xf40;
+/// Length of each per-block comment in the DexDrive header
+const GME_COMMENT_LEN: usize = 256;
+
+/// PSP header magic
+const VMP_MAGIC: &'static [u8] = b"\0PMV";
+/// PSP header size
+const VMP_HEADER_LEN: usize = 0x80;
+
+/// PS3 header magic
+const PSV_MAGIC: &'static [u8] = b"\0VSP";
+/// PS3 header size
+const PSV_HEADER_LEN: usize = 0x84;
+
+/// Action Replay header size
+const AR_HEADER_LEN: usize = 54;
+
+/// Memory card file formats
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    /// Raw 128KiB dump
+    Raw,
+    /// DexDrive
+    Gme,
+    /// PSP virtual memory card
+    Vmp,
+    /// Single save with its directory frame
+    Mcs,
+    /// Action Replay/GameShark single save
+    ActionReplay,
+    /// PS3 single save
+    Psv,
+}
+
+impl Format {
+    /// Guess the format from the file extension
+    pub fn from_extension(path: &Path) -> Option<Format> {
+        let ext =
+            match path.extension().and_then(|e| e.to_str()) {
+                Some(e) => e.to_lowercase(),
+                None => return None,
+            };
+
+        let format =
+            match &*ext {
+                "mcr" | "mcd" | "srm" | "mc" | "ddf" | "mem" =>
+                    Format::Raw,
+                "gme" => Format::Gme,
+                "vmp" => Format::Vmp,
+                "mcs" => Format::Mcs,
+                "psx" | "mcb" => Format::ActionReplay,
+                "psv" => Format::Psv,
+                _ => return None,
+            };
+
+        Some(format)
+    }
+
+    /// Detect the format from the contents of a file
+    pub fn detect(data: &[u8]) -> Option<Format> {
+        if data.starts_with(GME_MAGIC) {
+            Some(Format::Gme)
+        } else if data.starts_with(VMP_MAGIC) {
+            Some(Format::Vmp)
+        } else if data.starts_with(PSV_MAGIC) {
+            Some(Format::Psv)
+        } else if data.len() == CARD_SIZE {
+            Some(Format::Raw)
+        } else if is_save_len(data, SECTOR_SIZE) &&
+            data[0] == BLOCK_FIRST {
+            Some(Format::Mcs)
+        } else if is_save_len(data, AR_HEADER_LEN) {
+            Some(Format::ActionReplay)
+        } else {
+            None
+        }
+    }
+
+    /// Return true if the format contains a single save instead of a
+    /// full card image
+    pub fn is_single_save(self) -> bool {
+        match self {
+            Format::Raw | Format::Gme | Format::Vmp => false,
+            Format::Mcs | Format::ActionReplay | Format::Psv => true,
+        }
+    }
+}
+
+/// Return true if `data` contains a header of `header_len` bytes
+/// followed by a whole number of blocks
+fn is_save_len(data: &[u8], header_len: usize) -> bool {
+    if data.len() <= header_len {
+        return false;
+    }
+
+    let data_len = data.len() - header_len;
+
+    data_len % BLOCK_SIZE == 0 && data_len / BLOCK_SIZE <= SAVE_BLOCKS
+}
+
+/// Load a memory card file. The format is detected from the contents
+/// of the file. Single saves are imported into a freshly formatted
+/// card.
+pub fn load(path: &Path) -> Result<MemoryCard, Error> {
+    let mut f = try!(File::open(path));
+
+    let mut data = Vec::new();
+
+    try!(f.read_to_end(&mut data));
+
+    let memory = try!(import(&data));
+
+    Ok(MemoryCard::new(memory).unwrap())
+}
+
+/// Convert the contents of a memory card file into a raw 128KiB
+/// image
+pub fn import(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let format =
+        match Format::detect(data) {
+            Some(f) => f,
+            None => return Err(Error::UnknownFormat),
+        };
+
+    if format.is_single_save() {
+        let mut memory = vec![0; CARD_SIZE];
+
+        memcard::format(&mut memory);
+
+        try!(import_save(&mut memory, data));
+
+        return Ok(memory);
+    }
+
+    let offset =
+        match format {
+            Format::Raw => 0,
+            Format::Gme => GME_HEADER_LEN,
+            Format::Vmp => VMP_HEADER_LEN,
+            _ => unreachable!(),
+        };
+
+    if data.len() < offset + CARD_SIZE {
+        return Err(Error::BadSize(data.len()));
+    }
+
+    Ok(data[offset..offset + CARD_SIZE].into())
+}
+
+/// Convert the raw 128KiB image `memory` into a full card `format`
+pub fn export(memory: &[u8], format: Format) -> Result<Vec<u8>, Error> {
+    if memory.len() != CARD_SIZE {
+        return Err(Error::BadSize(memory.len()));
+    }
+
+    match format {
+        Format::Raw => Ok(memory.into()),
+        Format::Gme => Ok(export_gme(memory, &[])),
+        Format::Vmp => {
+            let mut out = vec![0; VMP_HEADER_LEN];
+
+            out[0..4].copy_from_slice(VMP_MAGIC);
+            out[4] = VMP_HEADER_LEN as u8;
+
+            // XXX We don't generate the salt seed at 0x0c and the
+            // signature at 0x20, other emulators don't check them
+            // but a real PSP will reject the card.
+
+            out.extend_from_slice(memory);
+
+            Ok(out)
+        }
+        _ => Err(Error::SingleSaveFormat(format)),
+    }
+}
+
+/// Export `memory` in the DexDrive format. `comments` contains the
+/// optional comments for each of the 15 save blocks.
+pub fn export_gme(memory: &[u8], comments: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = vec![0; GME_HEADER_LEN];
+
+    out[0..GME_MAGIC.len()].copy_from_slice(GME_MAGIC);
+
+    out[0x12] = 0x01;
+    out[0x14] = 0x01;
+    out[0x15] = b'M';
+
+    // Copy of the state and "next block" LSB of each directory frame
+    for block in 0..SAVE_BLOCKS {
+        let frame = directory_frame(memory, block);
+
+        out[0x16 + block] = frame[0];
+        out[0x26 + block] = frame[8];
+    }
+
+    for (block, comment) in comments.iter().take(SAVE_BLOCKS).enumerate() {
+        let start = 0x40 + block * GME_COMMENT_LEN;
+
+        // Keep the last byte for the NUL
+        let len = ::std::cmp::min(comment.len(), GME_COMMENT_LEN - 1);
+
+        out[start..start + len].copy_from_slice(&comment[..len]);
+    }
+
+    out.extend_from_slice(memory);
+
+    out
+}
+
+/// Return the per-block comments stored in a DexDrive file header
+pub fn gme_comments(data: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    if !data.starts_with(GME_MAGIC) || data.len() < GME_HEADER_LEN {
+        return Err(Error::UnknownFormat);
+    }
+
+    let comments =
+        (0..SAVE_BLOCKS).map(|block| {
+            let start = 0x40 + block * GME_COMMENT_LEN;
+
+            c_string(&data[start..start + GME_COMMENT_LEN]).into()
+        }).collect();
+
+    Ok(comments)
+}
+
+/// Export the save starting at `block` (0-based, not counting the
+/// directory block) as a single save in `format`
+pub fn export_save(memory: &[u8],
+                   block: usize,
+                   format: Format) -> Result<Vec<u8>, Error> {
+    if memory.len() != CARD_SIZE {
+        return Err(Error::BadSize(memory.len()));
+    }
+
+    let save = try!(mcfs::save(memory, block));
+
+    let name = save.file_name();
+    let data = mcfs::save_data(memory, &save);
+
+    let mut out =
+        match format {
+            Format::Mcs => directory_frame(memory, block).to_vec(),
+            Format::ActionReplay => {
+                let mut header = vec![0; AR_HEADER_LEN];
+
+                header[..name.len()].copy_from_slice(name);
+
+                header
+            }
+            Format::Psv => {
+                let mut header = vec![0; PSV_HEADER_LEN];
+
+                header[0..4].copy_from_slice(PSV_MAGIC);
+
+                // XXX We don't generate the key seed at 0x08 and the
+                // signature at 0x1c, a real PS3 will refuse the save
+                // until it's re-signed.
+
+                put_u32(&mut header[0x38..], 0x14);
+                // PlayStation 1 save
+                put_u32(&mut header[0x3c..], 1);
+                put_u32(&mut header[0x40..], data.len() as u32);
+                put_u32(&mut header[0x44..], PSV_HEADER_LEN as u32);
+                put_u32(&mut header[0x48..], 0x200);
+                put_u32(&mut header[0x50..], 0x2000);
+
+                header[0x64..0x64 + name.len()].copy_from_slice(name);
+
+                header
+            }
+            _ => return Err(Error::FullCardFormat(format)),
+        };
+
+    out.extend_from_slice(&data);
+
+    Ok(out)
+}
+
+/// Import the single save file `save` into the free blocks of the
+/// raw card image `memory`
+pub fn import_save(memory: &mut [u8], save: &[u8]) -> Result<(), Error> {
+    let format =
+        match Format::detect(save) {
+            Some(f) if f.is_single_save() => f,
+            Some(f) => return Err(Error::FullCardFormat(f)),
+            None => return Err(Error::UnknownFormat),
+        };
+
+    let (name, data) =
+        match format {
+            Format::Mcs =>
+                (c_string(&save[0x0a..0x0a + NAME_LEN + 1]),
+                 &save[SECTOR_SIZE..]),
+            Format::ActionReplay =>
+                (c_string(&save[0..NAME_LEN + 1]),
+                 &save[AR_HEADER_LEN..]),
+            Format::Psv => {
+                if save.len() < PSV_HEADER_LEN {
+                    return Err(Error::BadSize(save.len()));
+                }
+
+                let len = get_u32(&save[0x40..]) as usize;
+                let offset = get_u32(&save[0x44..]) as usize;
+
+                if offset.checked_add(len).map_or(true, |end| end > save.len()) {
+                    return Err(Error::BadSize(save.len()));
+                }
+
+                (c_string(&save[0x64..0x64 + NAME_LEN + 1]),
+                 &save[offset..offset + len])
+            }
+            _ => unreachable!(),
+        };
+
+    try!(mcfs::insert(memory, name, data));
+
+    Ok(())
+}
+
+/// Save `card` to `path` in `format`. The card is first written to a
+/// temporary file which is then renamed over the target, that way
+/// the original file is left untouched if something goes wrong.
+pub fn save(card: &MemoryCard, path: &Path, format: Format) -> Result<(), Error> {
+    let data = try!(export(card.memory(), format));
+
+    write_atomic(path, &data)
+}
+
+/// Write `data` to `path` through a temporary file
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+
+    let tmp_path = PathBuf::from(tmp_name);
+
+    let res =
+        File::create(&tmp_path).and_then(|mut f| {
+            try!(f.write_all(data));
+            // Make sure the data actually hits the disk before the
+            // rename
+            f.sync_all()
+        }).and_then(|_| fs::rename(&tmp_path, path));
+
+    if let Err(e) = res {
+        let _ = fs::remove_file(&tmp_path);
+
+        return Err(Error::IoError(e));
+    }
+
+    Ok(())
+}
+
+fn get_u32(b: &[u8]) -> u32 {
+    b[0] as u32 | ((b[1] as u32) << 8) | ((b[2] as u32) << 16) | ((b[3] as u32) << 24)
+}
+
+fn put_u32(b: &mut [u8], v: u32) {
+    b[0] = v as u8;
+    b[1] = (v >> 8) as u8;
+    b[2] = (v >> 16) as u8;
+    b[3] = (v >> 24) as u8;
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Error while reading or writing the file
+    IoError(io::Error),
+    /// Couldn't identify the file format
+    UnknownFormat,
+    /// The file or image has an unexpected size
+    BadSize(usize),
+    /// A full card operation was attempted with a single save format
+    SingleSaveFormat(Format),
+    /// A single save operation was attempted with a full card format
+    FullCardFormat(Format),
+    /// Memory card filesystem error
+    Mcfs(mcfs::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::IoError(err)
+    }
+}
+
+impl From<mcfs::Error> for Error {
+    fn from(err: mcfs::Error) -> Error {
+        Error::Mcfs(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memcard::{self, CARD_SIZE};
+    use super::super::mcfs::{self, BLOCK_SIZE};
+
+    const SAVE_NAME: &'static [u8] = b"BASCUS-94163FF7";
+
+    /// Build a formatted card containing a two block save
+    fn card() -> (Vec<u8>, Vec<u8>) {
+        let mut memory = vec![0; CARD_SIZE];
+
+        memcard::format(&mut memory);
+
+        let data: Vec<u8> =
+            (0..2 * BLOCK_SIZE).map(|i| (i * 7 + i / 256) as u8).collect();
+
+        // Put the save in the second block to make sure the export
+        // doesn't assume it starts at the beginning of the card
+        mcfs::insert(&mut memory, b"BASLUS-00000", &[0; BLOCK_SIZE]).unwrap();
+        mcfs::insert(&mut memory, SAVE_NAME, &data).unwrap();
+
+        (memory, data)
+    }
+
+    #[test]
+    fn full_card_round_trip() {
+        let (memory, _) = card();
+
+        for &format in &[Format::Raw, Format::Gme, Format::Vmp] {
+            let exported = export(&memory, format).unwrap();
+
+            assert_eq!(Format::detect(&exported), Some(format));
+            assert!(import(&exported).unwrap() == memory);
+        }
+    }
+
+    #[test]
+    fn gme_comments_round_trip() {
+        let (memory, _) = card();
+
+        let comments = vec![b"first".to_vec(), Vec::new(), b"third".to_vec()];
+
+        let exported = export_gme(&memory, &comments);
+
+        let read = gme_comments(&exported).unwrap();
+
+        assert_eq!(read.len(), SAVE_BLOCKS);
+        assert_eq!(&read[..3], &comments[..]);
+        assert!(read[3..].iter().all(|c| c.is_empty()));
+    }
+
+    #[test]
+    fn single_save_round_trip() {
+        let (memory, data) = card();
+
+        for &format in &[Format::Mcs, Format::ActionReplay, Format::Psv] {
+            let exported = export_save(&memory, 1, format).unwrap();
+
+            assert_eq!(Format::detect(&exported), Some(format));
+
+            let imported = import(&exported).unwrap();
+
+            let saves = mcfs::saves(&imported).unwrap();
+
+            assert_eq!(saves.len(), 1);
+            assert_eq!(saves[0].file_name(), SAVE_NAME);
+            assert_eq!(saves[0].size(), data.len() as u32);
+            assert!(mcfs::save_data(&imported, &saves[0]) == data);
+        }
+    }
+
+    #[test]
+    fn format_mismatch() {
+        let (memory, _) = card();
+
+        match export(&memory, Format::Mcs) {
+            Err(Error::SingleSaveFormat(Format::Mcs)) => (),
+            r => panic!("Unexpected result {:?}", r.map(|_| ())),
+        }
+
+        match export_save(&memory, 1, Format::Gme) {
+            Err(Error::FullCardFormat(Format::Gme)) => (),
+            r => panic!("Unexpected result {:?}", r.map(|_| ())),
+        }
+
+        let raw = export(&memory, Format::Raw).unwrap();
+        let mut blank = vec![0; CARD_SIZE];
+
+        match import_save(&mut blank, &raw) {
+            Err(Error::FullCardFormat(Format::Raw)) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+}
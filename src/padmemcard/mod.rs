@@ -7,8 +7,17 @@ use shared::SharedState;
 use tracer::module_tracer;
 
 use self::gamepad::GamePad;
+use self::memcard::MemoryCard;
+use self::multitap::Multitap;
 
 pub mod gamepad;
+pub mod memcard;
+pub mod memcard_file;
+pub mod mcfs;
+pub mod multitap;
+pub mod lightgun;
+pub mod mouse;
+pub mod negcon;
 
 #[derive(RustcDecodable, RustcEncodable)]
 pub struct PadMemCard {
@@ -18,10 +27,19 @@ pub struct PadMemCard {
     /// every `baud_div` which effectively means that the resulting
     /// frequency is CPU clock / (`baud_div` & 0xfe).
     baud_div: u16,
-    /// Serial config, not implemented for now...
-    mode: u8,
+    /// Serial config: bits [1:0] are the baudrate reload factor,
+    /// bits [3:2] the character length, bits 4 and 5 configure the
+    /// parity and bit 8 the clock polarity. Only the reload factor
+    /// and character length have an effect.
+    mode: u16,
+    /// Baudrate timer, counts down at the CPU clock rate and is
+    /// reloaded with half the baudrate period
+    baud_timer: u32,
     /// Transmission enabled if true
     tx_en: bool,
+    /// TX holding register, the byte waiting to be sent once the bus
+    /// is free
+    tx_data: Option<u8>,
     /// If true the targeted peripheral select signal is asserted (the
     /// actual signal is active low, so it's driving low on the
     /// controller port when `select` is true). The `target` field
@@ -34,28 +52,40 @@ pub struct PadMemCard {
     /// Control register bits 3 and 5 are read/write but I don't know
     /// what they do. I just same them here for accurate readback.
     unknown: u8,
-    /// XXX not sure what this does exactly, forces a read without any
-    /// TX?
+    /// Force the reception of the next byte even if `select` is
+    /// false. Automatically cleared once the byte is received.
     rx_en: bool,
     /// Data Set Ready signal, active low (driven by the gamepad)
     dsr: bool,
     /// If true an interrupt is generated when a DSR pulse is received
     /// from the pad/memory card
     dsr_it: bool,
+    /// If true an interrupt is generated when the RX FIFO contains
+    /// at least `1 << rx_it_mode` bytes
+    rx_it: bool,
+    /// RX interrupt threshold
+    rx_it_mode: u8,
+    /// If true an interrupt is generated when the TX holding register
+    /// becomes empty
+    tx_it: bool,
     /// Current interrupt level
     interrupt: bool,
-    /// Current response byte.
-    /// XXX Normally it should be a FIFO but I'm not sure how it works
-    /// really. Besides the game should check for the response after
-    /// each byte anyway, so it's probably unused the vast majority of
-    /// times.
-    response: u8,
-    /// True when we the RX FIFO is not empty.
-    rx_not_empty: bool,
+    /// Received bytes
+    rx_fifo: RxFifo,
     /// Gamepad in slot 1
     pad1: GamePad,
     /// Gamepad in slot 2
     pad2: GamePad,
+    /// Memory card in slot 1
+    memcard1: MemoryCard,
+    /// Memory card in slot 2
+    memcard2: MemoryCard,
+    /// Multitap plugged in port 1. When present `pad1` and `memcard1`
+    /// are unreachable.
+    multitap1: Option<Multitap>,
+    /// Multitap plugged in port 2. When present `pad2` and `memcard2`
+    /// are unreachable.
+    multitap2: Option<Multitap>,
     /// Bus state machine
     bus: BusState,
 }
@@ -65,7 +95,9 @@ impl PadMemCard {
         PadMemCard {
             baud_div: 0,
             mode: 0,
+            baud_timer: 0,
             tx_en: false,
+            tx_data: None,
             select: false,
             target: Target::PadMemCard1,
             interrupt: false,
@@ -73,10 +105,16 @@ impl PadMemCard {
             rx_en: false,
             dsr: false,
             dsr_it: false,
-            response: 0xff,
-            rx_not_empty: false,
+            rx_it: false,
+            rx_it_mode: 0,
+            tx_it: false,
+            rx_fifo: RxFifo::new(),
             pad1: GamePad::disconnected(),
             pad2: GamePad::disconnected(),
+            memcard1: MemoryCard::disconnected(),
+            memcard2: MemoryCard::disconnected(),
+            multitap1: None,
+            multitap2: None,
             bus: BusState::Idle,
         }
     }
@@ -98,14 +136,17 @@ impl PadMemCard {
 
         match offset {
             0  => {
-                if T::size() != 1 {
-                    panic!("Unhandled gamepad TX access ({})",
-                           T::size());
+                if self.tx_data.is_some() {
+                    warn!("Gamepad TX while holding register is full");
                 }
 
-                self.send_command(shared, val as u8);
+                // Only the low byte is sent, whatever the size of the
+                // access
+                self.tx_data = Some(val as u8);
+
+                self.maybe_start_transfer(shared);
             }
-            8  => self.set_mode(val as u8),
+            8  => self.set_mode(val as u16),
             10 => {
                 if T::size() == 1 {
                     // Byte access behaves like a halfword
@@ -113,10 +154,16 @@ impl PadMemCard {
                 }
                 self.set_control(shared, val as u16);
             }
-            14 => self.baud_div = val as u16,
+            14 => {
+                self.baud_div = val as u16;
+                // Writing the divider reloads the timer
+                self.baud_timer = self.baud_reload();
+            }
             _ => panic!("Unhandled write to gamepad register {} {:04x}",
                         offset, val as u16),
         }
+
+        self.predict_next_sync(shared);
     }
 
     pub fn load<T: Addressable>(&mut self,
@@ -134,17 +181,22 @@ impl PadMemCard {
 
         match offset {
             0 => {
-                if T::size() != 1 {
-                    panic!("Unhandled gamepad RX access ({})",
-                           T::size());
-                }
+                // Wider accesses return the following FIFO entries
+                // in the high bytes but only pop one byte
+                let mut res = 0;
 
-                let res = self.response as u32;
+                for i in 1..T::size() as usize {
+                    res |= (self.rx_fifo.peek(i) as u32) << (i * 8);
+                }
 
-                self.rx_not_empty = false;
-                self.response = 0xff;
+                let b =
+                    match self.rx_fifo.pop() {
+                        Some(b) => b,
+                        // XXX What's the proper behaviour here?
+                        None => 0xff,
+                    };
 
-                res
+                res | b as u32
             }
             4 => {
                 self.stat()
@@ -160,84 +212,165 @@ impl PadMemCard {
     pub fn sync(&mut self,
                 shared: &mut SharedState) {
 
-        let delta = shared.tk().sync(Peripheral::PadMemCard);
+        let mut delta = shared.tk().sync(Peripheral::PadMemCard);
 
-        match self.bus {
-            BusState::Idle =>
-                shared.tk().no_sync_needed(Peripheral::PadMemCard),
-            BusState::Transfer(r, dsr, delay) => {
-                if delta < delay {
-                    let delay = delay - delta;
-                    self.bus = BusState::Transfer(r, dsr, delay);
-
-                    if self.dsr_it {
-                        shared.tk().set_next_sync_delta(Peripheral::PadMemCard,
-                                                        delay);
-                    } else {
-                        shared.tk().no_sync_needed(Peripheral::PadMemCard);
-                    }
-                } else {
-                    // We reached the end of the transfer
+        self.update_baud_timer(delta);
 
-                    if self.rx_not_empty {
-                        // XXX should push in the non-emulated RX FIFO
-                        // instead of overwritting `self.response`
-                        panic!("Gamepad RX while FIFO isn't empty");
+        // Several transfers may have completed since the last sync if
+        // bytes were queued in the TX holding register
+        loop {
+            match self.bus {
+                BusState::Idle => {
+                    // If a byte is waiting in the TX holding register
+                    // the next transfer starts as soon as the bus
+                    // becomes idle, the remaining `delta` is applied
+                    // to it in the next iteration.
+                    if !self.maybe_start_transfer(shared) {
+                        break;
+                    }
+                }
+                BusState::Transfer(r, dsr, delay) => {
+                    if delta < delay {
+                        self.bus = BusState::Transfer(r, dsr, delay - delta);
+                        break;
                     }
 
-                    self.response = r;
-                    self.rx_not_empty = true;
-                    self.dsr = dsr;
-
-                    if self.dsr {
-                        if self.dsr_it {
-                            if !self.interrupt {
-                                // Rising edge of the interrupt
-                                let irq_state = shared.irq_state_mut();
-
-                                irq_state.assert(Interrupt::PadMemCard);
-                            }
-
-                            self.interrupt = true;
-                        }
+                    delta -= delay;
 
-                        // The DSR pulse is generated purely by the
-                        // controller without any input from the
-                        // console. Therefore the actual length of the
-                        // pulse changes from controller to
-                        // controller. I have two seemingly identical
-                        // SCPH-1080 controllers, one pulses the DSR
-                        // line for ~100CPU cycles while the other one
-                        // is slightly faster at around ~90 CPU
-                        // cycles.
-
-                        // XXX Because of timing inaccuracies
-                        // throughout the emulator I can't use the
-                        // proper timing otherwise the BIOS attempts
-                        // to ack the interrupt while DSR is still
-                        // active.
-                        let dsr_duration = 10;
-                        self.bus = BusState::Dsr(dsr_duration);
-                    } else {
-                        // We're done with this transaction
-                        self.bus = BusState::Idle;
+                    self.end_transfer(shared, r, dsr);
+                }
+                BusState::Dsr(delay) => {
+                    if delta < delay {
+                        self.bus = BusState::Dsr(delay - delta);
+                        break;
                     }
 
-                    shared.tk().no_sync_needed(Peripheral::PadMemCard);
-                }
-            }
-            BusState::Dsr(delay) => {
-                if delta < delay {
-                    let delay = delay - delta;
-                    self.bus = BusState::Dsr(delay);
-                } else {
+                    delta -= delay;
+
                     // DSR pulse is over, bus is idle
                     self.dsr = false;
                     self.bus = BusState::Idle;
                 }
-                shared.tk().no_sync_needed(Peripheral::PadMemCard);
             }
         }
+
+        self.predict_next_sync(shared);
+    }
+
+    /// Schedule the next sync if we need to trigger an interrupt
+    fn predict_next_sync(&self, shared: &mut SharedState) {
+        let interrupts = self.dsr_it || self.rx_it || self.tx_it;
+
+        match self.bus {
+            BusState::Transfer(_, _, delay) | BusState::Dsr(delay)
+                if interrupts =>
+                shared.tk().set_next_sync_delta(Peripheral::PadMemCard,
+                                                delay),
+            _ => shared.tk().no_sync_needed(Peripheral::PadMemCard),
+        }
+    }
+
+    /// Called when the last bit of a byte has been transferred
+    fn end_transfer(&mut self, shared: &mut SharedState, r: u8, dsr: bool) {
+        if self.select || self.rx_en {
+            // If the character length is less than 8 bits we don't
+            // receive the MSBs
+            let r = r & (0xff >> (8 - self.char_len()));
+
+            if !self.rx_fifo.push(r) {
+                warn!("Gamepad RX FIFO overflow");
+            }
+
+            self.rx_en = false;
+
+            if self.rx_it && self.rx_fifo.len() >= 1 << self.rx_it_mode {
+                self.raise_interrupt(shared);
+            }
+        }
+
+        self.dsr = dsr;
+
+        if self.dsr {
+            if self.dsr_it {
+                self.raise_interrupt(shared);
+            }
+
+            // The DSR pulse is generated purely by the
+            // controller without any input from the
+            // console. Therefore the actual length of the
+            // pulse changes from controller to
+            // controller. I have two seemingly identical
+            // SCPH-1080 controllers, one pulses the DSR
+            // line for ~100CPU cycles while the other one
+            // is slightly faster at around ~90 CPU
+            // cycles.
+
+            // XXX Because of timing inaccuracies
+            // throughout the emulator I can't use the
+            // proper timing otherwise the BIOS attempts
+            // to ack the interrupt while DSR is still
+            // active.
+            let dsr_duration = 10;
+            self.bus = BusState::Dsr(dsr_duration);
+        } else {
+            // We're done with this transaction
+            self.bus = BusState::Idle;
+        }
+    }
+
+    fn raise_interrupt(&mut self, shared: &mut SharedState) {
+        if !self.interrupt {
+            // Rising edge of the interrupt
+            shared.irq_state_mut().assert(Interrupt::PadMemCard);
+        }
+
+        self.interrupt = true;
+    }
+
+    /// Baudrate reload factor set in the mode register
+    fn baud_factor(&self) -> u32 {
+        match self.mode & 3 {
+            2 => 16,
+            3 => 64,
+            // 0 and 1 both select a factor of 1
+            _ => 1,
+        }
+    }
+
+    /// Character length in bits, between 5 and 8
+    fn char_len(&self) -> u32 {
+        5 + ((self.mode >> 2) & 3) as u32
+    }
+
+    /// Value loaded in the baudrate timer when it reaches 0
+    fn baud_reload(&self) -> u32 {
+        (self.baud_div as u32 * self.baud_factor()) / 2
+    }
+
+    fn update_baud_timer(&mut self, delta: Cycles) {
+        let reload = self.baud_reload() as Cycles;
+
+        if reload == 0 {
+            self.baud_timer = 0;
+            return;
+        }
+
+        let timer =
+            match self.baud_timer as Cycles {
+                0 => reload,
+                t => t,
+            };
+
+        let elapsed = delta % reload;
+
+        let timer =
+            if elapsed < timer {
+                timer - elapsed
+            } else {
+                timer + reload - elapsed
+            };
+
+        self.baud_timer = timer as u32;
     }
 
     /// Return a mutable reference to the gamepad profiles being used.
@@ -245,59 +378,119 @@ impl PadMemCard {
         [ &mut self.pad1, &mut self.pad2 ]
     }
 
-    fn send_command(&mut self, shared: &mut SharedState, cmd: u8) {
-        if !self.tx_en {
-            // It should be stored in the FIFO and sent when tx_en is
-            // set (I think)
-            panic!("Unhandled gamepad command while tx_en is disabled");
+    /// Return a mutable reference to the memory cards. A card can be
+    /// inserted or removed by replacing it with a new `MemoryCard`.
+    pub fn memory_cards_mut(&mut self) -> [&mut MemoryCard; 2] {
+        [ &mut self.memcard1, &mut self.memcard2 ]
+    }
+
+    /// Plug (or unplug if `multitap` is `None`) a multitap in `port`
+    /// (0 or 1)
+    pub fn set_multitap(&mut self, port: usize, multitap: Option<Multitap>) {
+        match port {
+            0 => self.multitap1 = multitap,
+            1 => self.multitap2 = multitap,
+            _ => panic!("Invalid controller port {}", port),
         }
+    }
 
-        if self.bus.is_busy() {
-            // I suppose the transfer should be queued in the TX FIFO?
-            warn!("Gamepad command {:x} while bus is busy!", cmd);
+    /// Return a mutable reference to the multitaps plugged in each
+    /// port, if any
+    pub fn multitaps_mut(&mut self) -> [Option<&mut Multitap>; 2] {
+        [ self.multitap1.as_mut(), self.multitap2.as_mut() ]
+    }
+
+    /// Start sending the contents of the TX holding register if
+    /// transmission is enabled and the bus is free. Returns true if a
+    /// transfer was started.
+    fn maybe_start_transfer(&mut self, shared: &mut SharedState) -> bool {
+        if !self.tx_en || self.bus.is_busy() {
+            return false;
         }
 
-        let (response, dsr) =
+        let cmd =
+            match self.tx_data.take() {
+                Some(c) => c,
+                None => return false,
+            };
+
+        if self.tx_it {
+            // The holding register is ready to accept a new byte
+            self.raise_interrupt(shared);
+        }
+
+        self.start_transfer(shared, cmd);
+
+        true
+    }
+
+    fn start_transfer(&mut self, shared: &mut SharedState, cmd: u8) {
+        let (response, dsr, dsr_delay) =
             if self.select {
-                match self.target {
-                    Target::PadMemCard1 => self.pad1.send_command(cmd),
-                    Target::PadMemCard2 => self.pad2.send_command(cmd),
+                let (pad, memcard, multitap) =
+                    match self.target {
+                        Target::PadMemCard1 => (&mut self.pad1,
+                                                &mut self.memcard1,
+                                                &mut self.multitap1),
+                        Target::PadMemCard2 => (&mut self.pad2,
+                                                &mut self.memcard2,
+                                                &mut self.multitap2),
+                    };
+
+                match *multitap {
+                    Some(ref mut tap) => tap.send_command(cmd),
+                    None => bus_send_command(pad, memcard, cmd),
                 }
             } else {
                 // No response
-                (0xff, false)
+                (0xff, false, 0)
             };
 
-        // XXX Handle `mode` as well, especially the "baudrate reload
-        // factor". For now I assume we're sending 8 bits, one every
-        // `baud_div` CPU cycles.
-        let tx_duration = 8 * self.baud_div as Cycles;
+        let bit_duration =
+            self.baud_div as Cycles * self.baud_factor() as Cycles;
+
+        let tx_duration = self.char_len() as Cycles * bit_duration + dsr_delay;
 
         self.bus = BusState::Transfer(response, dsr, tx_duration);
 
-        // XXX For now pretend that the DSR pulse follows
-        // immediately after the last byte, probably not accurate.
-        shared.tk().set_next_sync_delta(Peripheral::PadMemCard, tx_duration);
+        // The Justifier may have changed its interrupt configuration.
+        // XXX if the GPU already scheduled its next sync past the
+        // new target the interrupt will be late.
+        let lightgun_target =
+            self.pad1.profile().lightgun_target()
+            .or_else(|| self.pad2.profile().lightgun_target());
+
+        shared.set_lightgun_target(lightgun_target);
     }
 
     fn stat(&self) -> u32 {
         let mut stat = 0u32;
 
-        // TX Ready bits 1 and 2 (Not sure when they go low)
-        stat |= 5;
-        stat |= (self.rx_not_empty as u32) << 1;
+        // TX Ready 1: the holding register is empty
+        let tx_ready1 = self.tx_data.is_none();
+        // TX Ready 2: the holding register is empty and no transfer
+        // is taking place
+        let tx_ready2 =
+            tx_ready1 &&
+            match self.bus {
+                BusState::Transfer(..) => false,
+                _ => true,
+            };
+
+        stat |= tx_ready1 as u32;
+        stat |= (!self.rx_fifo.is_empty() as u32) << 1;
+        stat |= (tx_ready2 as u32) << 2;
         // RX parity error should always be 0 in our case.
         stat |= 0 << 3;
         stat |= (self.dsr as u32) << 7;
         stat |= (self.interrupt as u32) << 9;
-        // XXX needs to add the baudrate counter in bits [31:11];
-        stat |= 0 << 11;
+        stat |= (self.baud_timer & 0x1fffff) << 11;
 
         stat
     }
 
-    fn set_mode(&mut self, mode: u8) {
-        self.mode = mode;
+    fn set_mode(&mut self, mode: u16) {
+        self.mode = mode & 0x13f;
     }
 
     fn control(&self) -> u16 {
@@ -307,9 +500,10 @@ impl PadMemCard {
 
         ctrl |= (self.tx_en  as u16) << 0;
         ctrl |= (self.select as u16) << 1;
-        // XXX I assume this flag self-resets? When?
         ctrl |= (self.rx_en as u16) << 2;
-        // XXX Add other interrupts when they're implemented
+        ctrl |= (self.rx_it_mode as u16) << 8;
+        ctrl |= (self.tx_it as u16) << 10;
+        ctrl |= (self.rx_it as u16) << 11;
         ctrl |= (self.dsr_it as u16) << 12;
         ctrl |= (self.target as u16) << 13;
 
@@ -320,19 +514,25 @@ impl PadMemCard {
         if ctrl & 0x40 != 0 {
             // Soft reset
             self.baud_div = 0;
+            self.baud_timer = 0;
             self.mode = 0;
+            self.tx_en = false;
+            self.tx_data = None;
             self.select = false;
+            self.rx_en = false;
             self.target = Target::PadMemCard1;
             self.unknown = 0;
+            self.dsr_it = false;
+            self.rx_it = false;
+            self.rx_it_mode = 0;
+            self.tx_it = false;
             self.interrupt = false;
-            self.rx_not_empty = false;
+            self.rx_fifo.clear();
             self.bus = BusState::Idle;
             // XXX since the gamepad/memory card asserts this signal
             // it actually probably shouldn't release here but it'll
             // make our state machine simpler for the time being.
             self.dsr = false;
-
-            // It doesn't seem to reset the contents of the RX FIFO.
         } else {
             if ctrl & 0x10 != 0 {
                 // Interrupt acknowledge
@@ -363,31 +563,153 @@ impl PadMemCard {
             self.tx_en = ctrl & 1 != 0;
             self.select = (ctrl >> 1) & 1 != 0;
             self.rx_en = (ctrl >> 2) & 1 != 0;
+            self.rx_it_mode = ((ctrl >> 8) & 3) as u8;
+            self.tx_it = (ctrl >> 10) & 1 != 0;
+            self.rx_it = (ctrl >> 11) & 1 != 0;
             self.dsr_it = (ctrl >> 12) & 1 != 0;
             self.target = Target::from_control(ctrl);
 
-            if self.rx_en {
-                panic!("Gamepad rx_en not implemented");
-            }
-
-            if self.dsr_it && !self.interrupt && self.dsr {
-                // Interrupt should trigger here but that really
-                // shouldn't happen I think.
-                panic!("dsr_it enabled while DSR signal is active");
+            // The interrupt conditions are level-sensitive, enabling
+            // an interrupt while its condition is already true
+            // triggers it immediately.
+            if self.dsr_it && self.dsr {
+                warn!("dsr_it enabled while DSR signal is active");
+                self.raise_interrupt(shared);
             }
 
-            if ctrl & 0xf00 != 0 {
-                // XXX add support for those interrupts
-                panic!("Unsupported gamepad interrupts: {:04x}", ctrl);
+            if self.rx_it && self.rx_fifo.len() >= 1 << self.rx_it_mode {
+                self.raise_interrupt(shared);
             }
 
             if !prev_select && self.select {
-                // XXX Should probably also check self.target, not
-                // sure how it influences the select line. I assume
-                // only the targeted slot is selected?
-                self.pad1.select();
+                // XXX I assume only the targeted slot is selected?
+                let (pad, memcard, multitap) =
+                    match self.target {
+                        Target::PadMemCard1 => (&mut self.pad1,
+                                                &mut self.memcard1,
+                                                &mut self.multitap1),
+                        Target::PadMemCard2 => (&mut self.pad2,
+                                                &mut self.memcard2,
+                                                &mut self.multitap2),
+                    };
+
+                let info = shared.display_info();
+
+                match *multitap {
+                    Some(ref mut tap) => {
+                        for pad in tap.gamepads_mut().iter_mut() {
+                            pad.profile_mut().set_display_info(info);
+                        }
+
+                        tap.select();
+                    }
+                    None => {
+                        pad.profile_mut().set_display_info(info);
+                        pad.select();
+                        memcard.select();
+                    }
+                }
             }
+
+            // We might have a byte waiting for `tx_en`
+            self.maybe_start_transfer(shared);
         }
+
+        self.predict_next_sync(shared);
+    }
+}
+
+/// Send a command byte to a pad and memory card pair sharing the same
+/// lines. Returns the response byte, the DSR state and the delay
+/// before the DSR pulse.
+fn bus_send_command(pad: &mut GamePad,
+                    memcard: &mut MemoryCard,
+                    cmd: u8) -> (u8, bool, Cycles) {
+    // Both devices receive the command byte and only the one being
+    // addressed (the first byte is 0x01 for pads, 0x81 for memory
+    // cards) should reply. The data line is open collector so the
+    // responses are ANDed together and DSR is asserted if either
+    // device pulls it.
+    let (pad_response, pad_dsr) = pad.send_command(cmd);
+    let (card_response, card_dsr) = memcard.send_command(cmd);
+
+    // Memory cards take longer to assert DSR
+    let dsr_delay =
+        if card_dsr {
+            memcard::DSR_DELAY
+        } else {
+            0
+        };
+
+    (pad_response & card_response, pad_dsr || card_dsr, dsr_delay)
+}
+
+/// Serial receive FIFO
+#[derive(RustcDecodable, RustcEncodable)]
+struct RxFifo {
+    /// Circular buffer
+    buffer: [u8; 8],
+    /// Index of the oldest entry
+    read_idx: u8,
+    /// Number of bytes in the FIFO
+    len: u8,
+}
+
+impl RxFifo {
+    fn new() -> RxFifo {
+        RxFifo {
+            buffer: [0; 8],
+            read_idx: 0,
+            len: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn len(&self) -> u8 {
+        self.len
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Push a new byte. If the FIFO is full the most recent entry is
+    /// overwritten and we return false.
+    fn push(&mut self, b: u8) -> bool {
+        let full = self.len as usize == self.buffer.len();
+
+        if !full {
+            self.len += 1;
+        }
+
+        let idx = (self.read_idx + self.len - 1) as usize % self.buffer.len();
+
+        self.buffer[idx] = b;
+
+        !full
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let b = self.buffer[self.read_idx as usize];
+
+        self.read_idx = (self.read_idx + 1) % self.buffer.len() as u8;
+        self.len -= 1;
+
+        Some(b)
+    }
+
+    /// Return the entry `n` positions after the oldest one without
+    /// popping anything. If there aren't enough bytes in the FIFO
+    /// stale data is returned.
+    fn peek(&self, n: usize) -> u8 {
+        self.buffer[(self.read_idx as usize + n) % self.buffer.len()]
     }
 }
 
@@ -429,3 +751,293 @@ impl BusState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use memory::{HalfWord, Word};
+    use shared::SharedState;
+    use timekeeper::Cycles;
+
+    use super::{PadMemCard, RxFifo};
+    use super::gamepad::DigitalProfile;
+
+    /// Duration of a byte transfer with the configuration set by
+    /// `setup`: 8 bits, 0x88 cycles per bit
+    const BYTE_CYCLES: Cycles = 8 * 0x88;
+
+    /// Create a controller configured for 8bit characters with a
+    /// reload factor of 1 and a baudrate divider of 0x88
+    fn setup() -> (PadMemCard, SharedState) {
+        let mut pad = PadMemCard::new();
+        let mut shared = SharedState::new();
+
+        pad.store::<HalfWord>(&mut shared, 8, 0x0d);
+        pad.store::<HalfWord>(&mut shared, 14, 0x88);
+
+        (pad, shared)
+    }
+
+    fn stat(pad: &mut PadMemCard, shared: &mut SharedState) -> u32 {
+        pad.load::<Word>(shared, 4)
+    }
+
+    fn control(pad: &mut PadMemCard, shared: &mut SharedState, ctrl: u32) {
+        pad.store::<HalfWord>(shared, 10, ctrl);
+    }
+
+    fn send(pad: &mut PadMemCard, shared: &mut SharedState, b: u8) {
+        pad.store::<Word>(shared, 0, b as u32);
+    }
+
+    fn run(pad: &mut PadMemCard, shared: &mut SharedState, cycles: Cycles) {
+        shared.tk().tick(cycles);
+        pad.sync(shared);
+    }
+
+    /// Return true if the controller interrupt is asserted in the
+    /// interrupt controller
+    fn irq(shared: &mut SharedState) -> bool {
+        shared.irq_state().status() & (1 << 7) != 0
+    }
+
+    #[test]
+    fn rx_fifo() {
+        let mut fifo = RxFifo::new();
+
+        assert!(fifo.is_empty());
+        assert_eq!(fifo.pop(), None);
+
+        for b in 0..8 {
+            assert!(fifo.push(b));
+        }
+
+        assert_eq!(fifo.len(), 8);
+
+        // Overflow overwrites the most recent entry
+        assert!(!fifo.push(0xff));
+        assert_eq!(fifo.len(), 8);
+
+        assert_eq!(fifo.peek(0), 0);
+        assert_eq!(fifo.peek(3), 3);
+        assert_eq!(fifo.peek(7), 0xff);
+
+        assert_eq!(fifo.pop(), Some(0));
+        assert_eq!(fifo.pop(), Some(1));
+
+        // Wrap around
+        assert!(fifo.push(0x10));
+        assert!(fifo.push(0x11));
+        assert!(!fifo.push(0x12));
+
+        let mut v = Vec::new();
+
+        while let Some(b) = fifo.pop() {
+            v.push(b);
+        }
+
+        assert_eq!(v, vec![2, 3, 4, 5, 6, 0xff, 0x10, 0x12]);
+
+        fifo.push(0x20);
+        fifo.clear();
+        assert!(fifo.is_empty());
+    }
+
+    #[test]
+    fn rx_data_register() {
+        let (mut pad, mut shared) = setup();
+
+        // Nothing is connected, we receive 0xff for every byte
+        // since select is asserted
+        control(&mut pad, &mut shared, 0x03);
+
+        for b in 0..3 {
+            send(&mut pad, &mut shared, b);
+            run(&mut pad, &mut shared, BYTE_CYCLES);
+        }
+
+        assert_eq!(stat(&mut pad, &mut shared) & 2, 2);
+
+        // Wide accesses return the next entries in the high bytes but
+        // only pop one byte
+        pad.rx_fifo.clear();
+        pad.rx_fifo.push(0x11);
+        pad.rx_fifo.push(0x22);
+        pad.rx_fifo.push(0x33);
+
+        assert_eq!(pad.load::<Word>(&mut shared, 0) & 0xffffff, 0x332211);
+        assert_eq!(pad.load::<HalfWord>(&mut shared, 0), 0x3322);
+        assert_eq!(stat(&mut pad, &mut shared) & 2, 2);
+        assert_eq!(pad.load::<HalfWord>(&mut shared, 0) & 0xff, 0x33);
+        assert_eq!(stat(&mut pad, &mut shared) & 2, 0);
+
+        // Without select or rx_en nothing is received
+        control(&mut pad, &mut shared, 0x01);
+        send(&mut pad, &mut shared, 0x01);
+        run(&mut pad, &mut shared, BYTE_CYCLES);
+        assert_eq!(stat(&mut pad, &mut shared) & 2, 0);
+
+        // rx_en forces the reception of a single byte
+        control(&mut pad, &mut shared, 0x05);
+        send(&mut pad, &mut shared, 0x01);
+        send(&mut pad, &mut shared, 0x01);
+        run(&mut pad, &mut shared, 2 * BYTE_CYCLES);
+        assert_eq!(pad.rx_fifo.len(), 1);
+        assert_eq!(pad.control() & 4, 0);
+    }
+
+    #[test]
+    fn tx_holding_register() {
+        let (mut pad, mut shared) = setup();
+
+        // Nothing is sent until tx_en is set
+        send(&mut pad, &mut shared, 0x01);
+        assert_eq!(stat(&mut pad, &mut shared) & 5, 0);
+
+        run(&mut pad, &mut shared, 10 * BYTE_CYCLES);
+        assert_eq!(stat(&mut pad, &mut shared) & 5, 0);
+
+        // The transfer starts, the holding register is free
+        control(&mut pad, &mut shared, 0x01);
+        assert_eq!(stat(&mut pad, &mut shared) & 5, 1);
+
+        send(&mut pad, &mut shared, 0x42);
+        assert_eq!(stat(&mut pad, &mut shared) & 5, 0);
+
+        run(&mut pad, &mut shared, BYTE_CYCLES - 1);
+        assert_eq!(stat(&mut pad, &mut shared) & 5, 0);
+
+        // The second byte is sent as soon as the first one is done
+        run(&mut pad, &mut shared, 1);
+        assert_eq!(stat(&mut pad, &mut shared) & 5, 1);
+
+        run(&mut pad, &mut shared, BYTE_CYCLES);
+        assert_eq!(stat(&mut pad, &mut shared) & 5, 5);
+
+        // Same thing when both transfers end within the same sync,
+        // the second transfer starts when the bus becomes idle
+        send(&mut pad, &mut shared, 0x01);
+        send(&mut pad, &mut shared, 0x42);
+        run(&mut pad, &mut shared, BYTE_CYCLES + 100);
+        assert_eq!(stat(&mut pad, &mut shared) & 5, 1);
+
+        run(&mut pad, &mut shared, BYTE_CYCLES - 101);
+        assert_eq!(stat(&mut pad, &mut shared) & 5, 1);
+
+        run(&mut pad, &mut shared, 1);
+        assert_eq!(stat(&mut pad, &mut shared) & 5, 5);
+
+        // The TX interrupt triggers when the holding register is
+        // emptied
+        control(&mut pad, &mut shared, 0x401);
+        assert_eq!(stat(&mut pad, &mut shared) & 0x200, 0);
+
+        send(&mut pad, &mut shared, 0x01);
+        assert_eq!(stat(&mut pad, &mut shared) & 0x200, 0x200);
+        assert!(irq(&mut shared));
+    }
+
+    #[test]
+    fn baud_timer() {
+        let (mut pad, mut shared) = setup();
+
+        // Half the baudrate period
+        assert_eq!(stat(&mut pad, &mut shared) >> 11, 0x44);
+
+        run(&mut pad, &mut shared, 0x10);
+        assert_eq!(stat(&mut pad, &mut shared) >> 11, 0x34);
+
+        // Reloaded when it reaches 0
+        run(&mut pad, &mut shared, 0x34);
+        assert_eq!(stat(&mut pad, &mut shared) >> 11, 0x44);
+
+        run(&mut pad, &mut shared, 0x44 * 3 + 4);
+        assert_eq!(stat(&mut pad, &mut shared) >> 11, 0x40);
+
+        // The reload factor only takes effect when the divider is
+        // written
+        pad.store::<HalfWord>(&mut shared, 8, 0x0e);
+        assert_eq!(stat(&mut pad, &mut shared) >> 11, 0x40);
+
+        pad.store::<HalfWord>(&mut shared, 14, 0x88);
+        assert_eq!(stat(&mut pad, &mut shared) >> 11, 0x440);
+
+        // Soft reset
+        control(&mut pad, &mut shared, 0x40);
+        assert_eq!(stat(&mut pad, &mut shared) >> 11, 0);
+        assert_eq!(pad.load::<HalfWord>(&mut shared, 14), 0);
+    }
+
+    #[test]
+    fn stat_dsr() {
+        let (mut pad, mut shared) = setup();
+
+        pad.gamepads_mut()[0].set_profile(Box::new(DigitalProfile::new()));
+
+        // tx_en, select and dsr_it
+        control(&mut pad, &mut shared, 0x1003);
+
+        // TX ready 1 and 2
+        assert_eq!(stat(&mut pad, &mut shared), 0x44 << 11 | 5);
+
+        send(&mut pad, &mut shared, 0x01);
+        run(&mut pad, &mut shared, BYTE_CYCLES);
+
+        // The pad asserts DSR, we get an interrupt and the response
+        // in the FIFO
+        let s = stat(&mut pad, &mut shared);
+
+        assert_eq!(s & 0x80, 0x80);
+        assert_eq!(s & 0x200, 0x200);
+        assert_eq!(s & 7, 7);
+        assert!(irq(&mut shared));
+
+        assert_eq!(pad.load::<Word>(&mut shared, 0) & 0xff, 0xff);
+
+        // End of the DSR pulse
+        run(&mut pad, &mut shared, 10);
+        assert_eq!(stat(&mut pad, &mut shared) & 0x282, 0x200);
+
+        // Acknowledge
+        control(&mut pad, &mut shared, 0x1013);
+        assert_eq!(stat(&mut pad, &mut shared) & 0x200, 0);
+        assert_eq!(pad.control() & 0x10, 0);
+
+        // No DSR without select
+        control(&mut pad, &mut shared, 0x1001);
+        send(&mut pad, &mut shared, 0x01);
+        run(&mut pad, &mut shared, BYTE_CYCLES);
+        assert_eq!(stat(&mut pad, &mut shared) & 0x280, 0);
+    }
+
+    #[test]
+    fn rx_irq_threshold() {
+        for mode in 0..4 {
+            let (mut pad, mut shared) = setup();
+
+            let threshold = 1 << mode;
+
+            // tx_en, select, rx_it and the threshold
+            control(&mut pad, &mut shared, 0x803 | (mode << 8));
+
+            for i in 1..threshold + 1 {
+                send(&mut pad, &mut shared, 0x00);
+                run(&mut pad, &mut shared, BYTE_CYCLES);
+
+                let s = stat(&mut pad, &mut shared);
+
+                assert_eq!(s & 0x200 != 0, i == threshold);
+                assert_eq!(irq(&mut shared), i == threshold);
+            }
+
+            // The condition is level-sensitive: acknowledging while
+            // the FIFO is still above the threshold re-triggers
+            control(&mut pad, &mut shared, 0x813 | (mode << 8));
+            assert_eq!(stat(&mut pad, &mut shared) & 0x200, 0x200);
+
+            pad.load::<Word>(&mut shared, 0);
+
+            control(&mut pad, &mut shared, 0x813 | (mode << 8));
+            assert_eq!(stat(&mut pad, &mut shared) & 0x200, 0);
+        }
+    }
+}
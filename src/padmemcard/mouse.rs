@@ -0,0 +1,177 @@
+//! SCPH-1030: PlayStation Mouse
+
+use super::gamepad::{Profile, ProfileState, Button, ButtonState};
+
+/// The mouse buttons use the same bits as the L1 and R1 buttons of
+/// the gamepad: `Button::R1` is the left button and `Button::L1` the
+/// right one. The frontend reports the motion with
+/// `Profile::add_relative_motion`.
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+pub struct MouseProfile {
+    /// Button state: bit 3 is the left button, bit 2 the right
+    /// button. Active low, bits 0 and 1 are always 0.
+    buttons: u8,
+    /// Horizontal motion accumulated since the last read
+    dx: i32,
+    /// Vertical motion accumulated since the last read
+    dy: i32,
+    /// Motion latched at the beginning of the current transaction
+    delta: (i8, i8),
+}
+
+impl MouseProfile {
+    pub fn new() -> MouseProfile {
+        MouseProfile {
+            buttons: 0xfc,
+            dx: 0,
+            dy: 0,
+            delta: (0, 0),
+        }
+    }
+
+    /// Return the accumulated motion clamped to the signed 8bit
+    /// range, the remainder is kept for the next read.
+    fn take_delta(&mut self) -> (i8, i8) {
+        let clamp = |v: i32| {
+            if v < -128 {
+                -128
+            } else if v > 127 {
+                127
+            } else {
+                v
+            }
+        };
+
+        let dx = clamp(self.dx);
+        let dy = clamp(self.dy);
+
+        self.dx -= dx;
+        self.dy -= dy;
+
+        (dx as i8, dy as i8)
+    }
+}
+
+impl Profile for MouseProfile {
+    fn handle_command(&mut self, seq: u8, cmd: u8) -> (u8, bool) {
+        match seq {
+            0 => (0xff, (cmd == 0x01)),
+            1 => {
+                self.delta = self.take_delta();
+
+                (0x12, (cmd == 0x42))
+            }
+            2 => (0x5a, true),
+            // No buttons in the first byte
+            3 => (0xff, true),
+            4 => (self.buttons, true),
+            5 => (self.delta.0 as u8, true),
+            // No DSR for the last byte
+            6 => (self.delta.1 as u8, false),
+            _ => (0xff, false),
+        }
+    }
+
+    fn set_button_state(&mut self, button: Button, state: ButtonState) {
+        let mask =
+            match button {
+                Button::R1 => 0x08,
+                Button::L1 => 0x04,
+                _ => return,
+            };
+
+        match state {
+            ButtonState::Pressed  => self.buttons &= !mask,
+            ButtonState::Released => self.buttons |= mask,
+        }
+    }
+
+    fn add_relative_motion(&mut self, dx: i32, dy: i32) {
+        self.dx = self.dx.saturating_add(dx);
+        self.dy = self.dy.saturating_add(dy);
+    }
+
+    fn state(&self) -> ProfileState {
+        ProfileState::Mouse(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MouseProfile;
+    use super::super::gamepad::{Profile, Button, ButtonState};
+
+    /// Poll the mouse and return the response bytes
+    fn read(mouse: &mut MouseProfile) -> Vec<u8> {
+        let cmd = [0x01, 0x42, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+        let mut reply = Vec::new();
+
+        for (seq, &b) in cmd.iter().enumerate() {
+            let (r, dsr) = mouse.handle_command(seq as u8, b);
+
+            reply.push(r);
+
+            assert_eq!(dsr, seq + 1 < cmd.len());
+        }
+
+        reply
+    }
+
+    #[test]
+    fn buttons() {
+        let mut mouse = MouseProfile::new();
+
+        assert_eq!(read(&mut mouse),
+                   vec![0xff, 0x12, 0x5a, 0xff, 0xfc, 0x00, 0x00]);
+
+        // Left button
+        mouse.set_button_state(Button::R1, ButtonState::Pressed);
+        assert_eq!(read(&mut mouse)[4], 0xf4);
+
+        // Right button
+        mouse.set_button_state(Button::L1, ButtonState::Pressed);
+        assert_eq!(read(&mut mouse)[4], 0xf0);
+
+        // Not present on the mouse
+        mouse.set_button_state(Button::Cross, ButtonState::Pressed);
+        mouse.set_button_state(Button::R1, ButtonState::Released);
+        assert_eq!(read(&mut mouse)[4], 0xf8);
+
+        // Only "read" is supported
+        assert_eq!(mouse.handle_command(0, 0x01), (0xff, true));
+        assert_eq!(mouse.handle_command(1, 0x43), (0x12, false));
+    }
+
+    #[test]
+    fn motion() {
+        let mut mouse = MouseProfile::new();
+
+        mouse.add_relative_motion(3, -4);
+        mouse.add_relative_motion(2, 1);
+
+        assert_eq!(&read(&mut mouse)[5..], &[0x05, 0xfd]);
+
+        // The motion is reset after each read
+        assert_eq!(&read(&mut mouse)[5..], &[0x00, 0x00]);
+
+        // Large motions are clamped, the remainder is reported in the
+        // next reads
+        mouse.add_relative_motion(200, -300);
+
+        assert_eq!(&read(&mut mouse)[5..], &[0x7f, 0x80]);
+        assert_eq!(&read(&mut mouse)[5..], &[0x49, 0x80]);
+        assert_eq!(&read(&mut mouse)[5..], &[0x00, 0xd4]);
+        assert_eq!(&read(&mut mouse)[5..], &[0x00, 0x00]);
+
+        // The motion is latched at the beginning of the transaction
+        mouse.handle_command(0, 0x01);
+        mouse.handle_command(1, 0x42);
+        mouse.add_relative_motion(10, 10);
+        for seq in 2..7 {
+            mouse.handle_command(seq, 0x00);
+        }
+
+        assert_eq!(&read(&mut mouse)[5..], &[0x0a, 0x0a]);
+    }
+}
@@ -0,0 +1,354 @@
+//! SCPH-1070 Multitap emulation.
+//!
+//! The multitap plugs into one of the controller ports and provides
+//! four pad/memory card slots A to D. The slots can be addressed
+//! individually by changing the low nibble of the address byte
+//! (`0x01`-`0x04` for the pads, `0x81`-`0x84` for the memory cards),
+//! the multitap then forwards the transaction to the target slot.
+//!
+//! The multitap also has a special "multitap mode" used to read all
+//! four pads in a single transaction: when the 3rd byte of a pad
+//! transaction (usually ignored by the controllers) is `0x01` the
+//! next transaction addressed to `0x01` returns `0x80 0x5a` followed
+//! by four 8-byte controller replies.
+//!
+//! In multitap mode the command bytes for the four slots are
+//! buffered and only sent to the controllers at the beginning of the
+//! next multitap mode transaction, the replies are therefore always
+//! one transaction late.
+
+use timekeeper::Cycles;
+
+use super::gamepad::GamePad;
+use super::memcard::MemoryCard;
+use super::bus_send_command;
+
+/// Number of bytes reserved for each controller in multitap mode
+const SLOT_REPLY_LEN: u8 = 8;
+
+/// Commands sent to each slot in multitap mode until the game sends
+/// its own: read buttons
+const DEFAULT_SLOT_COMMAND: [u8; SLOT_REPLY_LEN as usize] =
+    [0x42, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct Multitap {
+    /// Gamepads in slots A to D
+    pads: [GamePad; 4],
+    /// Memory cards in slots A to D
+    memcards: [MemoryCard; 4],
+    /// Counter keeping track of the current position in the
+    /// transaction
+    seq: u8,
+    /// False if the multitap is done processing the current
+    /// transaction
+    active: bool,
+    /// Slot addressed by the current transaction, `None` if we're in
+    /// multitap mode
+    slot: Option<usize>,
+    /// True if the current transaction is in multitap mode
+    multitap_mode: bool,
+    /// Multitap mode requested for the next transaction
+    next_multitap_mode: bool,
+    /// True if the current transaction targets a pad (as opposed to a
+    /// memory card)
+    pad_access: bool,
+    /// Command bytes received for each slot during the last multitap
+    /// mode transaction
+    slot_commands: [[u8; SLOT_REPLY_LEN as usize]; 4],
+    /// Replies of each slot for the current multitap mode transaction
+    slot_replies: [[u8; SLOT_REPLY_LEN as usize]; 4],
+}
+
+impl Multitap {
+    /// Create a multitap with nothing plugged in
+    pub fn new() -> Multitap {
+        Multitap {
+            pads: [GamePad::disconnected(),
+                   GamePad::disconnected(),
+                   GamePad::disconnected(),
+                   GamePad::disconnected()],
+            memcards: [MemoryCard::disconnected(),
+                       MemoryCard::disconnected(),
+                       MemoryCard::disconnected(),
+                       MemoryCard::disconnected()],
+            seq: 0,
+            active: false,
+            slot: None,
+            multitap_mode: false,
+            next_multitap_mode: false,
+            pad_access: false,
+            slot_commands: [DEFAULT_SLOT_COMMAND; 4],
+            slot_replies: [[0xff; SLOT_REPLY_LEN as usize]; 4],
+        }
+    }
+
+    /// Return a mutable reference to the gamepads in slots A to D
+    pub fn gamepads_mut(&mut self) -> [&mut GamePad; 4] {
+        let (ab, cd) = self.pads.split_at_mut(2);
+        let (a, b) = ab.split_at_mut(1);
+        let (c, d) = cd.split_at_mut(1);
+
+        [ &mut a[0], &mut b[0], &mut c[0], &mut d[0] ]
+    }
+
+    /// Return a mutable reference to the memory cards in slots A to D
+    pub fn memory_cards_mut(&mut self) -> [&mut MemoryCard; 4] {
+        let (ab, cd) = self.memcards.split_at_mut(2);
+        let (a, b) = ab.split_at_mut(1);
+        let (c, d) = cd.split_at_mut(1);
+
+        [ &mut a[0], &mut b[0], &mut c[0], &mut d[0] ]
+    }
+
+    /// Called when the "select" line goes down.
+    pub fn select(&mut self) {
+        self.active = true;
+        self.seq = 0;
+        self.slot = None;
+        self.multitap_mode = false;
+        self.pad_access = false;
+    }
+
+    /// Handle a command byte. Returns the response byte, the DSR
+    /// state and the DSR delay like `bus_send_command`.
+    pub fn send_command(&mut self, cmd: u8) -> (u8, bool, Cycles) {
+        if !self.active {
+            return (0xff, false, 0);
+        }
+
+        let (response, dsr, delay) =
+            if self.seq == 0 {
+                self.address(cmd)
+            } else if self.multitap_mode {
+                self.multitap_mode_command(cmd)
+            } else {
+                self.forward(cmd)
+            };
+
+        self.active = dsr;
+        self.seq += 1;
+
+        (response, dsr, delay)
+    }
+
+    /// Handle the address byte
+    fn address(&mut self, cmd: u8) -> (u8, bool, Cycles) {
+        if cmd == 0x01 && self.next_multitap_mode {
+            self.multitap_mode = true;
+
+            return (0xff, true, 0);
+        }
+
+        let slot = (cmd & 0xf) as usize;
+
+        if slot < 1 || slot > 4 {
+            return (0xff, false, 0);
+        }
+
+        let slot = slot - 1;
+
+        self.slot = Some(slot);
+        self.pad_access = cmd & 0xf0 == 0;
+
+        self.pads[slot].select();
+        self.memcards[slot].select();
+
+        // The devices behind the multitap only see the regular slot 1
+        // addresses
+        bus_send_command(&mut self.pads[slot],
+                         &mut self.memcards[slot],
+                         (cmd & 0xf0) | 0x01)
+    }
+
+    /// Forward a command byte to the addressed slot
+    fn forward(&mut self, cmd: u8) -> (u8, bool, Cycles) {
+        let slot =
+            match self.slot {
+                Some(s) => s,
+                None => return (0xff, false, 0),
+            };
+
+        if self.seq == 2 && self.pad_access {
+            // The multitap snoops the "TAP" byte of pad commands
+            self.next_multitap_mode = cmd == 0x01;
+        }
+
+        bus_send_command(&mut self.pads[slot], &mut self.memcards[slot], cmd)
+    }
+
+    /// Handle a command byte in multitap mode. The replies of each
+    /// slot are padded to `SLOT_REPLY_LEN` bytes.
+    fn multitap_mode_command(&mut self, cmd: u8) -> (u8, bool, Cycles) {
+        match self.seq {
+            // Multitap ID
+            1 => (0x80, true, 0),
+            2 => {
+                self.next_multitap_mode = cmd == 0x01;
+
+                // Run the commands buffered during the previous
+                // transaction
+                self.poll_slots();
+
+                (0x5a, true, 0)
+            }
+            n => {
+                let n = n - 3;
+
+                let slot = (n / SLOT_REPLY_LEN) as usize;
+                let pos = (n % SLOT_REPLY_LEN) as usize;
+
+                if slot >= 4 {
+                    return (0xff, false, 0);
+                }
+
+                self.slot_commands[slot][pos] = cmd;
+
+                // DSR for every byte but the last of the last slot
+                let last = slot == 3 && pos == SLOT_REPLY_LEN as usize - 1;
+
+                (self.slot_replies[slot][pos], !last, 0)
+            }
+        }
+    }
+
+    /// Send the buffered commands to the controller in each slot and
+    /// store their replies
+    fn poll_slots(&mut self) {
+        for (slot, pad) in self.pads.iter_mut().enumerate() {
+            pad.select();
+            pad.send_command(0x01);
+
+            let commands = &self.slot_commands[slot];
+            let replies = &mut self.slot_replies[slot];
+
+            for (&cmd, reply) in commands.iter().zip(replies.iter_mut()) {
+                *reply = pad.send_command(cmd).0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Multitap;
+    use super::super::gamepad::{DigitalProfile, DualShockProfile, Profile};
+    use super::super::gamepad::{Button, ButtonState};
+    use super::super::memcard::MemoryCard;
+
+    /// Run a full transaction and return the response bytes
+    fn transfer(tap: &mut Multitap, cmd: &[u8]) -> Vec<u8> {
+        tap.select();
+
+        cmd.iter().map(|&b| tap.send_command(b).0).collect()
+    }
+
+    fn pad(button: Button) -> Box<Profile> {
+        let mut pad = DigitalProfile::new();
+
+        pad.set_button_state(button, ButtonState::Pressed);
+
+        Box::new(pad)
+    }
+
+    /// Multitap mode transaction with the same command for all the
+    /// slots
+    fn read_all(tap: &mut Multitap, slot_cmd: [u8; 8]) -> Vec<u8> {
+        let mut cmd = vec![0x01, 0x42, 0x01];
+
+        for _ in 0..4 {
+            cmd.extend_from_slice(&slot_cmd);
+        }
+
+        transfer(tap, &cmd)
+    }
+
+    #[test]
+    fn slot_addressing() {
+        let mut tap = Multitap::new();
+
+        tap.gamepads_mut()[2].set_profile(pad(Button::Start));
+
+        // Slot C
+        assert_eq!(transfer(&mut tap, &[0x03, 0x42, 0x00, 0x00, 0x00]),
+                   vec![0xff, 0x41, 0x5a, 0xf7, 0xff]);
+
+        // Slot A is empty
+        assert_eq!(transfer(&mut tap, &[0x01, 0x42]), vec![0xff, 0xff]);
+
+        // Invalid slots
+        assert_eq!(transfer(&mut tap, &[0x00, 0x42]), vec![0xff, 0xff]);
+        assert_eq!(transfer(&mut tap, &[0x05, 0x42]), vec![0xff, 0xff]);
+    }
+
+    #[test]
+    fn memory_card_addressing() {
+        let mut tap = Multitap::new();
+
+        *tap.memory_cards_mut()[1] = MemoryCard::formatted();
+
+        let get_id = [0x82, b'S', 0, 0, 0, 0, 0, 0, 0, 0];
+
+        assert_eq!(transfer(&mut tap, &get_id),
+                   vec![0xff, 0x08, 0x5a, 0x5d, 0x5c, 0x5d,
+                        0x04, 0x00, 0x00, 0x80]);
+
+        // The DSR delay of the memory card is reported
+        tap.select();
+
+        assert!(tap.send_command(0x82).2 > 0);
+
+        // No card in the other slots
+        assert_eq!(transfer(&mut tap, &[0x81, b'S', 0]),
+                   vec![0xff, 0xff, 0xff]);
+        assert_eq!(transfer(&mut tap, &[0x84, b'S', 0]),
+                   vec![0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn multitap_mode() {
+        let mut tap = Multitap::new();
+
+        tap.gamepads_mut()[0].set_profile(pad(Button::Start));
+        tap.gamepads_mut()[2].set_profile(pad(Button::Cross));
+        tap.gamepads_mut()[3].set_profile(Box::new(DualShockProfile::new()));
+
+        let poll = [0x42, 0, 0, 0, 0, 0, 0, 0];
+
+        // Without the TAP byte in the previous transaction we talk to
+        // slot A
+        assert_eq!(read_all(&mut tap, poll)[..5],
+                   [0xff, 0x41, 0x5a, 0xf7, 0xff]);
+
+        // Multitap mode, the TAP byte was set by the previous
+        // transaction
+        let reply = read_all(&mut tap, poll);
+
+        assert_eq!(reply.len(), 3 + 4 * 8);
+        assert_eq!(reply[..3], [0xff, 0x80, 0x5a]);
+        // Slot A
+        assert_eq!(reply[3..11],
+                   [0x41, 0x5a, 0xf7, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        // Slot B is empty
+        assert_eq!(reply[11..19], [0xff; 8]);
+        // Slot C
+        assert_eq!(reply[19..27],
+                   [0x41, 0x5a, 0xff, 0xbf, 0xff, 0xff, 0xff, 0xff]);
+        // Slot D
+        assert_eq!(reply[27..35],
+                   [0x41, 0x5a, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+
+        // Ask all the pads to enter config mode. The commands are
+        // only sent to the controllers during the next transaction.
+        read_all(&mut tap, [0x43, 0x00, 0x01, 0, 0, 0, 0, 0]);
+
+        let reply = read_all(&mut tap, poll);
+
+        assert_eq!(reply[27..29], [0x41, 0x5a]);
+
+        // The DualShock is now in config mode
+        let reply = read_all(&mut tap, poll);
+
+        assert_eq!(reply[27..31], [0xf3, 0x5a, 0xff, 0xff]);
+    }
+}
@@ -0,0 +1,177 @@
+//! NPC-101: Namco NeGcon
+
+use super::gamepad::{Profile, ProfileState, Button, ButtonState, Axis};
+
+/// The controller twists in the middle, it has three analog buttons
+/// (I, II and L) and a handful of digital ones.
+///
+/// The twist is set with `Axis::LeftX`, the analog buttons with
+/// `set_button_pressure`: `Button::Cross` for I, `Button::Square` for
+/// II and `Button::L1` for L. The A and B buttons are
+/// `Button::Circle` and `Button::Triangle`.
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+pub struct NeGconProfile {
+    /// Digital buttons, active low
+    buttons: u16,
+    /// Twist position, 0x80 is centered
+    twist: u8,
+    /// Pressure on the I button
+    i: u8,
+    /// Pressure on the II button
+    ii: u8,
+    /// Pressure on the L button
+    l: u8,
+}
+
+impl NeGconProfile {
+    pub fn new() -> NeGconProfile {
+        NeGconProfile {
+            buttons: 0xffff,
+            twist: 0x80,
+            i: 0,
+            ii: 0,
+            l: 0,
+        }
+    }
+}
+
+impl Profile for NeGconProfile {
+    fn handle_command(&mut self, seq: u8, cmd: u8) -> (u8, bool) {
+        match seq {
+            0 => (0xff, (cmd == 0x01)),
+            1 => (0x23, (cmd == 0x42)),
+            2 => (0x5a, true),
+            3 => (self.buttons as u8, true),
+            4 => ((self.buttons >> 8) as u8, true),
+            5 => (self.twist, true),
+            6 => (self.i, true),
+            7 => (self.ii, true),
+            // No DSR for the last byte
+            8 => (self.l, false),
+            _ => (0xff, false),
+        }
+    }
+
+    fn set_button_state(&mut self, button: Button, state: ButtonState) {
+        let pressure =
+            match state {
+                ButtonState::Pressed => 0xff,
+                ButtonState::Released => 0,
+            };
+
+        self.set_button_pressure(button, pressure);
+    }
+
+    fn set_button_pressure(&mut self, button: Button, pressure: u8) {
+        match button {
+            Button::Cross => self.i = pressure,
+            Button::Square => self.ii = pressure,
+            Button::L1 => self.l = pressure,
+            Button::Start | Button::DUp | Button::DRight | Button::DDown |
+            Button::DLeft | Button::R1 | Button::Circle | Button::Triangle => {
+                let mask = 1 << (button as usize);
+
+                if pressure > 0 {
+                    self.buttons &= !mask;
+                } else {
+                    self.buttons |= mask;
+                }
+            }
+            // Not present on the NeGcon
+            _ => (),
+        }
+    }
+
+    fn set_axis_state(&mut self, axis: Axis, value: u8) {
+        if let Axis::LeftX = axis {
+            self.twist = value;
+        }
+    }
+
+    fn state(&self) -> ProfileState {
+        ProfileState::NeGcon(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NeGconProfile;
+    use super::super::gamepad::{Profile, Button, ButtonState, Axis};
+
+    /// Poll the controller and return the response bytes
+    fn read(pad: &mut NeGconProfile) -> Vec<u8> {
+        let cmd = [0x01, 0x42, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+        let mut reply = Vec::new();
+
+        for (seq, &b) in cmd.iter().enumerate() {
+            let (r, dsr) = pad.handle_command(seq as u8, b);
+
+            reply.push(r);
+
+            assert_eq!(dsr, seq + 1 < cmd.len());
+        }
+
+        reply
+    }
+
+    #[test]
+    fn reply() {
+        let mut pad = NeGconProfile::new();
+
+        assert_eq!(read(&mut pad),
+                   vec![0xff, 0x23, 0x5a, 0xff, 0xff,
+                        0x80, 0x00, 0x00, 0x00]);
+
+        // Digital buttons
+        pad.set_button_state(Button::Start, ButtonState::Pressed);
+        pad.set_button_state(Button::Circle, ButtonState::Pressed);
+        pad.set_button_state(Button::Triangle, ButtonState::Pressed);
+        pad.set_button_state(Button::R1, ButtonState::Pressed);
+
+        // Not present on the NeGcon
+        pad.set_button_state(Button::Select, ButtonState::Pressed);
+        pad.set_button_state(Button::L2, ButtonState::Pressed);
+        pad.set_button_state(Button::Analog, ButtonState::Pressed);
+
+        assert_eq!(&read(&mut pad)[3..5], &[0xf7, 0xc7]);
+
+        // Analog buttons
+        pad.set_button_state(Button::Cross, ButtonState::Pressed);
+        pad.set_button_pressure(Button::Square, 0x40);
+        pad.set_button_pressure(Button::L1, 0x20);
+
+        assert_eq!(&read(&mut pad)[6..], &[0xff, 0x40, 0x20]);
+
+        // The analog buttons don't show in the digital state
+        assert_eq!(&read(&mut pad)[3..5], &[0xf7, 0xc7]);
+
+        pad.set_button_state(Button::Cross, ButtonState::Released);
+        pad.set_button_pressure(Button::Circle, 0);
+
+        assert_eq!(&read(&mut pad)[3..], &[0xf7, 0xe7,
+                                           0x80, 0x00, 0x40, 0x20]);
+
+        // Only "read" is supported
+        assert_eq!(pad.handle_command(0, 0x01), (0xff, true));
+        assert_eq!(pad.handle_command(1, 0x43), (0x23, false));
+    }
+
+    #[test]
+    fn twist() {
+        let mut pad = NeGconProfile::new();
+
+        pad.set_axis_state(Axis::LeftX, 0x00);
+        assert_eq!(read(&mut pad)[5], 0x00);
+
+        pad.set_axis_state(Axis::LeftX, 0xff);
+        assert_eq!(read(&mut pad)[5], 0xff);
+
+        // Only the twist is present
+        pad.set_axis_state(Axis::LeftY, 0x10);
+        pad.set_axis_state(Axis::RightX, 0x20);
+        pad.set_axis_state(Axis::RightY, 0x30);
+
+        assert_eq!(&read(&mut pad)[5..], &[0xff, 0x00, 0x00, 0x00]);
+    }
+}
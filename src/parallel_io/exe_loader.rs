@@ -7,6 +7,7 @@ use std::fs::File;
 use std::io;
 
 use cdrom::disc::Region;
+use cdrom::iso9660;
 use bios::Bios;
 use assembler::Assembler;
 use assembler::syntax::*;
@@ -301,6 +302,42 @@ impl ExeLoader {
         self.region
     }
 
+    /// Return the executable entry point
+    pub fn entry(&self) -> u32 {
+        self.entry
+    }
+
+    /// Return the address where the "text" section must be loaded
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    /// Return the "text" section of the executable
+    pub fn text(&self) -> &[u8] {
+        &self.text
+    }
+
+    /// Return the initial values of the GP and SP registers
+    pub fn initial_gp_sp(&self) -> (u32, u32) {
+        (self.initial_gp, self.initial_sp)
+    }
+
+    /// Return the base address and length of the 0-filled area
+    pub fn memfill(&self) -> (u32, u32) {
+        (self.memfill_base, self.memfill_len)
+    }
+
+    /// Set the initial stack pointer to `sp` if the executable header
+    /// doesn't specify one. That's what the BIOS does with the STACK
+    /// value from SYSTEM.CNF.
+    pub fn set_default_sp(&mut self, sp: u32) {
+        if self.initial_sp == 0 {
+            self.initial_sp = sp;
+            // Regenerate the loader with the new SP
+            self.assemble_loader();
+        }
+    }
+
     /// Patch the BIOS animation jump to run the loader code
     /// instead. Returns an error if the patching failed.
     pub fn patch_bios(&self, bios: &mut Bios) -> Result<(), ()> {
@@ -364,6 +401,11 @@ pub enum Error {
     UnknownFormat,
     /// The program is anormaly large
     TooBig(u32),
+    /// Error while loading the executable from a disc image
+    Iso9660(iso9660::Error),
+    /// The disc's SYSTEM.CNF isn't available, for instance because
+    /// the disc was restored from a savestate
+    MissingSystemCnf,
 }
 
 impl From<io::Error> for Error {
@@ -372,6 +414,49 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<iso9660::Error> for Error {
+    fn from(err: iso9660::Error) -> Error {
+        Error::Iso9660(err)
+    }
+}
+
+/// Build a PS-X EXE image containing `text` loaded at `base` with the
+/// entry point `entry`. The GP, SP and memfill fields are left
+/// empty. Mainly meant to package the output of the `Assembler` for
+/// tests.
+pub fn build_exe(base: u32, entry: u32, text: &[u8]) -> Vec<u8> {
+    // The text section is padded to a multiple of the header size
+    let text_len = (text.len() + HEADER_LEN - 1) & !(HEADER_LEN - 1);
+
+    let mut exe = Vec::with_capacity(HEADER_LEN + text_len);
+
+    exe.extend_from_slice(b"PS-X EXE\0\0\0\0\0\0\0\0");
+
+    // Entry, GP, base, text length, 2 unused words, memfill base and
+    // length, SP base and offset
+    let header = [entry, 0, base, text_len as u32, 0, 0, 0, 0, 0, 0];
+
+    for &w in &header {
+        for i in 0..4 {
+            exe.push((w >> (i * 8)) as u8);
+        }
+    }
+
+    // Padding
+    exe.extend_from_slice(&[0; 20]);
+
+    exe.extend_from_slice(
+        b"Sony Computer Entertainment Inc. for North America area");
+
+    exe.resize(HEADER_LEN, 0);
+
+    exe.extend_from_slice(text);
+
+    exe.resize(HEADER_LEN + text_len, 0);
+
+    exe
+}
+
 fn read_u32(r: &mut io::Read) -> Result<u32, io::Error> {
     let mut b = [0; 4];
 
@@ -383,6 +468,10 @@ fn read_u32(r: &mut io::Read) -> Result<u32, io::Error> {
        | ((b[3] as u32) << 24))
 }
 
+/// Length of the PS-X EXE header. The text section starts right after
+/// it.
+const HEADER_LEN: usize = 2048;
+
 /// Offset of the register containing the machine code FIFO for
 /// loading the EXE
 const EXE_FIFO_OFFSET: u32 = 0x100;
@@ -1,5 +1,6 @@
 use timekeeper::TimeKeeper;
 use interrupt::InterruptState;
+use gpu::DisplayInfo;
 
 /// State shared between various modules
 #[derive(RustcDecodable, RustcEncodable)]
@@ -7,6 +8,12 @@ pub struct SharedState {
     tk: TimeKeeper,
     irq_state: InterruptState,
     counters: Counters,
+    /// Beam position `(dotclock, line)` the lightgun is aimed at, if
+    /// any. The GPU triggers IRQ10 when the beam reaches it.
+    lightgun_target: Option<(u16, u16)>,
+    /// Display configuration set by the GPU, used by the lightguns
+    /// to convert screen coordinates into beam positions
+    display_info: DisplayInfo,
 }
 
 impl SharedState {
@@ -15,6 +22,8 @@ impl SharedState {
             tk: TimeKeeper::new(),
             irq_state: InterruptState::new(),
             counters: Counters::new(),
+            lightgun_target: None,
+            display_info: DisplayInfo::new(),
         }
     }
 
@@ -37,6 +46,22 @@ impl SharedState {
     pub fn counters_mut(&mut self) -> &mut Counters {
         &mut self.counters
     }
+
+    pub fn lightgun_target(&self) -> Option<(u16, u16)> {
+        self.lightgun_target
+    }
+
+    pub fn set_lightgun_target(&mut self, target: Option<(u16, u16)>) {
+        self.lightgun_target = target;
+    }
+
+    pub fn display_info(&self) -> &DisplayInfo {
+        &self.display_info
+    }
+
+    pub fn set_display_info(&mut self, info: DisplayInfo) {
+        self.display_info = info;
+    }
 }
 
 /// Struct holding various counters for debugging and profiling
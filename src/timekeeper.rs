@@ -101,6 +101,12 @@ impl TimeKeeper {
         self.timesheets[who as usize].set_next_sync(Cycles::max_value());
     }
 
+    /// Return the number of cycles until the next sync, 0 if a sync
+    /// is already pending
+    pub fn cycles_until_sync(&self) -> Cycles {
+        self.next_sync.saturating_sub(self.now)
+    }
+
     pub fn sync_pending(&self) -> bool{
         self.next_sync <= self.now
     }